@@ -3,6 +3,7 @@ use std::time;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use chimerai::llm::openai::OpenaiLlmClient;
+use chimerai::tools::{apply_arithmetic, Arithmetic, ToolArgs};
 use chimerai::Tool;
 use chimerai::{
     memory::{MemoryEntry, MemoryQuery},
@@ -15,14 +16,14 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-struct LTM {}
+struct Ltm {}
 
-struct STM {
+struct Stm {
     messages: Vec<Message>,
 }
 
 #[async_trait]
-impl LongTermMemory for LTM {
+impl LongTermMemory for Ltm {
     // 存储记忆
     async fn store(&mut self, _entry: MemoryEntry) -> Result<()> {
         Ok(())
@@ -40,7 +41,7 @@ impl LongTermMemory for LTM {
 }
 
 #[async_trait]
-impl ShortTermMemory for STM {
+impl ShortTermMemory for Stm {
     /// 添加一条消息到短期记忆
     fn add_message(&mut self, message: Message) {
         self.messages.push(message);
@@ -70,15 +71,11 @@ async fn math_agent() -> Result<()> {
         },
         temperature: 0.7,
         timeout: time::Duration::from_secs(600),
+        ..Default::default()
     };
-    let long_term_memory = LTM {};
-    let short_term_memory = STM { messages: vec![] };
-    let llm = OpenaiLlmClient {
-        api_key,
-        model,
-        api_url,
-        client: reqwest::Client::new(),
-    };
+    let long_term_memory = Ltm {};
+    let short_term_memory = Stm { messages: vec![] };
+    let llm = OpenaiLlmClient::new(api_key, model, api_url);
     let mut agent =
         chimerai::Agent::new(long_term_memory, short_term_memory, llm).with_config(config);
 
@@ -101,7 +98,7 @@ async fn math_agent() -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CalcTool;
 
 impl CalcTool {
@@ -144,34 +141,21 @@ impl Tool for CalcTool {
 
     async fn execute(&self, args: Value) -> Result<String> {
         println!("tool called: {args:?}");
-        let op = args
-            .get("op")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow!("Missing or invalid 'op' argument"))?;
-
-        let num1 = args
-            .get("num1")
-            .and_then(|v| v.as_f64())
-            .ok_or_else(|| anyhow!("Missing or invalid 'num1' argument"))?;
-
-        let num2 = args
-            .get("num2")
-            .and_then(|v| v.as_f64())
-            .ok_or_else(|| anyhow!("Missing or invalid 'num2' argument"))?;
-
-        let result = match op {
-            "add" => num1 + num2,
-            "subtract" => num1 - num2,
-            "multiply" => num1 * num2,
-            "divide" => {
-                if num2 == 0.0 {
-                    return Err(anyhow!("Division by zero"));
-                }
-                num1 / num2
-            }
+        let args = ToolArgs::new(self.name(), args);
+        let op = args.require_str("op")?;
+        let num1 = args.require_number("num1")?;
+        let num2 = args.require_number("num2")?;
+
+        let op = match op {
+            "add" => Arithmetic::Add,
+            "subtract" => Arithmetic::Sub,
+            "multiply" => Arithmetic::Mul,
+            "divide" => Arithmetic::Div,
             _ => return Err(anyhow!("Unsupported operation: {}", op)),
         };
+        // 整数运算的结果仍是整数，`2 + 2` 输出 4 而不是 4.00
+        let result = apply_arithmetic(op, num1, num2)?;
 
-        Ok(format!("result: {:.2}", result)) // 保留两位小数
+        Ok(format!("result: {}", result))
     }
 }
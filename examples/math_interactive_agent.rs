@@ -3,13 +3,13 @@ use std::time;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use chimerai::llm::openai::OpenaiLlmClient;
+use chimerai::tools::{apply_arithmetic, Arithmetic, ToolArgs};
 use chimerai::Tool;
 use chimerai::{
     memory::{MemoryEntry, MemoryQuery},
     LongTermMemory, Message, ShortTermMemory,
 };
 use serde_json::Value;
-use std::io;
 use tokio::io::{self as tokio_io, AsyncBufReadExt, AsyncWriteExt, BufReader};
 
 #[tokio::main]
@@ -18,14 +18,14 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-struct LTM {}
+struct Ltm {}
 
-struct STM {
+struct Stm {
     messages: Vec<Message>,
 }
 
 #[async_trait]
-impl LongTermMemory for LTM {
+impl LongTermMemory for Ltm {
     // 存储记忆
     async fn store(&mut self, _entry: MemoryEntry) -> Result<()> {
         Ok(())
@@ -43,7 +43,7 @@ impl LongTermMemory for LTM {
 }
 
 #[async_trait]
-impl ShortTermMemory for STM {
+impl ShortTermMemory for Stm {
     /// 添加一条消息到短期记忆
     fn add_message(&mut self, message: Message) {
         self.messages.push(message);
@@ -89,15 +89,11 @@ async fn math_interactive_agent() -> Result<()> {
         },
         temperature: 0.7,
         timeout: time::Duration::from_secs(600),
+        ..Default::default()
     };
-    let long_term_memory = LTM {};
-    let short_term_memory = STM { messages: vec![] };
-    let llm = OpenaiLlmClient {
-        api_key,
-        model,
-        api_url,
-        client: reqwest::Client::new(),
-    };
+    let long_term_memory = Ltm {};
+    let short_term_memory = Stm { messages: vec![] };
+    let llm = OpenaiLlmClient::new(api_key, model, api_url);
     let mut agent =
         chimerai::Agent::new(long_term_memory, short_term_memory, llm).with_config(config);
 
@@ -111,7 +107,7 @@ async fn math_interactive_agent() -> Result<()> {
     let mut exit_requested = false;
 
     println!("数学助手已启动，请输入您的数学问题...");
-    println!("(输入\"\"\"开始多行输入模式)");
+    println!("(输入\"\"\"开始多行输入模式，输入exit或quit退出)");
 
     while !exit_requested {
         // 提示用户输入
@@ -166,6 +162,11 @@ async fn math_interactive_agent() -> Result<()> {
             }
         }
 
+        if matches!(user_message.trim(), "exit" | "quit") {
+            exit_requested = true;
+            continue;
+        }
+
         // 为用户显示输入已被接收
         stdout.write_all("\n正在计算中...\n".as_bytes()).await?;
         stdout.flush().await?;
@@ -200,7 +201,7 @@ async fn math_interactive_agent() -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CalcTool;
 
 impl CalcTool {
@@ -243,33 +244,20 @@ impl Tool for CalcTool {
 
     async fn execute(&self, args: Value) -> Result<String> {
         println!("计算工具调用: {args:?}");
-        let op = args
-            .get("op")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow!("缺少或无效的 'op' 参数"))?;
-
-        let num1 = args
-            .get("num1")
-            .and_then(|v| v.as_f64())
-            .ok_or_else(|| anyhow!("缺少或无效的 'num1' 参数"))?;
-
-        let num2 = args
-            .get("num2")
-            .and_then(|v| v.as_f64())
-            .ok_or_else(|| anyhow!("缺少或无效的 'num2' 参数"))?;
-
-        let result = match op {
-            "add" => num1 + num2,
-            "subtract" => num1 - num2,
-            "multiply" => num1 * num2,
-            "divide" => {
-                if num2 == 0.0 {
-                    return Err(anyhow!("除数不能为零"));
-                }
-                num1 / num2
-            }
+        let args = ToolArgs::new(self.name(), args);
+        let op = args.require_str("op")?;
+        let num1 = args.require_number("num1")?;
+        let num2 = args.require_number("num2")?;
+
+        let op = match op {
+            "add" => Arithmetic::Add,
+            "subtract" => Arithmetic::Sub,
+            "multiply" => Arithmetic::Mul,
+            "divide" => Arithmetic::Div,
             _ => return Err(anyhow!("不支持的操作: {}", op)),
         };
+        // 整数运算的结果仍是整数，`2 + 2` 输出 4 而不是 4.00
+        let result = apply_arithmetic(op, num1, num2)?;
 
         Ok(format!("结果: {}", result))
     }
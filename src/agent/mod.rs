@@ -1,28 +1,325 @@
-use anyhow::{anyhow, Result};
+pub mod transcript;
+
+pub use transcript::messages_to_markdown;
+
+use anyhow::{anyhow, bail, Result};
 use async_stream::stream;
+use async_trait::async_trait;
+use chrono::Utc;
 use futures::{Stream, StreamExt};
-use std::{collections::HashMap, pin::Pin};
-use tokio::time::timeout;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use tracing::{warn, Instrument};
 
 use crate::{
-    llm::LLMClient,
-    memory::{LongTermMemory, ShortTermMemory},
-    tools::Tool,
-    types::{AgentConfig, AgentState, Decision, Message, ToolCallArgs, ToolExecutionResult},
+    llm::{LLMClient, LlmError, ModelInfo, RequestOptions},
+    memory::{estimate_tokens, LongTermMemory, MemoryQuery, ShortTermMemory},
+    stream::{forward_to_channel, StreamEvent, StreamInterrupted, ToolApprovalRequest},
+    tools::{validate_schema, AskUserTool, RenamedTool, Tool, ToolOutputStream, ASK_USER_TOOL},
+    types::{
+        AgentConfig, AgentState, AuditRecord, Decision, EmptyResponsePolicy, Message,
+        RequestContext, StrictStepMode, ToolCallArgs, ToolCalls, ToolExecutionResult,
+        ToolFailureMode, ToolMessageOrder, TraceStep, TurnTrace, Visibility,
+    },
 };
 
+tokio::task_local! {
+    /// 当前任务所处的 Agent 嵌套深度，由工具执行时递增
+    static AGENT_DEPTH: usize;
+}
+
+/// 获取当前任务所处的嵌套深度：顶层对话为 0，每经过一层工具调用加 1
+///
+/// 在工具内部调用其他 Agent（子 Agent）时，子 Agent 会读取该值并与自身的 `max_depth` 比较。
+pub fn current_depth() -> usize {
+    AGENT_DEPTH.try_with(|depth| *depth).unwrap_or(0)
+}
+
+/// 在下一层嵌套深度中执行工具；`Tool::is_blocking` 的工具在 `spawn_blocking` 的线程上执行
+async fn execute_nested(
+    tool: &Arc<dyn Tool>,
+    args: Value,
+    depth: usize,
+    partial_after: Option<Duration>,
+) -> Result<String> {
+    if tool.is_blocking() {
+        let tool = tool.clone();
+        let runtime = tokio::runtime::Handle::current();
+        return tokio::task::spawn_blocking(move || {
+            runtime.block_on(
+                AGENT_DEPTH.scope(depth + 1, run_tool(tool.as_ref(), args, partial_after)),
+            )
+        })
+        .await
+        .map_err(|err| anyhow!("blocking tool task failed: {err}"))?;
+    }
+    AGENT_DEPTH
+        .scope(depth + 1, run_tool(tool.as_ref(), args, partial_after))
+        .await
+}
+
+/// 执行工具；设置了 `partial_after` 时通过 `execute_stream` 执行并在到时后返回已产出的部分
+async fn run_tool(tool: &dyn Tool, args: Value, partial_after: Option<Duration>) -> Result<String> {
+    match partial_after {
+        Some(cutoff) => collect_partial_output(tool.execute_stream(args), cutoff).await,
+        None => tool.execute(args).await,
+    }
+}
+
+/// 收集工具的流式输出，超过 `cutoff` 时返回已产出的部分并附加说明
+async fn collect_partial_output(
+    mut output: ToolOutputStream<'_>,
+    cutoff: Duration,
+) -> Result<String> {
+    let deadline = tokio::time::sleep(cutoff);
+    tokio::pin!(deadline);
+    let mut collected = String::new();
+    loop {
+        tokio::select! {
+            chunk = output.next() => match chunk {
+                Some(chunk) => collected.push_str(&chunk?),
+                None => return Ok(collected),
+            },
+            _ = &mut deadline => {
+                collected.push_str(&format!(
+                    "\n[partial output: the tool was still running after {cutoff:?}]"
+                ));
+                return Ok(collected);
+            }
+        }
+    }
+}
+
+/// 执行一轮工具调用，每完成一个调用就产出 `(tool_call_id, 结果)`，失败时结果为错误信息
+///
+/// 默认按模型发出调用的顺序依次执行；开启 `enable_parallel` 时所有调用同时执行，按完成的先后产出。
+/// 轮次被取消后，正在执行和尚未执行的调用均以 `TOOL_ROUND_CANCELLED` 作为失败结果产出。
+/// `ToolFailureMode::FailFast` 下，依次执行时首个失败之后的调用不再执行，并且整轮结束后才产出结果：
+/// 只要有调用失败，成功的结果都被替换为 `TOOL_BATCH_DISCARDED`。
+fn execute_tool_round<'a>(
+    args: &'a ToolCalls,
+    tools: &'a HashMap<String, Arc<dyn Tool>>,
+    depth: usize,
+    canceller: &'a ToolRoundCanceller,
+    config: &'a AgentConfig,
+) -> impl Stream<Item = (String, std::result::Result<String, String>)> + Send + 'a {
+    let fail_fast = config.tool_failure_mode == ToolFailureMode::FailFast;
+    let partial_after = config.partial_tool_output_after;
+    // 找到可执行的工具；置信度不足或工具不存在时直接得到失败结果
+    let prepare = move |call: &ToolCallArgs| -> std::result::Result<&'a Arc<dyn Tool>, String> {
+        if let Some(feedback) = low_confidence_feedback(call, config.tool_confidence_threshold) {
+            return Err(feedback);
+        }
+        tools
+            .get(&call.tool_name)
+            .ok_or_else(|| format!("Tool {} does not exist!", call.tool_name))
+    };
+    let results = stream! {
+        let cancelled = canceller.notify.notified();
+        tokio::pin!(cancelled);
+        cancelled.as_mut().enable();
+        if config.enable_parallel {
+            let mut running = futures::stream::FuturesUnordered::new();
+            let mut pending = Vec::new();
+            for (tool_call_id, call) in args {
+                match prepare(call) {
+                    Ok(tool) => {
+                        pending.push(tool_call_id);
+                        running.push(async move {
+                            let result = execute_nested(tool, call.args.clone(), depth, partial_after).await;
+                            (tool_call_id, result.map_err(|e| e.to_string()))
+                        });
+                    }
+                    Err(err) => yield (tool_call_id.clone(), Err(err)),
+                }
+            }
+            loop {
+                tokio::select! {
+                    finished = running.next() => match finished {
+                        Some((tool_call_id, result)) => {
+                            pending.retain(|id| *id != tool_call_id);
+                            yield (tool_call_id.clone(), result);
+                        }
+                        None => break,
+                    },
+                    // 取消时已完成的结果保留，其余调用不再等待
+                    _ = &mut cancelled => {
+                        for tool_call_id in pending {
+                            yield (tool_call_id.clone(), Err(TOOL_ROUND_CANCELLED.to_string()));
+                        }
+                        break;
+                    }
+                }
+            }
+        } else {
+            let mut is_cancelled = false;
+            let mut failed = false;
+            for (tool_call_id, call) in args {
+                let result = if is_cancelled {
+                    Err(TOOL_ROUND_CANCELLED.to_string())
+                } else if fail_fast && failed {
+                    Err(TOOL_BATCH_SKIPPED.to_string())
+                } else {
+                    match prepare(call) {
+                        Ok(tool) => tokio::select! {
+                            result = execute_nested(tool, call.args.clone(), depth, partial_after) => {
+                                result.map_err(|e| e.to_string())
+                            }
+                            _ = &mut cancelled => {
+                                is_cancelled = true;
+                                Err(TOOL_ROUND_CANCELLED.to_string())
+                            }
+                        },
+                        Err(err) => Err(err),
+                    }
+                };
+                failed |= result.is_err();
+                yield (tool_call_id.clone(), result);
+            }
+        }
+    };
+    // 全有或全无时需要等整轮结束才能确定成功的结果是否保留
+    let results: Pin<Box<dyn Stream<Item = _> + Send + 'a>> = if fail_fast {
+        Box::pin(
+            futures::stream::once(results.collect::<Vec<_>>())
+                .flat_map(|results| futures::stream::iter(discard_on_failure(results))),
+        )
+    } else {
+        Box::pin(results)
+    };
+    results
+}
+
+/// 调用参数中的 `confidence` 低于阈值时，返回代替执行结果、要求模型先向用户确认的说明
+fn low_confidence_feedback(call: &ToolCallArgs, threshold: Option<f64>) -> Option<String> {
+    let threshold = threshold?;
+    let confidence = call.args.get("confidence")?.as_f64()?;
+    (confidence < threshold).then(|| {
+        format!(
+            "not executed: confidence {confidence} is below the threshold {threshold}. \
+             Ask the user to confirm before calling `{}` again.",
+            call.tool_name
+        )
+    })
+}
+
+/// 一轮中有调用失败时，将成功的结果替换为 `TOOL_BATCH_DISCARDED`
+fn discard_on_failure(
+    results: Vec<(String, std::result::Result<String, String>)>,
+) -> Vec<(String, std::result::Result<String, String>)> {
+    if results.iter().all(|(_, result)| result.is_ok()) {
+        return results;
+    }
+    results
+        .into_iter()
+        .map(|(id, result)| (id, result.and(Err(TOOL_BATCH_DISCARDED.to_string()))))
+        .collect()
+}
+
+/// 检查当前嵌套深度是否超过配置的上限
+fn check_depth(max_depth: usize) -> Result<usize> {
+    let depth = current_depth();
+    if depth > max_depth {
+        return Err(anyhow!("exceeded max_depth ({max_depth})"));
+    }
+    Ok(depth)
+}
+
+/// 每轮请求前调用的工具列表钩子：可根据当前上下文对发送给 LLM 的工具进行过滤、排序
+///
+/// 只影响模型看到的工具列表，工具执行时仍在全部已注册的工具中查找。
+pub type ToolsHook = Box<dyn Fn(&[Message], &mut Vec<&dyn Tool>) + Send + Sync>;
+
+/// 工具成功执行后、结果写入记忆之前调用的过滤器，参数为工具名称与原始输出，
+/// 返回值作为实际记录并发送给模型的结果（如脱敏、重新格式化）；执行失败的错误信息不经过过滤器
+pub type ToolResultFilter = Box<dyn Fn(&str, String) -> String + Send + Sync>;
+
+/// 状态通道的容量，订阅者落后超过该数量时会丢失最早的状态
+const STATUS_CHANNEL_CAPACITY: usize = 64;
+
+/// 开始执行一轮工具调用时发布的状态
+fn tool_round_status(round: usize, tool_calls: &ToolCalls) -> String {
+    let mut names: Vec<&str> = tool_calls
+        .values()
+        .map(|call| call.tool_name.as_str())
+        .collect();
+    names.sort();
+    let names: Vec<String> = names.iter().map(|name| format!("`{name}`")).collect();
+    format!("step {round}: calling {}", names.join(", "))
+}
+
+/// 一轮工具调用结束时发布的状态
+fn tool_results_status(round: usize, results: usize) -> String {
+    format!("step {round}: got {results} tool result(s), continuing")
+}
+
+/// 工具轮次被取消时，未完成的工具调用得到的结果
+const TOOL_ROUND_CANCELLED: &str =
+    "tools were cancelled; answer with the information available so far";
+
+/// `ToolFailureMode::FailFast` 下，同一轮中其他调用失败时未执行的调用得到的结果
+const TOOL_BATCH_SKIPPED: &str = "skipped because another tool call in this batch failed";
+
+/// `ToolFailureMode::FailFast` 下，同一轮中其他调用失败时已成功调用的结果被替换为该信息
+const TOOL_BATCH_DISCARDED: &str =
+    "result discarded because another tool call in this batch failed; no results from this batch were kept";
+
+/// 取消当前工具轮次的句柄，可在 Agent 处理消息期间从其他任务调用
+///
+/// 取消只影响正在执行的这一轮：尚未完成的工具调用不再等待，而是以取消说明作为结果，
+/// 随后对话照常继续，由模型根据已有信息回答。没有工具在执行时调用不产生任何效果。
+#[derive(Debug, Clone, Default)]
+pub struct ToolRoundCanceller {
+    notify: Arc<Notify>,
+}
+
+impl ToolRoundCanceller {
+    pub fn cancel(&self) {
+        self.notify.notify_waiters();
+    }
+}
+
+/// 模型通过 `ask_user` 提出、等待用户回答的问题
+#[derive(Debug, Clone)]
+struct PendingQuestion {
+    tool_call_id: String,
+    question: String,
+}
+
+/// 每条审计记录产生时调用的钩子，可用于写日志、上报指标等
+pub type AuditHook = Box<dyn Fn(&AuditRecord) + Send + Sync>;
+
 pub struct Agent<M, H, L>
 where
     M: LongTermMemory,
     H: ShortTermMemory,
     L: LLMClient,
 {
+    #[allow(dead_code)]
     long_term_memory: M, // not implemented yet
     short_term_memory: H,
     llm: L,
-    tools: HashMap<String, Box<dyn Tool>>,
+    tools: HashMap<String, Arc<dyn Tool>>,
+    /// 已注册但尚未成功调用 `Tool::init` 的工具
+    uninitialized_tools: HashSet<String>,
+    tools_hook: Option<ToolsHook>,
+    tool_result_filter: Option<ToolResultFilter>,
+    audit_hook: Option<AuditHook>,
+    tool_round_canceller: ToolRoundCanceller,
+    status: broadcast::Sender<String>,
+    conversation_id: String,
+    turns: usize,
     config: AgentConfig,
     state: AgentState,
+    pending_question: Option<PendingQuestion>,
+    last_turn_trace: TurnTrace,
 }
 
 impl<M, H, L> Agent<M, H, L>
@@ -37,8 +334,18 @@ where
             short_term_memory,
             llm,
             tools: HashMap::new(),
+            uninitialized_tools: HashSet::new(),
+            tools_hook: None,
+            tool_result_filter: None,
+            audit_hook: None,
+            tool_round_canceller: ToolRoundCanceller::default(),
+            status: broadcast::channel(STATUS_CHANNEL_CAPACITY).0,
+            conversation_id: uuid::Uuid::new_v4().to_string(),
+            turns: 0,
             config: AgentConfig::default(),
             state: AgentState::Ready,
+            pending_question: None,
+            last_turn_trace: TurnTrace::default(),
         }
     }
 
@@ -50,8 +357,196 @@ where
         self
     }
 
+    /// 设置每轮请求前调用的工具列表钩子
+    pub fn with_tools_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&[Message], &mut Vec<&dyn Tool>) + Send + Sync + 'static,
+    {
+        self.tools_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// 设置工具结果过滤器，见 `ToolResultFilter`
+    pub fn with_tool_result_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str, String) -> String + Send + Sync + 'static,
+    {
+        self.tool_result_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// 设置审计钩子，每次 `handle_message` 结束后以本轮的审计记录调用
+    pub fn with_audit_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&AuditRecord) + Send + Sync + 'static,
+    {
+        self.audit_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// 订阅处理进度，如 "step 2: calling `calculator`"、"step 2: got 1 tool result(s), continuing"、
+    /// "done"，与回复内容相互独立，可用于向用户展示进度
+    ///
+    /// 只能收到订阅之后发布的状态；没有订阅者时状态被直接丢弃。
+    pub fn subscribe_status(&self) -> broadcast::Receiver<String> {
+        self.status.subscribe()
+    }
+
+    /// 发布一条状态，没有订阅者时忽略
+    fn publish_status(&self, status: String) {
+        let _ = self.status.send(status);
+    }
+
+    /// 获取取消当前工具轮次的句柄
+    pub fn tool_round_canceller(&self) -> ToolRoundCanceller {
+        self.tool_round_canceller.clone()
+    }
+
+    /// 本次对话的唯一标识
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    /// 本次对话使用的系统提示词版本
+    pub fn prompt_version(&self) -> Option<&str> {
+        self.config.prompt_version.as_deref()
+    }
+
+    /// 最近一次 `handle_message` 中的决策与工具执行结果（流式处理不记录）
+    pub fn last_turn_trace(&self) -> &TurnTrace {
+        &self.last_turn_trace
+    }
+
+    /// 处于 `AgentState::WaitingForUserInput` 时，模型通过 `ask_user` 提出的问题
+    pub fn pending_question(&self) -> Option<&str> {
+        self.pending_question
+            .as_ref()
+            .map(|pending| pending.question.as_str())
+    }
+
+    /// 从 `AgentState::Error` 中恢复为 `Ready`，对话历史保持不变；
+    /// 若正在等待用户澄清，则放弃该问题
+    pub fn reset(&mut self) {
+        if let AgentState::Error(err) = &self.state {
+            warn!(error = %err, "resetting agent from error state");
+        }
+        if let Some(pending) = self.pending_question.take() {
+            self.short_term_memory.add_message(Message::Tool {
+                content: "The user did not answer this question.".to_string(),
+                tool_call_id: pending.tool_call_id,
+            });
+        }
+        self.state = AgentState::Ready;
+    }
+
+    /// 配置了 `conversation_timeout` 时返回本次处理的截止时间，在发起第一次 LLM 请求前调用，
+    /// 因此每次 `handle_message` 或流式处理都重新计时
+    fn conversation_deadline(&self) -> Option<(Instant, Duration)> {
+        let limit = self.config.conversation_timeout?;
+        Some((Instant::now() + limit, limit))
+    }
+
+    /// 处理新消息前检查状态，开启 `auto_recover_from_error` 时自动从错误状态恢复
+    fn check_ready(&mut self) -> Result<()> {
+        if self.config.auto_recover_from_error && matches!(self.state, AgentState::Error(_)) {
+            self.reset();
+        }
+        if !matches!(
+            self.state,
+            AgentState::Ready | AgentState::WaitingForUserInput
+        ) {
+            return Err(anyhow!("Agent is not in ready state"));
+        }
+        Ok(())
+    }
+
+    /// 注册工具，工具的 `Tool::init` 在处理下一条消息前调用（也可提前调用 `init_tools`）
     pub fn register_tool<T: Tool + 'static>(&mut self, tool: T) {
-        self.tools.insert(tool.name(), Box::new(tool));
+        self.uninitialized_tools.insert(tool.name());
+        self.tools.insert(tool.name(), Arc::new(tool));
+    }
+
+    /// 初始化所有尚未初始化的工具，任一工具失败时返回错误，成功的工具不会重复初始化
+    pub async fn init_tools(&mut self) -> Result<()> {
+        let mut pending: Vec<String> = self.uninitialized_tools.iter().cloned().collect();
+        pending.sort();
+        for name in pending {
+            if let Some(tool) = self.tools.get_mut(&name) {
+                // 未初始化的工具不会被执行，因此不存在其他持有者
+                let tool = Arc::get_mut(tool)
+                    .ok_or_else(|| anyhow!("tool `{name}` is in use and cannot be initialized"))?;
+                tool.init()
+                    .await
+                    .map_err(|err| anyhow!("failed to initialize tool `{name}`: {err}"))?;
+            }
+            self.uninitialized_tools.remove(&name);
+        }
+        Ok(())
+    }
+
+    /// 在对话的当前位置插入一条 system 消息（如“从现在起用法语回答”），随后的请求都会带上它
+    pub fn add_system_note(&mut self, content: impl Into<String>) {
+        self.short_term_memory.add_message(Message::System {
+            content: content.into(),
+        });
+    }
+
+    /// 在对话的当前位置插入一条 developer 消息
+    pub fn add_developer_note(&mut self, content: impl Into<String>) {
+        self.short_term_memory.add_message(Message::Developer {
+            content: content.into(),
+        });
+    }
+
+    /// 在对话的当前位置插入一条带可见性标记的消息，如仅供调试的备注或不展示给用户的提示
+    pub fn add_message_with_visibility(&mut self, message: Message, visibility: Visibility) {
+        self.short_term_memory
+            .add_message_with_visibility(message, visibility);
+    }
+
+    /// 面向用户展示的对话历史，不包含对用户隐藏的消息
+    pub fn user_history(&self) -> Vec<Message> {
+        self.short_term_memory.user_history()
+    }
+
+    /// 将面向用户的对话历史导出为 Markdown，见 `messages_to_markdown`
+    pub fn to_markdown(&self) -> String {
+        messages_to_markdown(&self.user_history())
+    }
+
+    /// 以 `query`（通常是最新的用户消息）检索长期记忆，将相似度达到阈值的前几条格式化为一条
+    /// developer 消息；没有相关记忆时返回 `None`。由调用方决定何时注入（如 `add_developer_note`）
+    pub async fn build_memory_context(&self, query: &str) -> Result<Option<Message>> {
+        let entries = self
+            .long_term_memory
+            .recall(&MemoryQuery::Semantic {
+                description: query.to_string(),
+                limit: self.config.memory_recall_limit,
+                min_similarity: self.config.memory_min_similarity,
+            })
+            .await?;
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        let mut content = String::from("Relevant information from long-term memory:");
+        for entry in entries.iter().take(self.config.memory_recall_limit) {
+            let text = match &entry.content {
+                Value::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            content.push_str(&format!("\n- {text}"));
+        }
+        Ok(Some(Message::Developer { content }))
+    }
+
+    /// 以指定的名称与描述注册工具（如本地化的描述），模型看到并调用的都是新名称
+    pub fn register_tool_as<T: Tool + 'static>(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        tool: T,
+    ) {
+        self.register_tool(RenamedTool::new(name, description, tool));
     }
 
     /// 处理传入的消息，并根据消息内容进行相应的操作
@@ -60,91 +555,415 @@ where
     /// 2. 将状态设置为Processing，表示正在处理消息
     /// 3. 将用户的消息添加到短期记忆中
     /// 4. 获取裁剪后的上下文消息，确保不超过最大token数
-    /// 5. 进入循环，最多重试max_retries次：
-    ///     a. 调用get_decision获取LLM的决策结果，并设置超时时间
-    ///     b. 处理决策结果：
-    ///         - 如果需要执行工具：
-    ///             * 将助手的回应和工具调用信息添加到短期记忆中
-    ///             * 执行所有指定的工具
-    ///             * 根据工具执行结果，更新短期记忆中的内容
-    ///         - 如果直接回应用户：
-    ///             * 将助手的消息添加到短期记忆中
-    ///             * 恢复代理状态为Ready
-    ///             * 返回响应消息
-    ///     c. 超时处理：增加重试次数或返回错误
+    /// 5. 进入循环，最多向 LLM 请求 max_retries 次（含首次请求）：
+    ///    - a. 调用get_decision获取LLM的决策结果，并设置超时时间
+    ///    - b. 处理决策结果：
+    ///      - 如果需要执行工具：
+    ///        * 将助手的回应和工具调用信息添加到短期记忆中
+    ///        * 执行所有指定的工具
+    ///        * 根据工具执行结果，更新短期记忆中的内容
+    ///      - 如果直接回应用户：
+    ///        * 将助手的消息添加到短期记忆中
+    ///        * 返回响应消息
+    ///    - c. 超时处理：增加重试次数或返回错误
+    ///    - d. 上下文超长：裁剪上下文后重试一次
     /// 6. 循环结束后，如果超过重试次数则返回相应错误
+    /// 7. 无论成功与否，处理结束后都将状态恢复为Ready
     pub async fn handle_message(&mut self, message: String) -> Result<String> {
-        // 1. 状态检查
-        if !matches!(self.state, AgentState::Ready) {
-            return Err(anyhow!("Agent is not in ready state"));
+        self.handle_message_with_context(message, RequestContext::default())
+            .await
+    }
+
+    /// 从存储的对话中提取用户消息，依次通过 `handle_message` 重新运行，按顺序返回每轮的回复
+    ///
+    /// 用于提示词的回归测试：在新的 Agent 上重放对话并比较输出。其余消息（助手回复、
+    /// 工具结果等）被忽略；任一轮失败时立即返回错误。
+    pub async fn replay_user_turns(&mut self, conversation: &[Message]) -> Result<Vec<String>> {
+        let mut responses = Vec::new();
+        for message in conversation {
+            if let Message::User { content } = message {
+                responses.push(self.handle_message(content.clone()).await?);
+            }
         }
+        Ok(responses)
+    }
+
+    /// 与 `handle_message` 相同，并将请求 id 与标签写入 tracing span（`handle_message`）与审计记录
+    pub async fn handle_message_with_context(
+        &mut self,
+        message: String,
+        context: RequestContext,
+    ) -> Result<String> {
+        // 1. 状态检查
+        self.check_ready()?;
+        self.config.validate()?;
+        check_depth(self.config.max_depth)?;
+        self.init_tools().await?;
+        let conversation_deadline = self.conversation_deadline();
         self.state = AgentState::Processing;
+        self.turns += 1;
+        let started_at = Utc::now();
+        let started = Instant::now();
+
+        let span = tracing::info_span!(
+            "handle_message",
+            conversation_id = %self.conversation_id,
+            turn = self.turns,
+            request_id = context.request_id.as_deref().unwrap_or_default(),
+            tags = ?context.tags,
+        );
+        let turn_timeout = self.config.turn_timeout;
+        let turn = self.process_message(message).instrument(span);
+        let turn = async {
+            match turn_timeout {
+                Some(limit) => timeout(limit, turn)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("turn exceeded turn_timeout ({limit:?})"))),
+                None => turn.await,
+            }
+        };
+        let result = match conversation_deadline {
+            Some((deadline, limit)) => timeout_at(deadline, turn).await.unwrap_or_else(|_| {
+                Err(anyhow!(
+                    "conversation exceeded conversation_timeout ({limit:?})"
+                ))
+            }),
+            None => turn.await,
+        };
+        self.state = if self.pending_question.is_some() {
+            AgentState::WaitingForUserInput
+        } else {
+            AgentState::Ready
+        };
+        self.publish_status(match &result {
+            Ok(_) if self.pending_question.is_some() => "waiting for the user's answer".to_string(),
+            Ok(_) => "done".to_string(),
+            Err(err) => format!("failed: {err}"),
+        });
+        if let Some(hook) = &self.audit_hook {
+            hook(&AuditRecord {
+                conversation_id: self.conversation_id.clone(),
+                prompt_version: self.config.prompt_version.clone(),
+                turn: self.turns,
+                started_at,
+                duration_ms: started.elapsed().as_millis() as u64,
+                error: result.as_ref().err().map(|err| err.to_string()),
+                request_id: context.request_id,
+                tags: context.tags,
+            });
+        }
+        result
+    }
+
+    /// 为当前对话生成一个简短的标题
+    ///
+    /// 只将第一轮用户消息与 assistant 回复发送给 LLM，请求不携带工具，
+    /// 也不会写入短期记忆，因此不影响主对话历史。
+    pub async fn generate_title(&self) -> Result<String> {
+        let history = self.short_term_memory.get_context_messages(None);
+        let user_index = history
+            .iter()
+            .position(|message| matches!(message, Message::User { .. }))
+            .ok_or_else(|| anyhow!("no user message to generate a title from"))?;
+        let mut exchange = format!("User: {}", history[user_index].content());
+        let reply = history[user_index + 1..]
+            .iter()
+            .find_map(|message| match message {
+                Message::Assistant { content, .. } if !content.is_empty() => Some(content),
+                _ => None,
+            });
+        if let Some(reply) = reply {
+            exchange.push_str(&format!("\nAssistant: {reply}"));
+        }
+
+        let messages = [
+            Message::System {
+                content: "Summarize the following conversation as a 3-5 word title. \
+                          Reply with the title only, without quotes or punctuation at the end."
+                    .to_string(),
+            },
+            Message::User { content: exchange },
+        ];
+        let decision = timeout(
+            self.config.timeout,
+            self.llm
+                .complete(&messages, Vec::new(), None, &self.request_options(0)),
+        )
+        .await
+        .map_err(|_| anyhow!("LLM request timed out"))??;
+        match decision {
+            Decision::Respond(title) => Ok(title
+                .trim()
+                .trim_matches(|c| matches!(c, '"' | '\'' | '“' | '”'))
+                .trim()
+                .to_string()),
+            Decision::ExecuteTool(..) => {
+                Err(anyhow!("unexpected tool call while generating title"))
+            }
+        }
+    }
 
+    async fn process_message(&mut self, message: String) -> Result<String> {
+        self.last_turn_trace = TurnTrace::default();
         // 2. 添加用户消息到短期记忆
-        self.short_term_memory
-            .add_message(Message::User { content: message });
+        self.add_user_message(message);
 
         // 3. 获取裁剪后的上下文
         let mut context = self
             .short_term_memory
-            .get_context_messages(self.config.max_tokens);
+            .get_context_messages(self.context_budget());
 
         // 4. 循环处理直到得到最终响应
         let mut retries = 0;
+        let mut pruned = false;
+        let mut validation_retries = 0;
+        let mut empty_retries = 0;
+        let mut tool_rounds = 0;
+        let mut loop_detector = ToolLoopDetector::default();
+        // 最近一轮成功的工具输出，以及是否已经因回复忽略了它们而重新询问过
+        let mut recent_tool_outputs = Vec::new();
+        let mut reprompted_ignored_tools = false;
         while retries < self.config.retry_config.max_retries {
-            // 设置超时
-            match timeout(self.config.timeout, self.get_decision(&context)).await {
+            // 设置超时；校验失败后的重新询问同样按重试计算温度
+            let attempt = retries + validation_retries;
+            match timeout(self.config.timeout, self.get_decision(&context, attempt)).await {
                 Ok(decision_result) => {
-                    let decision = decision_result?;
+                    let decision = match decision_result {
+                        Ok(decision) => decision,
+                        Err(err) if !pruned && LlmError::is_context_length_exceeded(&err) => {
+                            // 上下文超长时直接重试必然再次失败，裁剪后重试一次
+                            let pruned_context = prune_context(&context);
+                            if pruned_context.len() >= context.len() {
+                                return Err(err);
+                            }
+                            context = pruned_context;
+                            pruned = true;
+                            continue;
+                        }
+                        Err(err)
+                            if is_transient_error(&err, &self.config)
+                                && self.config.retry_config.allows_retry(retries) =>
+                        {
+                            warn!("LLM request failed, retrying: {err}");
+                            retries += 1;
+                            tokio::time::sleep(retry_wait(&err, &self.config)).await;
+                            continue;
+                        }
+                        Err(err) => {
+                            // 拒绝也是模型的一次回复，记录后再将错误交给调用方处理
+                            if let Some(refusal) = LlmError::refusal(&err) {
+                                self.short_term_memory.add_message(Message::Assistant {
+                                    content: refusal.to_string(),
+                                    tool_calls: None,
+                                });
+                            }
+                            return Err(err);
+                        }
+                    };
+                    self.last_turn_trace
+                        .steps
+                        .push(TraceStep::Decision(decision.clone()));
+                    if let Err(err) = self.validate_decision(&decision) {
+                        warn!("{err}");
+                    }
+                    let decision = match (self.config.strict_step_mode, decision) {
+                        (StrictStepMode::Off, decision) => decision,
+                        (mode, Decision::ExecuteTool(respond, tool_calls))
+                            if violates_strict_step(&respond, &tool_calls) =>
+                        {
+                            if mode == StrictStepMode::Reask
+                                && validation_retries < self.config.max_validation_retries
+                            {
+                                validation_retries += 1;
+                                self.short_term_memory.add_message(Message::User {
+                                    content: strict_step_feedback(&respond, &tool_calls),
+                                });
+                                context = self
+                                    .short_term_memory
+                                    .get_context_messages(self.context_budget());
+                                if pruned {
+                                    context = prune_context(&context);
+                                }
+                                continue;
+                            }
+                            Decision::ExecuteTool(String::new(), first_tool_call(tool_calls))
+                        }
+                        (_, decision) => decision,
+                    };
                     match decision {
                         Decision::ExecuteTool(respond, tool_calls) => {
-                            self.short_term_memory.add_message(Message::Assistant {
-                                content: respond.clone(),
-                                tool_calls: Some(tool_calls.clone()),
-                            });
+                            if let Some((tool_call_id, question)) = self.ask_user_call(&tool_calls)
+                            {
+                                // 只保留提问的调用，其余调用等用户回答后由模型重新决定
+                                let tool_calls = tool_calls
+                                    .into_iter()
+                                    .filter(|(id, _)| *id == tool_call_id)
+                                    .collect();
+                                self.short_term_memory.add_message(Message::Assistant {
+                                    content: respond,
+                                    tool_calls: Some(tool_calls),
+                                });
+                                self.pending_question = Some(PendingQuestion {
+                                    tool_call_id,
+                                    question: question.clone(),
+                                });
+                                return Ok(question);
+                            }
+                            let loop_check = loop_detector.check(&tool_calls, &self.config)?;
+                            check_max_turns(tool_rounds, &self.config)?;
+                            tool_rounds += 1;
+                            self.publish_status(tool_round_status(tool_rounds, &tool_calls));
+                            let results = self.execute_tool(&tool_calls).await?;
+                            self.publish_status(tool_results_status(
+                                tool_rounds,
+                                results.success_result.len() + results.failure_result.len(),
+                            ));
+                            self.last_turn_trace
+                                .steps
+                                .push(TraceStep::ToolResults(results.clone()));
                             let ToolExecutionResult {
                                 success_result,
                                 failure_result,
-                            } = self.execute_tool(&tool_calls).await?;
-                            success_result
-                                .into_iter()
-                                .for_each(|(tool_call_id, content)| {
-                                    self.short_term_memory.add_message(Message::Tool {
-                                        content,
-                                        tool_call_id,
-                                    });
+                            } = results;
+                            let mut tool_messages = Vec::new();
+                            recent_tool_outputs.clear();
+                            for (tool_call_id, content) in success_result {
+                                let content =
+                                    match (&self.tool_result_filter, tool_calls.get(&tool_call_id))
+                                    {
+                                        (Some(filter), Some(call)) => {
+                                            filter(&call.tool_name, content)
+                                        }
+                                        _ => content,
+                                    };
+                                let content =
+                                    summarize_tool_output(&self.llm, &self.config, content).await;
+                                recent_tool_outputs.push(content.clone());
+                                tool_messages.push(Message::Tool {
+                                    content,
+                                    tool_call_id,
                                 });
-                            failure_result.into_iter().for_each(
-                                        |(tool_call_id, error)| {
-                                            self.short_term_memory.add_message(Message::Tool {
-                                                content: format!(
-                                                    "工具 {} 执行失败（错误信息：{}）。由于无法重试，请考虑使用其他方式解决问题或给出合适的响应。",
-                                                    tool_calls.get(&tool_call_id).map(|t| t.tool_name.as_str()).unwrap_or(tool_call_id.as_str()),
-                                                    error,
-                                                ),
-                                                tool_call_id,
-                                            });
-                                        },
-                                    );
+                            }
+                            tool_messages.extend(failure_result.into_iter().map(
+                                |(tool_call_id, error)| {
+                                    let tool_name = tool_calls
+                                        .get(&tool_call_id)
+                                        .map(|t| t.tool_name.as_str())
+                                        .unwrap_or(tool_call_id.as_str());
+                                    Message::Tool {
+                                        content: tool_failure_message(tool_name, &error),
+                                        tool_call_id,
+                                    }
+                                },
+                            ));
+                            record_tool_round(
+                                &mut self.short_term_memory,
+                                self.config.tool_message_order,
+                                respond.clone(),
+                                tool_calls,
+                                tool_messages,
+                            );
+                            if self.should_stop() {
+                                return Ok(respond);
+                            }
+                            if let Some(note) = loop_check {
+                                self.short_term_memory.add_message(note);
+                            }
                             context = self
                                 .short_term_memory
-                                .get_context_messages(self.config.max_tokens);
+                                .get_context_messages(self.context_budget());
+                            if pruned {
+                                context = prune_context(&context);
+                            }
                             continue;
                         }
                         Decision::Respond(response) => {
+                            if response.trim().is_empty() {
+                                match self.config.on_empty_response {
+                                    EmptyResponsePolicy::ReturnEmpty => {}
+                                    EmptyResponsePolicy::Retry
+                                        if empty_retries < self.config.retry_config.max_retries =>
+                                    {
+                                        empty_retries += 1;
+                                        continue;
+                                    }
+                                    EmptyResponsePolicy::Retry | EmptyResponsePolicy::Error => {
+                                        bail!("the model returned an empty response");
+                                    }
+                                }
+                            }
                             self.short_term_memory.add_message(Message::Assistant {
                                 content: response.clone(),
                                 tool_calls: None,
                             });
-                            self.state = AgentState::Ready;
-                            return Ok(response);
+                            if self.should_stop() {
+                                return Ok(response);
+                            }
+                            if self.config.reprompt_ignored_tool_results
+                                && !reprompted_ignored_tools
+                                && !references_tool_output(&response, &recent_tool_outputs)
+                            {
+                                reprompted_ignored_tools = true;
+                                self.short_term_memory.add_message(Message::User {
+                                    content: IGNORED_TOOL_RESULTS_FEEDBACK.to_string(),
+                                });
+                                context = self
+                                    .short_term_memory
+                                    .get_context_messages(self.context_budget());
+                                if pruned {
+                                    context = prune_context(&context);
+                                }
+                                continue;
+                            }
+                            if self.config.reprompt_text_tool_calls
+                                && validation_retries < self.config.max_validation_retries
+                            {
+                                if let Some(tool) = text_tool_intent(&response, self.tools.keys()) {
+                                    validation_retries += 1;
+                                    self.short_term_memory.add_message(Message::User {
+                                        content: text_tool_call_feedback(tool),
+                                    });
+                                    context = self
+                                        .short_term_memory
+                                        .get_context_messages(self.context_budget());
+                                    if pruned {
+                                        context = prune_context(&context);
+                                    }
+                                    continue;
+                                }
+                            }
+                            let validation = match &self.config.response_validator {
+                                Some(validator) => validator.validate(&response),
+                                None => Ok(()),
+                            };
+                            let Err(err) = validation else {
+                                return Ok(response);
+                            };
+                            if validation_retries >= self.config.max_validation_retries {
+                                return Err(anyhow!("response failed validation: {err}"));
+                            }
+                            // 将校验错误反馈给模型并重新询问
+                            validation_retries += 1;
+                            self.short_term_memory.add_message(Message::User {
+                                content: validation_feedback(&err),
+                            });
+                            context = self
+                                .short_term_memory
+                                .get_context_messages(self.context_budget());
+                            if pruned {
+                                context = prune_context(&context);
+                            }
+                            continue;
                         }
                     }
                 }
-                Err(err) => {
-                    println!("running error: {}", err);
-                    if retries < self.config.retry_config.max_retries {
+                Err(_) => {
+                    if self.config.retry_config.should_retry_on_error
+                        && self.config.retry_config.allows_retry(retries)
+                    {
+                        warn!("LLM request timed out, retrying");
                         retries += 1;
+                        tokio::time::sleep(self.config.retry_config.retry_delay).await;
                         continue;
                     }
                     return Err(anyhow!("LLM request timed out"));
@@ -155,12 +974,145 @@ where
         Err(anyhow!("超过最大重试次数"))
     }
 
-    async fn get_decision(&self, messages: &[Message]) -> Result<Decision> {
-        let tools: Vec<&Box<dyn Tool>> = self.tools.values().collect();
+    /// 添加用户消息；正在等待用户澄清时，将其作为 `ask_user` 调用的结果
+    fn add_user_message(&mut self, message: String) {
+        match self.pending_question.take() {
+            Some(pending) => self.short_term_memory.add_message(Message::Tool {
+                content: message,
+                tool_call_id: pending.tool_call_id,
+            }),
+            None => self
+                .short_term_memory
+                .add_message(Message::User { content: message }),
+        }
+    }
+
+    /// 注册了 `AskUserTool` 时，查找本轮中参数有效的 `ask_user` 调用，返回其 id 与问题
+    fn ask_user_call(&self, tool_calls: &ToolCalls) -> Option<(String, String)> {
+        if !self.tools.contains_key(ASK_USER_TOOL) {
+            return None;
+        }
+        tool_calls
+            .iter()
+            .filter(|(_, call)| call.tool_name == ASK_USER_TOOL)
+            .find_map(|(id, call)| Some((id.clone(), AskUserTool::question(&call.args).ok()?)))
+    }
+
+    async fn get_decision(&self, messages: &[Message], retries: usize) -> Result<Decision> {
+        let mut tools: Vec<&dyn Tool> = self.tools.values().map(Arc::as_ref).collect();
+        if let Some(hook) = &self.tools_hook {
+            hook(messages, &mut tools);
+        }
+        let messages = prepend_system_layers(&self.config.system_layers, messages);
+        let messages = if self.config.include_tool_manifest {
+            inject_tool_manifest(&messages, &tool_manifest(&tools))
+        } else {
+            messages
+        };
 
-        self.llm
-            .complete(messages, tools, self.config.max_tokens)
+        let options = self.request_options(retries);
+        // 已附加过工具清单时不再重复附加
+        let fallback_manifest = (self.config.fallback_without_tools && !tools.is_empty())
+            .then(|| (!self.config.include_tool_manifest).then(|| tool_manifest(&tools)));
+        match self
+            .llm
+            .complete(&messages, tools, self.config.max_tokens, &options)
             .await
+        {
+            Err(err) if fallback_manifest.is_some() && LlmError::is_tools_unsupported(&err) => {
+                warn!("model does not support tools, retrying without them: {err}");
+                let messages = match fallback_manifest.flatten() {
+                    Some(manifest) => inject_tool_manifest(&messages, &manifest),
+                    None => messages,
+                };
+                self.llm
+                    .complete(&messages, Vec::new(), self.config.max_tokens, &options)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// 检查所有已注册工具的参数 schema 是否为合法的 JSON Schema，出错时列出每个有问题的工具及原因
+    ///
+    /// 部分服务商会直接拒绝带有错误函数定义的请求，可在开始服务前调用以尽早发现问题。
+    pub fn validate_tools(&self) -> Result<()> {
+        let mut tools: Vec<&dyn Tool> = self.tools.values().map(Arc::as_ref).collect();
+        tools.sort_by_key(|tool| tool.name());
+        let problems: Vec<String> = tools
+            .into_iter()
+            .filter_map(|tool| {
+                let schema = tool.args_schema()?;
+                let err = validate_schema(&schema).err()?;
+                Some(format!("`{}`: {err}", tool.name()))
+            })
+            .collect();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        bail!("invalid tool args schema: {}", problems.join("; "))
+    }
+
+    /// 检查决策中引用的工具是否都已注册，缺失时返回列出全部缺失工具的错误
+    ///
+    /// 可在执行多个工具调用前用于整体拒绝或告警，而不是在执行过程中逐个发现。
+    pub fn validate_decision(&self, decision: &Decision) -> Result<()> {
+        let Decision::ExecuteTool(_, tool_calls) = decision else {
+            return Ok(());
+        };
+        let mut missing: Vec<&str> = tool_calls
+            .values()
+            .map(|call| call.tool_name.as_str())
+            .filter(|name| !self.tools.contains_key(*name))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        missing.sort();
+        missing.dedup();
+        let missing: Vec<String> = missing.iter().map(|name| format!("`{name}`")).collect();
+        bail!(
+            "decision references unregistered tools: {}",
+            missing.join(", ")
+        )
+    }
+
+    /// 裁剪上下文的 token 预算：优先使用 `max_tokens`，未设置时按模型的上下文窗口取安全值
+    fn context_budget(&self) -> Option<usize> {
+        let budget = self
+            .config
+            .max_tokens
+            .or_else(|| self.llm.model_name().and_then(ModelInfo::default_budget))?;
+        let layers: usize = self
+            .config
+            .system_layers
+            .iter()
+            .map(|layer| estimate_tokens(layer))
+            .sum();
+        Some(budget.saturating_sub(layers))
+    }
+
+    /// 以完整历史检查配置的停止条件
+    fn should_stop(&self) -> bool {
+        self.config
+            .stop_condition
+            .as_ref()
+            .is_some_and(|condition| {
+                condition.should_stop(&self.short_term_memory.get_context_messages(None))
+            })
+    }
+
+    /// 根据配置构造每次请求的可选参数，`retries` 为当前已重试的次数
+    fn request_options(&self, retries: usize) -> RequestOptions {
+        RequestOptions {
+            prediction: self.config.prediction.clone(),
+            extra_params: self.config.extra_params.clone(),
+            temperature: Some(self.config.temperature_for_retry(retries)),
+            top_p: self.config.top_p,
+            frequency_penalty: self.config.frequency_penalty,
+            presence_penalty: self.config.presence_penalty,
+            logit_bias: self.config.logit_bias.clone(),
+        }
     }
 
     /// 执行一系列工具调用，并收集它们的结果。
@@ -172,36 +1124,23 @@ where
     ///
     /// # 返回值
     /// 如果所有工具成功执行，则返回一个`Result<HashMap<String, String>>`，其中键为工具名称，值为相应的执行结果。如果任何工具调用失败，则返回包含错误信息的`Result::Err`。
-    async fn execute_tool(
-        &self,
-        args: &HashMap<String, ToolCallArgs>,
-    ) -> Result<ToolExecutionResult> {
+    async fn execute_tool(&self, args: &ToolCalls) -> Result<ToolExecutionResult> {
         let mut success_result: HashMap<String, String> = HashMap::new();
         let mut failure_result: HashMap<String, String> = HashMap::new();
-        let tools = args
-            .iter()
-            .filter_map(|(tool_call_id, args)| {
-                let tool = self.tools.get(&args.tool_name);
-                if let None = tool {
-                    failure_result.insert(
-                        args.tool_name.clone(),
-                        format!("Tool {} does not exist!", args.tool_name),
-                    );
-                    None
-                } else {
-                    Some((tool.unwrap(), &args.args, tool_call_id))
-                }
-            })
-            .collect::<Vec<_>>();
-        for (tool, args, tool_call_id) in tools {
-            match tool.execute(args.clone()).await {
-                Ok(result) => {
-                    success_result.insert(tool_call_id.clone(), result);
-                }
-                Err(err) => {
-                    failure_result.insert(tool_call_id.clone(), err.to_string());
-                }
-            }
+        // 与流式处理共用同一套执行逻辑（并行、FailFast、取消与顺序）
+        let round = execute_tool_round(
+            args,
+            &self.tools,
+            current_depth(),
+            &self.tool_round_canceller,
+            &self.config,
+        );
+        tokio::pin!(round);
+        while let Some((tool_call_id, result)) = round.next().await {
+            match result {
+                Ok(result) => success_result.insert(tool_call_id, result),
+                Err(err) => failure_result.insert(tool_call_id, err),
+            };
         }
 
         Ok(ToolExecutionResult {
@@ -220,54 +1159,166 @@ where
     /// 5. 当 Decision 为 Respond 时，将完整回复加入记忆，恢复状态为 Ready，并结束循环
     ///
     /// 返回一个异步流，该流每次 yield Assistant 的部分回复或错误信息。
+    /// 需要工具审批等非文本事件时使用 `handle_message_events`。
     pub async fn handle_message_stream<'a>(
         &'a mut self,
         message: String,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + 'a>>> {
+        let events = self.handle_message_events(message).await?;
+        Ok(Box::pin(events.filter_map(|event| async move {
+            match event {
+                Ok(StreamEvent::Text(text)) => Some(Ok(text)),
+                // 审批请求在此被丢弃，相应的工具调用视为被拒绝
+                Ok(StreamEvent::AwaitingToolApproval(_)) => None,
+                Ok(StreamEvent::ToolCall { .. } | StreamEvent::ToolResult { .. }) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })))
+    }
+
+    /// 与 `handle_message_stream` 相同，但通过容量为 `stream_channel_capacity` 的有界 channel 输出
+    ///
+    /// 返回接收端与负责驱动 Agent 的 future；该 future 需要与接收端并发地 await（如 `tokio::join!`），
+    /// 它在 channel 满时等待，从而让慢速的消费者限制生产速度。
+    pub async fn handle_message_channel<'a>(
+        &'a mut self,
+        message: String,
+    ) -> Result<(
+        mpsc::Receiver<Result<String>>,
+        impl Future<Output = ()> + 'a,
+    )> {
+        let (tx, rx) = mpsc::channel(self.config.stream_channel_capacity.max(1));
+        let stream = self.handle_message_stream(message).await?;
+        Ok((rx, forward_to_channel(stream, tx)))
+    }
+
+    /// 与 `handle_message_stream` 相同，但产出 `StreamEvent`
+    ///
+    /// 开启 `require_tool_approval` 后，每轮工具调用执行前会产出
+    /// `StreamEvent::AwaitingToolApproval`，流在调用方答复前暂停。
+    /// 每个工具调用先产出 `StreamEvent::ToolCall`，执行完成后立即产出对应的 `StreamEvent::ToolResult`。
+    pub async fn handle_message_events<'a>(
+        &'a mut self,
+        message: String,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamEvent>> + 'a>>> {
         // 1. 状态检查
-        if !matches!(self.state, AgentState::Ready) {
-            return Err(anyhow!("Agent is not in ready state"));
-        }
+        self.check_ready()?;
+        self.config.validate()?;
+        let depth = check_depth(self.config.max_depth)?;
+        self.init_tools().await?;
+        let turn_deadline = self.config.turn_timeout.map(|limit| {
+            (
+                Instant::now() + limit,
+                format!("turn exceeded turn_timeout ({limit:?})"),
+            )
+        });
+        let conversation_deadline = self.conversation_deadline().map(|(deadline, limit)| {
+            (
+                deadline,
+                format!("conversation exceeded conversation_timeout ({limit:?})"),
+            )
+        });
+        // 两个时限同时配置时以先到者为准
+        let deadline = [turn_deadline, conversation_deadline]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(deadline, _)| *deadline);
         self.state = AgentState::Processing;
 
         // 2. 添加用户消息到短期记忆
-        self.short_term_memory
-            .add_message(Message::User { content: message });
+        self.add_user_message(message);
 
         // 3. 获取裁剪后的上下文
-        let mut context = self
-            .short_term_memory
-            .get_context_messages(self.config.max_tokens);
+        let context_budget = self.context_budget();
+        let mut context = self.short_term_memory.get_context_messages(context_budget);
+
+        let options = self.request_options(0);
 
         // 为避免克隆 short_term_memory，我们直接借用 self.short_term_memory 和 self.state
         let stm = &mut self.short_term_memory;
-        let state = &mut self.state;
+        // 流被消费者提前丢弃时，由守卫负责恢复 Agent 状态
+        let guard = ProcessingGuard {
+            state: &mut self.state,
+        };
         let config = self.config.clone(); // config 一般比较小，可以克隆
         let timeout_duration = self.config.timeout;
-        let max_retries = self.config.retry_config.max_retries;
         let llm = &self.llm;
-        let tools: Vec<&Box<dyn Tool>> = self.tools.values().collect();
+        let registered_tools = &self.tools;
+        let tools: Vec<&dyn Tool> = self.tools.values().map(Arc::as_ref).collect();
+        let tools_hook = &self.tools_hook;
+        let tool_result_filter = &self.tool_result_filter;
+        let canceller = self.tool_round_canceller.clone();
+        let status = self.status.clone();
 
         // 使用 async_stream::stream! 生成流，该闭包不使用 move，从而允许捕获 &mut stm、&mut state 等借用
         let output_stream = stream! {
+            // 将守卫移入流中，使其随流一起被释放
+            let guard = guard;
             let mut retries = 0;
+            let mut pruned = false;
             let mut full_response = String::new();
+            // 上一次请求在输出中途断开，本次请求以已收到的回复作为前缀续写
+            let mut resuming = false;
+            let mut tool_rounds = 0;
+            let mut strict_step_retries = 0;
+            let mut empty_retries = 0;
+            let mut loop_detector = ToolLoopDetector::default();
             loop {
                 // 调用流式 LLM 方法
+                let mut turn_tools = tools.clone();
+                if let Some(hook) = tools_hook {
+                    hook(&context, &mut turn_tools);
+                }
+                let request_context = prepend_system_layers(&config.system_layers, &context);
+                let mut request_context = if config.include_tool_manifest {
+                    inject_tool_manifest(&request_context, &tool_manifest(&turn_tools))
+                } else {
+                    request_context
+                };
+                if resuming {
+                    request_context.push(Message::Assistant {
+                        content: full_response.clone(),
+                        tool_calls: None,
+                    });
+                }
+                let options = RequestOptions {
+                    temperature: Some(config.temperature_for_retry(retries)),
+                    ..options.clone()
+                };
                 let stream_result = timeout(
                     timeout_duration,
-                    llm.stream_complete(&context, tools.clone(), config.max_tokens),
+                    llm.stream_complete(&request_context, turn_tools, config.max_tokens, &options),
                 )
                 .await;
                 let mut decision_stream = match stream_result {
                     Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) if !pruned && LlmError::is_context_length_exceeded(&e) => {
+                        let pruned_context = prune_context(&context);
+                        if pruned_context.len() >= context.len() {
+                            yield Err(e);
+                            break;
+                        }
+                        context = pruned_context;
+                        pruned = true;
+                        continue;
+                    }
                     Ok(Err(e)) => {
+                        // 尚未输出任何内容，重新打开流不会产生重复内容
+                        if is_transient_error(&e, &config) && config.retry_config.allows_retry(retries) {
+                            warn!("failed to open stream, retrying: {e}");
+                            retries += 1;
+                            tokio::time::sleep(retry_wait(&e, &config)).await;
+                            continue;
+                        }
                         yield Err(e);
                         break;
                     }
                     Err(_) => {
-                        if retries < max_retries {
+                        if config.retry_config.should_retry_on_error
+                            && config.retry_config.allows_retry(retries)
+                        {
                             retries += 1;
+                            tokio::time::sleep(config.retry_config.retry_delay).await;
                             continue;
                         } else {
                             yield Err(anyhow!("LLM request timed out"));
@@ -277,136 +1328,702 @@ where
                 };
 
                 // 标记是否遇到工具调用
-                let mut tool_calls: Option<HashMap<String, ToolCallArgs>> = None;
+                let mut tool_calls: Option<ToolCalls> = None;
 
                 // 遍历流中每个 Decision
+                let mut overflowed = false;
+                let mut received_any = false;
+                let mut failed_before_first_chunk = false;
+                let mut interrupted = false;
+                let mut wait = config.retry_config.retry_delay;
                 while let Some(decision_result) = decision_stream.next().await {
-                    match decision_result {
-                        Ok(decision) => match decision {
-                            Decision::ExecuteTool(partial_response, tc_map) => {
-                                full_response.push_str(&partial_response);
-                                // 记录工具调用信息（多次调用时取最后一次）
-                                tool_calls = Some(tc_map.clone());
-                                yield Ok(partial_response.clone());
-                            }
-                            Decision::Respond(partial_response) => {
-                                full_response.push_str(&partial_response);
-                                yield Ok(partial_response.clone());
-                            }
-                        },
+                    let partial_response = match decision_result {
+                        Ok(Decision::ExecuteTool(partial_response, tc_map)) => {
+                            // 记录工具调用信息（多次调用时取最后一次）
+                            tool_calls = Some(tc_map);
+                            partial_response
+                        }
+                        Ok(Decision::Respond(partial_response)) => partial_response,
+                        Err(e) if !received_any
+                            && is_transient_error(&e, &config)
+                            && config.retry_config.allows_retry(retries) =>
+                        {
+                            warn!("stream failed before the first chunk, retrying: {e}");
+                            wait = retry_wait(&e, &config);
+                            failed_before_first_chunk = true;
+                            break;
+                        }
+                        Err(e) if config.resume_interrupted_streams
+                            && tool_calls.is_none()
+                            && is_transient_error(&e, &config)
+                            && config.retry_config.allows_retry(retries) =>
+                        {
+                            warn!("stream interrupted, resuming from the partial response: {e}");
+                            wait = retry_wait(&e, &config);
+                            interrupted = true;
+                            break;
+                        }
                         Err(e) => {
-                            yield Err(e);
+                            // 已经输出过内容时不再重试，避免重复输出
+                            yield Err(StreamInterrupted::wrap(e, &full_response));
+                            continue;
+                        }
+                    };
+                    received_any = true;
+                    if let Some(limit) = config.max_stream_response_bytes {
+                        if full_response.len() + partial_response.len() > limit {
+                            overflowed = true;
+                            let e = anyhow!(
+                                "streamed response exceeded max_stream_response_bytes ({limit})"
+                            );
+                            yield Err(StreamInterrupted::wrap(e, &full_response));
+                            break;
                         }
                     }
+                    full_response.push_str(&partial_response);
+                    yield Ok(StreamEvent::Text(partial_response));
                 } // end while decision_stream
+                if overflowed {
+                    break;
+                }
+                if failed_before_first_chunk || interrupted {
+                    drop(decision_stream);
+                    resuming = resuming || (interrupted && !full_response.is_empty());
+                    retries += 1;
+                    tokio::time::sleep(wait).await;
+                    continue;
+                }
 
                 // 流结束后判断是否需要执行工具
-                if let Some(tc) = tool_calls {
-                    // 将 Assistant 的流式回复及工具调用信息加入记忆
-                    stm.add_message(Message::Assistant {
-                        content: full_response.clone(),
-                        tool_calls: Some(tc.clone()),
-                    });
-                    // 执行工具调用
-                    match Agent::<M, H, L>::execute_tool_static(&tc, tools.clone()).await {
-                        Ok(exec_result) => {
-                            // 成功工具响应
-                            for (tool_call_id, content) in exec_result.success_result {
-                                stm.add_message(Message::Tool {
-                                    content: content.clone(),
-                                    tool_call_id: tool_call_id.clone(),
-                                });
-                            }
-                            // 失败工具响应
-                            for (tool_call_id, error) in exec_result.failure_result {
-                                let err_msg = format!(
-                                    "工具 {} 执行失败（错误信息：{}）。",
-                                    tc.get(&tool_call_id).unwrap().tool_name,
-                                    error
-                                );
-                                stm.add_message(Message::Tool {
-                                    content: err_msg.clone(),
-                                    tool_call_id: tool_call_id.clone(),
-                                });
+                if let Some(mut tc) = tool_calls {
+                    if config.strict_step_mode != StrictStepMode::Off
+                        && violates_strict_step(&full_response, &tc)
+                    {
+                        // 已输出的文本无法撤回，但不会写入记忆
+                        let rejected = std::mem::take(&mut full_response);
+                        resuming = false;
+                        if config.strict_step_mode == StrictStepMode::Reask
+                            && strict_step_retries < config.max_validation_retries
+                        {
+                            strict_step_retries += 1;
+                            stm.add_message(Message::User {
+                                content: strict_step_feedback(&rejected, &tc),
+                            });
+                            context = stm.get_context_messages(context_budget);
+                            if pruned {
+                                context = prune_context(&context);
                             }
-                            // 更新上下文，然后继续循环获取后续回复
-                            context = stm.get_context_messages(config.max_tokens);
-                            full_response.clear();
-                            // 重置 tool_calls 后继续
                             continue;
                         }
+                        tc = first_tool_call(tc);
+                    }
+                    let loop_check = match loop_detector.check(&tc, &config) {
+                        Ok(loop_check) => loop_check,
                         Err(e) => {
-                            yield Err(e);
+                            yield Err(StreamInterrupted::wrap(e, &full_response));
                             break;
                         }
+                    };
+                    if let Err(e) = check_max_turns(tool_rounds, &config) {
+                        yield Err(StreamInterrupted::wrap(e, &full_response));
+                        break;
+                    }
+                    tool_rounds += 1;
+                    let _ = status.send(tool_round_status(tool_rounds, &tc));
+                    for (call_id, call) in &tc {
+                        yield Ok(StreamEvent::ToolCall {
+                            call_id: call_id.clone(),
+                            name: call.tool_name.clone(),
+                            args: call.args.clone(),
+                        });
+                    }
+                    // 需要审批时先交给调用方，只执行被批准的调用
+                    let mut tool_messages = Vec::new();
+                    let mut approved_calls = None;
+                    if config.require_tool_approval {
+                        let (request, reply) = ToolApprovalRequest::new(tc.clone());
+                        yield Ok(StreamEvent::AwaitingToolApproval(request));
+                        let approved = reply.await.unwrap_or_default();
+                        let (allowed, rejected): (ToolCalls, ToolCalls) = tc
+                            .iter()
+                            .map(|(id, call)| (id.clone(), call.clone()))
+                            .partition(|(id, _)| approved.contains(id));
+                        for (tool_call_id, call) in rejected {
+                            let content = format!(
+                                "工具 {} 执行失败（错误信息：denied by user）。",
+                                call.tool_name
+                            );
+                            yield Ok(StreamEvent::ToolResult {
+                                call_id: tool_call_id.clone(),
+                                name: call.tool_name,
+                                output: content.clone(),
+                            });
+                            tool_messages.push(Message::Tool { content, tool_call_id });
+                        }
+                        approved_calls = Some(allowed);
                     }
+                    // 逐个执行工具调用，每完成一个就产出其结果
+                    let to_execute = approved_calls.as_ref().unwrap_or(&tc);
+                    let mut round = execute_tool_round(
+                        to_execute,
+                        registered_tools,
+                        depth,
+                        &canceller,
+                        &config,
+                    );
+                    while let Some((tool_call_id, result)) = round.next().await {
+                        let name = tc[&tool_call_id].tool_name.clone();
+                        let content = match result {
+                            Ok(output) => {
+                                let output = match tool_result_filter {
+                                    Some(filter) => filter(&name, output),
+                                    None => output,
+                                };
+                                summarize_tool_output(llm, &config, output).await
+                            }
+                            Err(error) => format!("工具 {} 执行失败（错误信息：{}）。", name, error),
+                        };
+                        yield Ok(StreamEvent::ToolResult {
+                            call_id: tool_call_id.clone(),
+                            name,
+                            output: content.clone(),
+                        });
+                        tool_messages.push(Message::Tool { content, tool_call_id });
+                    }
+                    drop(round);
+                    let _ = status.send(tool_results_status(tool_rounds, tool_messages.len()));
+                    // 将 Assistant 的流式回复、工具调用信息及工具结果加入记忆
+                    let tool_messages = sort_tool_messages(tool_messages, &tc);
+                    record_tool_round(
+                        stm,
+                        config.tool_message_order,
+                        std::mem::take(&mut full_response),
+                        tc,
+                        tool_messages,
+                    );
+                    resuming = false;
+                    if config
+                        .stop_condition
+                        .as_ref()
+                        .is_some_and(|condition| condition.should_stop(&stm.get_context_messages(None)))
+                    {
+                        *guard.state = AgentState::Ready;
+                        break;
+                    }
+                    if let Some(note) = loop_check {
+                        stm.add_message(note);
+                    }
+                    // 更新上下文，然后继续循环获取后续回复
+                    context = stm.get_context_messages(context_budget);
+                    if pruned {
+                        context = prune_context(&context);
+                    }
+                    continue;
                 } else {
+                    if full_response.trim().is_empty() {
+                        match config.on_empty_response {
+                            EmptyResponsePolicy::ReturnEmpty => {}
+                            EmptyResponsePolicy::Retry
+                                if empty_retries < config.retry_config.max_retries =>
+                            {
+                                empty_retries += 1;
+                                full_response.clear();
+                                resuming = false;
+                                continue;
+                            }
+                            EmptyResponsePolicy::Retry | EmptyResponsePolicy::Error => {
+                                yield Err(anyhow!("the model returned an empty response"));
+                                break;
+                            }
+                        }
+                    }
                     // 如果没有工具调用，则认为回复已结束，更新记忆并恢复状态
                     stm.add_message(Message::Assistant {
-                        content: full_response.clone(),
+                        content: std::mem::take(&mut full_response),
                         tool_calls: None,
                     });
-                    *state = AgentState::Ready;
+                    *guard.state = AgentState::Ready;
+                    let _ = status.send("done".to_string());
                     break;
                 }
             } // end loop
         };
 
-        Ok(Box::pin(output_stream))
+        Ok(match deadline {
+            Some((deadline, error)) => Box::pin(with_deadline(output_stream, deadline, error)),
+            None => Box::pin(output_stream),
+        })
     }
+}
 
-    // 为了在 spawned async 块中使用 execute_tool，我们提供一个静态版本包装原有方法
-    async fn execute_tool_static(
-        args: &HashMap<String, ToolCallArgs>,
-        tools: Vec<&Box<dyn Tool>>,
-    ) -> Result<ToolExecutionResult> {
-        let mut success_result: HashMap<String, String> = HashMap::new();
-        let mut failure_result: HashMap<String, String> = HashMap::new();
-        // 根据传入的工具调用参数，从 tools 中查找并执行
-        for (tool_call_id, tc_args) in args.iter() {
-            // 在 tools 中查找名称匹配的工具
-            let tool_opt = tools.iter().find(|t| t.name() == tc_args.tool_name);
-            if let Some(tool) = tool_opt {
-                match tool.execute(tc_args.args.clone()).await {
-                    Ok(result) => {
-                        success_result.insert(tool_call_id.clone(), result);
-                    }
-                    Err(e) => {
-                        failure_result.insert(tool_call_id.clone(), e.to_string());
-                    }
+/// `Agent` 的对象安全接口，用于在同一容器中保存不同类型参数的 Agent（如 `Vec<Box<dyn DynAgent>>`）
+#[async_trait]
+pub trait DynAgent: Send + Sync {
+    /// 见 `Agent::handle_message`
+    async fn handle_message(&mut self, message: String) -> Result<String>;
+
+    /// 见 `Agent::handle_message_stream`
+    async fn handle_message_stream<'a>(
+        &'a mut self,
+        message: String,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + 'a>>>;
+}
+
+#[async_trait]
+impl<M, H, L> DynAgent for Agent<M, H, L>
+where
+    M: LongTermMemory,
+    H: ShortTermMemory,
+    L: LLMClient,
+{
+    async fn handle_message(&mut self, message: String) -> Result<String> {
+        Agent::handle_message(self, message).await
+    }
+
+    async fn handle_message_stream<'a>(
+        &'a mut self,
+        message: String,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + 'a>>> {
+        Agent::handle_message_stream(self, message).await
+    }
+}
+
+/// 并发处理一批互不相关的提示，每个提示由 `new_agent` 创建的独立 Agent 处理
+///
+/// 同时进行的请求最多 `concurrency` 个（为 0 时按 1 处理）；结果按输入顺序返回，
+/// 与各请求的完成顺序无关。
+pub async fn handle_batch<A, F>(
+    prompts: impl IntoIterator<Item = String>,
+    concurrency: usize,
+    mut new_agent: F,
+) -> Vec<Result<String>>
+where
+    A: DynAgent,
+    F: FnMut() -> A,
+{
+    let requests = prompts.into_iter().enumerate().map(|(index, prompt)| {
+        let mut agent = new_agent();
+        async move { (index, agent.handle_message(prompt).await) }
+    });
+    let mut results: Vec<(usize, Result<String>)> = futures::stream::iter(requests)
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// 流式处理期间持有 Agent 状态；若流在完成前被丢弃，则将状态从 Processing 恢复为 Ready
+struct ProcessingGuard<'a> {
+    state: &'a mut AgentState,
+}
+
+impl Drop for ProcessingGuard<'_> {
+    fn drop(&mut self) {
+        if matches!(self.state, AgentState::Processing) {
+            *self.state = AgentState::Ready;
+        }
+    }
+}
+
+/// 流在 `deadline` 前没有结束时将其丢弃（中止其中的 LLM 请求与工具执行），并以 `error` 结束
+///
+/// 工具轮次只在执行完成后才写入记忆，因此中止不会留下未完成的工具调用。
+fn with_deadline<'a, T: 'a>(
+    stream: impl Stream<Item = Result<T>> + 'a,
+    deadline: Instant,
+    error: String,
+) -> impl Stream<Item = Result<T>> + 'a {
+    stream! {
+        let mut stream = Box::pin(stream);
+        let expired = tokio::time::sleep_until(deadline);
+        tokio::pin!(expired);
+        loop {
+            tokio::select! {
+                item = stream.next() => match item {
+                    Some(item) => yield item,
+                    None => break,
+                },
+                _ = &mut expired => {
+                    // 先释放内部的流，使 Agent 在调用方收到错误时已恢复为 Ready
+                    drop(stream);
+                    yield Err(anyhow!(error));
+                    break;
                 }
-            } else {
-                failure_result.insert(
-                    tool_call_id.clone(),
-                    format!("Tool {} does not exist!", tc_args.tool_name),
-                );
             }
         }
-        Ok(ToolExecutionResult {
-            success_result,
-            failure_result,
+    }
+}
+
+/// 在执行一轮工具调用前调用：已执行的工具轮数达到 `max_turns` 时返回错误，
+/// 避免模型不断调用工具导致处理无法结束
+fn check_max_turns(tool_rounds: usize, config: &AgentConfig) -> Result<()> {
+    if tool_rounds >= config.max_turns {
+        bail!(
+            "exceeded max_turns: the model kept calling tools after {} rounds",
+            config.max_turns
+        );
+    }
+    Ok(())
+}
+
+/// 检测模型是否在反复发起完全相同的工具调用（名称与参数均相同）
+#[derive(Default)]
+struct ToolLoopDetector {
+    last: Vec<(String, String)>,
+    repeats: usize,
+}
+
+impl ToolLoopDetector {
+    /// 在执行一轮工具调用前调用
+    ///
+    /// 连续第 `tool_loop_window` 次相同时仍然执行，但返回一条要求模型停止调用工具的消息，
+    /// 调用方应在记录本轮结果后将其加入记忆；之后若模型仍然重复则返回错误。
+    fn check(&mut self, tool_calls: &ToolCalls, config: &AgentConfig) -> Result<Option<Message>> {
+        let mut signature: Vec<(String, String)> = tool_calls
+            .values()
+            .map(|call| (call.tool_name.clone(), call.args.to_string()))
+            .collect();
+        signature.sort();
+        if signature == self.last {
+            self.repeats += 1;
+        } else {
+            self.last = signature;
+            self.repeats = 1;
+        }
+
+        let Some(window) = config.tool_loop_window else {
+            return Ok(None);
+        };
+        if self.repeats > window {
+            bail!(
+                "tool call loop detected: the same tool calls were repeated {} times",
+                self.repeats
+            );
+        }
+        if self.repeats < window {
+            return Ok(None);
+        }
+        let names: Vec<&str> = self.last.iter().map(|(name, _)| name.as_str()).collect();
+        Ok(Some(Message::System {
+            content: format!(
+                "You have called {} with the same arguments {window} times in a row. \
+                 Stop calling tools and answer the user with the information you already have.",
+                names.join(", ")
+            ),
+        }))
+    }
+}
+
+/// 工具输出超过 `summarize_tool_output_over` 个 token 时，用 LLM 将其概括到预算之内
+///
+/// 概括失败（请求出错、超时或模型试图调用工具）时保留原始输出。
+async fn summarize_tool_output<L: LLMClient>(
+    llm: &L,
+    config: &AgentConfig,
+    output: String,
+) -> String {
+    let Some(budget) = config.summarize_tool_output_over else {
+        return output;
+    };
+    if estimate_tokens(&output) <= budget {
+        return output;
+    }
+    let messages = [
+        Message::System {
+            content: format!(
+                "Summarize the following tool output in at most {budget} tokens. \
+                 Keep every fact, number and identifier needed to answer the user; \
+                 reply with the summary only."
+            ),
+        },
+        Message::User {
+            content: output.clone(),
+        },
+    ];
+    let options = RequestOptions {
+        temperature: Some(0.0),
+        ..Default::default()
+    };
+    match timeout(
+        config.timeout,
+        llm.complete(&messages, Vec::new(), Some(budget), &options),
+    )
+    .await
+    {
+        Ok(Ok(Decision::Respond(summary))) => summary,
+        Ok(Ok(Decision::ExecuteTool(..))) => {
+            warn!("tool output summary returned a tool call, keeping the original output");
+            output
+        }
+        Ok(Err(err)) => {
+            warn!("failed to summarize tool output: {err}");
+            output
+        }
+        Err(_) => {
+            warn!("tool output summary timed out");
+            output
+        }
+    }
+}
+
+/// 严格步骤模式下，模型同时给出文本与工具调用或给出多个工具调用时的重新询问说明
+const STRICT_STEP_FEEDBACK: &str = "Each step must be exactly one tool call or a final answer, \
+     never both. Reply again with either a single tool call and no text, or only the final answer.";
+
+/// 严格步骤模式重新询问时发送的消息：`STRICT_STEP_FEEDBACK` 之后附上被拒绝的回复，
+/// 让模型知道是哪部分不符合要求（被拒绝的回复不会写入历史）
+fn strict_step_feedback(respond: &str, tool_calls: &ToolCalls) -> String {
+    let mut feedback = format!("{STRICT_STEP_FEEDBACK}\n\nYour rejected reply contained:");
+    if !respond.trim().is_empty() {
+        feedback.push_str(&format!("\n- text: {:?}", respond.trim()));
+    }
+    for call in tool_calls.values() {
+        feedback.push_str(&format!(
+            "\n- tool call `{}` with {}",
+            call.tool_name, call.args
+        ));
+    }
+    feedback
+}
+
+/// 严格步骤模式是否要处理该决策：同时包含文本与工具调用，或包含多个工具调用
+fn violates_strict_step(respond: &str, tool_calls: &ToolCalls) -> bool {
+    !respond.trim().is_empty() || tool_calls.len() > 1
+}
+
+/// 只保留模型发出的第一个工具调用
+fn first_tool_call(tool_calls: ToolCalls) -> ToolCalls {
+    tool_calls.into_iter().take(1).collect()
+}
+
+/// 校验失败时发送给模型的重新回答请求
+fn validation_feedback(err: &anyhow::Error) -> String {
+    format!(
+        "Your previous response failed validation: {err}. \
+         Please answer again and make sure the response satisfies the requirements."
+    )
+}
+
+/// 检测回复文本中未以结构化方式发出的工具调用意图，返回被提到的工具名
+///
+/// 识别两种写法：`name(...)` 形式的函数调用，以及同时包含 `"name"` 与 `"arguments"` 的 JSON 片段。
+fn text_tool_intent<'a>(
+    text: &str,
+    tool_names: impl Iterator<Item = &'a String>,
+) -> Option<&'a str> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    tool_names
+        .filter(|name| !name.is_empty())
+        .find(|name| {
+            let called = text.match_indices(name.as_str()).any(|(i, _)| {
+                let before = text[..i].chars().next_back();
+                let after = text[i + name.len()..].trim_start().chars().next();
+                !before.is_some_and(is_ident) && after == Some('(')
+            });
+            called || (text.contains(&format!("\"{name}\"")) && text.contains("\"arguments\""))
+        })
+        .map(String::as_str)
+}
+
+/// 回复没有用到最近的工具输出时发送给模型的提示
+const IGNORED_TOOL_RESULTS_FEEDBACK: &str =
+    "Your previous response did not use the tool results above. \
+     Please answer again using the information returned by the tools.";
+
+/// 粗略判断回复是否引用了工具输出：任一输出中的某个有效词（含数字，或至少 3 个字符）出现在回复中
+///
+/// 没有工具输出，或输出中没有有效词时视为已引用。
+fn references_tool_output(response: &str, outputs: &[String]) -> bool {
+    let response = response.to_lowercase();
+    let mut any_significant = false;
+    for output in outputs {
+        let output = output.to_lowercase();
+        for word in output.split(|c: char| !c.is_alphanumeric()) {
+            if word.chars().count() < 3 && !word.chars().any(|c| c.is_ascii_digit()) {
+                continue;
+            }
+            any_significant = true;
+            if response.contains(word) {
+                return true;
+            }
+        }
+    }
+    !any_significant
+}
+
+/// 回复中以文本形式描述工具调用时，要求模型改用结构化工具调用的提示
+fn text_tool_call_feedback(tool: &str) -> String {
+    format!(
+        "Your previous response described calling the `{tool}` tool in plain text, \
+         but the tool was not called. If you need the tool, call it with a proper tool call; \
+         otherwise answer the question directly."
+    )
+}
+
+/// 生成列出全部可用工具（名称、描述与参数 schema）的说明，按名称排序以保持稳定
+fn tool_manifest(tools: &[&dyn Tool]) -> String {
+    let mut tools = tools.to_vec();
+    tools.sort_by_key(|tool| tool.name());
+    let mut manifest =
+        String::from("## Available tools\nPrefer cheaper tools when several can do the job.\n");
+    for tool in tools {
+        manifest.push_str(&format!("- `{}`", tool.name()));
+        if let Some(description) = tool.description() {
+            manifest.push_str(&format!(": {description}"));
+        }
+        manifest.push('\n');
+        if let Some(schema) = tool.args_schema() {
+            manifest.push_str(&format!("  parameters: {schema}\n"));
+        }
+        manifest.push_str(&format!("  cost: {}\n", tool.cost_hint().as_str()));
+    }
+    manifest
+}
+
+/// 将分层的系统指令按顺序作为独立的 system 消息放在上下文最前面
+fn prepend_system_layers(layers: &[String], messages: &[Message]) -> Vec<Message> {
+    layers
+        .iter()
+        .map(|layer| Message::System {
+            content: layer.clone(),
         })
+        .chain(messages.iter().cloned())
+        .collect()
+}
+
+/// 将工具说明附加到开头的系统提示之后；没有系统提示时插入一条新的系统消息
+fn inject_tool_manifest(messages: &[Message], manifest: &str) -> Vec<Message> {
+    let mut messages = messages.to_vec();
+    match messages.first_mut() {
+        Some(Message::System { content }) => {
+            if !content.is_empty() {
+                content.push_str("\n\n");
+            }
+            content.push_str(manifest);
+        }
+        _ => messages.insert(
+            0,
+            Message::System {
+                content: manifest.to_string(),
+            },
+        ),
+    }
+    messages
+}
+
+/// 可以通过重新请求恢复的错误：开启了 `should_retry_on_error`，且不是 `LlmError` 描述的
+/// 确定性错误（认证失败、拒绝、上下文超长等）；请求频率超限等待后可以恢复，视为临时错误
+fn is_transient_error(err: &anyhow::Error, config: &AgentConfig) -> bool {
+    config.retry_config.should_retry_on_error
+        && (LlmError::find(err).is_none() || LlmError::is_rate_limited(err))
+}
+
+/// 重试前的等待时长：取 `retry_delay` 与服务端 `Retry-After` 建议值中较长的一个
+fn retry_wait(err: &anyhow::Error, config: &AgentConfig) -> Duration {
+    let delay = config.retry_config.retry_delay;
+    LlmError::retry_after(err).map_or(delay, |retry_after| retry_after.max(delay))
+}
+
+/// 工具执行失败时回传给模型的提示
+fn tool_failure_message(tool_name: &str, error: &str) -> String {
+    format!("工具 {tool_name} 执行失败（错误信息：{error}）。由于无法重试，请考虑使用其他方式解决问题或给出合适的响应。")
+}
+
+/// 按配置的顺序将一轮工具调用写入短期记忆
+///
+/// - `AssistantThenTools`: 带文本与工具调用的 assistant 消息在前，工具结果在后
+/// - `ToolsThenAssistant`: 仅含工具调用的 assistant 消息与工具结果在前，文本作为单独的 assistant 消息在后
+fn record_tool_round<H: ShortTermMemory>(
+    stm: &mut H,
+    order: ToolMessageOrder,
+    content: String,
+    tool_calls: ToolCalls,
+    tool_messages: Vec<Message>,
+) {
+    match order {
+        ToolMessageOrder::AssistantThenTools => {
+            stm.add_message(Message::Assistant {
+                content,
+                tool_calls: Some(tool_calls),
+            });
+            tool_messages.into_iter().for_each(|m| stm.add_message(m));
+        }
+        ToolMessageOrder::ToolsThenAssistant => {
+            stm.add_message(Message::Assistant {
+                content: String::new(),
+                tool_calls: Some(tool_calls),
+            });
+            tool_messages.into_iter().for_each(|m| stm.add_message(m));
+            if !content.is_empty() {
+                stm.add_message(Message::Assistant {
+                    content,
+                    tool_calls: None,
+                });
+            }
+        }
     }
 }
 
+/// 将工具结果消息按对应调用在 `tool_calls` 中的位置（即模型发出调用的顺序）排列，
+/// 使写入历史的顺序不受审批与执行完成顺序影响
+fn sort_tool_messages(mut messages: Vec<Message>, tool_calls: &ToolCalls) -> Vec<Message> {
+    messages.sort_by_key(|message| match message {
+        Message::Tool { tool_call_id, .. } => tool_calls.get_index_of(tool_call_id),
+        _ => None,
+    });
+    messages
+}
+
+/// 激进地裁剪上下文：保留开头的 System/Developer 消息，以及从最后一条用户消息开始的本轮对话
+fn prune_context(messages: &[Message]) -> Vec<Message> {
+    let preamble = messages
+        .iter()
+        .take_while(|m| matches!(m, Message::System { .. } | Message::Developer { .. }))
+        .count();
+    let current_turn = messages
+        .iter()
+        .rposition(|m| matches!(m, Message::User { .. }))
+        .unwrap_or(preamble)
+        .max(preamble);
+    messages[..preamble]
+        .iter()
+        .chain(&messages[current_turn..])
+        .cloned()
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        llm::tests::MockLLMClient,
-        memory::tests::{BasicShortTermMemory, MockLongTermMemory},
-        tools::tests::EchoTool,
+        llm::tests::{FuzzLLMClient, MockLLMClient, ScriptedLLMClient},
+        memory::{
+            tests::{BasicShortTermMemory, MockLongTermMemory},
+            MemoryEntry, MemoryMetadata,
+        },
+        tools::{tests::EchoTool, ToolCost},
+        types::{ResponseValidator, StopCondition},
     };
     use pretty_assertions::assert_eq;
     use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use std::time::Duration;
 
     // 辅助函数: 创建一个测试用的Agent
     fn create_test_agent() -> Agent<MockLongTermMemory, BasicShortTermMemory, MockLLMClient> {
+        create_test_agent_with_llm(MockLLMClient::new())
+    }
+
+    // 辅助函数: 使用指定的LLM创建一个测试用的Agent
+    fn create_test_agent_with_llm<L: LLMClient>(
+        llm: L,
+    ) -> Agent<MockLongTermMemory, BasicShortTermMemory, L> {
         let mut agent = Agent::new(
             MockLongTermMemory::new(),
-            BasicShortTermMemory::new(),
-            MockLLMClient::new(),
+            BasicShortTermMemory::default(),
+            llm,
         );
 
         // 配置Agent
@@ -422,6 +2039,7 @@ mod tests {
             },
             temperature: 0.7,
             timeout: Duration::from_secs(5),
+            ..Default::default()
         };
         agent = agent.with_config(config);
 
@@ -467,7 +2085,7 @@ mod tests {
     async fn test_agent_tool_execution() {
         let agent = create_test_agent();
         let tool_call_id = "tool_call_id".to_string();
-        let mut args = HashMap::new();
+        let mut args = ToolCalls::new();
         args.insert(
             tool_call_id.clone(),
             ToolCallArgs {
@@ -522,33 +2140,77 @@ mod tests {
         );
 
         // 4. 测试长期记忆存储和检索
-        // let test_data = serde_json::json!({
-        //     "important_info": "test_data"
-        // });
+        let test_data = serde_json::json!({
+            "important_info": "test_data"
+        });
+
+        let memory_entry = MemoryEntry {
+            content: test_data.clone(),
+            metadata: MemoryMetadata {
+                timestamp: chrono::Utc::now(),
+                tags: vec!["test".to_string()],
+                source: "test_tool".to_string(),
+            },
+        };
 
-        // let memory_entry = MemoryEntry {
-        //     result: test_data.clone(),
-        //     metadata: MemoryMetadata {
-        //         timestamp: chrono::Utc::now(),
-        //         tags: vec!["test".to_string()],
-        //         source: "test_tool".to_string(),
-        //     },
-        // };
+        agent.long_term_memory.store(memory_entry).await.unwrap();
 
-        // agent.long_term_memory.store(memory_entry).await.unwrap();
+        // 5. 通过语义查询验证记忆
+        let results = agent
+            .long_term_memory
+            .recall(&MemoryQuery::Semantic {
+                description: "test data".to_string(),
+                limit: 1,
+                min_similarity: 0.0,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].content, test_data);
+    }
+
+    #[tokio::test]
+    async fn test_build_memory_context_formats_recalled_entries() {
+        let mut agent = create_test_agent();
+        for (content, tag) in [
+            (json!("The user prefers metric units"), "preference"),
+            (json!({"user": "Ada", "units": "metric"}), "profile"),
+            (json!("Unrelated note about billing"), "billing"),
+        ] {
+            agent
+                .long_term_memory
+                .store(MemoryEntry {
+                    content,
+                    metadata: MemoryMetadata {
+                        timestamp: chrono::Utc::now(),
+                        tags: vec![tag.to_string()],
+                        source: "test".to_string(),
+                    },
+                })
+                .await
+                .unwrap();
+        }
 
-        // // 5. 通过语义查询验证记忆
-        // let results = agent
-        //     .long_term_memory
-        //     .recall(&MemoryQuery::Semantic {
-        //         description: "test data".to_string(),
-        //         limit: 1,
-        //     })
-        //     .await
-        //     .unwrap();
+        let message = agent
+            .build_memory_context("which units does the user like")
+            .await
+            .unwrap()
+            .unwrap();
+        let Message::Developer { content } = message else {
+            panic!("expected a developer message, got {message:?}");
+        };
+        assert!(content.contains("- The user prefers metric units"));
+        assert!(content.contains(r#"- {"units":"metric","user":"Ada"}"#));
+        assert!(!content.contains("billing"));
 
-        // assert_eq!(results.len(), 1);
-        // assert_eq!(results[0].content, test_data);
+        // 相似度阈值高于所有记忆时不注入
+        agent.config.memory_min_similarity = 0.9;
+        assert!(agent
+            .build_memory_context("which units does the user like")
+            .await
+            .unwrap()
+            .is_none());
     }
 
     #[tokio::test]
@@ -556,7 +2218,7 @@ mod tests {
         let mut agent = create_test_agent();
 
         // 1. 测试无效的工具调用
-        let mut args1 = HashMap::new();
+        let mut args1 = ToolCalls::new();
         args1.insert(
             "id".into(),
             ToolCallArgs {
@@ -569,7 +2231,7 @@ mod tests {
         assert!(!result.unwrap().failure_result.is_empty());
 
         // 2. 测试参数缺失的工具调用
-        let mut args2 = HashMap::new();
+        let mut args2 = ToolCalls::new();
         args2.insert(
             "id".into(),
             ToolCallArgs {
@@ -608,6 +2270,32 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_error_state_recovery() {
+        let mut agent = create_test_agent();
+
+        // 默认需要显式 reset
+        agent.state = AgentState::Error("upstream timeout".to_string());
+        assert!(agent.handle_message("Hello".to_string()).await.is_err());
+        agent.reset();
+        assert_eq!(
+            agent.handle_message("Hello".to_string()).await.unwrap(),
+            "Echo: Hello"
+        );
+
+        agent.config.auto_recover_from_error = true;
+        agent.state = AgentState::Error("upstream timeout".to_string());
+        assert_eq!(
+            agent.handle_message("Again".to_string()).await.unwrap(),
+            "Echo: Again"
+        );
+        assert!(matches!(agent.state, AgentState::Ready));
+
+        // 自动恢复只针对错误状态
+        agent.state = AgentState::Terminated;
+        assert!(agent.handle_message("Again".to_string()).await.is_err());
+    }
+
     #[tokio::test]
     async fn test_agent_complex_conversation() {
         let mut agent = create_test_agent();
@@ -640,7 +2328,7 @@ mod tests {
         let agent = create_test_agent();
 
         // 1. 执行第一个工具
-        let mut args = HashMap::new();
+        let mut args = ToolCalls::new();
         args.insert(
             "id1".into(),
             ToolCallArgs {
@@ -674,4 +2362,3143 @@ mod tests {
             .count();
         assert_eq!(tool_messages, 0); // 工具调用不会被添加到上下文中,因为我们直接调用了execute_tool
     }
+
+    #[tokio::test]
+    async fn test_agent_prunes_context_on_context_length_error() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("first answer".into())),
+            Err(LlmError::ContextLengthExceeded("too long".into()).into()),
+            Ok(Decision::Respond("second answer".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+
+        agent.handle_message("first".to_string()).await.unwrap();
+        let response = agent.handle_message("second".to_string()).await.unwrap();
+        assert_eq!(response, "second answer");
+        assert!(matches!(agent.state, AgentState::Ready));
+
+        let requests = agent.llm.requests();
+        assert_eq!(requests.len(), 3);
+        // 重试时发送的消息更少：只保留系统提示与本轮用户消息
+        assert!(requests[2].len() < requests[1].len());
+        assert_eq!(
+            requests[2],
+            vec![
+                Message::System {
+                    content: "You are a helpful assistant.".to_string()
+                },
+                Message::User {
+                    content: "second".to_string()
+                },
+            ]
+        );
+    }
+
+    fn echo_tool_call(id: &str, text: &str) -> ToolCalls {
+        let mut tool_calls = ToolCalls::new();
+        tool_calls.insert(
+            id.to_string(),
+            ToolCallArgs {
+                tool_type: "function".to_string(),
+                tool_name: "echo".to_string(),
+                args: json!({ "text": text }),
+            },
+        );
+        tool_calls
+    }
+
+    #[tokio::test]
+    async fn test_tool_message_order() {
+        for order in [
+            ToolMessageOrder::AssistantThenTools,
+            ToolMessageOrder::ToolsThenAssistant,
+        ] {
+            let llm = ScriptedLLMClient::new(vec![
+                Ok(Decision::ExecuteTool(
+                    "Let me echo.".into(),
+                    echo_tool_call("call_1", "hi"),
+                )),
+                Ok(Decision::Respond("done".into())),
+            ]);
+            let mut agent = create_test_agent_with_llm(llm);
+            agent.config.tool_message_order = order;
+            agent.handle_message("Echo hi".to_string()).await.unwrap();
+
+            let context = agent.short_term_memory.get_context_messages(None);
+            let tool_result = Message::Tool {
+                content: "hi".into(),
+                tool_call_id: "call_1".into(),
+            };
+            let round = match order {
+                ToolMessageOrder::AssistantThenTools => vec![
+                    Message::Assistant {
+                        content: "Let me echo.".into(),
+                        tool_calls: Some(echo_tool_call("call_1", "hi")),
+                    },
+                    tool_result,
+                ],
+                ToolMessageOrder::ToolsThenAssistant => vec![
+                    Message::Assistant {
+                        content: String::new(),
+                        tool_calls: Some(echo_tool_call("call_1", "hi")),
+                    },
+                    tool_result,
+                    Message::Assistant {
+                        content: "Let me echo.".into(),
+                        tool_calls: None,
+                    },
+                ],
+            };
+            // system + user 之后是本轮工具调用，最后是最终回复
+            assert_eq!(context[2..context.len() - 1], round[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropping_stream_early_resets_state() {
+        let mut agent = create_test_agent();
+
+        let mut stream = agent
+            .handle_message_stream("Hello".to_string())
+            .await
+            .unwrap();
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(chunk, "Echo: Hello");
+        drop(stream);
+
+        assert!(matches!(agent.state, AgentState::Ready));
+        let response = agent.handle_message("Again".to_string()).await.unwrap();
+        assert_eq!(response, "Echo: Again");
+    }
+
+    #[tokio::test]
+    async fn test_dropping_unpolled_stream_resets_state() {
+        let mut agent = create_test_agent();
+
+        let stream = agent
+            .handle_message_stream("Hello".to_string())
+            .await
+            .unwrap();
+        drop(stream);
+
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    #[tokio::test]
+    async fn test_stream_waits_for_tool_approval() {
+        let mut calls = echo_tool_call("call_1", "approved");
+        calls.extend(echo_tool_call("call_2", "denied"));
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool("Echoing.".into(), calls.clone())),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.require_tool_approval = true;
+
+        let mut events = agent
+            .handle_message_events("Echo twice".to_string())
+            .await
+            .unwrap();
+        let mut text = String::new();
+        let mut approvals = 0;
+        while let Some(event) = events.next().await {
+            match event.unwrap() {
+                StreamEvent::Text(chunk) => text.push_str(&chunk),
+                StreamEvent::AwaitingToolApproval(request) => {
+                    approvals += 1;
+                    assert_eq!(request.calls(), &calls);
+                    // 审批前模型的文本已经输出
+                    assert_eq!(text, "Echoing.");
+                    request.approve(["call_1"]);
+                }
+                StreamEvent::ToolCall { .. } | StreamEvent::ToolResult { .. } => {}
+            }
+        }
+        drop(events);
+
+        assert_eq!(approvals, 1);
+        assert_eq!(text, "Echoing.done");
+        let context = agent.short_term_memory.get_context_messages(None);
+        let tool_results: HashMap<_, _> = context
+            .iter()
+            .filter_map(|message| match message {
+                Message::Tool {
+                    content,
+                    tool_call_id,
+                } => Some((tool_call_id.as_str(), content.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tool_results["call_1"], "approved");
+        assert!(tool_results["call_2"].contains("denied by user"));
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    #[tokio::test]
+    async fn test_handle_message_channel_delivers_stream() {
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond("Hello there".into()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.stream_channel_capacity = 1;
+
+        let (mut rx, pump) = agent
+            .handle_message_channel("Hi".to_string())
+            .await
+            .unwrap();
+        let consumer = async {
+            let mut text = String::new();
+            while let Some(chunk) = rx.recv().await {
+                text.push_str(&chunk.unwrap());
+            }
+            text
+        };
+        let ((), text) = tokio::join!(pump, consumer);
+
+        assert_eq!(text, "Hello there");
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    #[tokio::test]
+    async fn test_stream_yields_tool_results_between_call_and_reply() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                "Echoing.".into(),
+                echo_tool_call("call_1", "hi"),
+            )),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+
+        let events: Vec<_> = agent
+            .handle_message_events("Echo".to_string())
+            .await
+            .unwrap()
+            .map(|event| match event.unwrap() {
+                StreamEvent::Text(text) => format!("text:{text}"),
+                StreamEvent::ToolCall { call_id, name, .. } => format!("call:{call_id}:{name}"),
+                StreamEvent::ToolResult {
+                    call_id,
+                    name,
+                    output,
+                } => format!("result:{call_id}:{name}:{output}"),
+                StreamEvent::AwaitingToolApproval(_) => unreachable!(),
+            })
+            .collect()
+            .await;
+
+        assert_eq!(
+            events,
+            vec![
+                "text:Echoing.",
+                "call:call_1:echo",
+                "result:call_1:echo:hi",
+                "text:done",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_records_tool_results_in_call_order() {
+        // 服务商的 id 是随机的，按字符串排序与调用顺序不同
+        let ids = [
+            "call_Xa9", "call_3f", "call_b2", "call_A7", "call_zz", "call_0c",
+        ];
+        let calls: ToolCalls = ids.iter().flat_map(|id| echo_tool_call(id, id)).collect();
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(String::new(), calls.clone())),
+            Ok(Decision::Respond("done".into())),
+            Ok(Decision::ExecuteTool(String::new(), calls)),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        let tool_result_ids = |agent: &Agent<_, BasicShortTermMemory, _>| {
+            agent
+                .short_term_memory
+                .get_context_messages(None)
+                .into_iter()
+                .filter_map(|message| match message {
+                    Message::Tool { tool_call_id, .. } => Some(tool_call_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let results: Vec<_> = agent
+            .handle_message_events("Echo".to_string())
+            .await
+            .unwrap()
+            .filter_map(|event| async move {
+                match event.unwrap() {
+                    StreamEvent::ToolResult { call_id, .. } => Some(call_id),
+                    _ => None,
+                }
+            })
+            .collect()
+            .await;
+        assert_eq!(results, ids);
+        assert_eq!(tool_result_ids(&agent), ids);
+
+        // 部分调用被拒绝时，拒绝与执行的结果仍按调用顺序记录
+        agent.config.require_tool_approval = true;
+        let mut events = agent
+            .handle_message_events("Echo again".to_string())
+            .await
+            .unwrap();
+        while let Some(event) = events.next().await {
+            if let StreamEvent::AwaitingToolApproval(request) = event.unwrap() {
+                request.approve(["call_3f", "call_zz"]);
+            }
+        }
+        drop(events);
+        assert_eq!(tool_result_ids(&agent)[ids.len()..], ids);
+    }
+
+    /// 只提供名称与参数 schema 的工具
+    #[derive(Debug)]
+    struct SchemaTool {
+        name: &'static str,
+        schema: Value,
+    }
+
+    #[async_trait::async_trait]
+    impl Tool for SchemaTool {
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn description(&self) -> Option<String> {
+            None
+        }
+
+        fn args_schema(&self) -> Option<Value> {
+            Some(self.schema.clone())
+        }
+
+        async fn execute(&self, _args: Value) -> Result<String> {
+            Ok(String::new())
+        }
+    }
+
+    #[test]
+    fn test_validate_tools_reports_invalid_schemas_by_name() {
+        let mut agent = create_test_agent();
+        agent.register_tool(SchemaTool {
+            name: "search",
+            schema: json!({"type": "object", "properties": {"query": {"type": "string"}}}),
+        });
+        agent.validate_tools().unwrap();
+
+        agent.register_tool(SchemaTool {
+            name: "resize",
+            schema: json!({"type": "object", "properties": {"width": {"type": "int"}}}),
+        });
+        agent.register_tool(SchemaTool {
+            name: "lookup",
+            schema: json!({"properties": {"id": {"type": "string"}}, "required": ["key"]}),
+        });
+        let err = agent.validate_tools().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid tool args schema: \
+             `lookup`: /required: \"key\" is not defined in properties; \
+             `resize`: /properties/width/type: unknown type \"int\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hidden_from_model_message_is_not_sent() {
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond("Hi".into()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        let note = Message::System {
+            content: "debug: user came from the pricing page".to_string(),
+        };
+        agent.add_message_with_visibility(note.clone(), Visibility::HiddenFromModel);
+
+        agent.handle_message("Hello".to_string()).await.unwrap();
+
+        assert!(!agent.llm.requests()[0].contains(&note));
+        assert!(agent.user_history().contains(&note));
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast_discards_successful_tool_results() {
+        let mut calls = echo_tool_call("call_ok", "kept?");
+        calls.insert(
+            "call_bad".to_string(),
+            ToolCallArgs {
+                tool_type: "function".to_string(),
+                tool_name: "echo".to_string(),
+                args: json!({}),
+            },
+        );
+        for streaming in [false, true] {
+            let llm = ScriptedLLMClient::new(vec![
+                Ok(Decision::ExecuteTool(String::new(), calls.clone())),
+                Ok(Decision::Respond("batch failed".into())),
+            ]);
+            let mut agent = create_test_agent_with_llm(llm);
+            agent.config.tool_failure_mode = ToolFailureMode::FailFast;
+
+            if streaming {
+                let stream = agent
+                    .handle_message_stream("Echo".to_string())
+                    .await
+                    .unwrap();
+                let _: Vec<_> = stream.collect().await;
+            } else {
+                agent.handle_message("Echo".to_string()).await.unwrap();
+            }
+
+            let tool_results: HashMap<_, _> = agent
+                .short_term_memory
+                .get_context_messages(None)
+                .into_iter()
+                .filter_map(|message| match message {
+                    Message::Tool {
+                        content,
+                        tool_call_id,
+                    } => Some((tool_call_id, content)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(tool_results.len(), 2);
+            assert!(tool_results
+                .values()
+                .all(|content| !content.contains("kept?")));
+            assert!(tool_results["call_bad"].contains("Missing 'text' argument"));
+            // 成功的调用先执行则结果被丢弃，后执行则被跳过
+            assert!(
+                tool_results["call_ok"].contains(TOOL_BATCH_DISCARDED)
+                    || tool_results["call_ok"].contains(TOOL_BATCH_SKIPPED)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast_runs_calls_in_call_order() {
+        // id 的字典序与调用顺序相反：按调用顺序时先成功、再失败、最后一个被跳过
+        let mut calls = echo_tool_call("call_z", "first");
+        calls.insert(
+            "call_m".to_string(),
+            ToolCallArgs {
+                tool_type: "function".to_string(),
+                tool_name: "echo".to_string(),
+                args: json!({}),
+            },
+        );
+        calls.extend(echo_tool_call("call_a", "never"));
+        for streaming in [false, true] {
+            let llm = ScriptedLLMClient::new(vec![
+                Ok(Decision::ExecuteTool(String::new(), calls.clone())),
+                Ok(Decision::Respond("batch failed".into())),
+            ]);
+            let mut agent = create_test_agent_with_llm(llm);
+            agent.config.tool_failure_mode = ToolFailureMode::FailFast;
+
+            if streaming {
+                let stream = agent
+                    .handle_message_stream("Echo".to_string())
+                    .await
+                    .unwrap();
+                let _: Vec<_> = stream.collect().await;
+            } else {
+                agent.handle_message("Echo".to_string()).await.unwrap();
+            }
+
+            let tool_results: HashMap<_, _> = agent
+                .short_term_memory
+                .get_context_messages(None)
+                .into_iter()
+                .filter_map(|message| match message {
+                    Message::Tool {
+                        content,
+                        tool_call_id,
+                    } => Some((tool_call_id, content)),
+                    _ => None,
+                })
+                .collect();
+            assert!(tool_results["call_z"].contains(TOOL_BATCH_DISCARDED));
+            assert!(tool_results["call_m"].contains("Missing 'text' argument"));
+            assert!(tool_results["call_a"].contains(TOOL_BATCH_SKIPPED));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_text_tool_call_is_reprompted() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("I'll call echo(\"hi\") now.".into())),
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                echo_tool_call("call_1", "hi"),
+            )),
+            Ok(Decision::Respond("The tool said hi.".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.reprompt_text_tool_calls = true;
+
+        let response = agent.handle_message("Echo hi".to_string()).await.unwrap();
+
+        assert_eq!(response, "The tool said hi.");
+        let requests = agent.llm.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1]
+            .last()
+            .unwrap()
+            .content()
+            .contains("described calling the `echo` tool"));
+    }
+
+    #[tokio::test]
+    async fn test_ignored_tool_results_are_reprompted_once() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                echo_tool_call("call_1", "42 apples"),
+            )),
+            Ok(Decision::Respond("I am not sure.".into())),
+            Ok(Decision::Respond("Still not sure.".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.reprompt_ignored_tool_results = true;
+
+        // 只重新询问一次，第二次的回复原样返回
+        let response = agent.handle_message("Count".to_string()).await.unwrap();
+        assert_eq!(response, "Still not sure.");
+        let requests = agent.llm.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[2].last().unwrap().content(),
+            IGNORED_TOOL_RESULTS_FEEDBACK
+        );
+    }
+
+    #[test]
+    fn test_references_tool_output() {
+        let outputs = ["{\"count\": 42, \"fruit\": \"apples\"}".to_string()];
+        assert!(references_tool_output("There are 42.", &outputs));
+        assert!(references_tool_output("Lots of APPLES!", &outputs));
+        assert!(!references_tool_output("I am not sure.", &outputs));
+        assert!(references_tool_output("Anything", &[]));
+    }
+
+    #[tokio::test]
+    async fn test_stop_condition_ends_the_loop() {
+        let stop_on_sentinel = StopCondition::new(|messages: &[Message]| {
+            messages.iter().any(|message| {
+                matches!(message, Message::Assistant { content, .. } if content.contains("[DONE]"))
+            })
+        });
+        for streaming in [false, true] {
+            let llm = ScriptedLLMClient::new(vec![
+                Ok(Decision::ExecuteTool(
+                    "All set [DONE]".into(),
+                    echo_tool_call("call_1", "hi"),
+                )),
+                Ok(Decision::Respond("should not be requested".into())),
+            ]);
+            let mut agent = create_test_agent_with_llm(llm);
+            agent.config.stop_condition = Some(stop_on_sentinel.clone());
+
+            let response = if streaming {
+                let stream = agent.handle_message_stream("Go".to_string()).await.unwrap();
+                let chunks: Vec<_> = stream.map(|chunk| chunk.unwrap()).collect().await;
+                chunks.concat()
+            } else {
+                agent.handle_message("Go".to_string()).await.unwrap()
+            };
+
+            assert_eq!(response, "All set [DONE]");
+            assert_eq!(agent.llm.requests().len(), 1);
+            assert!(matches!(agent.state, AgentState::Ready));
+        }
+    }
+
+    #[test]
+    fn test_text_tool_intent_requires_call_syntax() {
+        let names = ["echo".to_string(), "calculator".to_string()];
+        assert_eq!(
+            text_tool_intent("I'll call calculator (2+2)", names.iter()),
+            Some("calculator")
+        );
+        assert_eq!(
+            text_tool_intent(r#"{"name": "echo", "arguments": {}}"#, names.iter()),
+            Some("echo")
+        );
+        assert_eq!(text_tool_intent("The echo was loud.", names.iter()), None);
+        assert_eq!(text_tool_intent("recalculator(1)", names.iter()), None);
+    }
+
+    #[tokio::test]
+    async fn test_generate_title_leaves_history_unchanged() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("Sure, 4.".into())),
+            Ok(Decision::Respond("\"Simple Addition Question\"\n".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent
+            .handle_message("What is 2+2?".to_string())
+            .await
+            .unwrap();
+        let history = agent.short_term_memory.get_context_messages(None);
+
+        let title = agent.generate_title().await.unwrap();
+        assert_eq!(title, "Simple Addition Question");
+        assert_eq!(agent.short_term_memory.get_context_messages(None), history);
+
+        // 标题请求只包含第一轮问答，且不携带工具
+        let requests = agent.llm.requests();
+        let title_request = requests.last().unwrap();
+        assert_eq!(title_request.len(), 2);
+        assert_eq!(
+            title_request[1].content(),
+            "User: What is 2+2?\nAssistant: Sure, 4."
+        );
+        assert!(agent.llm.tool_names().last().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dyn_agents_in_one_vec() {
+        let scripted = create_test_agent_with_llm(ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("scripted".into())),
+            Ok(Decision::Respond("streamed".into())),
+        ]));
+        let mut agents: Vec<Box<dyn DynAgent>> =
+            vec![Box::new(create_test_agent()), Box::new(scripted)];
+
+        let mut responses = Vec::new();
+        for agent in agents.iter_mut() {
+            responses.push(agent.handle_message("Hi".to_string()).await.unwrap());
+        }
+        assert_eq!(responses, ["Echo: Hi", "scripted"]);
+
+        let mut streamed = Vec::new();
+        for agent in agents.iter_mut() {
+            let stream = agent.handle_message_stream("Again".to_string()).await;
+            let chunks: Vec<String> = stream.unwrap().map(|c| c.unwrap()).collect().await;
+            streamed.push(chunks.concat());
+        }
+        assert_eq!(streamed, ["Echo: Again", "streamed"]);
+    }
+
+    #[tokio::test]
+    async fn test_register_tool_under_custom_name() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([(
+                    "call_1".to_string(),
+                    ToolCallArgs {
+                        tool_type: "function".to_string(),
+                        tool_name: "repeat".to_string(),
+                        args: json!({ "text": "bonjour" }),
+                    },
+                )]),
+            )),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool_as("repeat", "Répète le texte donné", EchoTool::new());
+        agent.config.include_tool_manifest = true;
+
+        agent.handle_message("Repeat".to_string()).await.unwrap();
+
+        let mut names = agent.llm.tool_names()[0].clone();
+        names.sort();
+        assert_eq!(names, ["echo", "repeat"]);
+        assert!(agent.llm.requests()[0][0]
+            .content()
+            .contains("`repeat`: Répète le texte donné"));
+        let context = agent.short_term_memory.get_context_messages(None);
+        assert!(context.contains(&Message::Tool {
+            content: "bonjour".into(),
+            tool_call_id: "call_1".into(),
+        }));
+    }
+
+    /// 记录每次请求时刻的 LLM
+    #[derive(Default)]
+    struct TimestampLLMClient {
+        times: std::sync::Mutex<Vec<Instant>>,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for TimestampLLMClient {
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            self.times.lock().unwrap().push(Instant::now());
+            Ok(Decision::Respond("ok".into()))
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let response = self.complete(messages, tools, max_tokens, options).await?;
+            Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_is_shared_between_agents() {
+        let llm =
+            crate::llm::RateLimitedLlmClient::new(TimestampLLMClient::default(), 2.0).unwrap();
+        let mut agents: Vec<_> = (0..3)
+            .map(|_| create_test_agent_with_llm(llm.clone()))
+            .collect();
+
+        // 每个 Agent 依次处理两条消息，三个 Agent 并发
+        let turns = agents.iter_mut().map(|agent| async move {
+            agent.handle_message("one".to_string()).await.unwrap();
+            agent.handle_message("two".to_string()).await.unwrap();
+        });
+        futures::future::join_all(turns).await;
+
+        let times = llm.inner().times.lock().unwrap().clone();
+        assert_eq!(times.len(), 6);
+        // 任意两个相邻请求至少间隔 0.5 秒，即任意 1 秒内不超过 2 个请求
+        for pair in times.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(500));
+        }
+    }
+
+    /// 报告指定模型名称的 `ScriptedLLMClient`
+    struct NamedLLMClient {
+        model: &'static str,
+        inner: ScriptedLLMClient,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for NamedLLMClient {
+        fn model_name(&self) -> Option<&str> {
+            Some(self.model)
+        }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Decision> {
+            self.inner
+                .complete(messages, tools, max_tokens, options)
+                .await
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            self.inner
+                .stream_complete(messages, tools, max_tokens, options)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_context_is_trimmed_to_model_window_without_max_tokens() {
+        ModelInfo::set_context_window("agent-test-tiny-model", 20);
+        let llm = NamedLLMClient {
+            model: "agent-test-tiny-model",
+            inner: ScriptedLLMClient::new(vec![Ok(Decision::Respond("ok".into()))]),
+        };
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.max_tokens = None;
+        for i in 0..10 {
+            agent.add_system_note(format!("earlier note number {i}"));
+        }
+
+        agent.handle_message("Hi".to_string()).await.unwrap();
+
+        // 窗口为 20 时预算为 15，只能容纳最近的几条消息
+        let request = &agent.llm.inner.requests()[0];
+        assert!(request.len() < 11);
+        assert_eq!(
+            request.last(),
+            Some(&Message::User {
+                content: "Hi".to_string()
+            })
+        );
+    }
+
+    /// 前若干次请求一直挂起（触发超时重试），之后直接回复的 LLM，并记录每次请求的参数
+    struct StallingLLMClient {
+        stalls: usize,
+        options: std::sync::Mutex<Vec<RequestOptions>>,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for StallingLLMClient {
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Decision> {
+            let attempt = {
+                let mut recorded = self.options.lock().unwrap();
+                recorded.push(options.clone());
+                recorded.len()
+            };
+            if attempt <= self.stalls {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            }
+            Ok(Decision::Respond("finally".into()))
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let response = self.complete(messages, tools, max_tokens, options).await?;
+            Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_temperature_ramps_across_retries() {
+        let llm = StallingLLMClient {
+            stalls: 2,
+            options: Default::default(),
+        };
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.retry_config.max_retries = 3;
+        agent.config.temperature = 0.5;
+        agent.config.temperature_step = 0.25;
+
+        let response = agent.handle_message("Hi".to_string()).await.unwrap();
+        assert_eq!(response, "finally");
+
+        let temperatures: Vec<_> = agent
+            .llm
+            .options
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|options| options.temperature)
+            .collect();
+        assert_eq!(temperatures, [Some(0.5), Some(0.75), Some(1.0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_wait_retry_delay() {
+        let llm = ScriptedLLMClient::new(vec![
+            Err(anyhow!("connection reset")),
+            Ok(Decision::Respond("recovered".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.retry_config.retry_delay = Duration::from_secs(3);
+
+        let started = Instant::now();
+        let response = agent.handle_message("Hi".to_string()).await.unwrap();
+        assert_eq!(response, "recovered");
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+        assert_eq!(agent.llm.requests().len(), 2);
+
+        // 超时后同样等待 retry_delay 再重试
+        let llm = StallingLLMClient {
+            stalls: 1,
+            options: Default::default(),
+        };
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.retry_config.retry_delay = Duration::from_secs(3);
+
+        let started = Instant::now();
+        let response = agent.handle_message("Hi".to_string()).await.unwrap();
+        assert_eq!(response, "finally");
+        assert_eq!(
+            started.elapsed(),
+            agent.config.timeout + Duration::from_secs(3)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limited_requests_are_retried_after_retry_after() {
+        let rate_limited = |retry_after: u64| {
+            Err(anyhow::Error::new(LlmError::RateLimited {
+                message: "slow down".into(),
+                retry_after: Some(Duration::from_secs(retry_after)),
+            }))
+        };
+        // Retry-After 长于 retry_delay 时以 Retry-After 为准，否则仍等待 retry_delay
+        for (retry_after, expected) in [(5, 5), (1, 3)] {
+            let llm = ScriptedLLMClient::new(vec![
+                rate_limited(retry_after),
+                Ok(Decision::Respond("recovered".into())),
+            ]);
+            let mut agent = create_test_agent_with_llm(llm);
+            agent.config.retry_config.retry_delay = Duration::from_secs(3);
+
+            let started = Instant::now();
+            let response = agent.handle_message("Hi".to_string()).await.unwrap();
+            assert_eq!(response, "recovered");
+            assert_eq!(started.elapsed(), Duration::from_secs(expected));
+            assert_eq!(agent.llm.requests().len(), 2);
+
+            let llm = ScriptedLLMClient::new(vec![
+                rate_limited(retry_after),
+                Ok(Decision::Respond("recovered".into())),
+            ]);
+            let mut agent = create_test_agent_with_llm(llm);
+            agent.config.retry_config.retry_delay = Duration::from_secs(3);
+
+            let started = Instant::now();
+            let stream = agent.handle_message_stream("Hi".to_string()).await.unwrap();
+            let chunks: Vec<String> = stream.map(Result::unwrap).collect().await;
+            assert_eq!(chunks.concat(), "recovered");
+            assert_eq!(started.elapsed(), Duration::from_secs(expected));
+            assert_eq!(agent.llm.requests().len(), 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_retry_when_should_retry_on_error_is_disabled() {
+        let llm = ScriptedLLMClient::new(vec![
+            Err(anyhow!("connection reset")),
+            Ok(Decision::Respond("unused".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.retry_config.should_retry_on_error = false;
+
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+        assert_eq!(err.to_string(), "connection reset");
+        assert_eq!(agent.llm.requests().len(), 1);
+
+        let llm = StallingLLMClient {
+            stalls: 1,
+            options: Default::default(),
+        };
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.retry_config.should_retry_on_error = false;
+
+        let started = Instant::now();
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+        assert_eq!(err.to_string(), "LLM request timed out");
+        assert_eq!(started.elapsed(), agent.config.timeout);
+        assert_eq!(agent.llm.options.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_configured_temperature_reaches_openai_request_body() {
+        use crate::llm::openai::tests::serve_once;
+        use crate::llm::openai::OpenaiLlmClient;
+
+        fn request_body(raw: &str) -> Value {
+            let (_, body) = raw.split_once("\r\n\r\n").unwrap();
+            serde_json::from_str(body).unwrap()
+        }
+
+        let (url, server) = serve_once(
+            200,
+            r#"{"choices":[{"message":{"role":"assistant","content":"hi"}}]}"#,
+        )
+        .await;
+        let mut agent = create_test_agent_with_llm(OpenaiLlmClient::new("key", "gpt-4o", &url));
+        agent.config.temperature = 0.3;
+        assert_eq!(agent.handle_message("Hi".to_string()).await.unwrap(), "hi");
+        assert_eq!(
+            request_body(&server.await.unwrap())["temperature"],
+            json!(0.3)
+        );
+
+        // 流式请求同样使用配置的温度
+        let (url, server) = serve_once(
+            200,
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"hi\"}}]}\n\ndata: [DONE]\n\n",
+        )
+        .await;
+        let mut agent = create_test_agent_with_llm(OpenaiLlmClient::new("key", "gpt-4o", &url));
+        agent.config.temperature = 1.2;
+        let chunks: Vec<_> = agent
+            .handle_message_stream("Hi".to_string())
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert!(chunks.iter().all(Result::is_ok));
+        let body = request_body(&server.await.unwrap());
+        assert_eq!(body["temperature"], json!(1.2));
+        assert_eq!(body["stream"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_response_is_re_asked_with_feedback() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("not json".into())),
+            Ok(Decision::Respond(r#"{"answer": 4}"#.into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.response_validator = Some(ResponseValidator::new(|response| {
+            serde_json::from_str::<Value>(response)?;
+            Ok(())
+        }));
+
+        let response = agent
+            .handle_message("2+2 as JSON".to_string())
+            .await
+            .unwrap();
+        assert_eq!(response, r#"{"answer": 4}"#);
+
+        // 第二次请求包含被拒绝的回复以及校验错误
+        let requests = agent.llm.requests();
+        assert_eq!(requests.len(), 2);
+        let retry = &requests[1];
+        assert_eq!(retry[retry.len() - 2].content(), "not json");
+        assert!(retry
+            .last()
+            .unwrap()
+            .content()
+            .starts_with("Your previous response failed validation: expected ident"));
+    }
+
+    #[tokio::test]
+    async fn test_validation_gives_up_after_max_retries() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("bad".into())),
+            Ok(Decision::Respond("still bad".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.max_validation_retries = 1;
+        agent.config.response_validator =
+            Some(ResponseValidator::new(|_| Err(anyhow!("always invalid"))));
+
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "response failed validation: always invalid"
+        );
+        assert_eq!(agent.llm.requests().len(), 2);
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    #[tokio::test]
+    async fn test_long_tool_output_is_summarized() {
+        let long_text = "lorem ipsum ".repeat(100);
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                echo_tool_call("call_1", &long_text),
+            )),
+            Ok(Decision::Respond("Lorem ipsum, repeated.".into())),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.max_tokens = None;
+        agent.config.summarize_tool_output_over = Some(50);
+
+        agent.handle_message("Echo".to_string()).await.unwrap();
+
+        // 第二次请求是概括请求，原始输出只出现在其中
+        let requests = agent.llm.requests();
+        assert_eq!(requests[1][1].content(), long_text);
+        let context = agent.short_term_memory.get_context_messages(None);
+        assert!(context.contains(&Message::Tool {
+            content: "Lorem ipsum, repeated.".into(),
+            tool_call_id: "call_1".into(),
+        }));
+        assert!(!context.iter().any(|message| message.content() == long_text));
+    }
+
+    #[tokio::test]
+    async fn test_notes_are_sent_with_the_next_turn() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("Hello!".into())),
+            Ok(Decision::Respond("Bonjour !".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.handle_message("Hi".to_string()).await.unwrap();
+
+        agent.add_system_note("From now on respond in French.");
+        agent.add_developer_note("Keep answers short.");
+        agent.handle_message("Hi again".to_string()).await.unwrap();
+
+        let requests = agent.llm.requests();
+        let second = &requests[1];
+        assert_eq!(
+            second[second.len() - 3..],
+            [
+                Message::System {
+                    content: "From now on respond in French.".into(),
+                },
+                Message::Developer {
+                    content: "Keep answers short.".into(),
+                },
+                Message::User {
+                    content: "Hi again".into(),
+                },
+            ]
+        );
+        assert!(!requests[0]
+            .iter()
+            .any(|message| message.content().contains("French")));
+    }
+
+    #[tokio::test]
+    async fn test_strict_step_mode_drops_preamble_from_mixed_response() {
+        let mut calls = echo_tool_call("call_2", "second");
+        calls.extend(echo_tool_call("call_1", "first"));
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                "Let me echo that for you.".into(),
+                calls,
+            )),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.strict_step_mode = StrictStepMode::KeepToolCall;
+
+        let response = agent.handle_message("Echo".to_string()).await.unwrap();
+        assert_eq!(response, "done");
+
+        let context = agent.short_term_memory.get_context_messages(None);
+        assert!(!context
+            .iter()
+            .any(|message| message.content().contains("Let me echo")));
+        let Some(Message::Assistant {
+            content,
+            tool_calls: Some(tool_calls),
+        }) = context.iter().find(|message| {
+            matches!(
+                message,
+                Message::Assistant {
+                    tool_calls: Some(_),
+                    ..
+                }
+            )
+        })
+        else {
+            panic!("missing tool call message");
+        };
+        assert_eq!(content, "");
+        // 保留模型先发出的调用，而不是 id 最小的
+        assert_eq!(tool_calls.keys().collect::<Vec<_>>(), ["call_2"]);
+        let results: Vec<_> = context
+            .iter()
+            .filter(|message| matches!(message, Message::Tool { .. }))
+            .collect();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_strict_step_mode_reasks_on_mixed_response() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                "Sure, echoing.".into(),
+                echo_tool_call("call_1", "hi"),
+            )),
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                echo_tool_call("call_2", "hi"),
+            )),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.strict_step_mode = StrictStepMode::Reask;
+
+        let response = agent.handle_message("Echo".to_string()).await.unwrap();
+        assert_eq!(response, "done");
+
+        let requests = agent.llm.requests();
+        assert_eq!(requests.len(), 3);
+        // 重新询问时附上被拒绝的回复，让模型知道哪里不符合要求
+        assert_eq!(
+            requests[1].last().unwrap().content(),
+            format!(
+                "{STRICT_STEP_FEEDBACK}\n\nYour rejected reply contained:\n\
+                 - text: \"Sure, echoing.\"\n\
+                 - tool call `echo` with {{\"text\":\"hi\"}}"
+            )
+        );
+        // 混合的决策没有写入历史，只执行了重新回答后的调用
+        let context = agent.short_term_memory.get_context_messages(None);
+        assert!(!context.iter().any(|message| matches!(
+            message,
+            Message::Assistant { content, .. } if content.contains("Sure, echoing.")
+        )));
+        let result_ids: Vec<_> = context
+            .iter()
+            .filter_map(|message| match message {
+                Message::Tool { tool_call_id, .. } => Some(tool_call_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(result_ids, ["call_2"]);
+    }
+
+    #[tokio::test]
+    async fn test_strict_step_mode_applies_to_streams() {
+        let mut calls = echo_tool_call("call_2", "second");
+        calls.extend(echo_tool_call("call_1", "first"));
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool("Echoing both.".into(), calls.clone())),
+            Ok(Decision::Respond("done".into())),
+            Ok(Decision::ExecuteTool("Echoing both.".into(), calls)),
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                echo_tool_call("call_3", "third"),
+            )),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        let tool_result_ids = |agent: &Agent<_, BasicShortTermMemory, _>| {
+            agent
+                .short_term_memory
+                .get_context_messages(None)
+                .into_iter()
+                .filter_map(|message| match message {
+                    Message::Tool { tool_call_id, .. } => Some(tool_call_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // KeepToolCall：文本不写入记忆，只执行先发出的调用
+        agent.config.strict_step_mode = StrictStepMode::KeepToolCall;
+        let stream = agent
+            .handle_message_stream("Echo".to_string())
+            .await
+            .unwrap();
+        stream.for_each(|_| async {}).await;
+        assert_eq!(tool_result_ids(&agent), ["call_2"]);
+        let context = agent.short_term_memory.get_context_messages(None);
+        assert!(!context
+            .iter()
+            .any(|message| message.content().contains("Echoing both.")));
+
+        // Reask：混合的决策不执行，要求模型重新回答
+        agent.config.strict_step_mode = StrictStepMode::Reask;
+        let stream = agent
+            .handle_message_stream("Echo again".to_string())
+            .await
+            .unwrap();
+        stream.for_each(|_| async {}).await;
+        assert_eq!(tool_result_ids(&agent), ["call_2", "call_3"]);
+        let requests = agent.llm.requests();
+        let feedback = requests[3].last().unwrap().content();
+        assert!(feedback.starts_with(STRICT_STEP_FEEDBACK));
+        assert!(feedback.contains("- text: \"Echoing both.\""));
+        assert!(feedback.contains("- tool call `echo` with {\"text\":\"second\"}"));
+        assert!(feedback.contains("- tool call `echo` with {\"text\":\"first\"}"));
+    }
+
+    #[tokio::test]
+    async fn test_identical_tool_calls_break_after_window() {
+        let repeated = || {
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                echo_tool_call("call_1", "again"),
+            ))
+        };
+        // 默认不检测，需要显式设置窗口
+        assert_eq!(AgentConfig::default().tool_loop_window, None);
+        let llm = ScriptedLLMClient::new((0..10).map(|_| repeated()).collect());
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.tool_loop_window = Some(3);
+
+        let err = agent.handle_message("Loop".to_string()).await.unwrap_err();
+        assert!(err.to_string().starts_with("tool call loop detected"));
+
+        // 前 3 次正常执行，第 3 次之后要求模型停止，第 4 次重复时中止
+        let requests = agent.llm.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[3]
+            .last()
+            .unwrap()
+            .content()
+            .contains("Stop calling tools"));
+        let context = agent.short_term_memory.get_context_messages(None);
+        let executed = context
+            .iter()
+            .filter(|message| matches!(message, Message::Tool { .. }))
+            .count();
+        assert_eq!(executed, 3);
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    #[tokio::test]
+    async fn test_zero_max_tokens_is_rejected_before_any_request() {
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond("unused".into()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.max_tokens = Some(0);
+
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("max_tokens must be greater than 0"));
+        assert!(agent.llm.requests().is_empty());
+        // 用户消息没有写入记忆，上下文保持不变
+        assert_eq!(agent.short_term_memory.get_context_messages(None).len(), 1);
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    /// 每次都要求调用工具（参数各不相同，不会触发重复调用检测），永远不给出最终回复
+    #[derive(Default)]
+    struct EndlessToolLLMClient {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for EndlessToolLLMClient {
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                echo_tool_call(&format!("call_{call}"), &call.to_string()),
+            ))
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let response = self.complete(messages, tools, max_tokens, options).await?;
+            Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tool_rounds_stop_at_max_turns() {
+        let mut agent = create_test_agent_with_llm(EndlessToolLLMClient::default());
+        agent.config.max_turns = 3;
+
+        let err = agent.handle_message("Go".to_string()).await.unwrap_err();
+        assert!(err.to_string().starts_with("exceeded max_turns"));
+        // 执行了 3 轮工具，第 4 次要求调用工具时中止
+        assert_eq!(agent.llm.calls.load(Ordering::SeqCst), 4);
+        let context = agent.short_term_memory.get_context_messages(None);
+        let executed = context
+            .iter()
+            .filter(|message| matches!(message, Message::Tool { .. }))
+            .count();
+        assert_eq!(executed, 3);
+        assert!(matches!(agent.state, AgentState::Ready));
+
+        let mut agent = create_test_agent_with_llm(EndlessToolLLMClient::default());
+        agent.config.max_turns = 2;
+        let items: Vec<_> = agent
+            .handle_message_stream("Go".to_string())
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        let err = items.last().unwrap().as_ref().unwrap_err();
+        assert!(err.to_string().starts_with("exceeded max_turns"));
+        assert_eq!(agent.llm.calls.load(Ordering::SeqCst), 3);
+    }
+
+    /// 回复前等待一段与提示相关的时间，并统计同时进行中的请求数
+    #[derive(Default)]
+    struct ConcurrencyProbeLLMClient {
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for ConcurrencyProbeLLMClient {
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            let current = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(current, Ordering::SeqCst);
+            let prompt = messages.last().unwrap().content().to_string();
+            // 编号越小等待越久，使完成顺序与输入顺序相反
+            let n: u64 = prompt.trim_start_matches("prompt ").parse().unwrap();
+            tokio::time::sleep(Duration::from_millis(100 - n * 10)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(Decision::Respond(format!("answer to {prompt}")))
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let response = self.complete(messages, tools, max_tokens, options).await?;
+            Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handle_batch_bounds_concurrency_and_keeps_order() {
+        let probe = ConcurrencyProbeLLMClient::default();
+        let prompts: Vec<String> = (0..10).map(|i| format!("prompt {i}")).collect();
+
+        let results = handle_batch(prompts, 3, || {
+            create_test_agent_with_llm(ConcurrencyProbeLLMClient {
+                in_flight: probe.in_flight.clone(),
+                max_in_flight: probe.max_in_flight.clone(),
+            })
+        })
+        .await;
+
+        let results: Vec<String> = results.into_iter().map(|r| r.unwrap()).collect();
+        let expected: Vec<String> = (0..10).map(|i| format!("answer to prompt {i}")).collect();
+        assert_eq!(results, expected);
+        assert_eq!(probe.max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    /// 输出两个片段后连接中断的 LLM
+    struct BrokenStreamLLMClient;
+
+    #[async_trait::async_trait]
+    impl LLMClient for BrokenStreamLLMClient {
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            Err(anyhow!("connection reset"))
+        }
+
+        async fn stream_complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            Ok(Box::pin(futures::stream::iter([
+                Ok(Decision::Respond("The answer ".into())),
+                Ok(Decision::Respond("is".into())),
+                Err(anyhow!("connection reset")),
+            ])))
+        }
+    }
+
+    /// 第一次打开的流在输出前失败，第二次输出一个片段后失败的 LLM
+    #[derive(Default)]
+    struct FlakyStreamLLMClient {
+        attempts: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for FlakyStreamLLMClient {
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            Err(anyhow!("connection reset"))
+        }
+
+        async fn stream_complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let items = match self.attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => vec![Err(anyhow!("connection reset"))],
+                _ => vec![
+                    Ok(Decision::Respond("partial".into())),
+                    Err(anyhow!("connection reset")),
+                ],
+            };
+            Ok(Box::pin(futures::stream::iter(items)))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_open_failures_are_retried() {
+        let llm = ScriptedLLMClient::new(vec![
+            Err(anyhow!("connection reset")),
+            Err(anyhow!("HTTP 503")),
+            Ok(Decision::Respond("hello".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.retry_config.max_retries = 3;
+
+        let stream = agent.handle_message_stream("Hi".to_string()).await.unwrap();
+        let chunks: Vec<String> = stream.map(Result::unwrap).collect().await;
+
+        assert_eq!(chunks, ["hello"]);
+        assert_eq!(agent.llm.requests().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sync_and_stream_share_the_retry_bound() {
+        let failures = || {
+            ScriptedLLMClient::new(vec![
+                Err(anyhow!("connection reset")),
+                Err(anyhow!("connection reset")),
+                Err(anyhow!("connection reset")),
+                Ok(Decision::Respond("unused".into())),
+            ])
+        };
+
+        let mut agent = create_test_agent_with_llm(failures());
+        agent.config.retry_config.max_retries = 3;
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+        assert_eq!(err.to_string(), "connection reset");
+        assert_eq!(agent.llm.requests().len(), 3);
+
+        let mut agent = create_test_agent_with_llm(failures());
+        agent.config.retry_config.max_retries = 3;
+        let stream = agent.handle_message_stream("Hi".to_string()).await.unwrap();
+        let items: Vec<Result<String>> = stream.collect().await;
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0].as_ref().unwrap_err().to_string(),
+            "connection reset"
+        );
+        assert_eq!(agent.llm.requests().len(), 3);
+
+        // 超时同样计入请求次数
+        let stalling = || StallingLLMClient {
+            stalls: 3,
+            options: Default::default(),
+        };
+        let mut agent = create_test_agent_with_llm(stalling());
+        agent.config.retry_config.max_retries = 3;
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+        assert_eq!(err.to_string(), "LLM request timed out");
+        assert_eq!(agent.llm.options.lock().unwrap().len(), 3);
+
+        let mut agent = create_test_agent_with_llm(stalling());
+        agent.config.retry_config.max_retries = 3;
+        let stream = agent.handle_message_stream("Hi".to_string()).await.unwrap();
+        let items: Vec<Result<String>> = stream.collect().await;
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0].as_ref().unwrap_err().to_string(),
+            "LLM request timed out"
+        );
+        assert_eq!(agent.llm.options.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_is_retried_only_before_the_first_chunk() {
+        let mut agent = create_test_agent_with_llm(FlakyStreamLLMClient::default());
+
+        let stream = agent.handle_message_stream("Hi".to_string()).await.unwrap();
+        let items: Vec<Result<String>> = stream.collect().await;
+
+        // 第一次失败时尚无输出，重试；第二次已输出内容，失败直接交给调用方
+        assert_eq!(agent.llm.attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(items[0].as_deref().unwrap(), "partial");
+        let err = items[1].as_ref().unwrap_err();
+        assert_eq!(StreamInterrupted::partial(err), Some("partial"));
+    }
+
+    /// 第一次流式请求在输出一部分后连接断开，之后的请求输出剩余内容
+    #[derive(Debug, Default)]
+    struct DroppingStreamLLMClient {
+        requests: std::sync::Mutex<Vec<Vec<Message>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for DroppingStreamLLMClient {
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            unreachable!("only streaming is used")
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(messages.to_vec());
+            let items = match requests.len() {
+                1 => vec![
+                    Ok(Decision::Respond("Once upon".into())),
+                    Ok(Decision::Respond(" a time".into())),
+                    Err(anyhow!("connection reset")),
+                ],
+                _ => vec![
+                    Ok(Decision::Respond(" there was".into())),
+                    Ok(Decision::Respond(" a fox.".into())),
+                ],
+            };
+            Ok(Box::pin(futures::stream::iter(items)))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interrupted_stream_is_resumed_with_prefill() {
+        let mut agent = create_test_agent_with_llm(DroppingStreamLLMClient::default());
+        agent.config.resume_interrupted_streams = true;
+
+        let stream = agent
+            .handle_message_stream("Tell a story".to_string())
+            .await
+            .unwrap();
+        let chunks: Vec<String> = stream.map(Result::unwrap).collect().await;
+
+        assert_eq!(chunks.concat(), "Once upon a time there was a fox.");
+        let requests = agent.llm.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        // 续写请求以已收到的部分回复作为末尾的 assistant 消息
+        assert_eq!(
+            requests[1].last(),
+            Some(&Message::Assistant {
+                content: "Once upon a time".into(),
+                tool_calls: None,
+            })
+        );
+        assert_eq!(requests[1][..requests[1].len() - 1], requests[0][..]);
+        assert_eq!(
+            agent.user_history().last(),
+            Some(&Message::Assistant {
+                content: "Once upon a time there was a fox.".into(),
+                tool_calls: None,
+            })
+        );
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_carries_partial_text() {
+        let mut agent = create_test_agent_with_llm(BrokenStreamLLMClient);
+
+        let mut stream = agent
+            .handle_message_stream("Question".to_string())
+            .await
+            .unwrap();
+        let mut chunks = Vec::new();
+        let mut error = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(chunk) => chunks.push(chunk),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        drop(stream);
+
+        assert_eq!(chunks, ["The answer ", "is"]);
+        let error = error.unwrap();
+        assert_eq!(error.to_string(), "connection reset");
+        assert_eq!(StreamInterrupted::partial(&error), Some("The answer is"));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_version_is_recorded_in_audit_records() {
+        let records = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("first".into())),
+            // 普通错误会重试，重试用尽后返回最后一次的错误
+            Err(anyhow!("boom")),
+            Err(anyhow!("boom")),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm)
+            .with_audit_hook(move |record| sink.lock().unwrap().push(record.clone()));
+        agent.config.prompt_version = Some("v2-concise".into());
+
+        agent.handle_message("Hi".to_string()).await.unwrap();
+        agent.handle_message("Again".to_string()).await.unwrap_err();
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(record.conversation_id, agent.conversation_id());
+            assert_eq!(record.prompt_version.as_deref(), Some("v2-concise"));
+            assert_eq!(record.turn, i + 1);
+        }
+        assert_eq!(records[0].error, None);
+        assert_eq!(records[1].error.as_deref(), Some("boom"));
+        // 审计记录可以直接序列化输出
+        let json = serde_json::to_value(&records[0]).unwrap();
+        assert_eq!(json["prompt_version"], "v2-concise");
+    }
+
+    type RecordedSpan = (String, HashMap<String, String>);
+
+    /// 记录所有新建 span 的名称与字段，用于断言 tracing 输出
+    #[derive(Default)]
+    struct SpanRecorder {
+        spans: Arc<std::sync::Mutex<Vec<RecordedSpan>>>,
+        next_id: std::sync::atomic::AtomicU64,
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            self.spans
+                .lock()
+                .unwrap()
+                .push((attrs.metadata().name().to_string(), fields));
+            let id = self
+                .next_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            tracing::span::Id::from_u64(id + 1)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {}
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn test_ask_user_pauses_until_the_user_answers() {
+        let ask = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: ASK_USER_TOOL.to_string(),
+            args: json!({"question": "Which city do you mean?"}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([("call_1".to_string(), ask)]),
+            )),
+            Ok(Decision::Respond("It is sunny in Paris.".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(AskUserTool::new());
+
+        let reply = agent
+            .handle_message("What's the weather there?".to_string())
+            .await
+            .unwrap();
+        assert_eq!(reply, "Which city do you mean?");
+        assert!(matches!(agent.state, AgentState::WaitingForUserInput));
+        assert_eq!(agent.pending_question(), Some("Which city do you mean?"));
+
+        let reply = agent.handle_message("Paris".to_string()).await.unwrap();
+        assert_eq!(reply, "It is sunny in Paris.");
+        assert!(matches!(agent.state, AgentState::Ready));
+        assert_eq!(agent.pending_question(), None);
+        // 用户的回答作为 ask_user 调用的结果发送给模型
+        let last_request = agent.llm.requests().pop().unwrap();
+        assert!(last_request.contains(&Message::Tool {
+            content: "Paris".to_string(),
+            tool_call_id: "call_1".to_string(),
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_validate_decision_reports_missing_tools() {
+        let mut agent = create_test_agent();
+        let call = |name: &str| ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: name.to_string(),
+            args: json!({}),
+        };
+        let decision = Decision::ExecuteTool(
+            String::new(),
+            ToolCalls::from([
+                ("call_1".to_string(), call("echo")),
+                ("call_2".to_string(), call("weather")),
+                ("call_3".to_string(), call("weather")),
+            ]),
+        );
+
+        let err = agent.validate_decision(&decision).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "decision references unregistered tools: `weather`"
+        );
+        assert!(agent
+            .validate_decision(&Decision::Respond("hi".into()))
+            .is_ok());
+
+        // 缺失工具的失败结果以调用 id 为键
+        let Decision::ExecuteTool(_, calls) = &decision else {
+            unreachable!()
+        };
+        let results = agent.execute_tool(calls).await.unwrap();
+        assert!(results.failure_result.contains_key("call_2"));
+        assert!(results.failure_result.contains_key("call_3"));
+        assert!(!results.failure_result.contains_key("weather"));
+
+        agent.register_tool(RenamedTool::new("weather", "weather", EchoTool::new()));
+        assert!(agent.validate_decision(&decision).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_system_layers_are_sent_in_order_and_never_trimmed() {
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond("ok".into()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.system_layers = vec![
+            "You are Ada, a friendly tutor.".to_string(),
+            "Today's task: explain fractions.".to_string(),
+            "Never reveal personal data.".to_string(),
+        ];
+        agent.config.max_tokens = Some(30);
+        for i in 0..20 {
+            agent.add_system_note(format!("a fairly long earlier note number {i}"));
+        }
+
+        agent.handle_message("Hi".to_string()).await.unwrap();
+
+        let request = &agent.llm.requests()[0];
+        let layers: Vec<&str> = request[..3].iter().map(Message::content).collect();
+        assert_eq!(
+            layers,
+            [
+                "You are Ada, a friendly tutor.",
+                "Today's task: explain fractions.",
+                "Never reveal personal data.",
+            ]
+        );
+        // 历史被裁剪，分层指令仍然完整保留
+        assert!(request.len() < 10);
+        assert_eq!(request.last().unwrap().content(), "Hi");
+        // 分层指令不写入历史
+        let history = agent.short_term_memory.get_context_messages(None);
+        assert!(history
+            .iter()
+            .all(|m| m.content() != "Never reveal personal data."));
+    }
+
+    #[tokio::test]
+    async fn test_to_markdown_renders_tool_using_conversation() {
+        let echo = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "echo".to_string(),
+            args: json!({"text": "ping"}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([("call_1".to_string(), echo)]),
+            )),
+            Ok(Decision::Respond("The tool said ping.".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.add_message_with_visibility(
+            Message::Developer {
+                content: "internal note".into(),
+            },
+            Visibility::HiddenFromUser,
+        );
+        agent.handle_message("Ping it".to_string()).await.unwrap();
+
+        let markdown = agent.to_markdown();
+        assert!(markdown.contains("### User\n\nPing it"));
+        assert!(markdown
+            .contains("Tool call `echo` (`call_1`):\n\n```json\n{\n  \"text\": \"ping\"\n}\n```"));
+        assert!(markdown.contains("### Tool result (`call_1`)\n\n> ping"));
+        assert!(markdown.ends_with("### Assistant\n\nThe tool said ping.\n"));
+        // 对用户隐藏的消息不导出
+        assert!(!markdown.contains("internal note"));
+    }
+
+    #[tokio::test]
+    async fn test_empty_response_is_retried_under_retry_policy() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond(String::new())),
+            Ok(Decision::Respond("Hello!".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.on_empty_response = EmptyResponsePolicy::Retry;
+
+        let response = agent.handle_message("Hi".to_string()).await.unwrap();
+        assert_eq!(response, "Hello!");
+        let requests = agent.llm.requests();
+        assert_eq!(requests.len(), 2);
+        // 空回复没有写入历史，重试使用相同的上下文
+        assert_eq!(requests[0], requests[1]);
+        assert_eq!(
+            agent.user_history().last(),
+            Some(&Message::Assistant {
+                content: "Hello!".into(),
+                tool_calls: None,
+            })
+        );
+
+        // 重试次数用尽后返回错误
+        let llm =
+            ScriptedLLMClient::new((0..3).map(|_| Ok(Decision::Respond("  ".into()))).collect());
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.on_empty_response = EmptyResponsePolicy::Retry;
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+        assert_eq!(err.to_string(), "the model returned an empty response");
+        assert_eq!(agent.llm.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_empty_response_policies_return_empty_or_error() {
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond(String::new()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        assert_eq!(agent.handle_message("Hi".to_string()).await.unwrap(), "");
+
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond(String::new()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.on_empty_response = EmptyResponsePolicy::Error;
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+        assert_eq!(err.to_string(), "the model returned an empty response");
+        assert_eq!(agent.llm.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_empty_response_policy_applies_to_streams() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond(String::new())),
+            Ok(Decision::Respond("Hello!".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.on_empty_response = EmptyResponsePolicy::Retry;
+        let stream = agent.handle_message_stream("Hi".to_string()).await.unwrap();
+        let chunks: Vec<String> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(chunks.concat(), "Hello!");
+        assert_eq!(agent.llm.requests().len(), 2);
+        assert_eq!(
+            agent.user_history().last(),
+            Some(&Message::Assistant {
+                content: "Hello!".into(),
+                tool_calls: None,
+            })
+        );
+
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond(String::new()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.on_empty_response = EmptyResponsePolicy::Error;
+        let stream = agent.handle_message_stream("Hi".to_string()).await.unwrap();
+        let items: Vec<Result<String>> = stream.collect().await;
+        let err = items.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(err.to_string(), "the model returned an empty response");
+        assert_eq!(agent.llm.requests().len(), 1);
+        assert!(!agent
+            .user_history()
+            .iter()
+            .any(|message| matches!(message, Message::Assistant { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_replay_user_turns_reruns_stored_user_messages() {
+        let stored = vec![
+            Message::Developer {
+                content: "be brief".into(),
+            },
+            Message::User {
+                content: "Hello".into(),
+            },
+            Message::Assistant {
+                content: "Old greeting".into(),
+                tool_calls: None,
+            },
+            Message::User {
+                content: "What's new?".into(),
+            },
+            Message::Tool {
+                content: "stale result".into(),
+                tool_call_id: "call_1".into(),
+            },
+            Message::Assistant {
+                content: "Old answer".into(),
+                tool_calls: None,
+            },
+        ];
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("Hi".into())),
+            Ok(Decision::Respond("Nothing much".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+
+        let responses = agent.replay_user_turns(&stored).await.unwrap();
+        assert_eq!(
+            responses,
+            vec!["Hi".to_string(), "Nothing much".to_string()]
+        );
+
+        let user_turns: Vec<_> = agent
+            .user_history()
+            .into_iter()
+            .filter(|message| matches!(message, Message::User { .. }))
+            .collect();
+        assert_eq!(user_turns, vec![stored[1].clone(), stored[3].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_low_confidence_tool_calls_are_deferred() {
+        let echo = |text: &str, confidence: f64| ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "echo".to_string(),
+            args: json!({"text": text, "confidence": confidence}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([
+                    ("call_1".to_string(), echo("delete everything", 0.3)),
+                    ("call_2".to_string(), echo("hello", 0.95)),
+                ]),
+            )),
+            Ok(Decision::Respond(
+                "Should I really delete everything?".into(),
+            )),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.tool_confidence_threshold = Some(0.7);
+
+        let response = agent.handle_message("Clean up".to_string()).await.unwrap();
+        assert_eq!(response, "Should I really delete everything?");
+
+        let results = match &agent.last_turn_trace().steps[1] {
+            TraceStep::ToolResults(results) => results.clone(),
+            step => panic!("expected tool results, got {step:?}"),
+        };
+        assert_eq!(results.success_result["call_2"], "hello");
+        assert!(!results.success_result.contains_key("call_1"));
+        assert_eq!(
+            results.failure_result["call_1"],
+            "not executed: confidence 0.3 is below the threshold 0.7. \
+             Ask the user to confirm before calling `echo` again."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_last_turn_trace_records_decisions_and_tool_results() {
+        let echo = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "echo".to_string(),
+            args: json!({"text": "ping"}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                "Let me check.".into(),
+                ToolCalls::from([("call_1".to_string(), echo)]),
+            )),
+            Ok(Decision::Respond("pong".into())),
+            Ok(Decision::Respond("bye".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+
+        agent.handle_message("Ping".to_string()).await.unwrap();
+
+        let steps = &agent.last_turn_trace().steps;
+        assert_eq!(steps.len(), 3);
+        assert!(matches!(
+            &steps[0],
+            TraceStep::Decision(Decision::ExecuteTool(text, calls))
+                if text == "Let me check." && calls.contains_key("call_1")
+        ));
+        let TraceStep::ToolResults(results) = &steps[1] else {
+            panic!("expected tool results, got {:?}", steps[1]);
+        };
+        assert!(results.success_result.contains_key("call_1"));
+        assert!(matches!(
+            &steps[2],
+            TraceStep::Decision(Decision::Respond(text)) if text == "pong"
+        ));
+
+        // 每次处理开始时重置
+        agent.handle_message("Bye".to_string()).await.unwrap();
+        assert_eq!(agent.last_turn_trace().decisions().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_text_when_tools_are_unsupported() {
+        let llm = ScriptedLLMClient::new(vec![
+            Err(LlmError::ToolsUnsupported("model does not support tools".into()).into()),
+            Ok(Decision::Respond("It is 12:00.".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.fallback_without_tools = true;
+
+        let response = agent.handle_message("What time is it?".to_string()).await;
+
+        assert_eq!(response.unwrap(), "It is 12:00.");
+        let tool_names = agent.llm.tool_names();
+        assert_eq!(tool_names.len(), 2);
+        assert!(!tool_names[0].is_empty());
+        assert!(tool_names[1].is_empty());
+        // 不带工具的请求通过系统提示中的清单了解可用工具
+        let Message::System { content } = &agent.llm.requests()[1][0] else {
+            panic!("expected a system prompt");
+        };
+        assert!(content.contains("## Available tools"));
+    }
+
+    #[tokio::test]
+    async fn test_tools_unsupported_error_is_returned_without_fallback() {
+        let llm = ScriptedLLMClient::new(vec![
+            Err(LlmError::ToolsUnsupported("model does not support tools".into()).into()),
+            Ok(Decision::Respond("unused".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+
+        let err = agent.handle_message("Hi".to_string()).await.unwrap_err();
+
+        assert!(LlmError::is_tools_unsupported(&err));
+        assert_eq!(agent.llm.tool_names().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_context_is_propagated_to_span_and_audit() {
+        let recorder = SpanRecorder::default();
+        let spans = recorder.spans.clone();
+        let _guard = tracing::subscriber::set_default(recorder);
+
+        let records = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond("ok".into()))]);
+        let mut agent = create_test_agent_with_llm(llm)
+            .with_audit_hook(move |record| sink.lock().unwrap().push(record.clone()));
+
+        let context = RequestContext::new("req-42").with_tag("tenant", "acme");
+        agent
+            .handle_message_with_context("Hi".to_string(), context)
+            .await
+            .unwrap();
+
+        let spans = spans.lock().unwrap();
+        let (_, fields) = spans
+            .iter()
+            .find(|(name, _)| name == "handle_message")
+            .expect("handle_message span should be emitted");
+        assert_eq!(fields["request_id"], "req-42");
+        assert_eq!(fields["conversation_id"], agent.conversation_id());
+        assert!(fields["tags"].contains("acme"));
+
+        let records = records.lock().unwrap();
+        assert_eq!(records[0].request_id.as_deref(), Some("req-42"));
+        assert_eq!(records[0].tags["tenant"], "acme");
+    }
+
+    /// 需要先在 `init` 中建立“连接”才能执行的工具
+    #[derive(Debug, Default)]
+    struct ConnectedTool {
+        connection: Option<String>,
+        init_calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Tool for ConnectedTool {
+        fn name(&self) -> String {
+            "db".to_string()
+        }
+
+        fn description(&self) -> Option<String> {
+            None
+        }
+
+        fn args_schema(&self) -> Option<Value> {
+            None
+        }
+
+        async fn execute(&self, _args: Value) -> Result<String> {
+            let connection = self
+                .connection
+                .as_ref()
+                .ok_or_else(|| anyhow!("not connected"))?;
+            Ok(format!("rows from {connection}"))
+        }
+
+        async fn init(&mut self) -> Result<()> {
+            self.init_calls.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            self.connection = Some("db://local".to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tools_are_initialized_before_first_use() {
+        let tool = ConnectedTool::default();
+        assert_eq!(
+            tool.execute(json!({})).await.unwrap_err().to_string(),
+            "not connected"
+        );
+
+        let init_calls = tool.init_calls.clone();
+        let query = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "db".to_string(),
+            args: json!({}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([("call_1".to_string(), query)]),
+            )),
+            Ok(Decision::Respond("done".into())),
+            Ok(Decision::Respond("again".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(tool);
+
+        agent.handle_message("Query".to_string()).await.unwrap();
+        agent.handle_message("Again".to_string()).await.unwrap();
+
+        assert!(agent.llm.requests()[1].contains(&Message::Tool {
+            content: "rows from db://local".to_string(),
+            tool_call_id: "call_1".to_string(),
+        }));
+        // 只初始化一次
+        assert_eq!(init_calls.load(Ordering::SeqCst), 1);
+    }
+
+    /// 执行时一直挂起的工具
+    #[derive(Debug)]
+    struct SlowTool;
+
+    #[async_trait::async_trait]
+    impl Tool for SlowTool {
+        fn name(&self) -> String {
+            "slow".to_string()
+        }
+
+        fn description(&self) -> Option<String> {
+            None
+        }
+
+        fn args_schema(&self) -> Option<Value> {
+            None
+        }
+
+        fn cost_hint(&self) -> ToolCost {
+            ToolCost::Expensive
+        }
+
+        async fn execute(&self, _args: Value) -> Result<String> {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            Ok("slow result".to_string())
+        }
+    }
+
+    /// 等待参数 `secs` 指定的秒数后返回
+    #[derive(Debug)]
+    struct SleepTool;
+
+    #[async_trait::async_trait]
+    impl Tool for SleepTool {
+        fn name(&self) -> String {
+            "sleep".to_string()
+        }
+
+        fn description(&self) -> Option<String> {
+            None
+        }
+
+        fn args_schema(&self) -> Option<Value> {
+            None
+        }
+
+        async fn execute(&self, args: Value) -> Result<String> {
+            let secs = args["secs"].as_u64().unwrap_or_default();
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            Ok(format!("slept {secs}s"))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_parallel_tool_calls_take_the_longest_not_the_sum() {
+        let sleep = |secs: u64| ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "sleep".to_string(),
+            args: json!({ "secs": secs }),
+        };
+        for (enable_parallel, expected) in [(true, 2), (false, 3)] {
+            let llm = ScriptedLLMClient::new(vec![
+                Ok(Decision::ExecuteTool(
+                    String::new(),
+                    ToolCalls::from([
+                        ("call_1".to_string(), sleep(1)),
+                        ("call_2".to_string(), sleep(2)),
+                    ]),
+                )),
+                Ok(Decision::Respond("rested".into())),
+            ]);
+            let mut agent = create_test_agent_with_llm(llm);
+            agent.register_tool(SleepTool);
+            agent.config.enable_parallel = enable_parallel;
+
+            let started = tokio::time::Instant::now();
+            let response = agent.handle_message("Rest".to_string()).await.unwrap();
+            assert_eq!(response, "rested");
+            assert_eq!(started.elapsed().as_secs(), expected);
+
+            let outputs: HashMap<String, String> = agent
+                .short_term_memory
+                .get_context_messages(None)
+                .into_iter()
+                .filter_map(|message| match message {
+                    Message::Tool {
+                        content,
+                        tool_call_id,
+                    } => Some((tool_call_id, content)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(outputs["call_1"], "slept 1s");
+            assert_eq!(outputs["call_2"], "slept 2s");
+
+            // 流式处理走同一套执行逻辑，耗时应当一致
+            let llm = ScriptedLLMClient::new(vec![
+                Ok(Decision::ExecuteTool(
+                    String::new(),
+                    ToolCalls::from([
+                        ("call_1".to_string(), sleep(1)),
+                        ("call_2".to_string(), sleep(2)),
+                    ]),
+                )),
+                Ok(Decision::Respond("rested".into())),
+            ]);
+            let mut agent = create_test_agent_with_llm(llm);
+            agent.register_tool(SleepTool);
+            agent.config.enable_parallel = enable_parallel;
+
+            let started = tokio::time::Instant::now();
+            let stream = agent
+                .handle_message_stream("Rest".to_string())
+                .await
+                .unwrap();
+            let chunks: Vec<String> = stream.map(|chunk| chunk.unwrap()).collect().await;
+            assert_eq!(chunks.concat(), "rested");
+            assert_eq!(started.elapsed().as_secs(), expected);
+        }
+    }
+
+    /// 逐行缓慢输出日志的工具
+    #[derive(Debug)]
+    struct TailLogTool;
+
+    #[async_trait::async_trait]
+    impl Tool for TailLogTool {
+        fn name(&self) -> String {
+            "tail_log".to_string()
+        }
+
+        fn description(&self) -> Option<String> {
+            None
+        }
+
+        fn args_schema(&self) -> Option<Value> {
+            None
+        }
+
+        async fn execute(&self, args: Value) -> Result<String> {
+            self.execute_stream(args)
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect()
+        }
+
+        fn execute_stream(&self, _args: Value) -> crate::tools::ToolOutputStream<'_> {
+            Box::pin(async_stream::stream! {
+                yield Ok("line 1\n".to_string());
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                yield Ok("line 2\n".to_string());
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+                yield Ok("line 3\n".to_string());
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partial_tool_output_is_sent_after_cutoff() {
+        let tail = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "tail_log".to_string(),
+            args: json!({}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([("call_1".to_string(), tail)]),
+            )),
+            Ok(Decision::Respond("the log is still growing".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(TailLogTool);
+        agent.config.partial_tool_output_after = Some(Duration::from_secs(5));
+
+        let started = Instant::now();
+        let response = agent.handle_message("Tail".to_string()).await.unwrap();
+
+        assert_eq!(response, "the log is still growing");
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        assert!(agent.llm.requests()[1].contains(&Message::Tool {
+            content: "line 1\nline 2\n\n[partial output: the tool was still running after 5s]"
+                .to_string(),
+            tool_call_id: "call_1".to_string(),
+        }));
+    }
+
+    /// 忙等一段时间的 CPU 密集型工具，返回执行期间计数器的增量
+    #[derive(Debug)]
+    struct BusyTool {
+        ticks: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Tool for BusyTool {
+        fn name(&self) -> String {
+            "busy".to_string()
+        }
+
+        fn description(&self) -> Option<String> {
+            None
+        }
+
+        fn args_schema(&self) -> Option<Value> {
+            None
+        }
+
+        fn is_blocking(&self) -> bool {
+            true
+        }
+
+        async fn execute(&self, _args: Value) -> Result<String> {
+            use std::sync::atomic::Ordering;
+            let before = self.ticks.load(Ordering::SeqCst);
+            let started = std::time::Instant::now();
+            while started.elapsed() < std::time::Duration::from_millis(300) {
+                std::hint::spin_loop();
+            }
+            Ok((self.ticks.load(Ordering::SeqCst) - before).to_string())
+        }
+    }
+
+    /// 在当前运行时中执行 `BusyTool`，返回工具执行期间另一个任务的计数次数
+    async fn ticks_during_blocking_tool() -> usize {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        });
+
+        let busy = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "busy".to_string(),
+            args: json!({}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([("call_1".to_string(), busy)]),
+            )),
+            Ok(Decision::Respond("done".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(BusyTool {
+            ticks: ticks.clone(),
+        });
+
+        // 在唯一的工作线程上运行 Agent：若工具阻塞该线程，计数任务将无法推进
+        let agent = tokio::spawn(async move {
+            agent.handle_message("Crunch".to_string()).await.unwrap();
+            agent
+        })
+        .await
+        .unwrap();
+        ticker.abort();
+
+        let Some(Message::Tool { content, .. }) = agent.llm.requests()[1]
+            .iter()
+            .find(|message| matches!(message, Message::Tool { .. }))
+            .cloned()
+        else {
+            panic!("missing tool result");
+        };
+        content.parse().unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_blocking_tool_does_not_starve_other_tasks() {
+        let ticks_during_tool = ticks_during_blocking_tool().await;
+        assert!(ticks_during_tool >= 5, "only {ticks_during_tool} ticks");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_blocking_tool_does_not_starve_current_thread_runtime() {
+        let ticks_during_tool = ticks_during_blocking_tool().await;
+        assert!(ticks_during_tool >= 5, "only {ticks_during_tool} ticks");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_turn_timeout_aborts_slow_tools() {
+        let slow_call = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "slow".to_string(),
+            args: json!({}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([("call_1".to_string(), slow_call)]),
+            )),
+            Ok(Decision::Respond("next turn".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(SlowTool);
+        agent.config.turn_timeout = Some(Duration::from_secs(10));
+
+        let started = Instant::now();
+        let err = agent.handle_message("Go".to_string()).await.unwrap_err();
+        assert_eq!(err.to_string(), "turn exceeded turn_timeout (10s)");
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        assert!(matches!(agent.state, AgentState::Ready));
+
+        // 中止的处理没有留下未完成的工具调用，下一条消息照常处理
+        let response = agent.handle_message("Hello".to_string()).await.unwrap();
+        assert_eq!(response, "next turn");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_turn_timeout_aborts_slow_tools_in_streams() {
+        let slow_call = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "slow".to_string(),
+            args: json!({}),
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([("call_1".to_string(), slow_call)]),
+            )),
+            Ok(Decision::Respond("next turn".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(SlowTool);
+        agent.config.turn_timeout = Some(Duration::from_secs(10));
+
+        let started = Instant::now();
+        let stream = agent.handle_message_stream("Go".to_string()).await.unwrap();
+        let items: Vec<Result<String>> = stream.collect().await;
+        let err = items.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(err.to_string(), "turn exceeded turn_timeout (10s)");
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        assert!(matches!(agent.state, AgentState::Ready));
+
+        let stream = agent
+            .handle_message_stream("Hello".to_string())
+            .await
+            .unwrap();
+        let chunks: Vec<String> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(chunks.concat(), "next turn");
+    }
+
+    /// 调用 sleep 工具的决策，`secs` 为该轮工具执行的秒数
+    fn sleep_call(id: &str, secs: u64) -> Result<Decision> {
+        Ok(Decision::ExecuteTool(
+            String::new(),
+            ToolCalls::from([(
+                id.to_string(),
+                ToolCallArgs {
+                    tool_type: "function".to_string(),
+                    tool_name: "sleep".to_string(),
+                    args: json!({ "secs": secs }),
+                },
+            )]),
+        ))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_conversation_timeout_aborts_many_slow_rounds() {
+        let llm = ScriptedLLMClient::new(vec![
+            sleep_call("call_1", 6),
+            Ok(Decision::Respond("first".into())),
+            sleep_call("call_2", 3),
+            sleep_call("call_3", 3),
+            sleep_call("call_4", 3),
+            sleep_call("call_5", 3),
+            Ok(Decision::Respond("next message".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(SleepTool);
+        agent.config.conversation_timeout = Some(Duration::from_secs(10));
+
+        let started = Instant::now();
+        let response = agent.handle_message("One".to_string()).await.unwrap();
+        assert_eq!(response, "first");
+        assert_eq!(started.elapsed(), Duration::from_secs(6));
+
+        // 每次处理重新计时：第二条消息的多轮工具调用在其开始后 10 秒时中止
+        let err = agent.handle_message("Two".to_string()).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "conversation exceeded conversation_timeout (10s)"
+        );
+        assert_eq!(started.elapsed(), Duration::from_secs(16));
+        assert_eq!(agent.llm.requests().len(), 6);
+        assert!(matches!(agent.state, AgentState::Ready));
+
+        // 中止之后的消息照常处理
+        let response = agent.handle_message("Three".to_string()).await.unwrap();
+        assert_eq!(response, "next message");
+        assert_eq!(agent.llm.requests().len(), 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_conversation_timeout_applies_to_streams() {
+        let llm = ScriptedLLMClient::new(vec![
+            sleep_call("call_1", 6),
+            Ok(Decision::Respond("first".into())),
+            sleep_call("call_2", 4),
+            sleep_call("call_3", 4),
+            sleep_call("call_4", 4),
+            Ok(Decision::Respond("next message".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(SleepTool);
+        agent.config.conversation_timeout = Some(Duration::from_secs(10));
+
+        let started = Instant::now();
+        let stream = agent
+            .handle_message_stream("One".to_string())
+            .await
+            .unwrap();
+        let chunks: Vec<String> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(chunks.concat(), "first");
+
+        let stream = agent
+            .handle_message_stream("Two".to_string())
+            .await
+            .unwrap();
+        let items: Vec<Result<String>> = stream.collect().await;
+        let err = items.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "conversation exceeded conversation_timeout (10s)"
+        );
+        assert_eq!(started.elapsed(), Duration::from_secs(16));
+        assert_eq!(agent.llm.requests().len(), 5);
+        assert!(matches!(agent.state, AgentState::Ready));
+
+        let stream = agent
+            .handle_message_stream("Three".to_string())
+            .await
+            .unwrap();
+        let chunks: Vec<String> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(chunks.concat(), "next message");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelling_tool_round_still_produces_answer() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(
+                String::new(),
+                ToolCalls::from([(
+                    "call_1".to_string(),
+                    ToolCallArgs {
+                        tool_type: "function".to_string(),
+                        tool_name: "slow".to_string(),
+                        args: json!({}),
+                    },
+                )]),
+            )),
+            Ok(Decision::Respond("answer without the slow tool".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(SlowTool);
+        let canceller = agent.tool_round_canceller();
+
+        let cancel = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceller.cancel();
+        };
+        let (response, _) = tokio::join!(agent.handle_message("Go".to_string()), cancel);
+        assert_eq!(response.unwrap(), "answer without the slow tool");
+
+        // 模型在第二次请求中得知工具已被取消
+        let requests = agent.llm.requests();
+        let tool_message = requests[1].last().unwrap();
+        assert!(
+            matches!(tool_message, Message::Tool { tool_call_id, .. } if tool_call_id == "call_1")
+        );
+        assert!(tool_message.content().contains(TOOL_ROUND_CANCELLED));
+    }
+
+    /// 将回复拆成大量小片段逐个流式输出的 LLM
+    struct ChunkedLLMClient {
+        chunks: usize,
+    }
+
+    impl ChunkedLLMClient {
+        fn chunk(i: usize) -> String {
+            format!("{i},")
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl LLMClient for ChunkedLLMClient {
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            Ok(Decision::Respond(
+                (0..self.chunks).map(Self::chunk).collect(),
+            ))
+        }
+
+        async fn stream_complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            Ok(Box::pin(futures::stream::iter(
+                (0..self.chunks).map(|i| Ok(Decision::Respond(Self::chunk(i)))),
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_assembles_many_chunks() {
+        let chunks = 10_000;
+        let mut agent = create_test_agent_with_llm(ChunkedLLMClient { chunks });
+        let expected: String = (0..chunks).map(ChunkedLLMClient::chunk).collect();
+
+        let mut stream = agent
+            .handle_message_stream("Count".to_string())
+            .await
+            .unwrap();
+        let mut received = 0;
+        let mut streamed = String::new();
+        while let Some(chunk) = stream.next().await {
+            // 每次只产出增量片段，而不是累积的全文
+            let chunk = chunk.unwrap();
+            assert_eq!(chunk, ChunkedLLMClient::chunk(received));
+            streamed.push_str(&chunk);
+            received += 1;
+        }
+        drop(stream);
+
+        assert_eq!(received, chunks);
+        assert_eq!(streamed, expected);
+        let context = agent.short_term_memory.get_context_messages(None);
+        assert_eq!(context.last().unwrap().content(), expected);
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    #[tokio::test]
+    async fn test_stream_stops_when_response_exceeds_cap() {
+        // 默认不限制，需要显式开启
+        assert_eq!(AgentConfig::default().max_stream_response_bytes, None);
+        let mut agent = create_test_agent_with_llm(ChunkedLLMClient { chunks: 100 });
+        agent.config.max_stream_response_bytes = Some(50);
+
+        let mut stream = agent
+            .handle_message_stream("Count".to_string())
+            .await
+            .unwrap();
+        let mut streamed = String::new();
+        let mut error = None;
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => streamed.push_str(&chunk),
+                Err(e) => error = Some(e),
+            }
+        }
+        drop(stream);
+
+        assert!(streamed.len() <= 50);
+        assert!(error
+            .unwrap()
+            .to_string()
+            .contains("max_stream_response_bytes (50)"));
+        // 超限的回复不会写入记忆
+        let context = agent.short_term_memory.get_context_messages(None);
+        assert!(matches!(context.last().unwrap(), Message::User { .. }));
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    /// 总是调用 `recurse` 工具，拿到工具结果后再直接回复的 LLM
+    struct RecursingLLMClient;
+
+    #[async_trait::async_trait]
+    impl LLMClient for RecursingLLMClient {
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            match messages.last() {
+                Some(Message::Tool { content, .. }) => Ok(Decision::Respond(content.clone())),
+                _ => {
+                    let mut tool_calls = ToolCalls::new();
+                    tool_calls.insert(
+                        "call_recurse".to_string(),
+                        ToolCallArgs {
+                            tool_type: "function".into(),
+                            tool_name: "recurse".into(),
+                            args: json!({}),
+                        },
+                    );
+                    Ok(Decision::ExecuteTool(String::new(), tool_calls))
+                }
+            }
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let response = self.complete(messages, tools, max_tokens, options).await?;
+            Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+        }
+    }
+
+    /// 每次执行都启动一个同样配置的子 Agent，从而无限自我调用
+    #[derive(Debug, Clone)]
+    struct RecursiveAgentTool {
+        max_depth: usize,
+        deepest: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Tool for RecursiveAgentTool {
+        fn name(&self) -> String {
+            "recurse".to_string()
+        }
+
+        fn description(&self) -> Option<String> {
+            None
+        }
+
+        fn args_schema(&self) -> Option<Value> {
+            None
+        }
+
+        async fn execute(&self, _args: Value) -> Result<String> {
+            self.deepest
+                .fetch_max(current_depth(), std::sync::atomic::Ordering::SeqCst);
+            let mut agent = create_test_agent_with_llm(RecursingLLMClient);
+            agent.config.max_depth = self.max_depth;
+            agent.register_tool(self.clone());
+            agent.handle_message("go deeper".to_string()).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nested_agents_stop_at_max_depth() {
+        let deepest = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let tool = RecursiveAgentTool {
+            max_depth: 3,
+            deepest: deepest.clone(),
+        };
+        let mut agent = create_test_agent_with_llm(RecursingLLMClient);
+        agent.config.max_depth = 3;
+        agent.register_tool(tool);
+
+        // 最深一层的子 Agent 拒绝执行，错误经由工具结果逐层返回
+        let response = agent.handle_message("start".to_string()).await.unwrap();
+        assert!(response.contains("exceeded max_depth (3)"));
+        assert_eq!(deepest.load(std::sync::atomic::Ordering::SeqCst), 4);
+        assert_eq!(current_depth(), 0);
+        assert!(matches!(agent.state, AgentState::Ready));
+    }
+
+    #[tokio::test]
+    async fn test_tool_manifest_is_injected_into_system_context() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("one".into())),
+            Ok(Decision::Respond("two".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm);
+
+        agent.handle_message("Hi".to_string()).await.unwrap();
+        agent.config.include_tool_manifest = true;
+        agent.handle_message("Hi again".to_string()).await.unwrap();
+
+        let requests = agent.llm.requests();
+        // 未开启时系统提示保持原样
+        assert_eq!(requests[0][0].content(), "You are a helpful assistant.");
+
+        let Message::System { content } = &requests[1][0] else {
+            panic!("Expected system message first");
+        };
+        assert!(content.starts_with("You are a helpful assistant.\n\n## Available tools"));
+        assert!(content.contains("- `echo`: A simple echo tool that returns the input text"));
+        assert!(content.contains("\"required\":[\"text\"]"));
+
+        // 记忆中的系统提示不会被修改
+        let history = agent.short_term_memory.get_context_messages(None);
+        assert_eq!(history[0].content(), "You are a helpful assistant.");
+    }
+
+    #[tokio::test]
+    async fn test_tool_manifest_includes_cost_hints() {
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond("ok".into()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.register_tool(SlowTool);
+        agent.config.include_tool_manifest = true;
+
+        agent.handle_message("Hi".to_string()).await.unwrap();
+
+        let content = agent.llm.requests()[0][0].content().to_string();
+        assert!(content.contains("Prefer cheaper tools when several can do the job."));
+        // 清单按名称排序，每个工具的开销写在其参数之后
+        let (echo, slow) = content.split_once("- `slow`").unwrap();
+        assert!(echo.contains("- `echo`"));
+        assert!(echo.ends_with("  cost: moderate\n"));
+        assert_eq!(slow, "\n  cost: expensive\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fuzz_agent_always_returns_to_ready() {
+        for seed in 0..200 {
+            let mut agent = create_test_agent_with_llm(FuzzLLMClient::new(seed));
+            agent.config.timeout = Duration::from_secs(1);
+
+            for turn in 0..3 {
+                let _ = agent.handle_message(format!("turn {turn}")).await;
+                assert_eq!(agent.state, AgentState::Ready, "seed {seed}");
+            }
+
+            let stream = agent
+                .handle_message_stream("streamed turn".to_string())
+                .await
+                .unwrap();
+            let _ = stream.collect::<Vec<_>>().await;
+            assert_eq!(agent.state, AgentState::Ready, "seed {seed}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extra_params_reach_the_llm() {
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond("ok".into()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent
+            .config
+            .extra_params
+            .insert("top_p".to_string(), json!(0.5));
+
+        agent.handle_message("Hi".to_string()).await.unwrap();
+        assert_eq!(agent.llm.options()[0].extra_params["top_p"], json!(0.5));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_config_is_rejected_before_sending() {
+        let llm = ScriptedLLMClient::new(vec![Ok(Decision::Respond("ok".into()))]);
+        let mut agent = create_test_agent_with_llm(llm);
+        agent.config.top_p = Some(2.0);
+
+        assert!(agent.handle_message("Hi".to_string()).await.is_err());
+        assert!(agent.llm.requests().is_empty());
+        assert_eq!(agent.state, AgentState::Ready);
+
+        agent.config.top_p = Some(0.3);
+        agent.handle_message("Hi".to_string()).await.unwrap();
+        assert_eq!(agent.llm.options()[0].top_p, Some(0.3));
+    }
+
+    #[tokio::test]
+    async fn test_status_updates_are_published_during_tool_rounds() {
+        let echo = |text: &str| ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "echo".to_string(),
+            args: json!({ "text": text }),
+        };
+        let rounds = || {
+            vec![
+                Ok(Decision::ExecuteTool(
+                    String::new(),
+                    ToolCalls::from([
+                        ("call_1".to_string(), echo("a")),
+                        ("call_2".to_string(), echo("b")),
+                    ]),
+                )),
+                Ok(Decision::ExecuteTool(
+                    String::new(),
+                    ToolCalls::from([("call_3".to_string(), echo("c"))]),
+                )),
+                Ok(Decision::Respond("all done".into())),
+            ]
+        };
+        let expected = [
+            "step 1: calling `echo`, `echo`",
+            "step 1: got 2 tool result(s), continuing",
+            "step 2: calling `echo`",
+            "step 2: got 1 tool result(s), continuing",
+            "done",
+        ];
+
+        let mut agent = create_test_agent_with_llm(ScriptedLLMClient::new(rounds()));
+        let mut status = agent.subscribe_status();
+        agent.handle_message("Go".to_string()).await.unwrap();
+        let published: Vec<String> = std::iter::from_fn(|| status.try_recv().ok()).collect();
+        assert_eq!(published, expected);
+
+        let mut agent = create_test_agent_with_llm(ScriptedLLMClient::new(rounds()));
+        let mut status = agent.subscribe_status();
+        let stream = agent.handle_message_stream("Go".to_string()).await.unwrap();
+        let _ = stream.collect::<Vec<_>>().await;
+        let published: Vec<String> = std::iter::from_fn(|| status.try_recv().ok()).collect();
+        assert_eq!(published, expected);
+    }
+
+    #[tokio::test]
+    async fn test_tool_result_filter_redacts_recorded_output() {
+        let echo = |id: &str| {
+            ToolCalls::from([(
+                id.to_string(),
+                ToolCallArgs {
+                    tool_type: "function".to_string(),
+                    tool_name: "echo".to_string(),
+                    args: json!({"text": "token is sk-abc123, keep it safe"}),
+                },
+            )])
+        };
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::ExecuteTool(String::new(), echo("call_1"))),
+            Ok(Decision::Respond("done".into())),
+            Ok(Decision::ExecuteTool(String::new(), echo("call_2"))),
+            Ok(Decision::Respond("done again".into())),
+        ]);
+        let secret = regex::Regex::new(r"sk-[A-Za-z0-9]+").unwrap();
+        let mut agent =
+            create_test_agent_with_llm(llm).with_tool_result_filter(move |tool, output| {
+                assert_eq!(tool, "echo");
+                secret.replace_all(&output, "[REDACTED]").into_owned()
+            });
+        let redacted = "token is [REDACTED], keep it safe";
+
+        agent.handle_message("Echo".to_string()).await.unwrap();
+        let stream = agent
+            .handle_message_stream("Echo".to_string())
+            .await
+            .unwrap();
+        let _ = stream.collect::<Vec<_>>().await;
+
+        let tool_results: Vec<_> = agent
+            .user_history()
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::Tool { content, .. } => Some(content),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tool_results, [redacted, redacted]);
+        assert!(agent.llm.requests()[1].contains(&Message::Tool {
+            content: redacted.to_string(),
+            tool_call_id: "call_1".to_string(),
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_tools_hook_filters_tools_per_turn() {
+        let llm = ScriptedLLMClient::new(vec![
+            Ok(Decision::Respond("1".into())),
+            Ok(Decision::Respond("2".into())),
+            Ok(Decision::Respond("3".into())),
+        ]);
+        let mut agent = create_test_agent_with_llm(llm).with_tools_hook(|messages, tools| {
+            // 偶数轮隐藏 echo 工具
+            let turn = messages
+                .iter()
+                .filter(|m| matches!(m, Message::User { .. }))
+                .count();
+            if turn % 2 == 0 {
+                tools.retain(|tool| tool.name() != "echo");
+            }
+            tools.sort_by_key(|tool| tool.name());
+        });
+        agent.register_tool(crate::tools::ClockTool::new());
+
+        for turn in 1..=3 {
+            agent.handle_message(format!("turn {turn}")).await.unwrap();
+        }
+
+        assert_eq!(
+            agent.llm.tool_names(),
+            vec![
+                vec!["clock".to_string(), "echo".to_string()],
+                vec!["clock".to_string()],
+                vec!["clock".to_string(), "echo".to_string()],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refusal_is_recorded_and_returned_as_error() {
+        let llm = ScriptedLLMClient::new(vec![Err(LlmError::Refusal(
+            "I can't help with that.".into(),
+        )
+        .into())]);
+        let mut agent = create_test_agent_with_llm(llm);
+
+        let err = agent
+            .handle_message("Do something bad".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(LlmError::refusal(&err), Some("I can't help with that."));
+        assert_eq!(
+            agent.short_term_memory.get_context_messages(None).last(),
+            Some(&Message::Assistant {
+                content: "I can't help with that.".into(),
+                tool_calls: None,
+            })
+        );
+        assert_eq!(agent.state, AgentState::Ready);
+    }
 }
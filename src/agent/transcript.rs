@@ -0,0 +1,99 @@
+use crate::types::Message;
+
+/// 将对话渲染为 Markdown：每条消息以角色作为标题，工具调用渲染为 JSON 代码块，
+/// 工具结果渲染为引用块
+pub fn messages_to_markdown(messages: &[Message]) -> String {
+    let sections: Vec<String> = messages.iter().map(message_to_markdown).collect();
+    let mut markdown = sections.join("\n\n");
+    markdown.push('\n');
+    markdown
+}
+
+fn message_to_markdown(message: &Message) -> String {
+    match message {
+        Message::Developer { content } => format!("### Developer\n\n{content}"),
+        Message::System { content } => format!("### System\n\n{content}"),
+        Message::User { content } => format!("### User\n\n{content}"),
+        Message::Assistant {
+            content,
+            tool_calls,
+        } => {
+            let mut parts = vec!["### Assistant".to_string()];
+            if !content.is_empty() {
+                parts.push(content.clone());
+            }
+            let mut calls: Vec<_> = tool_calls.iter().flatten().collect();
+            calls.sort_by_key(|(id, _)| id.as_str());
+            for (id, call) in calls {
+                let args = serde_json::to_string_pretty(&call.args)
+                    .unwrap_or_else(|_| call.args.to_string());
+                parts.push(format!(
+                    "Tool call `{}` (`{id}`):\n\n```json\n{args}\n```",
+                    call.tool_name
+                ));
+            }
+            parts.join("\n\n")
+        }
+        Message::Tool {
+            content,
+            tool_call_id,
+        } => {
+            let quoted: Vec<String> = content
+                .lines()
+                .map(|line| {
+                    if line.is_empty() {
+                        ">".to_string()
+                    } else {
+                        format!("> {line}")
+                    }
+                })
+                .collect();
+            format!(
+                "### Tool result (`{tool_call_id}`)\n\n{}",
+                quoted.join("\n")
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ToolCallArgs, ToolCalls};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_renders_roles_tool_calls_and_results() {
+        let call = ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "weather".to_string(),
+            args: json!({"city": "Paris"}),
+        };
+        let messages = [
+            Message::User {
+                content: "Weather in Paris?".to_string(),
+            },
+            Message::Assistant {
+                content: String::new(),
+                tool_calls: Some(ToolCalls::from([("call_1".to_string(), call)])),
+            },
+            Message::Tool {
+                content: "sunny\n\n21°C".to_string(),
+                tool_call_id: "call_1".to_string(),
+            },
+            Message::Assistant {
+                content: "It is sunny.".to_string(),
+                tool_calls: None,
+            },
+        ];
+
+        assert_eq!(
+            messages_to_markdown(&messages),
+            "### User\n\nWeather in Paris?\n\n\
+             ### Assistant\n\nTool call `weather` (`call_1`):\n\n```json\n{\n  \"city\": \"Paris\"\n}\n```\n\n\
+             ### Tool result (`call_1`)\n\n> sunny\n>\n> 21°C\n\n\
+             ### Assistant\n\nIt is sunny.\n"
+        );
+    }
+}
@@ -1,10 +1,11 @@
 pub mod agent;
 pub mod llm;
 pub mod memory;
+pub mod stream;
 pub mod tools;
 pub mod types;
 
-pub use agent::Agent;
+pub use agent::{Agent, DynAgent};
 pub use memory::{LongTermMemory, ShortTermMemory};
 pub use tools::Tool;
 pub use types::{AgentConfig, Decision, Message};
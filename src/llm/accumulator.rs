@@ -0,0 +1,191 @@
+//! 将流式片段重新组装为完整 `Decision` 的累加器
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
+use super::json_repair::parse_tool_arguments;
+use crate::types::{Decision, ToolCallArgs, ToolCalls};
+
+/// 流式响应中的单个片段
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamFragment {
+    /// assistant 文本增量
+    Text(String),
+    /// 工具调用的一部分
+    ToolCall(ToolCallFragment),
+}
+
+/// 工具调用片段：同一调用的多个片段共享 `index`，`id` 与 `name` 通常只出现在第一个片段中，
+/// `arguments` 需要按到达顺序拼接
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolCallFragment {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+/// 流式状态的累加器：逐个接收片段，结束时产出完整的决策
+pub trait DecisionAccumulator: Send {
+    /// 接收一个片段
+    fn push(&mut self, fragment: StreamFragment);
+
+    /// 产出累积的决策并清空内部状态，以便复用
+    fn finish(&mut self) -> Result<Decision>;
+}
+
+/// 默认累加器：拼接文本，按 `index` 合并工具调用片段
+#[derive(Debug, Default)]
+pub struct DefaultDecisionAccumulator {
+    text: String,
+    tool_calls: BTreeMap<usize, ToolCallFragment>,
+}
+
+impl DefaultDecisionAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DecisionAccumulator for DefaultDecisionAccumulator {
+    fn push(&mut self, fragment: StreamFragment) {
+        match fragment {
+            StreamFragment::Text(text) => self.text.push_str(&text),
+            StreamFragment::ToolCall(fragment) => {
+                let call =
+                    self.tool_calls
+                        .entry(fragment.index)
+                        .or_insert_with(|| ToolCallFragment {
+                            index: fragment.index,
+                            ..Default::default()
+                        });
+                if fragment.id.is_some() {
+                    call.id = fragment.id;
+                }
+                if fragment.name.is_some() {
+                    call.name = fragment.name;
+                }
+                call.arguments.push_str(&fragment.arguments);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<Decision> {
+        let text = std::mem::take(&mut self.text);
+        let fragments = std::mem::take(&mut self.tool_calls);
+        if fragments.is_empty() {
+            return Ok(Decision::Respond(text));
+        }
+
+        let mut tool_calls = ToolCalls::new();
+        for (index, call) in fragments {
+            let id = call
+                .id
+                .ok_or_else(|| anyhow!("tool call at index {index} has no id"))?;
+            let name = call
+                .name
+                .ok_or_else(|| anyhow!("tool call at index {index} has no name"))?;
+            let args = parse_tool_arguments(&name, &call.arguments)?;
+            tool_calls.insert(
+                id,
+                ToolCallArgs {
+                    tool_type: "function".to_string(),
+                    tool_name: name,
+                    args,
+                },
+            );
+        }
+        Ok(Decision::ExecuteTool(text, tool_calls))
+    }
+}
+
+/// 将一个完整或部分的 `Decision` 拆为片段，`next_index` 为下一个工具调用可用的序号
+pub(crate) fn decision_into_fragments(
+    decision: Decision,
+    next_index: &mut usize,
+) -> Vec<StreamFragment> {
+    let (text, tool_calls) = match decision {
+        Decision::Respond(text) => (text, ToolCalls::new()),
+        Decision::ExecuteTool(text, tool_calls) => (text, tool_calls),
+    };
+    let mut fragments = vec![StreamFragment::Text(text)];
+    // 序号沿用调用在 `ToolCalls` 中的顺序，即模型发出调用的顺序
+    for (id, call) in tool_calls {
+        fragments.push(StreamFragment::ToolCall(ToolCallFragment {
+            index: *next_index,
+            id: Some(id),
+            name: Some(call.tool_name),
+            arguments: call.args.to_string(),
+        }));
+        *next_index += 1;
+    }
+    fragments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn fragment(
+        index: usize,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: &str,
+    ) -> StreamFragment {
+        StreamFragment::ToolCall(ToolCallFragment {
+            index,
+            id: id.map(str::to_string),
+            name: name.map(str::to_string),
+            arguments: arguments.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_accumulator_merges_interleaved_tool_fragments() {
+        let mut accumulator = DefaultDecisionAccumulator::new();
+        for fragment in [
+            StreamFragment::Text("Let me ".into()),
+            fragment(1, Some("call_b"), Some("echo"), ""),
+            fragment(0, Some("call_a"), Some("echo"), "{\"te"),
+            fragment(1, None, None, "{\"text\": \"b\"}"),
+            StreamFragment::Text("check.".into()),
+            fragment(0, None, None, "xt\": \"a\"}"),
+        ] {
+            accumulator.push(fragment);
+        }
+
+        let Decision::ExecuteTool(text, tool_calls) = accumulator.finish().unwrap() else {
+            panic!("expected tool calls");
+        };
+        assert_eq!(text, "Let me check.");
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls["call_a"].args, json!({"text": "a"}));
+        assert_eq!(tool_calls["call_b"].args, json!({"text": "b"}));
+
+        // finish 之后状态被清空，可以复用
+        accumulator.push(StreamFragment::Text("done".into()));
+        assert!(matches!(accumulator.finish().unwrap(), Decision::Respond(t) if t == "done"));
+    }
+
+    #[test]
+    fn test_accumulator_rejects_tool_call_without_id() {
+        let mut accumulator = DefaultDecisionAccumulator::new();
+        accumulator.push(fragment(0, None, Some("echo"), "{}"));
+        assert_eq!(
+            accumulator.finish().unwrap_err().to_string(),
+            "tool call at index 0 has no id"
+        );
+    }
+
+    #[test]
+    fn test_accumulator_rejects_unrepairable_arguments() {
+        let mut accumulator = DefaultDecisionAccumulator::new();
+        accumulator.push(fragment(0, Some("call_a"), Some("echo"), "{\"text\": "));
+        let err = accumulator.finish().unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid JSON arguments for tool `echo`"));
+    }
+}
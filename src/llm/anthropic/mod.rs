@@ -0,0 +1,675 @@
+//! Anthropic Messages API（`/v1/messages`）客户端
+//!
+//! 与 OpenAI 的主要区别：系统提示放在顶层的 `system` 字段而不是消息数组中；
+//! assistant 与 user 消息必须交替出现，工具调用（`tool_use`）与工具结果（`tool_result`）
+//! 都是消息 `content` 中的内容块；流式输出是带类型的 SSE 事件。
+
+use anyhow::{anyhow, bail, Result};
+use async_stream::stream;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::llm::lines::byte_lines;
+use crate::llm::openai::{f32_to_json, DEFAULT_USER_AGENT};
+use crate::llm::{
+    parse_tool_arguments, ClaudeToolCallFormatter, LLMClient, LlmError, PromptRedactor,
+    RegexRedactor, RequestOptions, ToolCallFormatter,
+};
+use crate::types::{ToolCallArgs, ToolCalls};
+use crate::{Decision, Message, Tool};
+
+/// 请求头 `anthropic-version` 的值
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Anthropic 要求每个请求都设置 `max_tokens`，调用方未指定时使用该值
+pub const DEFAULT_MAX_TOKENS: usize = 4096;
+
+pub struct ClaudeLlmClient {
+    pub api_key: String,
+    pub model: String,
+    /// 例如：https://api.anthropic.com/v1/messages
+    pub api_url: String,
+    pub client: Client,
+    /// 请求携带的 `User-Agent`，默认为 `DEFAULT_USER_AGENT`
+    pub user_agent: String,
+    /// 调试日志中的请求与响应内容在输出前经过脱敏，默认为 `RegexRedactor::default()`
+    pub redactor: Arc<dyn PromptRedactor>,
+}
+
+impl ClaudeLlmClient {
+    pub fn new(
+        api_key: impl Into<String>,
+        model: impl Into<String>,
+        api_url: impl Into<String>,
+    ) -> Self {
+        Self {
+            api_key: api_key.into(),
+            model: model.into(),
+            api_url: api_url.into(),
+            client: Client::new(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            redactor: Arc::new(RegexRedactor::default()),
+        }
+    }
+
+    /// 设置请求携带的 `User-Agent`
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// 设置调试日志使用的脱敏器，传入 `NoRedaction` 可关闭脱敏
+    pub fn with_redactor(mut self, redactor: impl PromptRedactor + 'static) -> Self {
+        self.redactor = Arc::new(redactor);
+        self
+    }
+
+    /// 构造 Messages API 请求体
+    fn build_request_body(
+        &self,
+        messages: &[Message],
+        tools: &[&dyn Tool],
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+        stream: bool,
+    ) -> Value {
+        let (system, messages) = convert_messages(messages);
+        let mut request_body = json!({
+            "model": self.model,
+            "max_tokens": max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "messages": messages,
+            "stream": stream,
+        });
+        if let Some(system) = system {
+            request_body["system"] = system.into();
+        }
+        if !tools.is_empty() {
+            request_body["tools"] = convert_tools(tools).into();
+        }
+        if let Some(temperature) = options.temperature {
+            request_body["temperature"] = f32_to_json(temperature);
+        }
+        if let Some(top_p) = options.top_p {
+            request_body["top_p"] = f32_to_json(top_p);
+        }
+        // 额外参数最后合并，因此会覆盖同名字段
+        for (key, value) in &options.extra_params {
+            request_body[key] = value.clone();
+        }
+        request_body
+    }
+
+    async fn send(&self, request_body: &Value) -> Result<reqwest::Response> {
+        Ok(self
+            .client
+            .post(&self.api_url)
+            .header("Content-Type", "application/json")
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(request_body)
+            .send()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl LLMClient for ClaudeLlmClient {
+    fn model_name(&self) -> Option<&str> {
+        Some(&self.model)
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Decision> {
+        let request_body = self.build_request_body(messages, &tools, max_tokens, options, false);
+        debug!(
+            "request: {}",
+            self.redactor.redact(&request_body.to_string())
+        );
+
+        let response = self.send(&request_body).await?;
+        let code = response.status();
+        let response_text = response.text().await?;
+        debug!(
+            "response: {code:?} {}",
+            self.redactor.redact(&response_text)
+        );
+        let response_json: Value = serde_json::from_str(&response_text)
+            .map_err(|e| anyhow!("invalid response (HTTP {}): {e}", code.as_u16()))?;
+        check_anthropic_error(code.as_u16(), &response_json)?;
+
+        parse_response_into_decision(&response_json)
+    }
+
+    async fn stream_complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+        let request_body = self.build_request_body(messages, &tools, max_tokens, options, true);
+        debug!(
+            "stream request: {}",
+            self.redactor.redact(&request_body.to_string())
+        );
+
+        let response = self.send(&request_body).await?;
+        debug!("stream status: {}", response.status());
+        let code = response.status();
+        if !code.is_success() {
+            let response_text = response.text().await?;
+            let response_json = serde_json::from_str(&response_text)
+                .unwrap_or_else(|_| json!({"error": {"message": response_text}}));
+            check_anthropic_error(code.as_u16(), &response_json)?;
+            bail!("stream request failed (HTTP {})", code.as_u16());
+        }
+        let decision_stream = parse_event_stream(response.bytes_stream(), self.redactor.clone());
+        Ok(Box::pin(decision_stream))
+    }
+}
+
+/// 将 SSE 字节流解析为 Decision 流，文本增量产出为 `Decision::Respond`，
+/// 工具调用在对应内容块结束后产出为 `Decision::ExecuteTool`
+fn parse_event_stream<S, B, E>(
+    byte_stream: S,
+    redactor: Arc<dyn PromptRedactor>,
+) -> impl Stream<Item = Result<Decision>> + Send
+where
+    S: Stream<Item = std::result::Result<B, E>> + Send,
+    B: AsRef<[u8]> + Send,
+    E: Into<anyhow::Error> + Send,
+{
+    stream! {
+        let mut parser = ClaudeStreamParser::default();
+        let mut lines = Box::pin(byte_lines(byte_stream));
+        // 事件可能跨越多个字节块，只处理已完整接收的行
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            };
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data.is_empty() {
+                continue;
+            }
+            debug!("stream recieved: {}", redactor.redact(data));
+            let event = match serde_json::from_str::<Value>(data) {
+                Ok(event) => event,
+                Err(e) => {
+                    yield Err(anyhow!("JSON parse error: {}", e));
+                    continue;
+                }
+            };
+            if let Some(decision) = parser.parse_event(&event).transpose() {
+                yield decision;
+            }
+        }
+    }
+}
+
+/// 将消息转换为 Messages API 的格式，返回 `(system, messages)`
+///
+/// system 与 developer 消息以空行连接后作为顶层的 `system`；工具结果作为 user 消息中的
+/// `tool_result` 内容块；相邻的同角色消息合并为一条，以满足 user 与 assistant 交替的要求。
+fn convert_messages(messages: &[Message]) -> (Option<String>, Vec<Value>) {
+    let mut system: Vec<&str> = Vec::new();
+    let mut converted: Vec<(&str, Vec<Value>)> = Vec::new();
+    for message in messages {
+        let (role, blocks) = match message {
+            Message::Developer { content } | Message::System { content } => {
+                if !content.is_empty() {
+                    system.push(content);
+                }
+                continue;
+            }
+            Message::User { content } => ("user", text_block(content).into_iter().collect()),
+            Message::Assistant {
+                content,
+                tool_calls,
+            } => {
+                let mut blocks: Vec<Value> = text_block(content).into_iter().collect();
+                let mut calls: Vec<_> = tool_calls.iter().flatten().collect();
+                calls.sort_by_key(|(id, _)| id.as_str());
+                blocks.extend(
+                    calls
+                        .into_iter()
+                        .map(|(id, call)| ClaudeToolCallFormatter.format_tool_call(id, call)),
+                );
+                ("assistant", blocks)
+            }
+            Message::Tool {
+                content,
+                tool_call_id,
+            } => (
+                "user",
+                vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": tool_call_id,
+                    "content": content,
+                })],
+            ),
+        };
+        if blocks.is_empty() {
+            continue;
+        }
+        match converted.last_mut() {
+            Some((last_role, last_blocks)) if *last_role == role => last_blocks.extend(blocks),
+            _ => converted.push((role, blocks)),
+        }
+    }
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    let messages = converted
+        .into_iter()
+        .map(|(role, content)| json!({"role": role, "content": content}))
+        .collect();
+    (system, messages)
+}
+
+/// Anthropic 不接受空的文本块，空文本不生成内容块
+fn text_block(text: &str) -> Option<Value> {
+    (!text.is_empty()).then(|| json!({"type": "text", "text": text}))
+}
+
+/// 将本地的 `Tool` 转换为 Anthropic 的工具定义，`input_schema` 为必填字段
+fn convert_tools(tools: &[&dyn Tool]) -> Vec<Value> {
+    tools
+        .iter()
+        .map(|tool| {
+            let mut definition = json!({
+                "name": tool.name(),
+                "input_schema": tool
+                    .args_schema()
+                    .unwrap_or_else(|| json!({"type": "object", "properties": {}})),
+            });
+            if let Some(description) = tool.description() {
+                definition["description"] = description.into();
+            }
+            definition
+        })
+        .collect()
+}
+
+/// 检查响应中的 error 对象，将可识别的错误转换为 `LlmError`
+fn check_anthropic_error(status: u16, response_json: &Value) -> Result<()> {
+    let Some(error) = response_json.get("error").filter(|error| !error.is_null()) else {
+        return Ok(());
+    };
+    let message = error["message"]
+        .as_str()
+        .unwrap_or("request failed")
+        .to_string();
+    if matches!(status, 401 | 403)
+        || matches!(
+            error["type"].as_str(),
+            Some("authentication_error" | "permission_error")
+        )
+    {
+        return Err(LlmError::Authentication { status, message }.into());
+    }
+    if message.contains("prompt is too long") {
+        return Err(LlmError::ContextLengthExceeded(message).into());
+    }
+    bail!("{message}")
+}
+
+/// 将 `tool_use` 内容块转换为工具调用
+fn tool_call_from_block(id: &str, name: &str, input: Value) -> (String, ToolCallArgs) {
+    (
+        id.to_string(),
+        ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: name.to_string(),
+            args: input,
+        },
+    )
+}
+
+/// 解析非流式响应的 `content` 内容块
+fn parse_response_into_decision(response_json: &Value) -> Result<Decision> {
+    let mut content = String::new();
+    let mut tool_calls = ToolCalls::new();
+    for block in response_json["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str() {
+            Some("text") => content.push_str(block["text"].as_str().unwrap_or_default()),
+            Some("tool_use") => {
+                let (Some(id), Some(name)) = (block["id"].as_str(), block["name"].as_str()) else {
+                    continue;
+                };
+                tool_calls.extend([tool_call_from_block(id, name, block["input"].clone())]);
+            }
+            _ => {}
+        }
+    }
+    if response_json["stop_reason"] == "refusal" {
+        return Err(LlmError::Refusal(content).into());
+    }
+    if tool_calls.is_empty() {
+        Ok(Decision::Respond(content))
+    } else {
+        Ok(Decision::ExecuteTool(content, tool_calls))
+    }
+}
+
+/// 正在接收参数的 `tool_use` 内容块
+#[derive(Debug)]
+struct PendingToolUse {
+    id: String,
+    name: String,
+    partial_json: String,
+}
+
+/// Messages API 流式事件的解析器
+///
+/// 文本增量（`text_delta`）直接转换为 `Decision::Respond`；`tool_use` 内容块的参数以
+/// `input_json_delta` 分段到达，在内容块结束时解析，所有工具调用在消息结束时
+/// 以一个 `Decision::ExecuteTool` 一并产出。
+#[derive(Debug, Default)]
+struct ClaudeStreamParser {
+    /// 内容块序号 => 正在接收的工具调用
+    pending: HashMap<u64, PendingToolUse>,
+    tool_calls: ToolCalls,
+}
+
+impl ClaudeStreamParser {
+    /// 处理一个事件，不产生输出的事件（如 `ping`）返回 `None`
+    fn parse_event(&mut self, event: &Value) -> Result<Option<Decision>> {
+        let index = event["index"].as_u64().unwrap_or_default();
+        match event["type"].as_str().unwrap_or_default() {
+            "content_block_start" => {
+                let block = &event["content_block"];
+                match block["type"].as_str() {
+                    Some("tool_use") => {
+                        self.pending.insert(
+                            index,
+                            PendingToolUse {
+                                id: block["id"].as_str().unwrap_or_default().to_string(),
+                                name: block["name"].as_str().unwrap_or_default().to_string(),
+                                partial_json: String::new(),
+                            },
+                        );
+                        Ok(None)
+                    }
+                    Some("text") => Ok(block["text"]
+                        .as_str()
+                        .filter(|text| !text.is_empty())
+                        .map(|text| Decision::Respond(text.to_string()))),
+                    _ => Ok(None),
+                }
+            }
+            "content_block_delta" => {
+                let delta = &event["delta"];
+                match delta["type"].as_str() {
+                    Some("text_delta") => Ok(Some(Decision::Respond(
+                        delta["text"].as_str().unwrap_or_default().to_string(),
+                    ))),
+                    Some("input_json_delta") => {
+                        if let Some(pending) = self.pending.get_mut(&index) {
+                            pending
+                                .partial_json
+                                .push_str(delta["partial_json"].as_str().unwrap_or_default());
+                        }
+                        Ok(None)
+                    }
+                    _ => Ok(None),
+                }
+            }
+            "content_block_stop" => {
+                if let Some(pending) = self.pending.remove(&index) {
+                    let input = parse_tool_arguments(&pending.name, &pending.partial_json)?;
+                    self.tool_calls.extend([tool_call_from_block(
+                        &pending.id,
+                        &pending.name,
+                        input,
+                    )]);
+                }
+                Ok(None)
+            }
+            "message_delta" if event["delta"]["stop_reason"] == "refusal" => {
+                Err(LlmError::Refusal(String::new()).into())
+            }
+            "message_stop" if !self.tool_calls.is_empty() => Ok(Some(Decision::ExecuteTool(
+                String::new(),
+                std::mem::take(&mut self.tool_calls),
+            ))),
+            "error" => {
+                check_anthropic_error(200, event)?;
+                bail!("stream error")
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::openai::tests::serve_once;
+    use crate::llm::NoRedaction;
+    use crate::tools::tests::EchoTool;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_text_reply_is_parsed_and_system_is_top_level() {
+        let body = json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "content": [{"type": "text", "text": "Hello!"}],
+            "stop_reason": "end_turn"
+        });
+        let (url, request) = serve_once(200, &body.to_string()).await;
+        let client = ClaudeLlmClient::new("sk-ant-test", "claude-sonnet-4-0", url);
+        let messages = [
+            Message::System {
+                content: "Be brief.".into(),
+            },
+            Message::User {
+                content: "Hi".into(),
+            },
+        ];
+
+        let decision = client
+            .complete(&messages, vec![], None, &RequestOptions::default())
+            .await
+            .unwrap();
+        assert!(matches!(decision, Decision::Respond(ref s) if s == "Hello!"));
+
+        let request = request.await.unwrap();
+        let (headers, request_body) = request.split_once("\r\n\r\n").unwrap();
+        let headers = headers.to_lowercase();
+        assert!(headers.contains("x-api-key: sk-ant-test"));
+        assert!(headers.contains("anthropic-version: 2023-06-01"));
+        let request_body: Value = serde_json::from_str(request_body).unwrap();
+        assert_eq!(request_body["system"], "Be brief.");
+        assert_eq!(request_body["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert_eq!(
+            request_body["messages"],
+            json!([{"role": "user", "content": [{"type": "text", "text": "Hi"}]}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tool_use_reply_is_parsed() {
+        let body = json!({
+            "id": "msg_2",
+            "type": "message",
+            "role": "assistant",
+            "content": [
+                {"type": "text", "text": "Let me echo that."},
+                {"type": "tool_use", "id": "toolu_1", "name": "echo", "input": {"text": "hi"}}
+            ],
+            "stop_reason": "tool_use"
+        });
+        let (url, request) = serve_once(200, &body.to_string()).await;
+        let client = ClaudeLlmClient::new("key", "claude-sonnet-4-0", url);
+        let echo = EchoTool::new();
+
+        let decision = client
+            .complete(&[], vec![&echo], Some(256), &RequestOptions::default())
+            .await
+            .unwrap();
+        let Decision::ExecuteTool(content, tool_calls) = decision else {
+            panic!("expected tool calls, got {decision:?}");
+        };
+        assert_eq!(content, "Let me echo that.");
+        assert_eq!(tool_calls["toolu_1"].tool_name, "echo");
+        assert_eq!(tool_calls["toolu_1"].args, json!({"text": "hi"}));
+
+        let request = request.await.unwrap();
+        let request_body: Value =
+            serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(request_body["max_tokens"], 256);
+        assert_eq!(request_body["tools"][0]["name"], "echo");
+        assert_eq!(
+            request_body["tools"][0]["input_schema"]["required"],
+            json!(["text"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_events_yield_text_and_tool_use() {
+        let events = [
+            json!({"type": "message_start", "message": {"id": "msg_3", "content": []}}),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Echoing"}}),
+            json!({"type": "ping"}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "content_block_start", "index": 1, "content_block": {
+                "type": "tool_use", "id": "toolu_1", "name": "echo", "input": {}
+            }}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": "{\"text\": \"h"}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": "i\"}"}}),
+            json!({"type": "content_block_stop", "index": 1}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "tool_use"}}),
+            json!({"type": "message_stop"}),
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {event}\n\n",
+                    event["type"].as_str().unwrap()
+                )
+            })
+            .collect();
+        let (url, request) = serve_once(200, &body).await;
+        let client = ClaudeLlmClient::new("key", "claude-sonnet-4-0", url);
+
+        let decisions: Vec<Decision> = client
+            .stream_complete(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap()
+            .map(|decision| decision.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(decisions.len(), 2);
+        assert!(matches!(&decisions[0], Decision::Respond(s) if s == "Echoing"));
+        let Decision::ExecuteTool(_, tool_calls) = &decisions[1] else {
+            panic!("expected tool calls, got {:?}", decisions[1]);
+        };
+        assert_eq!(tool_calls["toolu_1"].args, json!({"text": "hi"}));
+        assert!(request.await.unwrap().contains("\"stream\":true"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_keeps_characters_split_across_chunks() {
+        let events = [
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "你好，世界"}}),
+            json!({"type": "message_stop"}),
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| format!("data: {event}\n\n"))
+            .collect();
+        // 在“你”的第二个字节处切开
+        let split = body.find('你').unwrap() + 1;
+        let chunks: Vec<std::result::Result<Vec<u8>, anyhow::Error>> = vec![
+            Ok(body.as_bytes()[..split].to_vec()),
+            Ok(body.as_bytes()[split..].to_vec()),
+        ];
+
+        let decisions: Vec<Decision> =
+            parse_event_stream(futures::stream::iter(chunks), Arc::new(NoRedaction))
+                .map(|decision| decision.unwrap())
+                .collect()
+                .await;
+        assert_eq!(decisions.len(), 1);
+        assert!(matches!(&decisions[0], Decision::Respond(s) if s == "你好，世界"));
+    }
+
+    #[test]
+    fn test_tool_round_is_converted_to_content_blocks() {
+        let mut tool_calls = ToolCalls::new();
+        for (id, text) in [("toolu_2", "b"), ("toolu_1", "a")] {
+            tool_calls.insert(
+                id.into(),
+                ToolCallArgs {
+                    tool_type: "function".into(),
+                    tool_name: "echo".into(),
+                    args: json!({"text": text}),
+                },
+            );
+        }
+        let messages = [
+            Message::System {
+                content: "Be brief.".into(),
+            },
+            Message::Developer {
+                content: "Use tools.".into(),
+            },
+            Message::User {
+                content: "Echo a and b".into(),
+            },
+            Message::Assistant {
+                content: String::new(),
+                tool_calls: Some(tool_calls),
+            },
+            Message::Tool {
+                content: "a".into(),
+                tool_call_id: "toolu_1".into(),
+            },
+            Message::Tool {
+                content: "b".into(),
+                tool_call_id: "toolu_2".into(),
+            },
+        ];
+
+        let (system, converted) = convert_messages(&messages);
+        assert_eq!(system.as_deref(), Some("Be brief.\n\nUse tools."));
+        assert_eq!(
+            converted,
+            vec![
+                json!({"role": "user", "content": [{"type": "text", "text": "Echo a and b"}]}),
+                json!({"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "echo", "input": {"text": "a"}},
+                    {"type": "tool_use", "id": "toolu_2", "name": "echo", "input": {"text": "b"}},
+                ]}),
+                // 同一轮的工具结果合并为一条 user 消息
+                json!({"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "a"},
+                    {"type": "tool_result", "tool_use_id": "toolu_2", "content": "b"},
+                ]}),
+            ]
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! 模型生成的工具参数的宽松解析
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+/// 解析模型给出的工具参数 JSON
+///
+/// 严格解析失败时会尝试修复常见错误：markdown 代码块包裹、尾随逗号、未加引号的键。
+/// 空字符串视为无参数（`{}`）；修复后仍无法解析时返回指明工具名的错误，而不是静默地使用空参数。
+pub fn parse_tool_arguments(tool: &str, raw: &str) -> Result<Value> {
+    let trimmed = strip_code_fence(raw.trim());
+    if trimmed.is_empty() {
+        return Ok(Value::Object(Default::default()));
+    }
+    let original_error = match serde_json::from_str(trimmed) {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+    serde_json::from_str(&repair(trimmed)).map_err(|_| {
+        anyhow!("invalid JSON arguments for tool `{tool}`: {original_error} (arguments: {raw})")
+    })
+}
+
+/// 去掉包裹在外层的 ```json ... ``` 代码块标记
+fn strip_code_fence(text: &str) -> &str {
+    let Some(body) = text.strip_prefix("```") else {
+        return text;
+    };
+    let Some(body) = body.trim_end().strip_suffix("```") else {
+        return text;
+    };
+    // 第一行可能是语言标记，如 json
+    match body.split_once('\n') {
+        Some((lang, rest)) if !lang.trim_start().starts_with(['{', '[']) => rest.trim(),
+        _ => body.trim(),
+    }
+}
+
+/// 在字符串字面量之外删除尾随逗号，并为未加引号的键补上引号
+fn repair(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len() + 8);
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if in_string {
+            out.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            i += 1;
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            ',' if next_significant(&chars, i + 1).is_some_and(|n| n == '}' || n == ']') => {}
+            c if (c.is_alphabetic() || c == '_')
+                && last_significant(&out).is_some_and(|p| p == '{' || p == ',') =>
+            {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let key: String = chars[start..i].iter().collect();
+                if next_significant(&chars, i) == Some(':') {
+                    out.push('"');
+                    out.push_str(&key);
+                    out.push('"');
+                } else {
+                    out.push_str(&key);
+                }
+                continue;
+            }
+            _ => out.push(c),
+        }
+        i += 1;
+    }
+    out
+}
+
+fn next_significant(chars: &[char], from: usize) -> Option<char> {
+    chars[from.min(chars.len())..]
+        .iter()
+        .copied()
+        .find(|c| !c.is_whitespace())
+}
+
+fn last_significant(text: &str) -> Option<char> {
+    text.chars().rev().find(|c| !c.is_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_tool_arguments_repairs_trailing_commas() {
+        assert_eq!(
+            parse_tool_arguments("calc", r#"{"a": 1, "b": [1, 2,],}"#).unwrap(),
+            json!({"a": 1, "b": [1, 2]})
+        );
+        // 字符串中的逗号保持不变
+        assert_eq!(
+            parse_tool_arguments("echo", r#"{"text": "a,}",}"#).unwrap(),
+            json!({"text": "a,}"})
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_arguments_repairs_unquoted_keys() {
+        assert_eq!(
+            parse_tool_arguments("calc", r#"{op: "add", num_1: 2, "num2": true}"#).unwrap(),
+            json!({"op": "add", "num_1": 2, "num2": true})
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_arguments_strips_code_fences() {
+        assert_eq!(
+            parse_tool_arguments("echo", "```json\n{\"text\": \"hi\"}\n```").unwrap(),
+            json!({"text": "hi"})
+        );
+        assert_eq!(
+            parse_tool_arguments("echo", "```{\"text\": \"hi\"}```").unwrap(),
+            json!({"text": "hi"})
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_arguments_treats_empty_as_no_arguments() {
+        assert_eq!(parse_tool_arguments("clock", "  ").unwrap(), json!({}));
+    }
+
+    #[test]
+    fn test_parse_tool_arguments_reports_unrepairable_input() {
+        let err = parse_tool_arguments("calc", r#"{"op": "add", "num1": "#).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.starts_with("invalid JSON arguments for tool `calc`: "),
+            "{message}"
+        );
+        assert!(message.ends_with(r#"(arguments: {"op": "add", "num1": )"#));
+    }
+}
@@ -0,0 +1,55 @@
+//! 将流式响应的字节流切分为文本行，供 SSE 与 NDJSON 解析使用
+
+use anyhow::Result;
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+
+/// 将字节流切分为文本行（不含行尾的换行符）
+///
+/// 先缓冲原始字节，只对已完整接收的行做 UTF-8 解码，因此跨越字节块的多字节字符
+/// （如中文）不会被拆开解码为 U+FFFD。末尾没有换行符的不完整内容不会产出。
+pub(crate) fn byte_lines<S, B, E>(byte_stream: S) -> impl Stream<Item = Result<String>> + Send
+where
+    S: Stream<Item = std::result::Result<B, E>> + Send,
+    B: AsRef<[u8]> + Send,
+    E: Into<anyhow::Error> + Send,
+{
+    stream! {
+        let mut byte_stream = Box::pin(byte_stream);
+        let mut buffer: Vec<u8> = Vec::new();
+        while let Some(chunk) = byte_stream.next().await {
+            match chunk {
+                Ok(chunk) => buffer.extend_from_slice(chunk.as_ref()),
+                Err(e) => {
+                    yield Err(e.into());
+                    break;
+                }
+            }
+            while let Some(newline) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=newline).collect();
+                let line = String::from_utf8_lossy(&line);
+                yield Ok(line.trim_end_matches(['\n', '\r']).to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_multibyte_characters_split_across_chunks_are_kept() {
+        let body = "data: 你好，世界\r\n\ndata: 第二行\nincomplete";
+        // 逐字节切分，每个中文字符都跨越多个字节块
+        let chunks: Vec<std::result::Result<Vec<u8>, anyhow::Error>> =
+            body.bytes().map(|byte| Ok(vec![byte])).collect();
+
+        let lines: Vec<String> = byte_lines(futures::stream::iter(chunks))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(lines, ["data: 你好，世界", "", "data: 第二行"]);
+    }
+}
@@ -1,33 +1,245 @@
+pub mod accumulator;
+pub mod anthropic;
+pub mod json_repair;
+mod lines;
+pub mod model_info;
+pub mod ollama;
 pub mod openai;
+pub mod rate_limit;
+pub mod redact;
+pub mod tool_format;
+use std::collections::HashMap;
 use std::pin::Pin;
 
 use anyhow::Result;
 use async_trait::async_trait;
-use futures::Stream;
+use futures::{Stream, StreamExt};
+
+use accumulator::decision_into_fragments;
+pub use accumulator::{
+    DecisionAccumulator, DefaultDecisionAccumulator, StreamFragment, ToolCallFragment,
+};
+pub use anthropic::ClaudeLlmClient;
+pub use json_repair::parse_tool_arguments;
+pub use model_info::ModelInfo;
+pub use ollama::OllamaLlmClient;
+pub use rate_limit::RateLimitedLlmClient;
+pub use redact::{NoRedaction, PromptRedactor, RegexRedactor};
+pub use tool_format::{
+    ClaudeToolCallFormatter, OllamaToolCallFormatter, OpenaiResponsesToolCallFormatter,
+    OpenaiToolCallFormatter, ToolArgsEncoding, ToolCallFormatter,
+};
 
 use crate::tools::Tool;
 use crate::types::{Decision, Message};
 
+/// LLM 调用中可被 Agent 识别并处理的错误
+#[derive(Debug, thiserror::Error)]
+pub enum LlmError {
+    /// 请求的上下文超出了模型的最大长度
+    #[error("context length exceeded: {0}")]
+    ContextLengthExceeded(String),
+    /// 模型出于策略原因拒绝回答，携带拒绝说明
+    #[error("model refused: {0}")]
+    Refusal(String),
+    /// 服务端拒绝了凭据（HTTP 401/403），通常是 API key 无效或没有权限
+    #[error("authentication failed (HTTP {status}): {message}")]
+    Authentication { status: u16, message: String },
+    /// 模型或服务商不支持工具调用（function calling）
+    #[error("tools not supported: {0}")]
+    ToolsUnsupported(String),
+    /// 请求频率或用量超出限制（HTTP 429），调用方应等待后再重试；
+    /// `retry_after` 为服务端通过 `Retry-After` 头建议的等待时长
+    #[error("rate limited (HTTP 429): {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<std::time::Duration>,
+    },
+}
+
+impl LlmError {
+    /// 在错误链中查找 `LlmError`（错误可能被 `StreamInterrupted` 等包装过）
+    pub fn find(err: &anyhow::Error) -> Option<&LlmError> {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<LlmError>())
+    }
+
+    /// 判断一个 anyhow 错误是否为上下文超长错误
+    pub fn is_context_length_exceeded(err: &anyhow::Error) -> bool {
+        matches!(
+            LlmError::find(err),
+            Some(LlmError::ContextLengthExceeded(_))
+        )
+    }
+
+    /// 判断一个 anyhow 错误是否为认证失败
+    pub fn is_authentication(err: &anyhow::Error) -> bool {
+        matches!(LlmError::find(err), Some(LlmError::Authentication { .. }))
+    }
+
+    /// 判断一个 anyhow 错误是否为请求频率超限
+    pub fn is_rate_limited(err: &anyhow::Error) -> bool {
+        matches!(LlmError::find(err), Some(LlmError::RateLimited { .. }))
+    }
+
+    /// 若错误为请求频率超限且服务端给出了 `Retry-After`，返回建议的等待时长
+    pub fn retry_after(err: &anyhow::Error) -> Option<std::time::Duration> {
+        match LlmError::find(err) {
+            Some(LlmError::RateLimited { retry_after, .. }) => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// 判断一个 anyhow 错误是否为不支持工具调用
+    pub fn is_tools_unsupported(err: &anyhow::Error) -> bool {
+        matches!(LlmError::find(err), Some(LlmError::ToolsUnsupported(_)))
+    }
+
+    /// 若错误为模型拒绝，返回拒绝说明
+    pub fn refusal(err: &anyhow::Error) -> Option<&str> {
+        match LlmError::find(err) {
+            Some(LlmError::Refusal(text)) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// 单次请求的可选参数，未设置的字段不会出现在请求中
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestOptions {
+    /// 预测输出（Predicted Outputs）：对于改写类任务，提供预期与输出大部分相同的参考内容以降低延迟
+    pub prediction: Option<String>,
+    /// 直接合并到请求体顶层的额外参数，与已有字段冲突时以此处为准
+    pub extra_params: serde_json::Map<String, serde_json::Value>,
+    /// 采样温度，未设置时由客户端使用自己的默认值
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    /// token id => 偏置值
+    pub logit_bias: Option<HashMap<String, f32>>,
+}
+
+/// 一次请求的 token 用量，取自服务商响应中的 `usage` 字段
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+/// 合并相邻的同角色消息，供要求角色严格交替的服务商使用
+///
+/// 内容以空行连接，相邻 assistant 消息的工具调用合并为一组；tool 消息各自对应
+/// 不同的 tool_call_id，因此不会合并。
+pub fn compact_messages(messages: &[Message]) -> Vec<Message> {
+    fn join(into: &mut String, content: &str) {
+        if !into.is_empty() && !content.is_empty() {
+            into.push_str("\n\n");
+        }
+        into.push_str(content);
+    }
+
+    let mut compacted: Vec<Message> = Vec::with_capacity(messages.len());
+    for message in messages {
+        match (compacted.last_mut(), message) {
+            (Some(Message::Developer { content }), Message::Developer { content: next })
+            | (Some(Message::System { content }), Message::System { content: next })
+            | (Some(Message::User { content }), Message::User { content: next }) => {
+                join(content, next)
+            }
+            (
+                Some(Message::Assistant {
+                    content,
+                    tool_calls,
+                }),
+                Message::Assistant {
+                    content: next,
+                    tool_calls: next_calls,
+                },
+            ) => {
+                join(content, next);
+                if let Some(next_calls) = next_calls {
+                    tool_calls
+                        .get_or_insert_with(Default::default)
+                        .extend(next_calls.clone());
+                }
+            }
+            _ => compacted.push(message.clone()),
+        }
+    }
+    compacted
+}
+
 #[async_trait]
 pub trait LLMClient: Send + Sync {
+    /// 请求使用的模型名称，用于查询 `ModelInfo`；未知时返回 `None`
+    fn model_name(&self) -> Option<&str> {
+        None
+    }
+
     async fn complete(
         &self,
         messages: &[Message],
-        tools: Vec<&Box<dyn Tool>>,
+        tools: Vec<&dyn Tool>,
         max_tokens: Option<usize>,
+        options: &RequestOptions,
     ) -> Result<Decision>;
 
+    /// 与 `complete` 相同，同时返回本次请求的 token 用量
+    ///
+    /// 默认实现不解析用量，返回 `None`；能从响应中拿到用量的客户端应覆盖该方法。
+    async fn complete_with_usage(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<(Decision, Option<Usage>)> {
+        let decision = self.complete(messages, tools, max_tokens, options).await?;
+        Ok((decision, None))
+    }
+
     async fn stream_complete(
         &self,
         messages: &[Message],
-        tools: Vec<&Box<dyn Tool>>,
+        tools: Vec<&dyn Tool>,
         max_tokens: Option<usize>,
+        options: &RequestOptions,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>>;
+
+    /// 流式请求并将片段交给调用方提供的累加器，返回累加器产出的完整决策
+    ///
+    /// 默认实现将 `stream_complete` 产出的每个 `Decision` 拆为片段；能直接拿到原始片段
+    /// （如带 index 的工具调用参数分片）的客户端可以覆盖该方法。
+    async fn stream_complete_into(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+        accumulator: &mut dyn DecisionAccumulator,
+    ) -> Result<Decision> {
+        let mut stream = self
+            .stream_complete(messages, tools, max_tokens, options)
+            .await?;
+        let mut next_index = 0;
+        while let Some(decision) = stream.next().await {
+            for fragment in decision_into_fragments(decision?, &mut next_index) {
+                accumulator.push(fragment);
+            }
+        }
+        accumulator.finish()
+    }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crate::types::{ToolCallArgs, ToolCalls};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::time::Duration;
 
     #[derive(Debug, Default)]
     pub struct MockLLMClient;
@@ -43,8 +255,9 @@ pub(crate) mod tests {
         async fn complete(
             &self,
             messages: &[Message],
-            _tools: Vec<&Box<dyn Tool>>,
+            _tools: Vec<&dyn Tool>,
             _max_tokens: Option<usize>,
+            _options: &RequestOptions,
         ) -> Result<Decision> {
             if let Some(Message::User { content }) = messages.last() {
                 Ok(Decision::Respond(format!("Echo: {}", content)))
@@ -56,14 +269,304 @@ pub(crate) mod tests {
         async fn stream_complete(
             &self,
             messages: &[Message],
-            tools: Vec<&Box<dyn Tool>>,
+            tools: Vec<&dyn Tool>,
             max_tokens: Option<usize>,
+            options: &RequestOptions,
         ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
-            let response = self.complete(messages, tools, max_tokens).await?;
+            let response = self.complete(messages, tools, max_tokens, options).await?;
             Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
         }
     }
 
+    /// 按脚本依次返回预设结果的 LLM，同时记录每次请求收到的消息
+    #[derive(Debug, Default)]
+    pub struct ScriptedLLMClient {
+        script: Mutex<VecDeque<Result<Decision>>>,
+        requests: Mutex<Vec<Vec<Message>>>,
+        options: Mutex<Vec<RequestOptions>>,
+        tool_names: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl ScriptedLLMClient {
+        pub fn new(script: Vec<Result<Decision>>) -> Self {
+            Self {
+                script: Mutex::new(script.into()),
+                requests: Mutex::new(Vec::new()),
+                options: Mutex::new(Vec::new()),
+                tool_names: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// 已收到的全部请求（每次请求的消息列表）
+        pub fn requests(&self) -> Vec<Vec<Message>> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// 每次请求收到的可选参数
+        pub fn options(&self) -> Vec<RequestOptions> {
+            self.options.lock().unwrap().clone()
+        }
+
+        /// 每次请求中发送给模型的工具名称（保持传入顺序）
+        pub fn tool_names(&self) -> Vec<Vec<String>> {
+            self.tool_names.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl LLMClient for ScriptedLLMClient {
+        async fn complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Decision> {
+            self.requests.lock().unwrap().push(messages.to_vec());
+            self.tool_names
+                .lock()
+                .unwrap()
+                .push(tools.iter().map(|tool| tool.name()).collect());
+            self.options.lock().unwrap().push(options.clone());
+            self.script
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Err(anyhow::anyhow!("script exhausted")))
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let response = self.complete(messages, tools, max_tokens, options).await?;
+            Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+        }
+    }
+
+    /// 各类结果的相对权重
+    #[derive(Debug, Clone, Copy)]
+    pub struct FuzzWeights {
+        pub execute_tool: u64,
+        pub respond: u64,
+        pub error: u64,
+        pub timeout: u64,
+    }
+
+    impl Default for FuzzWeights {
+        fn default() -> Self {
+            Self {
+                execute_tool: 40,
+                respond: 35,
+                error: 15,
+                timeout: 10,
+            }
+        }
+    }
+
+    /// 由种子驱动、按权重随机返回工具调用、回复、错误或超时的 LLM，用于对 Agent 循环做模糊测试
+    #[derive(Debug)]
+    pub struct FuzzLLMClient {
+        state: Mutex<u64>,
+        weights: FuzzWeights,
+        /// 模拟超时时的等待时长，应大于 Agent 配置的超时时间
+        pub delay: Duration,
+    }
+
+    impl FuzzLLMClient {
+        pub fn new(seed: u64) -> Self {
+            Self::with_weights(seed, FuzzWeights::default())
+        }
+
+        pub fn with_weights(seed: u64, weights: FuzzWeights) -> Self {
+            Self {
+                // xorshift 的状态不能为 0
+                state: Mutex::new(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1),
+                weights,
+                delay: Duration::from_secs(3600),
+            }
+        }
+
+        /// xorshift64* 伪随机数
+        fn next(&self) -> u64 {
+            let mut state = self.state.lock().unwrap();
+            *state ^= *state >> 12;
+            *state ^= *state << 25;
+            *state ^= *state >> 27;
+            state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+        }
+
+        fn random_tool_calls(&self) -> ToolCalls {
+            let count = 1 + self.next() % 3;
+            (0..count)
+                .map(|i| {
+                    // 偶尔调用不存在的工具或缺少参数
+                    let (tool_name, args) = match self.next() % 4 {
+                        0 => ("missing_tool", serde_json::json!({})),
+                        1 => ("echo", serde_json::json!({})),
+                        _ => ("echo", serde_json::json!({"text": self.next().to_string()})),
+                    };
+                    (
+                        format!("call_{i}_{}", self.next()),
+                        ToolCallArgs {
+                            tool_type: "function".to_string(),
+                            tool_name: tool_name.to_string(),
+                            args,
+                        },
+                    )
+                })
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl LLMClient for FuzzLLMClient {
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: Vec<&dyn Tool>,
+            _max_tokens: Option<usize>,
+            _options: &RequestOptions,
+        ) -> Result<Decision> {
+            let FuzzWeights {
+                execute_tool,
+                respond,
+                error,
+                timeout,
+            } = self.weights;
+            let mut roll = self.next() % (execute_tool + respond + error + timeout);
+            if roll < execute_tool {
+                return Ok(Decision::ExecuteTool(
+                    String::new(),
+                    self.random_tool_calls(),
+                ));
+            }
+            roll -= execute_tool;
+            if roll < respond {
+                return Ok(Decision::Respond(format!("answer {}", self.next())));
+            }
+            roll -= respond;
+            if roll < error {
+                return Err(if self.next() & 1 == 0 {
+                    LlmError::ContextLengthExceeded("fuzz".into()).into()
+                } else {
+                    anyhow::anyhow!("fuzz error")
+                });
+            }
+            tokio::time::sleep(self.delay).await;
+            Ok(Decision::Respond("too late".into()))
+        }
+
+        async fn stream_complete(
+            &self,
+            messages: &[Message],
+            tools: Vec<&dyn Tool>,
+            max_tokens: Option<usize>,
+            options: &RequestOptions,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+            let response = self.complete(messages, tools, max_tokens, options).await?;
+            Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+        }
+    }
+
+    #[test]
+    fn test_fuzz_llm_client_is_reproducible() {
+        let a = FuzzLLMClient::new(42);
+        let b = FuzzLLMClient::new(42);
+        let c = FuzzLLMClient::new(43);
+        let seq = |client: &FuzzLLMClient| (0..8).map(|_| client.next()).collect::<Vec<_>>();
+        let sa = seq(&a);
+        assert_eq!(sa, seq(&b));
+        assert_ne!(sa, seq(&c));
+    }
+
+    #[test]
+    fn test_compact_messages_merges_consecutive_roles() {
+        let messages = [
+            Message::System {
+                content: "sys".into(),
+            },
+            Message::User {
+                content: "first".into(),
+            },
+            Message::User {
+                content: "second".into(),
+            },
+            Message::Tool {
+                content: "a".into(),
+                tool_call_id: "call_1".into(),
+            },
+            Message::Tool {
+                content: "b".into(),
+                tool_call_id: "call_2".into(),
+            },
+            Message::Assistant {
+                content: "done".into(),
+                tool_calls: None,
+            },
+        ];
+
+        assert_eq!(
+            compact_messages(&messages),
+            vec![
+                Message::System {
+                    content: "sys".into(),
+                },
+                Message::User {
+                    content: "first\n\nsecond".into(),
+                },
+                messages[3].clone(),
+                messages[4].clone(),
+                messages[5].clone(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_complete_into_uses_the_accumulator() {
+        let mut tool_calls = ToolCalls::new();
+        tool_calls.insert(
+            "call_1".to_string(),
+            ToolCallArgs {
+                tool_type: "function".to_string(),
+                tool_name: "echo".to_string(),
+                args: serde_json::json!({"text": "hi"}),
+            },
+        );
+        let client = ScriptedLLMClient::new(vec![Ok(Decision::ExecuteTool(
+            "Echoing".into(),
+            tool_calls.clone(),
+        ))]);
+
+        let mut accumulator = DefaultDecisionAccumulator::new();
+        let decision = client
+            .stream_complete_into(
+                &[],
+                vec![],
+                None,
+                &RequestOptions::default(),
+                &mut accumulator,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            decision,
+            Decision::ExecuteTool(text, calls) if text == "Echoing" && calls == tool_calls
+        ));
+    }
+
+    #[test]
+    fn test_context_length_error_detection() {
+        let err = anyhow::Error::from(LlmError::ContextLengthExceeded("too long".into()));
+        assert!(LlmError::is_context_length_exceeded(&err));
+        assert!(!LlmError::is_context_length_exceeded(&anyhow::anyhow!(
+            "other"
+        )));
+    }
+
     #[tokio::test]
     async fn test_mock_llm_client() {
         let client = MockLLMClient::new();
@@ -72,7 +575,10 @@ pub(crate) mod tests {
         };
         let messages = vec![message];
 
-        let response = client.complete(&messages, vec![], Some(100)).await.unwrap();
+        let response = client
+            .complete(&messages, vec![], Some(100), &RequestOptions::default())
+            .await
+            .unwrap();
 
         match response {
             Decision::Respond(response) => {
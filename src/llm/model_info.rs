@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+/// 常见模型的上下文窗口大小（token），按名称前缀匹配
+const DEFAULT_CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-4", 8_192),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4o-mini", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("o1", 200_000),
+    ("o1-mini", 128_000),
+    ("o3", 200_000),
+    ("o3-mini", 200_000),
+    ("o4-mini", 200_000),
+    ("claude-3", 200_000),
+    ("claude-3-5", 200_000),
+    ("claude-3-7", 200_000),
+    ("claude-sonnet-4", 200_000),
+    ("claude-opus-4", 200_000),
+    ("deepseek-chat", 65_536),
+    ("deepseek-reasoner", 65_536),
+];
+
+/// 通过 `ModelInfo::set_context_window` 注册的覆盖值，优先于默认表
+fn overrides() -> &'static RwLock<HashMap<String, usize>> {
+    static OVERRIDES: OnceLock<RwLock<HashMap<String, usize>>> = OnceLock::new();
+    OVERRIDES.get_or_init(Default::default)
+}
+
+/// 模型元数据查询
+///
+/// 未设置 `max_tokens` 时，Agent 根据 `LLMClient::model_name` 在此查询上下文窗口，
+/// 以其 `SAFE_BUDGET_RATIO` 作为裁剪上下文的预算，避免历史无限增长后超出窗口。
+pub struct ModelInfo;
+
+impl ModelInfo {
+    /// 作为默认裁剪预算时使用的窗口比例，其余部分留给输出与 token 估算误差
+    pub const SAFE_BUDGET_RATIO: f32 = 0.75;
+
+    /// 查询模型的上下文窗口大小
+    ///
+    /// 先精确匹配覆盖值，再按最长前缀匹配覆盖值与默认表（如 `gpt-4o-2024-08-06` 匹配 `gpt-4o`），
+    /// 未知模型返回 `None`。
+    pub fn context_window(model: &str) -> Option<usize> {
+        let overrides = overrides().read().unwrap();
+        if let Some(window) = overrides.get(model) {
+            return Some(*window);
+        }
+        overrides
+            .iter()
+            .map(|(name, window)| (name.as_str(), *window))
+            .chain(DEFAULT_CONTEXT_WINDOWS.iter().copied())
+            .filter(|(name, _)| is_model_prefix(name, model))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, window)| window)
+    }
+
+    /// 注册或覆盖模型的上下文窗口大小，对整个进程生效
+    pub fn set_context_window(model: impl Into<String>, window: usize) {
+        overrides().write().unwrap().insert(model.into(), window);
+    }
+
+    /// 根据上下文窗口计算默认的裁剪预算
+    pub fn default_budget(model: &str) -> Option<usize> {
+        Self::context_window(model).map(|window| (window as f32 * Self::SAFE_BUDGET_RATIO) as usize)
+    }
+}
+
+/// `prefix` 与 `model` 相同，或是 `model` 以 `-` 分隔的前缀
+fn is_model_prefix(prefix: &str, model: &str) -> bool {
+    model
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_models() {
+        assert_eq!(ModelInfo::context_window("gpt-4o"), Some(128_000));
+        assert_eq!(
+            ModelInfo::context_window("gpt-4o-2024-08-06"),
+            Some(128_000)
+        );
+        assert_eq!(ModelInfo::context_window("gpt-4"), Some(8_192));
+        assert_eq!(ModelInfo::context_window("gpt-4-0613"), Some(8_192));
+        assert_eq!(ModelInfo::context_window("gpt-4-32k-0613"), Some(32_768));
+        assert_eq!(ModelInfo::context_window("gpt-3.5-turbo"), Some(16_385));
+        assert_eq!(
+            ModelInfo::context_window("claude-3-5-sonnet-20241022"),
+            Some(200_000)
+        );
+        assert_eq!(ModelInfo::default_budget("gpt-4"), Some(6_144));
+    }
+
+    #[test]
+    fn test_unknown_models() {
+        assert_eq!(ModelInfo::context_window("my-local-llama"), None);
+        // 只在 `-` 处匹配前缀
+        assert_eq!(ModelInfo::context_window("gpt-4oo"), None);
+        assert_eq!(ModelInfo::default_budget("my-local-llama"), None);
+    }
+
+    #[test]
+    fn test_override() {
+        ModelInfo::set_context_window("test-override-model", 4_096);
+        assert_eq!(
+            ModelInfo::context_window("test-override-model"),
+            Some(4_096)
+        );
+        assert_eq!(
+            ModelInfo::context_window("test-override-model-v2"),
+            Some(4_096)
+        );
+    }
+}
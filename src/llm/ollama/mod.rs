@@ -0,0 +1,474 @@
+//! Ollama（`/api/chat`）客户端，用于本地模型
+//!
+//! 与 OpenAI 的区别：工具调用没有 id，参数是 JSON 对象而不是字符串；流式输出是
+//! 每行一个 JSON 对象（NDJSON），而不是 SSE 的 `data:` 行，最后一行带有 `"done": true`。
+
+use anyhow::{anyhow, bail, Result};
+use async_stream::stream;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::llm::lines::byte_lines;
+use crate::llm::openai::{f32_to_json, mentions_tools_unsupported, DEFAULT_USER_AGENT};
+use crate::llm::{
+    parse_tool_arguments, LLMClient, LlmError, OllamaToolCallFormatter, PromptRedactor,
+    RegexRedactor, RequestOptions, ToolCallFormatter,
+};
+use crate::types::{ToolCallArgs, ToolCallIdGenerator, ToolCalls};
+use crate::{Decision, Message, Tool};
+
+pub struct OllamaLlmClient {
+    /// 例如：http://localhost:11434
+    pub base_url: String,
+    pub model: String,
+    pub client: Client,
+    /// 请求携带的 `User-Agent`，默认为 `DEFAULT_USER_AGENT`
+    pub user_agent: String,
+    /// Ollama 的工具调用没有 id，由该生成器生成
+    pub call_ids: ToolCallIdGenerator,
+    /// 调试日志中的请求与响应内容在输出前经过脱敏，默认为 `RegexRedactor::default()`
+    pub redactor: Arc<dyn PromptRedactor>,
+}
+
+impl OllamaLlmClient {
+    pub fn new(base_url: impl Into<String>, model: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            model: model.into(),
+            client: Client::new(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            call_ids: ToolCallIdGenerator::new(),
+            redactor: Arc::new(RegexRedactor::default()),
+        }
+    }
+
+    /// 设置请求携带的 `User-Agent`
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// 设置生成工具调用 id 的生成器
+    pub fn with_call_id_generator(mut self, call_ids: ToolCallIdGenerator) -> Self {
+        self.call_ids = call_ids;
+        self
+    }
+
+    /// 设置调试日志使用的脱敏器，传入 `NoRedaction` 可关闭脱敏
+    pub fn with_redactor(mut self, redactor: impl PromptRedactor + 'static) -> Self {
+        self.redactor = Arc::new(redactor);
+        self
+    }
+
+    fn api_url(&self) -> String {
+        format!("{}/api/chat", self.base_url.trim_end_matches('/'))
+    }
+
+    /// 构造 `/api/chat` 请求体，采样参数放在 `options` 中
+    fn build_request_body(
+        &self,
+        messages: &[Message],
+        tools: &[&dyn Tool],
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+        stream: bool,
+    ) -> Value {
+        let mut model_options = serde_json::Map::new();
+        if let Some(temperature) = options.temperature {
+            model_options.insert("temperature".into(), f32_to_json(temperature));
+        }
+        if let Some(top_p) = options.top_p {
+            model_options.insert("top_p".into(), f32_to_json(top_p));
+        }
+        if let Some(max) = max_tokens {
+            model_options.insert("num_predict".into(), json!(max));
+        }
+        let mut request_body = json!({
+            "model": self.model,
+            "messages": convert_messages(messages),
+            "stream": stream,
+        });
+        if !tools.is_empty() {
+            request_body["tools"] = convert_tools(tools).into();
+        }
+        if !model_options.is_empty() {
+            request_body["options"] = model_options.into();
+        }
+        // 额外参数最后合并，因此会覆盖同名字段
+        for (key, value) in &options.extra_params {
+            request_body[key] = value.clone();
+        }
+        request_body
+    }
+
+    async fn send(&self, request_body: &Value) -> Result<reqwest::Response> {
+        let response = self
+            .client
+            .post(self.api_url())
+            .header("Content-Type", "application/json")
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .json(request_body)
+            .send()
+            .await?;
+        let code = response.status();
+        if code.is_success() {
+            return Ok(response);
+        }
+        let response_text = response.text().await?;
+        debug!(
+            "response: {code:?} {}",
+            self.redactor.redact(&response_text)
+        );
+        let response_json = serde_json::from_str(&response_text)
+            .unwrap_or_else(|_| json!({ "error": response_text }));
+        check_ollama_error(&response_json)?;
+        bail!("request failed (HTTP {})", code.as_u16())
+    }
+}
+
+#[async_trait]
+impl LLMClient for OllamaLlmClient {
+    fn model_name(&self) -> Option<&str> {
+        Some(&self.model)
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Decision> {
+        let request_body = self.build_request_body(messages, &tools, max_tokens, options, false);
+        debug!(
+            "request: {}",
+            self.redactor.redact(&request_body.to_string())
+        );
+
+        let response = self.send(&request_body).await?;
+        let response_text = response.text().await?;
+        debug!("response: {}", self.redactor.redact(&response_text));
+        let response_json: Value = serde_json::from_str(&response_text)?;
+        check_ollama_error(&response_json)?;
+
+        let (content, tool_calls) = parse_message(&response_json["message"], &self.call_ids)?;
+        if tool_calls.is_empty() {
+            Ok(Decision::Respond(content))
+        } else {
+            Ok(Decision::ExecuteTool(content, tool_calls))
+        }
+    }
+
+    async fn stream_complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+        let request_body = self.build_request_body(messages, &tools, max_tokens, options, true);
+        debug!(
+            "stream request: {}",
+            self.redactor.redact(&request_body.to_string())
+        );
+
+        let response = self.send(&request_body).await?;
+        debug!("stream status: {}", response.status());
+        Ok(Box::pin(parse_ndjson_stream(
+            response.bytes_stream(),
+            self.call_ids.clone(),
+            self.redactor.clone(),
+        )))
+    }
+}
+
+/// 将消息转换为 Ollama 的 `messages`
+///
+/// 工具结果需要携带工具名称（`tool_name`），根据之前 assistant 消息中同 id 的调用查找。
+fn convert_messages(messages: &[Message]) -> Vec<Value> {
+    let mut tool_names: HashMap<&str, &str> = HashMap::new();
+    messages
+        .iter()
+        .map(|message| match message {
+            Message::Developer { content } | Message::System { content } => {
+                json!({"role": "system", "content": content})
+            }
+            Message::User { content } => json!({"role": "user", "content": content}),
+            Message::Assistant {
+                content,
+                tool_calls,
+            } => {
+                let mut converted = json!({"role": "assistant", "content": content});
+                if let Some(tool_calls) = tool_calls {
+                    let mut calls: Vec<_> = tool_calls.iter().collect();
+                    calls.sort_by_key(|(id, _)| id.as_str());
+                    converted["tool_calls"] = calls
+                        .into_iter()
+                        .map(|(id, call)| {
+                            tool_names.insert(id, &call.tool_name);
+                            OllamaToolCallFormatter.format_tool_call(id, call)
+                        })
+                        .collect::<Vec<_>>()
+                        .into();
+                }
+                converted
+            }
+            Message::Tool {
+                content,
+                tool_call_id,
+            } => {
+                let mut converted = json!({"role": "tool", "content": content});
+                if let Some(name) = tool_names.get(tool_call_id.as_str()) {
+                    converted["tool_name"] = (*name).into();
+                }
+                converted
+            }
+        })
+        .collect()
+}
+
+/// Ollama 的工具定义与 OpenAI 的 function 格式相同
+fn convert_tools(tools: &[&dyn Tool]) -> Vec<Value> {
+    tools
+        .iter()
+        .map(|tool| {
+            let mut function = json!({"name": tool.name()});
+            if let Some(description) = tool.description() {
+                function["description"] = description.into();
+            }
+            function["parameters"] = tool
+                .args_schema()
+                .unwrap_or_else(|| json!({"type": "object", "properties": {}}));
+            json!({"type": "function", "function": function})
+        })
+        .collect()
+}
+
+/// 检查响应中的 `error` 字段（Ollama 的错误是一个字符串）
+fn check_ollama_error(response_json: &Value) -> Result<()> {
+    let Some(message) = response_json["error"].as_str() else {
+        return Ok(());
+    };
+    if mentions_tools_unsupported(message) {
+        return Err(LlmError::ToolsUnsupported(message.to_string()).into());
+    }
+    bail!("{message}")
+}
+
+/// 解析响应中的 `message`，返回文本与工具调用；工具调用的 id 由 `call_ids` 生成
+fn parse_message(message: &Value, call_ids: &ToolCallIdGenerator) -> Result<(String, ToolCalls)> {
+    let content = message["content"].as_str().unwrap_or_default().to_string();
+    let mut tool_calls = ToolCalls::new();
+    for call in message["tool_calls"].as_array().into_iter().flatten() {
+        let function = &call["function"];
+        let Some(name) = function["name"].as_str() else {
+            continue;
+        };
+        // 一般是 JSON 对象，部分模型会返回 JSON 字符串
+        let args = match &function["arguments"] {
+            Value::String(raw) => parse_tool_arguments(name, raw)?,
+            Value::Null => json!({}),
+            args => args.clone(),
+        };
+        tool_calls.insert(
+            call_ids.next_id(),
+            ToolCallArgs {
+                tool_type: "function".to_string(),
+                tool_name: name.to_string(),
+                args,
+            },
+        );
+    }
+    Ok((content, tool_calls))
+}
+
+/// 将 NDJSON 字节流解析为 Decision 流，收到 `"done": true` 的一行后结束
+///
+/// 每行的文本增量产出为 `Decision::Respond`，带有工具调用的行产出为 `Decision::ExecuteTool`。
+fn parse_ndjson_stream<S, B, E>(
+    byte_stream: S,
+    call_ids: ToolCallIdGenerator,
+    redactor: Arc<dyn PromptRedactor>,
+) -> impl Stream<Item = Result<Decision>> + Send
+where
+    S: Stream<Item = std::result::Result<B, E>> + Send,
+    B: AsRef<[u8]> + Send,
+    E: Into<anyhow::Error> + Send,
+{
+    stream! {
+        let mut lines = Box::pin(byte_lines(byte_stream));
+        // 一行 JSON 可能跨越多个字节块，只处理已完整接收的行
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            debug!("stream recieved: {}", redactor.redact(line));
+            let chunk = match serde_json::from_str::<Value>(line) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Err(anyhow!("JSON parse error: {}", e));
+                    continue;
+                }
+            };
+            if let Err(e) = check_ollama_error(&chunk) {
+                yield Err(e);
+                break;
+            }
+            match parse_message(&chunk["message"], &call_ids) {
+                Ok((content, tool_calls)) if !tool_calls.is_empty() => {
+                    yield Ok(Decision::ExecuteTool(content, tool_calls));
+                }
+                Ok((content, _)) if !content.is_empty() => {
+                    yield Ok(Decision::Respond(content));
+                }
+                Ok(_) => {}
+                Err(e) => yield Err(e),
+            }
+            if chunk["done"] == true {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::openai::tests::serve_once;
+    use crate::llm::NoRedaction;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_ndjson_stream_yields_chunks_until_done() {
+        let body = [
+            json!({"model": "llama3.2", "message": {"role": "assistant", "content": "Let me "}, "done": false}),
+            json!({"model": "llama3.2", "message": {"role": "assistant", "content": "查一下天气。"}, "done": false}),
+            json!({"model": "llama3.2", "message": {"role": "assistant", "content": "", "tool_calls": [
+                {"function": {"name": "echo", "arguments": {"text": "hi"}}}
+            ]}, "done": false}),
+            json!({"model": "llama3.2", "message": {"role": "assistant", "content": ""}, "done": true, "done_reason": "stop"}),
+            json!({"model": "llama3.2", "message": {"role": "assistant", "content": "ignored"}, "done": false}),
+        ]
+        .iter()
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+        // 以固定大小切分，使部分行与中文字符跨越多个字节块
+        let chunks: Vec<std::result::Result<Vec<u8>, anyhow::Error>> = body
+            .as_bytes()
+            .chunks(7)
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect();
+
+        let decisions: Vec<Decision> = parse_ndjson_stream(
+            futures::stream::iter(chunks),
+            ToolCallIdGenerator::seeded(1),
+            Arc::new(NoRedaction),
+        )
+        .map(|decision| decision.unwrap())
+        .collect()
+        .await;
+
+        assert_eq!(decisions.len(), 3);
+        assert!(matches!(&decisions[0], Decision::Respond(s) if s == "Let me "));
+        assert!(matches!(&decisions[1], Decision::Respond(s) if s == "查一下天气。"));
+        let Decision::ExecuteTool(_, tool_calls) = &decisions[2] else {
+            panic!("expected tool calls, got {:?}", decisions[2]);
+        };
+        let expected_id = ToolCallIdGenerator::seeded(1).next_id();
+        assert_eq!(tool_calls[&expected_id].tool_name, "echo");
+        assert_eq!(tool_calls[&expected_id].args, json!({"text": "hi"}));
+    }
+
+    #[tokio::test]
+    async fn test_complete_posts_to_api_chat_and_parses_tool_calls() {
+        let body = json!({
+            "model": "llama3.2",
+            "message": {"role": "assistant", "content": "", "tool_calls": [
+                {"function": {"name": "echo", "arguments": {"text": "hi"}}}
+            ]},
+            "done": true
+        });
+        let (url, request) = serve_once(200, &body.to_string()).await;
+        let base_url = url.trim_end_matches("/v1/chat/completions").to_string();
+        let client = OllamaLlmClient::new(base_url, "llama3.2");
+        let messages = [
+            Message::System {
+                content: "Be brief.".into(),
+            },
+            Message::User {
+                content: "Echo hi".into(),
+            },
+        ];
+
+        let decision = client
+            .complete(&messages, vec![], Some(64), &RequestOptions::default())
+            .await
+            .unwrap();
+        let Decision::ExecuteTool(_, tool_calls) = decision else {
+            panic!("expected tool calls, got {decision:?}");
+        };
+        let call = tool_calls.values().next().unwrap();
+        assert_eq!(call.tool_name, "echo");
+        assert_eq!(call.args, json!({"text": "hi"}));
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /api/chat "));
+        let request_body: Value =
+            serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(request_body["stream"], false);
+        assert_eq!(request_body["options"], json!({"num_predict": 64}));
+        assert_eq!(
+            request_body["messages"],
+            json!([
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Echo hi"}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tool_results_carry_tool_name() {
+        let tool_calls = ToolCalls::from([(
+            "call_1".to_string(),
+            ToolCallArgs {
+                tool_type: "function".into(),
+                tool_name: "echo".into(),
+                args: json!({"text": "hi"}),
+            },
+        )]);
+        let messages = [
+            Message::Assistant {
+                content: String::new(),
+                tool_calls: Some(tool_calls),
+            },
+            Message::Tool {
+                content: "hi".into(),
+                tool_call_id: "call_1".into(),
+            },
+        ];
+
+        assert_eq!(
+            convert_messages(&messages),
+            vec![
+                json!({"role": "assistant", "content": "", "tool_calls": [
+                    {"function": {"name": "echo", "arguments": {"text": "hi"}}}
+                ]}),
+                json!({"role": "tool", "content": "hi", "tool_name": "echo"}),
+            ]
+        );
+    }
+}
@@ -1,15 +1,38 @@
-use crate::types::{ToolCallArgs, ToolCalls};
+pub mod responses;
+
+pub use responses::OpenaiResponsesLlmClient;
+
+use crate::llm::{
+    compact_messages, parse_tool_arguments, DecisionAccumulator, DefaultDecisionAccumulator,
+    LlmError, OpenaiToolCallFormatter, PromptRedactor, RegexRedactor, RequestOptions,
+    StreamFragment, ToolCallFormatter, ToolCallFragment, Usage,
+};
+use crate::types::{ToolCallArgs, ToolCallIdGenerator, ToolCalls};
 use crate::{llm::LLMClient, Decision, Message, Tool};
 use anyhow::*;
+use async_stream::stream;
 use async_trait::async_trait;
 use futures::{Stream, StreamExt, TryStreamExt};
 use reqwest::Client;
 use serde_json::json;
-use std::collections::HashMap;
 use std::pin::Pin;
 use std::result::Result::Ok;
+use std::sync::{Arc, Mutex};
 use tracing::debug;
 
+/// 有缺陷的服务端可能在同一响应中返回 id 相同的多个工具调用
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateToolCallIds {
+    /// 为重复的 id 追加 `_2`、`_3` 等后缀，保留全部工具调用
+    #[default]
+    Disambiguate,
+    /// 返回错误
+    Error,
+}
+
+/// 默认的 `User-Agent` 请求头
+pub const DEFAULT_USER_AGENT: &str = concat!("chimerai/", env!("CARGO_PKG_VERSION"));
+
 pub struct OpenaiLlmClient {
     pub api_key: String,
     pub model: String,
@@ -17,91 +40,287 @@ pub struct OpenaiLlmClient {
     pub api_url: String,
     /// 可选的超时设置等
     pub client: Client,
+    /// 请求携带的 `User-Agent`，默认为 `DEFAULT_USER_AGENT`
+    pub user_agent: String,
+    /// 调试模式下保留最近一次的原始响应，生产环境建议关闭以节省内存
+    pub debug: bool,
+    /// 响应中出现重复 tool_call_id 时的处理方式
+    pub duplicate_tool_call_ids: DuplicateToolCallIds,
+    /// 发送前合并相邻的同角色消息（见 `compact_messages`），用于要求角色交替的兼容服务
+    pub compact_messages: bool,
+    /// 为没有 id 的工具调用（旧版 function_call）生成 id
+    pub call_ids: ToolCallIdGenerator,
+    /// 调试日志中的请求与响应内容在输出前经过脱敏，默认为 `RegexRedactor::default()`
+    pub redactor: Arc<dyn PromptRedactor>,
+    /// 流式请求携带 `stream_options: {"include_usage": true}`，让服务端在最后一个 chunk 中返回用量
+    pub include_stream_usage: bool,
+    last_raw_response: Mutex<Option<serde_json::Value>>,
 }
 
-#[async_trait]
-impl LLMClient for OpenaiLlmClient {
-    async fn complete(
+/// 一次流式请求的 token 用量，由 `OpenaiLlmClient::stream_complete_with_usage` 返回
+///
+/// 每个请求各自持有，并发请求之间不会互相覆盖；流读取完毕后才可用，且需要开启 `with_stream_usage`。
+#[derive(Debug, Clone, Default)]
+pub struct StreamUsage(Arc<Mutex<Option<Usage>>>);
+
+impl StreamUsage {
+    /// 获取该请求的用量；流尚未读取完毕或服务端没有返回 `usage` 时为 `None`
+    pub fn get(&self) -> Option<Usage> {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl OpenaiLlmClient {
+    pub fn new(
+        api_key: impl Into<String>,
+        model: impl Into<String>,
+        api_url: impl Into<String>,
+    ) -> Self {
+        Self {
+            api_key: api_key.into(),
+            model: model.into(),
+            api_url: api_url.into(),
+            client: Client::new(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            debug: false,
+            duplicate_tool_call_ids: DuplicateToolCallIds::default(),
+            compact_messages: false,
+            call_ids: ToolCallIdGenerator::new(),
+            redactor: Arc::new(RegexRedactor::default()),
+            include_stream_usage: false,
+            last_raw_response: Mutex::new(None),
+        }
+    }
+
+    /// 设置请求携带的 `User-Agent`
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// 开启或关闭调试模式
+    pub fn with_debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// 设置合成 tool_call_id 所用的生成器，测试中可传入 `ToolCallIdGenerator::seeded` 以获得固定的 id
+    pub fn with_call_id_generator(mut self, call_ids: ToolCallIdGenerator) -> Self {
+        self.call_ids = call_ids;
+        self
+    }
+
+    /// 设置调试日志使用的脱敏器，传入 `NoRedaction` 可关闭脱敏
+    pub fn with_redactor(mut self, redactor: impl PromptRedactor + 'static) -> Self {
+        self.redactor = Arc::new(redactor);
+        self
+    }
+
+    /// 设置重复 tool_call_id 的处理方式
+    pub fn with_duplicate_tool_call_ids(mut self, policy: DuplicateToolCallIds) -> Self {
+        self.duplicate_tool_call_ids = policy;
+        self
+    }
+
+    /// 开启或关闭发送前的同角色消息合并
+    pub fn with_message_compaction(mut self, compact: bool) -> Self {
+        self.compact_messages = compact;
+        self
+    }
+
+    /// 开启或关闭流式请求的用量统计
+    pub fn with_stream_usage(mut self, include_usage: bool) -> Self {
+        self.include_stream_usage = include_usage;
+        self
+    }
+
+    /// 获取最近一次 `complete` 调用的原始响应，仅在调试模式下记录
+    pub fn last_response(&self) -> Option<serde_json::Value> {
+        self.last_raw_response.lock().unwrap().clone()
+    }
+
+    /// 构造 chat completions 请求体
+    fn build_request_body(
         &self,
         messages: &[Message],
-        tools: Vec<&Box<dyn Tool>>,
+        tools: &[&dyn Tool],
         max_tokens: Option<usize>,
-    ) -> Result<Decision> {
-        // 1. 转换 messages 为 OpenAI 格式
-        let openai_messages = convert_messages(messages);
-
-        // 2. 转换 tools 为 OpenAI function 格式
-        let openai_functions = convert_tools_to_openai_functions(&tools);
-
-        // 3. 构造请求体
+        options: &RequestOptions,
+        stream: bool,
+    ) -> serde_json::Value {
+        let messages = if self.compact_messages {
+            convert_messages(&compact_messages(messages))
+        } else {
+            convert_messages(messages)
+        };
         let mut request_body = serde_json::json!({
             "model": self.model,
-            "messages": openai_messages,
-            "tools": openai_functions,
+            "messages": messages,
+            "tools": convert_tools_to_openai_functions(tools),
             "tool_choice": "auto",
-            "temperature": 0.7,
-            "stream": false
+            "temperature": f32_to_json(options.temperature.unwrap_or(0.7)),
+            "stream": stream,
         });
-
+        if stream && self.include_stream_usage {
+            request_body["stream_options"] = json!({"include_usage": true});
+        }
         if let Some(max) = max_tokens {
             request_body["max_tokens"] = serde_json::json!(max);
         }
+        if let Some(prediction) = &options.prediction {
+            request_body["prediction"] = json!({
+                "type": "content",
+                "content": prediction,
+            });
+        }
+        for (key, value) in [
+            ("top_p", options.top_p),
+            ("frequency_penalty", options.frequency_penalty),
+            ("presence_penalty", options.presence_penalty),
+        ] {
+            if let Some(value) = value {
+                request_body[key] = f32_to_json(value);
+            }
+        }
+        if let Some(logit_bias) = &options.logit_bias {
+            request_body["logit_bias"] = logit_bias
+                .iter()
+                .map(|(token, bias)| (token.clone(), f32_to_json(*bias)))
+                .collect();
+        }
+        // 额外参数最后合并，因此会覆盖同名字段
+        for (key, value) in &options.extra_params {
+            request_body[key] = value.clone();
+        }
+        request_body
+    }
+}
 
-        debug!("request: {}", request_body.to_string());
+#[async_trait]
+impl LLMClient for OpenaiLlmClient {
+    fn model_name(&self) -> Option<&str> {
+        Some(&self.model)
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Decision> {
+        let (decision, _) = self
+            .complete_with_usage(messages, tools, max_tokens, options)
+            .await?;
+        Ok(decision)
+    }
+
+    async fn complete_with_usage(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<(Decision, Option<Usage>)> {
+        // 1-3. 转换 messages 与 tools 为 OpenAI 格式并构造请求体
+        let request_body = self.build_request_body(messages, &tools, max_tokens, options, false);
+
+        debug!(
+            "request: {}",
+            self.redactor.redact(&request_body.to_string())
+        );
 
         // 4. 发送请求
         let response = self
             .client
             .post(&self.api_url)
             .header("Content-Type", "application/json")
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
             .bearer_auth(&self.api_key)
             .json(&request_body)
             .send()
             .await?;
 
         let code = response.status();
+        let retry_after = parse_retry_after(response.headers());
         let response_text = response.text().await?.to_string();
-        debug!("response: {code:?} {response_text}");
+        debug!(
+            "response: {code:?} {}",
+            self.redactor.redact(&response_text)
+        );
+        if !code.is_success() {
+            return Err(http_status_error(code, retry_after, &response_text));
+        }
         let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
+        if self.debug {
+            *self.last_raw_response.lock().unwrap() = Some(response_json.clone());
+        }
+        check_openai_error(&response_json)?;
+        let usage = parse_usage(&response_json["usage"]);
 
         // 5. 解析响应
-        parse_openai_response_into_decision(response_json)
+        let decision = parse_openai_response_into_decision(
+            response_json,
+            self.duplicate_tool_call_ids,
+            &self.call_ids,
+        )?;
+        Ok((decision, usage))
     }
 
     async fn stream_complete(
         &self,
         messages: &[Message],
-        tools: Vec<&Box<dyn Tool>>,
+        tools: Vec<&dyn Tool>,
         max_tokens: Option<usize>,
+        options: &RequestOptions,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
-        // 1. 将 messages 与 tools 转换为 OpenAI 所需格式
-        let openai_messages = convert_messages(messages);
-        let openai_functions = convert_tools_to_openai_functions(&tools);
+        let (decision_stream, _) = self
+            .stream_complete_with_usage(messages, tools, max_tokens, options)
+            .await?;
+        Ok(decision_stream)
+    }
+}
 
-        // 2. 构造请求体，注意 stream 字段设为 true
-        let mut request_body = serde_json::json!({
-            "model": self.model,
-            "messages": openai_messages,
-            "tools": openai_functions,
-            "tool_choice": "auto",
-            "temperature": 0.7,
-            "stream": true,
-        });
-        if let Some(max) = max_tokens {
-            request_body["max_tokens"] = serde_json::json!(max);
-        }
-        debug!("stream request: {}", request_body.to_string());
+impl OpenaiLlmClient {
+    /// 与 `stream_complete` 相同，同时返回本次请求的用量，流读取完毕后可从中取得
+    pub async fn stream_complete_with_usage(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<(
+        Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>,
+        StreamUsage,
+    )> {
+        // 1-2. 将 messages 与 tools 转换为 OpenAI 所需格式并构造请求体，注意 stream 字段设为 true
+        let request_body = self.build_request_body(messages, &tools, max_tokens, options, true);
+        check_single_stream_choice(&request_body)?;
+        debug!(
+            "stream request: {}",
+            self.redactor.redact(&request_body.to_string())
+        );
 
         // 3. 发送请求
         let response = self
             .client
             .post(&self.api_url)
             .header("Content-Type", "application/json")
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
             .bearer_auth(&self.api_key)
             .json(&request_body)
             .send()
             .await?;
         debug!("stream status: {}", response.status());
+        let code = response.status();
+        if !code.is_success() {
+            let retry_after = parse_retry_after(response.headers());
+            return Err(http_status_error(
+                code,
+                retry_after,
+                &response.text().await?,
+            ));
+        }
 
         // 4. 获取响应字节流
         let byte_stream = response.bytes_stream();
@@ -109,9 +328,9 @@ impl LLMClient for OpenaiLlmClient {
         // 将每个字节块转换为字符串，并按行拆分，过滤掉不需要的部分（例如 "[DONE]"）
         // 假设 byte_stream 的类型为 impl Stream<Item = Result<bytes::Bytes, reqwest::Error>>
         let line_stream = byte_stream
-            .map_err(|e: reqwest::Error| anyhow!(e))
-            .and_then(|chunk| async move {
+            .map(|chunk| -> Result<String> {
                 // 使用 chunk.as_ref() 来获取 &[u8]
+                let chunk = chunk.map_err(|e: reqwest::Error| anyhow!(e))?;
                 let s = std::str::from_utf8(chunk.as_ref())
                     .map_err(|e| anyhow!("UTF8 error: {}", e))?
                     .to_string();
@@ -128,7 +347,7 @@ impl LLMClient for OpenaiLlmClient {
                             if data.is_empty() {
                                 None
                             } else if data == "[DONE]" {
-                                println!("");
+                                println!();
                                 None
                             } else {
                                 Some(data.to_string())
@@ -143,49 +362,83 @@ impl LLMClient for OpenaiLlmClient {
             })
             .try_flatten();
 
-        // 5. 将每一行的 JSON 字符串转换为 Decision（调用辅助函数解析每个流式 chunk）
-        let decision_stream = line_stream.map(|json_line_result: Result<String>| {
-            // 解析每一行 JSON，生成 Decision
-            let json_line = json_line_result?;
-            debug!("stream recieved: {json_line}");
-            let json_value: serde_json::Value =
-                serde_json::from_str(&json_line).map_err(|e| anyhow!("JSON parse error: {}", e))?;
-            parse_openai_stream_chunk_into_decision(json_value)
-        });
+        // 5. 将每一行的 JSON 字符串转换为 Decision，工具调用片段在解析器中累积到完整后再产出
+        let redactor = self.redactor.clone();
+        let mut parser = OpenaiStreamParser::new(self.call_ids.clone());
+        let usage = StreamUsage::default();
+        let request_usage = usage.clone();
+        let decision_stream = stream! {
+            let mut line_stream = Box::pin(line_stream);
+            while let Some(json_line_result) = line_stream.next().await {
+                let decision = json_line_result.and_then(|json_line| {
+                    debug!("stream recieved: {}", redactor.redact(&json_line));
+                    let json_value: serde_json::Value = serde_json::from_str(&json_line)
+                        .map_err(|e| anyhow!("JSON parse error: {}", e))?;
+                    parser.parse_chunk(&json_value)
+                });
+                if let Some(decision) = decision.transpose() {
+                    yield decision;
+                }
+            }
+            *request_usage.0.lock().unwrap() = parser.usage;
+            if let Some(decision) = parser.finish().transpose() {
+                yield decision;
+            }
+        };
+
+        Ok((Box::pin(decision_stream), usage))
+    }
+}
 
-        Ok(Box::pin(decision_stream))
+/// 流式解析只跟踪一个候选，因此拒绝（通过 `extra_params` 设置的）`n > 1`，
+/// 避免其余候选的增量被静默丢弃
+fn check_single_stream_choice(request_body: &serde_json::Value) -> Result<()> {
+    match request_body["n"].as_u64() {
+        Some(n) if n > 1 => bail!(
+            "streaming supports a single choice, but the request sets n = {n}; use complete() for multiple choices"
+        ),
+        _ => Ok(()),
     }
 }
 
+/// 将 f32 转换为 JSON 数字，避免 `0.9f32` 被序列化为 `0.8999999761581543`
+pub(crate) fn f32_to_json(value: f32) -> serde_json::Value {
+    value
+        .to_string()
+        .parse::<f64>()
+        .map(serde_json::Value::from)
+        .unwrap_or(serde_json::Value::Null)
+}
+
 /// 将 `Vec<Message>` 转换为 OpenAI 的 `messages`
 fn convert_messages(messages: &[Message]) -> Vec<serde_json::Value> {
     messages
         .iter()
-        .filter_map(|m| {
+        .map(|m| {
             match m {
                 Message::Developer { content } => {
                     // 有些人会将 Developer 也当作 "system" 角色
-                    Some(json_msg("system", content, None, None))
+                    json_msg("system", content, None, None)
                 }
-                Message::System { content } => Some(json_msg("system", content, None, None)),
-                Message::User { content } => Some(json_msg("user", content, None, None)),
+                Message::System { content } => json_msg("system", content, None, None),
+                Message::User { content } => json_msg("user", content, None, None),
                 Message::Assistant {
                     content,
                     tool_calls,
                 } => {
                     // 如果是单纯的 assistant 输出，则 content 直接放入
-                    Some(json_msg("assistant", content, None, tool_calls.clone()))
+                    json_msg("assistant", content, None, tool_calls.clone())
                 }
                 Message::Tool {
                     content,
                     tool_call_id,
                 } => {
                     // 工具调用的响应需要包含 tool_call_id
-                    Some(serde_json::json!({
+                    serde_json::json!({
                         "role": "tool",
                         "content": content,
                         "tool_call_id": tool_call_id
-                    }))
+                    })
                 }
             }
         })
@@ -203,6 +456,10 @@ fn json_msg(
         "role": role,
         "content": content,
     });
+    // 只有工具调用的 assistant 消息，其空内容按 OpenAI 的约定以 null 发送
+    if content.is_empty() && tool_calls.is_some() {
+        res["content"] = serde_json::Value::Null;
+    }
     if let Some(name) = name {
         res["name"] = name.into();
     }
@@ -210,14 +467,7 @@ fn json_msg(
         res["tool_calls"] = tool_calls
             .iter()
             .map(|(tool_call_id, args)| {
-                json!({
-                    "id": tool_call_id,
-                    "type": args.tool_type,
-                    "function": {
-                        "arguments": args.args.to_string(),
-                        "name": args.tool_name,
-                    },
-                })
+                OpenaiToolCallFormatter.format_tool_call(tool_call_id, args)
             })
             .collect::<Vec<_>>()
             .into();
@@ -226,7 +476,7 @@ fn json_msg(
 }
 
 /// 将本地的 `Tool` 转换为 OpenAI Functions 定义
-fn convert_tools_to_openai_functions(tools: &[&Box<dyn Tool>]) -> Vec<serde_json::Value> {
+fn convert_tools_to_openai_functions(tools: &[&dyn Tool]) -> Vec<serde_json::Value> {
     tools
         .iter()
         .map(|tool| {
@@ -247,8 +497,128 @@ fn convert_tools_to_openai_functions(tools: &[&Box<dyn Tool>]) -> Vec<serde_json
         .collect()
 }
 
+/// 401/403 的响应体中没有 choices，若继续解析会得到空回复，因此需要先检查状态码
+fn is_authentication_failure(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 401 | 403)
+}
+
+/// 从认证失败的响应体中提取错误说明，构造 `LlmError::Authentication`
+fn authentication_error(status: reqwest::StatusCode, response_text: &str) -> Error {
+    LlmError::Authentication {
+        status: status.as_u16(),
+        message: error_message(response_text),
+    }
+    .into()
+}
+
+/// 错误信息中保留的响应体最大长度（字符数）
+const MAX_ERROR_BODY_CHARS: usize = 500;
+
+/// 非 2xx 响应对应的错误，调用方不应再按正常响应解析响应体
+///
+/// 认证失败与 429 分别转换为 `LlmError::Authentication` 与 `LlmError::RateLimited`，
+/// 响应体中可识别的错误（如上下文超长）按 `check_openai_error` 转换，其余返回带状态码与响应体（截断）的错误。
+pub(crate) fn http_status_error(
+    status: reqwest::StatusCode,
+    retry_after: Option<std::time::Duration>,
+    response_text: &str,
+) -> Error {
+    if is_authentication_failure(status) {
+        return authentication_error(status, response_text);
+    }
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return LlmError::RateLimited {
+            message: error_message(response_text),
+            retry_after,
+        }
+        .into();
+    }
+    if let Some(err) = serde_json::from_str(response_text)
+        .ok()
+        .and_then(|json| check_openai_error(&json).err())
+    {
+        return err;
+    }
+    let mut body: String = response_text.chars().take(MAX_ERROR_BODY_CHARS).collect();
+    if body.len() < response_text.len() {
+        body.push_str("...");
+    }
+    anyhow!("request failed with HTTP {}: {body}", status.as_u16())
+}
+
+/// 解析 `Retry-After` 头（秒数形式）
+pub(crate) fn parse_retry_after(
+    headers: &reqwest::header::HeaderMap,
+) -> Option<std::time::Duration> {
+    let seconds = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(std::time::Duration::from_secs(seconds))
+}
+
+/// 错误响应体中 `error.message` 的内容，不是 JSON 时返回原文
+fn error_message(response_text: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(response_text)
+        .ok()
+        .and_then(|json| json["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| response_text.to_string())
+}
+
+/// 检查响应中的 error 对象，将可识别的错误码转换为 `LlmError`
+fn check_openai_error(response_json: &serde_json::Value) -> Result<()> {
+    let error = &response_json["error"];
+    if error["code"].as_str() == Some("context_length_exceeded") {
+        let message = error["message"].as_str().unwrap_or_default().to_string();
+        return Err(LlmError::ContextLengthExceeded(message).into());
+    }
+    if let Some(message) = error["message"].as_str() {
+        if mentions_tools_unsupported(message) {
+            return Err(LlmError::ToolsUnsupported(message.to_string()).into());
+        }
+    }
+    Ok(())
+}
+
+/// 各服务商对“不支持工具调用”没有统一的错误码，只能根据错误说明判断
+pub(crate) fn mentions_tools_unsupported(message: &str) -> bool {
+    let message = message.to_lowercase();
+    ["tools", "tool use", "tool calling", "function calling"]
+        .iter()
+        .any(|feature| {
+            message.contains(&format!("does not support {feature}"))
+                || message.contains(&format!("{feature} is not supported"))
+                || message.contains(&format!("{feature} are not supported"))
+        })
+}
+
+/// 按策略处理与已有工具调用重复的 id
+fn unique_tool_call_id(
+    tool_calls: &ToolCalls,
+    id: &str,
+    policy: DuplicateToolCallIds,
+) -> Result<String> {
+    if !tool_calls.contains_key(id) {
+        return Ok(id.to_string());
+    }
+    match policy {
+        DuplicateToolCallIds::Error => bail!("duplicate tool_call_id in response: {id}"),
+        DuplicateToolCallIds::Disambiguate => Ok((2..)
+            .map(|n| format!("{id}_{n}"))
+            .find(|candidate| !tool_calls.contains_key(candidate))
+            .unwrap()),
+    }
+}
+
 /// 解析OpenAI返回的JSON，根据是否有function_call来决定返回ExecuteTool或Respond
-fn parse_openai_response_into_decision(response_json: serde_json::Value) -> Result<Decision> {
+fn parse_openai_response_into_decision(
+    response_json: serde_json::Value,
+    duplicate_ids: DuplicateToolCallIds,
+    call_ids: &ToolCallIdGenerator,
+) -> Result<Decision> {
     let empty = vec![];
     let choices = response_json["choices"].as_array().unwrap_or(&empty);
     if choices.is_empty() {
@@ -256,11 +626,15 @@ fn parse_openai_response_into_decision(response_json: serde_json::Value) -> Resu
         return Ok(Decision::Respond("".to_string()));
     }
     let message = &choices[0]["message"];
+    // 模型拒绝回答时 content 为空，拒绝说明位于 refusal 字段
+    if let Some(refusal) = message["refusal"].as_str().filter(|r| !r.is_empty()) {
+        return Err(LlmError::Refusal(refusal.to_string()).into());
+    }
     let content = message["content"].as_str().unwrap_or("").to_string();
 
     // 检查是否有工具调用
     if let Some(tool_calls) = message["tool_calls"].as_array() {
-        let mut tool_calls_map = HashMap::new();
+        let mut tool_calls_map = ToolCalls::new();
 
         for tool_call in tool_calls {
             if let (Some(id), Some(function)) =
@@ -269,13 +643,11 @@ fn parse_openai_response_into_decision(response_json: serde_json::Value) -> Resu
                 if let (Some(name), Some(args_str)) =
                     (function["name"].as_str(), function["arguments"].as_str())
                 {
-                    let parsed_args = match serde_json::from_str(args_str) {
-                        Ok(v) => v,
-                        Err(_) => serde_json::json!({}),
-                    };
+                    let parsed_args = parse_tool_arguments(name, args_str)?;
 
+                    let id = unique_tool_call_id(&tool_calls_map, id, duplicate_ids)?;
                     tool_calls_map.insert(
-                        id.to_string(),
+                        id,
                         ToolCallArgs {
                             tool_type: "function".to_string(),
                             tool_name: name.to_string(),
@@ -294,62 +666,935 @@ fn parse_openai_response_into_decision(response_json: serde_json::Value) -> Resu
         }
     }
 
+    // 旧版 function calling：单个 function_call 字段，没有 id，需要自行生成
+    if let Some(name) = message["function_call"]["name"].as_str() {
+        let parsed_args = parse_tool_arguments(
+            name,
+            message["function_call"]["arguments"].as_str().unwrap_or(""),
+        )?;
+        let mut tool_calls_map = ToolCalls::new();
+        tool_calls_map.insert(
+            call_ids.next_id(),
+            ToolCallArgs {
+                tool_type: "function".to_string(),
+                tool_name: name.to_string(),
+                args: parsed_args,
+            },
+        );
+        return Ok(Decision::ExecuteTool(content, tool_calls_map));
+    }
+
     // 如果没有工具调用或工具调用解析失败，返回内容
     Ok(Decision::Respond(content))
 }
 
-/// 将流式返回的 JSON chunk 解析为 Decision。
-/// 该函数根据 chunk 中 "choices" 内的 "delta" 字段提取 assistant 的内容或工具调用信息。
-fn parse_openai_stream_chunk_into_decision(chunk: serde_json::Value) -> Result<Decision> {
-    // 流式返回的 chunk 结构类似：
-    // {
-    //   "choices": [
-    //     {
-    //       "delta": { "content": "部分内容", "tool_calls": [...] },
-    //       "index": 0,
-    //       "finish_reason": null
-    //     }
-    //   ]
-    // }
-    let choices = match chunk["choices"].as_array() {
-        Some(c) => c,
-        None => return Ok(Decision::Respond(String::new())),
-    };
-    let delta = &choices[0]["delta"];
-    let content = delta["content"].as_str().unwrap_or("").to_string();
-
-    // 如果有 tool_calls，则构造 ExecuteTool 决策
-    if let Some(tool_calls) = delta.get("tool_calls").and_then(|v| v.as_array()) {
-        let mut tool_calls_map = std::collections::HashMap::new();
-        for tool_call in tool_calls {
-            if let (Some(id), Some(function)) = (
-                tool_call.get("id").and_then(|v| v.as_str()),
-                tool_call.get("function").and_then(|v| v.as_object()),
-            ) {
-                if let (Some(name), Some(args_str)) = (
-                    function.get("name").and_then(|v| v.as_str()),
-                    function.get("arguments").and_then(|v| v.as_str()),
-                ) {
-                    let parsed_args =
-                        serde_json::from_str(args_str).unwrap_or(serde_json::json!({}));
-                    tool_calls_map.insert(
-                        id.to_string(),
-                        ToolCallArgs {
-                            tool_type: "function".to_string(),
-                            tool_name: name.to_string(),
-                            args: parsed_args,
-                        },
-                    );
+/// 解析响应中的 `usage` 对象，不存在（或为 `null`）时返回 `None`
+fn parse_usage(usage: &serde_json::Value) -> Option<Usage> {
+    let usage = usage.as_object()?;
+    let field = |name: &str| usage.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+    Some(Usage {
+        prompt_tokens: field("prompt_tokens"),
+        completion_tokens: field("completion_tokens"),
+        total_tokens: field("total_tokens"),
+    })
+}
+
+/// 流式响应的解析状态
+///
+/// 文本增量直接产出；工具调用以片段形式到达（`id` 与 `name` 只出现在某个调用的第一个增量中，
+/// 之后的增量只带 `arguments` 片段与 `index`），因此按 `index` 累积，
+/// 在 `finish_reason` 为 `tool_calls` 时（或流结束时仍有未产出的片段）合并为一个 `Decision::ExecuteTool`。
+struct OpenaiStreamParser {
+    accumulator: DefaultDecisionAccumulator,
+    has_tool_calls: bool,
+    call_ids: ToolCallIdGenerator,
+    /// 开启 `include_usage` 时，最后一个 chunk（`choices` 为空）带有整个请求的用量
+    usage: Option<Usage>,
+}
+
+impl OpenaiStreamParser {
+    fn new(call_ids: ToolCallIdGenerator) -> Self {
+        Self {
+            accumulator: DefaultDecisionAccumulator::new(),
+            has_tool_calls: false,
+            call_ids,
+            usage: None,
+        }
+    }
+
+    /// 处理一个 chunk，没有可产出内容（如只有 role、工具调用片段尚未结束）时返回 `None`
+    fn parse_chunk(&mut self, chunk: &serde_json::Value) -> Result<Option<Decision>> {
+        // 流式返回的 chunk 结构类似：
+        // {
+        //   "choices": [
+        //     {
+        //       "delta": { "content": "部分内容", "tool_calls": [...] },
+        //       "index": 0,
+        //       "finish_reason": null
+        //     }
+        //   ]
+        // }
+        if let Some(usage) = parse_usage(&chunk["usage"]) {
+            self.usage = Some(usage);
+        }
+        let Some(choices) = chunk["choices"].as_array() else {
+            return Ok(None);
+        };
+        // 多个候选时各自的增量以 index 区分，只支持 index 为 0 的单一候选
+        if let Some(index) = choices
+            .iter()
+            .filter_map(|choice| choice["index"].as_u64())
+            .find(|index| *index > 0)
+        {
+            bail!(
+                "received a stream delta for choice index {index}; streaming supports only n = 1"
+            );
+        }
+        let Some(choice) = choices.first() else {
+            return Ok(None);
+        };
+        let delta = &choice["delta"];
+        let content = delta["content"].as_str().unwrap_or_default().to_string();
+
+        let tool_calls = delta["tool_calls"].as_array().into_iter().flatten();
+        for (position, tool_call) in tool_calls.enumerate() {
+            let function = &tool_call["function"];
+            let name = function["name"].as_str().map(str::to_string);
+            // 部分兼容服务不返回 id，在调用的第一个片段（带 name）中补上
+            let id = tool_call["id"]
+                .as_str()
+                .map(str::to_string)
+                .or_else(|| name.as_ref().map(|_| self.call_ids.next_id()));
+            self.accumulator
+                .push(StreamFragment::ToolCall(ToolCallFragment {
+                    index: tool_call["index"]
+                        .as_u64()
+                        .map_or(position, |index| index as usize),
+                    id,
+                    name,
+                    arguments: function["arguments"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                }));
+            self.has_tool_calls = true;
+        }
+
+        if choice["finish_reason"] == "tool_calls" {
+            self.accumulator.push(StreamFragment::Text(content));
+            return self.finish();
+        }
+        Ok((!content.is_empty()).then_some(Decision::Respond(content)))
+    }
+
+    /// 产出尚未产出的工具调用；在流结束时调用
+    fn finish(&mut self) -> Result<Option<Decision>> {
+        if !std::mem::take(&mut self.has_tool_calls) {
+            return Ok(None);
+        }
+        self.accumulator.finish().map(Some)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// 启动一个只响应一次的本地 HTTP 服务，返回其地址以及收到的原始请求
+    pub(crate) async fn serve_once(status: u16, body: &str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let body = body.to_string();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(header_end) = text.find("\r\n\r\n") {
+                    let content_length = text[..header_end]
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap_or(0);
+                    if request.len() >= header_end + 4 + content_length {
+                        break;
+                    }
                 }
             }
+            let response = format!(
+                "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            socket.shutdown().await.ok();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        (format!("http://{addr}/v1/chat/completions"), handle)
+    }
+
+    #[test]
+    fn test_null_content_tool_call_round_trip() {
+        let response = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "echo", "arguments": "{\"text\":\"hi\"}"}
+                    }]
+                }
+            }]
+        });
+        let Decision::ExecuteTool(content, tool_calls) = parse_openai_response_into_decision(
+            response,
+            DuplicateToolCallIds::default(),
+            &ToolCallIdGenerator::seeded(0),
+        )
+        .unwrap() else {
+            panic!("Expected ExecuteTool variant");
+        };
+
+        // 存入记忆（经过序列化）后再发送
+        let stored = serde_json::to_string(&Message::Assistant {
+            content,
+            tool_calls: Some(tool_calls),
+        })
+        .unwrap();
+        let message: Message = serde_json::from_str(&stored).unwrap();
+        let converted = convert_messages(&[message]);
+
+        assert!(converted[0]["content"].is_null());
+        assert_eq!(converted[0]["tool_calls"][0]["id"], "call_1");
+
+        // 普通的空 assistant 消息仍然发送空字符串
+        let converted = convert_messages(&[Message::Assistant {
+            content: String::new(),
+            tool_calls: None,
+        }]);
+        assert_eq!(converted[0]["content"], "");
+    }
+
+    #[test]
+    fn test_refusal_is_surfaced_distinctly() {
+        let response = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "refusal": "I'm sorry, I can't help with that."
+                }
+            }]
+        });
+        let err = parse_openai_response_into_decision(
+            response,
+            DuplicateToolCallIds::default(),
+            &ToolCallIdGenerator::seeded(0),
+        )
+        .unwrap_err();
+        assert_eq!(
+            LlmError::refusal(&err),
+            Some("I'm sorry, I can't help with that.")
+        );
+
+        // refusal 为 null 时按普通内容处理
+        let response = json!({
+            "choices": [{"message": {"content": "Sure!", "refusal": null}}]
+        });
+        let decision = parse_openai_response_into_decision(
+            response,
+            DuplicateToolCallIds::default(),
+            &ToolCallIdGenerator::seeded(0),
+        )
+        .unwrap();
+        assert!(matches!(decision, Decision::Respond(ref s) if s == "Sure!"));
+    }
+
+    fn duplicate_id_response() -> serde_json::Value {
+        let call = |text: &str| {
+            json!({
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "echo", "arguments": json!({"text": text}).to_string()}
+            })
+        };
+        json!({
+            "choices": [{
+                "message": {"content": "", "tool_calls": [call("a"), call("b")]}
+            }]
+        })
+    }
+
+    #[test]
+    fn test_fenced_tool_arguments_are_parsed() {
+        let response = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {
+                            "name": "calculator",
+                            "arguments": "```json\n{\"op\": \"add\", \"num1\": 2, \"num2\": 3}\n```"
+                        }
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }]
+        });
+
+        let decision = parse_openai_response_into_decision(
+            response,
+            DuplicateToolCallIds::default(),
+            &ToolCallIdGenerator::seeded(0),
+        )
+        .unwrap();
+        let Decision::ExecuteTool(_, tool_calls) = decision else {
+            panic!("expected a tool call, got {decision:?}");
+        };
+        assert_eq!(
+            tool_calls["call_1"].args,
+            json!({"op": "add", "num1": 2, "num2": 3})
+        );
+    }
+
+    #[test]
+    fn test_legacy_function_call_is_parsed() {
+        let response = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "function_call": {
+                        "name": "echo",
+                        "arguments": "{\"text\": \"hi\"}"
+                    }
+                },
+                "finish_reason": "function_call"
+            }]
+        });
+
+        let decision = parse_openai_response_into_decision(
+            response,
+            DuplicateToolCallIds::default(),
+            &ToolCallIdGenerator::seeded(0),
+        )
+        .unwrap();
+        let Decision::ExecuteTool(content, tool_calls) = decision else {
+            panic!("expected a tool call, got {decision:?}");
+        };
+        assert_eq!(content, "");
+        assert_eq!(tool_calls.len(), 1);
+        let (id, call) = tool_calls.into_iter().next().unwrap();
+        assert_eq!(id, ToolCallIdGenerator::seeded(0).next_id());
+        assert_eq!(call.tool_name, "echo");
+        assert_eq!(call.args, json!({"text": "hi"}));
+    }
+
+    #[test]
+    fn test_duplicate_tool_call_ids_are_disambiguated() {
+        let decision = parse_openai_response_into_decision(
+            duplicate_id_response(),
+            DuplicateToolCallIds::Disambiguate,
+            &ToolCallIdGenerator::seeded(0),
+        )
+        .unwrap();
+        let Decision::ExecuteTool(_, tool_calls) = decision else {
+            panic!("Expected ExecuteTool variant");
+        };
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls["call_1"].args, json!({"text": "a"}));
+        assert_eq!(tool_calls["call_1_2"].args, json!({"text": "b"}));
+    }
+
+    #[test]
+    fn test_duplicate_tool_call_ids_error() {
+        let err = parse_openai_response_into_decision(
+            duplicate_id_response(),
+            DuplicateToolCallIds::Error,
+            &ToolCallIdGenerator::seeded(0),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("duplicate tool_call_id"));
+        assert!(err.to_string().contains("call_1"));
+    }
+
+    #[test]
+    fn test_prediction_is_serialized_into_request_body() {
+        let client = OpenaiLlmClient::new("key", "gpt-4o", "http://localhost");
+        let messages = [Message::User {
+            content: "Rename the variable".into(),
+        }];
+
+        let body =
+            client.build_request_body(&messages, &[], None, &RequestOptions::default(), false);
+        assert!(body.get("prediction").is_none());
+
+        let options = RequestOptions {
+            prediction: Some("fn main() {}".into()),
+            ..Default::default()
+        };
+        let body = client.build_request_body(&messages, &[], None, &options, true);
+        assert_eq!(
+            body["prediction"],
+            json!({"type": "content", "content": "fn main() {}"})
+        );
+    }
+
+    #[test]
+    fn test_message_compaction_is_applied_when_enabled() {
+        let messages = [
+            Message::User {
+                content: "first".into(),
+            },
+            Message::User {
+                content: "second".into(),
+            },
+        ];
+        let options = RequestOptions::default();
+
+        let client = OpenaiLlmClient::new("key", "gpt-4o", "http://localhost");
+        let body = client.build_request_body(&messages, &[], None, &options, false);
+        assert_eq!(body["messages"].as_array().unwrap().len(), 2);
+
+        let client = client.with_message_compaction(true);
+        let body = client.build_request_body(&messages, &[], None, &options, false);
+        assert_eq!(
+            body["messages"],
+            json!([{"role": "user", "content": "first\n\nsecond"}])
+        );
+    }
+
+    #[test]
+    fn test_extra_params_are_merged_into_request_body() {
+        let client = OpenaiLlmClient::new("key", "gpt-4o", "http://localhost");
+        let mut options = RequestOptions::default();
+        options.extra_params.insert("top_p".into(), json!(0.9));
+        options
+            .extra_params
+            .insert("temperature".into(), json!(0.1));
+
+        let body = client.build_request_body(&[], &[], None, &options, false);
+        assert_eq!(body["top_p"], json!(0.9));
+        // 冲突时以 extra_params 为准
+        assert_eq!(body["temperature"], json!(0.1));
+        assert_eq!(body["model"], "gpt-4o");
+    }
+
+    #[test]
+    fn test_sampling_params_are_serialized_when_set() {
+        let client = OpenaiLlmClient::new("key", "gpt-4o", "http://localhost");
+
+        let body = client.build_request_body(&[], &[], None, &RequestOptions::default(), false);
+        for key in ["top_p", "frequency_penalty", "presence_penalty"] {
+            assert!(body.get(key).is_none());
         }
-        if !tool_calls_map.is_empty() {
-            // 如果同时有 content 和 tool_calls，可以选择先输出部分内容
-            if !content.is_empty() {
-                eprintln!("Partial content: {}", content);
+
+        let options = RequestOptions {
+            top_p: Some(0.9),
+            frequency_penalty: Some(0.5),
+            presence_penalty: Some(-1.2),
+            ..Default::default()
+        };
+        let body = client.build_request_body(&[], &[], None, &options, false);
+        assert_eq!(body["top_p"], json!(0.9));
+        assert_eq!(body["frequency_penalty"], json!(0.5));
+        assert_eq!(body["presence_penalty"], json!(-1.2));
+    }
+
+    #[test]
+    fn test_logit_bias_is_serialized_as_object() {
+        let client = OpenaiLlmClient::new("key", "gpt-4o", "http://localhost");
+
+        let body = client.build_request_body(&[], &[], None, &RequestOptions::default(), false);
+        assert!(body.get("logit_bias").is_none());
+
+        let options = RequestOptions {
+            logit_bias: Some(HashMap::from([
+                ("50256".to_string(), -100.0),
+                ("1734".to_string(), 2.5),
+            ])),
+            ..Default::default()
+        };
+        let body = client.build_request_body(&[], &[], None, &options, false);
+        assert_eq!(body["logit_bias"], json!({"50256": -100.0, "1734": 2.5}));
+    }
+
+    #[tokio::test]
+    async fn test_last_response_is_captured_in_debug_mode() {
+        let body = json!({
+            "id": "chatcmpl-1",
+            "choices": [{"message": {"role": "assistant", "content": "hello"}}]
+        });
+        let (url, _request) = serve_once(200, &body.to_string()).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url).with_debug(true);
+        assert!(client.last_response().is_none());
+
+        let decision = client
+            .complete(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap();
+        assert!(matches!(decision, Decision::Respond(ref s) if s == "hello"));
+        assert_eq!(client.last_response(), Some(body));
+    }
+
+    #[tokio::test]
+    async fn test_usage_is_parsed_from_response() {
+        let body = json!({
+            "id": "chatcmpl-1",
+            "choices": [{"message": {"role": "assistant", "content": "hello"}}],
+            "usage": {"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15}
+        });
+        let (url, _request) = serve_once(200, &body.to_string()).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url);
+
+        let (decision, usage) = client
+            .complete_with_usage(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap();
+        assert!(matches!(decision, Decision::Respond(ref s) if s == "hello"));
+        let expected = Usage {
+            prompt_tokens: 12,
+            completion_tokens: 3,
+            total_tokens: 15,
+        };
+        assert_eq!(usage, Some(expected));
+        assert_eq!(parse_usage(&json!(null)), None);
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_header_is_sent() {
+        let body = json!({"choices": [{"message": {"role": "assistant", "content": "hi"}}]});
+        for (client_ua, expected) in [
+            (None, DEFAULT_USER_AGENT.to_string()),
+            (Some("my-app/2.1"), "my-app/2.1".to_string()),
+        ] {
+            let (url, request) = serve_once(200, &body.to_string()).await;
+            let mut client = OpenaiLlmClient::new("key", "gpt-4o", url);
+            if let Some(user_agent) = client_ua {
+                client = client.with_user_agent(user_agent);
             }
-            return Ok(Decision::ExecuteTool(content, tool_calls_map));
+            client
+                .complete(&[], vec![], None, &RequestOptions::default())
+                .await
+                .unwrap();
+
+            let request = request.await.unwrap();
+            let header = request
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("user-agent")
+                        .then(|| value.trim().to_string())
+                })
+                .unwrap();
+            assert_eq!(header, expected);
         }
+        assert!(DEFAULT_USER_AGENT.starts_with("chimerai/"));
+    }
+
+    /// 收集全部日志事件的 message 字段
+    #[derive(Default)]
+    struct LogRecorder {
+        logs: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct MessageVisitor<'a>(&'a mut Vec<String>);
+
+    impl tracing::field::Visit for MessageVisitor<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0.push(format!("{value:?}"));
+            }
+        }
+    }
+
+    impl tracing::Subscriber for LogRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            event.record(&mut MessageVisitor(&mut self.logs.lock().unwrap()));
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn test_secrets_are_redacted_in_debug_logs() {
+        let recorder = LogRecorder::default();
+        let logs = recorder.logs.clone();
+        let _guard = tracing::subscriber::set_default(recorder);
+
+        let secret = "sk-proj-4f9a8b7c6d5e4f3a2b1c";
+        let body = json!({"choices": [{"message": {"role": "assistant", "content": "noted"}}]});
+        let (url, request) = serve_once(200, &body.to_string()).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url);
+        let messages = [Message::User {
+            content: format!("my key is {secret}"),
+        }];
+        client
+            .complete(&messages, vec![], None, &RequestOptions::default())
+            .await
+            .unwrap();
+
+        // 请求本身不受影响
+        assert!(request.await.unwrap().contains(secret));
+        let logs = logs.lock().unwrap();
+        let request_log = logs
+            .iter()
+            .find(|log| log.starts_with("request:"))
+            .expect("request body should be logged");
+        assert!(request_log.contains("my key is [REDACTED]"));
+        assert!(logs.iter().all(|log| !log.contains(secret)));
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_response_is_an_authentication_error() {
+        let body = json!({
+            "error": {
+                "message": "Incorrect API key provided: sk-bad.",
+                "type": "invalid_request_error",
+                "code": "invalid_api_key"
+            }
+        });
+        let (url, _request) = serve_once(401, &body.to_string()).await;
+        let client = OpenaiLlmClient::new("sk-bad", "gpt-4o", url);
+
+        let err = client
+            .complete(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap_err();
+        assert!(LlmError::is_authentication(&err));
+        assert_eq!(
+            err.to_string(),
+            "authentication failed (HTTP 401): Incorrect API key provided: sk-bad."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_response_is_a_distinct_error() {
+        let body = json!({
+            "error": {
+                "message": "Rate limit reached for gpt-4o.",
+                "type": "requests",
+                "code": "rate_limit_exceeded"
+            }
+        })
+        .to_string();
+        let (url, _request) = serve_once(429, &body).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url);
+        let err = client
+            .complete(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap_err();
+        assert!(LlmError::is_rate_limited(&err));
+        assert_eq!(
+            err.to_string(),
+            "rate limited (HTTP 429): Rate limit reached for gpt-4o."
+        );
+
+        let (url, _request) = serve_once(429, &body).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url);
+        let err = match client
+            .stream_complete(&[], vec![], None, &RequestOptions::default())
+            .await
+        {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(LlmError::is_rate_limited(&err));
+
+        let err = http_status_error(
+            reqwest::StatusCode::TOO_MANY_REQUESTS,
+            Some(std::time::Duration::from_secs(20)),
+            &body,
+        );
+        assert!(matches!(
+            LlmError::find(&err),
+            Some(LlmError::RateLimited {
+                retry_after: Some(delay),
+                ..
+            }) if delay.as_secs() == 20
+        ));
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, "20".parse().unwrap());
+        assert_eq!(
+            parse_retry_after(&headers),
+            Some(std::time::Duration::from_secs(20))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_non_success_status_reports_code_and_truncated_body() {
+        let body = "x".repeat(MAX_ERROR_BODY_CHARS + 100);
+        let (url, _request) = serve_once(502, &body).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url);
+        let err = client
+            .complete(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap_err();
+        assert!(LlmError::find(&err).is_none());
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "request failed with HTTP 502: {}...",
+                "x".repeat(MAX_ERROR_BODY_CHARS)
+            )
+        );
+
+        // 错误响应体中可识别的错误仍转换为对应的 LlmError
+        let body = json!({
+            "error": {"message": "too long", "code": "context_length_exceeded"}
+        });
+        let err = http_status_error(reqwest::StatusCode::BAD_REQUEST, None, &body.to_string());
+        assert!(LlmError::is_context_length_exceeded(&err));
+    }
+
+    #[tokio::test]
+    async fn test_last_response_is_not_captured_without_debug() {
+        let body = json!({"choices": [{"message": {"content": "hello"}}]});
+        let (url, _request) = serve_once(200, &body.to_string()).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url);
+
+        client
+            .complete(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap();
+        assert!(client.last_response().is_none());
+    }
+
+    #[test]
+    fn test_multi_choice_stream_chunk_is_rejected() {
+        let chunk = json!({
+            "choices": [
+                {"index": 0, "delta": {"content": "Hello"}, "finish_reason": null},
+                {"index": 1, "delta": {"content": "Hi"}, "finish_reason": null}
+            ]
+        });
+        let mut parser = OpenaiStreamParser::new(ToolCallIdGenerator::seeded(0));
+        let err = parser.parse_chunk(&chunk).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "received a stream delta for choice index 1; streaming supports only n = 1"
+        );
+
+        let single = json!({"choices": [{"index": 0, "delta": {"content": "Hello"}}]});
+        assert!(matches!(
+            parser.parse_chunk(&single).unwrap(),
+            Some(Decision::Respond(content)) if content == "Hello"
+        ));
+    }
+
+    fn tool_call_delta(tool_calls: serde_json::Value) -> serde_json::Value {
+        json!({"choices": [{"index": 0, "delta": {"tool_calls": tool_calls}, "finish_reason": null}]})
+    }
+
+    #[test]
+    fn test_stream_tool_call_fragments_are_accumulated() {
+        let mut parser = OpenaiStreamParser::new(ToolCallIdGenerator::seeded(0));
+        let chunks = [
+            tool_call_delta(json!([{
+                "index": 0, "id": "call_1", "type": "function",
+                "function": {"name": "echo", "arguments": ""}
+            }])),
+            tool_call_delta(json!([{"index": 0, "function": {"arguments": "{\"te"}}])),
+            tool_call_delta(json!([{"index": 0, "function": {"arguments": "xt\": \"hi\"}"}}])),
+        ];
+        for chunk in &chunks {
+            assert!(parser.parse_chunk(chunk).unwrap().is_none());
+        }
+
+        let finish = json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "tool_calls"}]});
+        let Some(Decision::ExecuteTool(content, tool_calls)) = parser.parse_chunk(&finish).unwrap()
+        else {
+            panic!("expected tool calls");
+        };
+        assert_eq!(content, "");
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls["call_1"].tool_name, "echo");
+        assert_eq!(tool_calls["call_1"].args, json!({"text": "hi"}));
+        // 已产出的工具调用不会在流结束时重复产出
+        assert!(parser.finish().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_complete_emits_tool_calls_once_complete() {
+        let events = [
+            json!({"choices": [{"index": 0, "delta": {"role": "assistant", "content": "Checking."}}]}),
+            tool_call_delta(json!([
+                {"index": 0, "id": "call_z", "function": {"name": "echo", "arguments": "{\"text\":"}},
+                {"index": 1, "id": "call_b", "function": {"name": "echo", "arguments": ""}}
+            ])),
+            tool_call_delta(json!([{"index": 1, "function": {"arguments": "{\"text\": \"b\"}"}}])),
+            tool_call_delta(json!([{"index": 0, "function": {"arguments": " \"a\"}"}}])),
+            // 部分兼容服务不发送 finish_reason，流结束时同样产出
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| format!("data: {event}\n\n"))
+            .chain(["data: [DONE]\n\n".to_string()])
+            .collect();
+        let (url, _request) = serve_once(200, &body).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url);
+
+        let decisions: Vec<Decision> = client
+            .stream_complete(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(decisions.len(), 2);
+        assert!(matches!(&decisions[0], Decision::Respond(text) if text == "Checking."));
+        let Decision::ExecuteTool(_, tool_calls) = &decisions[1] else {
+            panic!("expected tool calls");
+        };
+        assert_eq!(tool_calls["call_z"].args, json!({"text": "a"}));
+        assert_eq!(tool_calls["call_b"].args, json!({"text": "b"}));
+        // 调用保持流中的 index 顺序，而不是按 id 排序
+        let ids: Vec<&String> = tool_calls.keys().collect();
+        assert_eq!(ids, ["call_z", "call_b"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_usage_is_parsed_from_final_chunk() {
+        let client = OpenaiLlmClient::new("key", "gpt-4o", "unused").with_stream_usage(true);
+        let options = RequestOptions::default();
+        let body = client.build_request_body(&[], &[], None, &options, true);
+        assert_eq!(body["stream_options"], json!({"include_usage": true}));
+        // 非流式请求不接受 stream_options
+        let body = client.build_request_body(&[], &[], None, &options, false);
+        assert!(body.get("stream_options").is_none());
+
+        let events = [
+            json!({"choices": [{"index": 0, "delta": {"role": "assistant", "content": "Hi"}}], "usage": null}),
+            json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}], "usage": null}),
+            json!({"choices": [], "usage": {"prompt_tokens": 9, "completion_tokens": 1, "total_tokens": 10}}),
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| format!("data: {event}\n\n"))
+            .chain(["data: [DONE]\n\n".to_string()])
+            .collect();
+        let (url, _request) = serve_once(200, &body).await;
+        let client = OpenaiLlmClient::new("key", "gpt-4o", url).with_stream_usage(true);
+
+        let (stream, usage) = client
+            .stream_complete_with_usage(&[], vec![], None, &options)
+            .await
+            .unwrap();
+        let decisions: Vec<Decision> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(decisions.len(), 1);
+        assert!(matches!(&decisions[0], Decision::Respond(text) if text == "Hi"));
+        assert_eq!(
+            usage.get(),
+            Some(Usage {
+                prompt_tokens: 9,
+                completion_tokens: 1,
+                total_tokens: 10,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_streams_keep_their_own_usage() {
+        let serve = |total_tokens: u64| {
+            let events = [
+                json!({"choices": [{"index": 0, "delta": {"content": "Hi"}}]}),
+                json!({"choices": [], "usage": {"prompt_tokens": total_tokens - 1, "completion_tokens": 1, "total_tokens": total_tokens}}),
+            ];
+            let body: String = events
+                .iter()
+                .map(|event| format!("data: {event}\n\n"))
+                .collect();
+            async move { serve_once(200, &body).await.0 }
+        };
+        let options = RequestOptions::default();
+        let mut client =
+            OpenaiLlmClient::new("key", "gpt-4o", serve(10).await).with_stream_usage(true);
+        let (first, first_usage) = client
+            .stream_complete_with_usage(&[], vec![], None, &options)
+            .await
+            .unwrap();
+        // 第一个流尚未读取时，同一个客户端发起第二个请求
+        client.api_url = serve(20).await;
+        let (second, second_usage) = client
+            .stream_complete_with_usage(&[], vec![], None, &options)
+            .await
+            .unwrap();
+
+        second.for_each(|_| async {}).await;
+        first.for_each(|_| async {}).await;
+        assert_eq!(first_usage.get().map(|usage| usage.total_tokens), Some(10));
+        assert_eq!(second_usage.get().map(|usage| usage.total_tokens), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_streaming_with_n_greater_than_one_is_rejected_before_sending() {
+        // 请求在发送前就被拒绝，因此地址不需要可达
+        let client =
+            OpenaiLlmClient::new("key", "gpt-4o", "http://127.0.0.1:9/v1/chat/completions");
+        let mut options = RequestOptions::default();
+        options.extra_params.insert("n".into(), json!(2));
+
+        let err = client
+            .stream_complete(&[], vec![], None, &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "streaming supports a single choice, but the request sets n = 2; use complete() for multiple choices"
+        );
+    }
+
+    #[test]
+    fn test_context_length_error_is_detected() {
+        let response = json!({
+            "error": {
+                "message": "This model's maximum context length is 8192 tokens.",
+                "type": "invalid_request_error",
+                "code": "context_length_exceeded"
+            }
+        });
+        let err = check_openai_error(&response).unwrap_err();
+        assert!(LlmError::is_context_length_exceeded(&err));
+
+        let ok = json!({"choices": [{"message": {"content": "hi"}}]});
+        assert!(check_openai_error(&ok).is_ok());
+
+        let unsupported = json!({
+            "error": {"message": "registry.ollama.ai/library/gemma:2b does not support tools"}
+        });
+        let err = check_openai_error(&unsupported).unwrap_err();
+        assert!(LlmError::is_tools_unsupported(&err));
     }
-    Ok(Decision::Respond(content))
 }
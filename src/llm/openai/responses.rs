@@ -0,0 +1,493 @@
+//! OpenAI Responses API（`/v1/responses`）客户端
+//!
+//! 与 chat completions 不同，Responses API 的输入是一组 item（消息、`function_call`、
+//! `function_call_output`），流式输出则是带类型的事件（如 `response.output_text.delta`）。
+
+use anyhow::{anyhow, bail, Result};
+use async_stream::stream;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::debug;
+
+use super::{
+    check_openai_error, f32_to_json, http_status_error, parse_retry_after, DEFAULT_USER_AGENT,
+};
+use crate::llm::lines::byte_lines;
+use crate::llm::{
+    parse_tool_arguments, LLMClient, OpenaiResponsesToolCallFormatter, PromptRedactor,
+    RegexRedactor, RequestOptions, ToolCallFormatter,
+};
+use crate::types::{ToolCallArgs, ToolCalls};
+use crate::{Decision, Message, Tool};
+
+pub struct OpenaiResponsesLlmClient {
+    pub api_key: String,
+    pub model: String,
+    /// 例如：https://api.openai.com/v1/responses
+    pub api_url: String,
+    pub client: Client,
+    /// 请求携带的 `User-Agent`，默认为 `DEFAULT_USER_AGENT`
+    pub user_agent: String,
+    /// 调试日志中的请求与响应内容在输出前经过脱敏，默认为 `RegexRedactor::default()`
+    pub redactor: Arc<dyn PromptRedactor>,
+}
+
+impl OpenaiResponsesLlmClient {
+    pub fn new(
+        api_key: impl Into<String>,
+        model: impl Into<String>,
+        api_url: impl Into<String>,
+    ) -> Self {
+        Self {
+            api_key: api_key.into(),
+            model: model.into(),
+            api_url: api_url.into(),
+            client: Client::new(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            redactor: Arc::new(RegexRedactor::default()),
+        }
+    }
+
+    /// 设置请求携带的 `User-Agent`
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// 设置调试日志使用的脱敏器，传入 `NoRedaction` 可关闭脱敏
+    pub fn with_redactor(mut self, redactor: impl PromptRedactor + 'static) -> Self {
+        self.redactor = Arc::new(redactor);
+        self
+    }
+
+    /// 构造 Responses API 请求体
+    fn build_request_body(
+        &self,
+        messages: &[Message],
+        tools: &[&dyn Tool],
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+        stream: bool,
+    ) -> Value {
+        let mut request_body = json!({
+            "model": self.model,
+            "input": convert_messages_to_input(messages),
+            "tools": convert_tools(tools),
+            "temperature": f32_to_json(options.temperature.unwrap_or(0.7)),
+            "stream": stream,
+        });
+        if let Some(max) = max_tokens {
+            request_body["max_output_tokens"] = json!(max);
+        }
+        if let Some(top_p) = options.top_p {
+            request_body["top_p"] = f32_to_json(top_p);
+        }
+        // 额外参数最后合并，因此会覆盖同名字段
+        for (key, value) in &options.extra_params {
+            request_body[key] = value.clone();
+        }
+        request_body
+    }
+
+    async fn send(&self, request_body: &Value) -> Result<reqwest::Response> {
+        Ok(self
+            .client
+            .post(&self.api_url)
+            .header("Content-Type", "application/json")
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .bearer_auth(&self.api_key)
+            .json(request_body)
+            .send()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl LLMClient for OpenaiResponsesLlmClient {
+    fn model_name(&self) -> Option<&str> {
+        Some(&self.model)
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Decision> {
+        let request_body = self.build_request_body(messages, &tools, max_tokens, options, false);
+        debug!(
+            "request: {}",
+            self.redactor.redact(&request_body.to_string())
+        );
+
+        let response = self.send(&request_body).await?;
+        let code = response.status();
+        let retry_after = parse_retry_after(response.headers());
+        let response_text = response.text().await?;
+        debug!(
+            "response: {code:?} {}",
+            self.redactor.redact(&response_text)
+        );
+        if !code.is_success() {
+            return Err(http_status_error(code, retry_after, &response_text));
+        }
+        let response_json: Value = serde_json::from_str(&response_text)?;
+        check_openai_error(&response_json)?;
+
+        parse_response_into_decision(&response_json)
+    }
+
+    async fn stream_complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+        let request_body = self.build_request_body(messages, &tools, max_tokens, options, true);
+        debug!(
+            "stream request: {}",
+            self.redactor.redact(&request_body.to_string())
+        );
+
+        let response = self.send(&request_body).await?;
+        debug!("stream status: {}", response.status());
+        let code = response.status();
+        if !code.is_success() {
+            let retry_after = parse_retry_after(response.headers());
+            return Err(http_status_error(
+                code,
+                retry_after,
+                &response.text().await?,
+            ));
+        }
+        let decision_stream = parse_event_stream(response.bytes_stream(), self.redactor.clone());
+        Ok(Box::pin(decision_stream))
+    }
+}
+
+/// 将 SSE 字节流解析为 Decision 流，文本增量产出为 `Decision::Respond`，
+/// 函数调用在响应结束后一起产出为 `Decision::ExecuteTool`
+fn parse_event_stream<S, B, E>(
+    byte_stream: S,
+    redactor: Arc<dyn PromptRedactor>,
+) -> impl Stream<Item = Result<Decision>> + Send
+where
+    S: Stream<Item = std::result::Result<B, E>> + Send,
+    B: AsRef<[u8]> + Send,
+    E: Into<anyhow::Error> + Send,
+{
+    stream! {
+        let mut parser = ResponsesStreamParser::default();
+        let mut lines = Box::pin(byte_lines(byte_stream));
+        // 事件可能跨越多个字节块，只处理已完整接收的行
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            };
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data.is_empty() || data == "[DONE]" {
+                continue;
+            }
+            debug!("stream recieved: {}", redactor.redact(data));
+            let event = match serde_json::from_str::<Value>(data) {
+                Ok(event) => event,
+                Err(e) => {
+                    yield Err(anyhow!("JSON parse error: {}", e));
+                    continue;
+                }
+            };
+            if let Some(decision) = parser.parse_event(&event).transpose() {
+                yield decision;
+            }
+        }
+    }
+}
+
+/// 将消息转换为 Responses API 的 input item
+fn convert_messages_to_input(messages: &[Message]) -> Vec<Value> {
+    let mut input = Vec::with_capacity(messages.len());
+    for message in messages {
+        match message {
+            Message::Developer { content } => {
+                input.push(json!({"role": "developer", "content": content}))
+            }
+            Message::System { content } => {
+                input.push(json!({"role": "system", "content": content}))
+            }
+            Message::User { content } => input.push(json!({"role": "user", "content": content})),
+            Message::Assistant {
+                content,
+                tool_calls,
+            } => {
+                if !content.is_empty() {
+                    input.push(json!({"role": "assistant", "content": content}));
+                }
+                // 工具调用是独立的 item，而不是 assistant 消息的字段
+                for (call_id, call) in tool_calls.iter().flatten() {
+                    input.push(OpenaiResponsesToolCallFormatter.format_tool_call(call_id, call));
+                }
+            }
+            Message::Tool {
+                content,
+                tool_call_id,
+            } => input.push(json!({
+                "type": "function_call_output",
+                "call_id": tool_call_id,
+                "output": content,
+            })),
+        }
+    }
+    input
+}
+
+/// Responses API 的工具定义是扁平的，不再嵌套在 `function` 字段中
+fn convert_tools(tools: &[&dyn Tool]) -> Vec<Value> {
+    tools
+        .iter()
+        .map(|tool| {
+            let mut function = json!({
+                "type": "function",
+                "name": tool.name(),
+            });
+            if let Some(description) = tool.description() {
+                function["description"] = description.into();
+            }
+            if let Some(args) = tool.args_schema() {
+                function["parameters"] = args;
+            }
+            function
+        })
+        .collect()
+}
+
+/// 将 `function_call` item 转换为工具调用，缺少 id 或名称的 item 被忽略
+fn function_call_from_item(item: &Value) -> Result<Option<(String, ToolCallArgs)>> {
+    let (Some(call_id), Some(name)) = (item["call_id"].as_str(), item["name"].as_str()) else {
+        return Ok(None);
+    };
+    let args = parse_tool_arguments(name, item["arguments"].as_str().unwrap_or_default())?;
+    Ok(Some((
+        call_id.to_string(),
+        ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: name.to_string(),
+            args,
+        },
+    )))
+}
+
+/// 解析非流式响应的 `output` 数组
+fn parse_response_into_decision(response_json: &Value) -> Result<Decision> {
+    let mut content = String::new();
+    let mut tool_calls = ToolCalls::new();
+    for item in response_json["output"].as_array().into_iter().flatten() {
+        match item["type"].as_str() {
+            Some("message") => {
+                for part in item["content"].as_array().into_iter().flatten() {
+                    if part["type"] == "output_text" {
+                        content.push_str(part["text"].as_str().unwrap_or_default());
+                    }
+                }
+            }
+            Some("function_call") => tool_calls.extend(function_call_from_item(item)?),
+            _ => {}
+        }
+    }
+    if tool_calls.is_empty() {
+        Ok(Decision::Respond(content))
+    } else {
+        Ok(Decision::ExecuteTool(content, tool_calls))
+    }
+}
+
+/// Responses API 流式事件的解析器
+///
+/// 文本增量事件直接转换为 `Decision::Respond`；工具调用在各自的 item 完成时收集，
+/// 直到整个响应完成时才以一个 `Decision::ExecuteTool` 一并产出，避免只执行最后一个调用。
+#[derive(Debug, Default)]
+struct ResponsesStreamParser {
+    tool_calls: ToolCalls,
+}
+
+impl ResponsesStreamParser {
+    /// 处理一个事件，不产生输出的事件（如 `response.created`）返回 `None`
+    fn parse_event(&mut self, event: &Value) -> Result<Option<Decision>> {
+        match event["type"].as_str().unwrap_or_default() {
+            "response.output_text.delta" => {
+                let delta = event["delta"].as_str().unwrap_or_default();
+                Ok(Some(Decision::Respond(delta.to_string())))
+            }
+            "response.output_item.done" if event["item"]["type"] == "function_call" => {
+                self.tool_calls
+                    .extend(function_call_from_item(&event["item"])?);
+                Ok(None)
+            }
+            "response.completed" if !self.tool_calls.is_empty() => Ok(Some(Decision::ExecuteTool(
+                String::new(),
+                std::mem::take(&mut self.tool_calls),
+            ))),
+            "response.failed" => {
+                check_openai_error(&event["response"])?;
+                let message = event["response"]["error"]["message"]
+                    .as_str()
+                    .unwrap_or("response failed");
+                bail!("{message}")
+            }
+            "error" => bail!("{}", event["message"].as_str().unwrap_or("stream error")),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::serve_once;
+    use super::*;
+    use crate::llm::NoRedaction;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_output_text_delta_maps_to_text_chunk() {
+        let mut parser = ResponsesStreamParser::default();
+        let event = json!({
+            "type": "response.output_text.delta",
+            "item_id": "msg_1",
+            "output_index": 0,
+            "content_index": 0,
+            "delta": "Hel"
+        });
+
+        let decision = parser.parse_event(&event).unwrap();
+        assert!(matches!(decision, Some(Decision::Respond(ref s)) if s == "Hel"));
+        assert!(parser
+            .parse_event(&json!({"type": "response.created"}))
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_keeps_characters_split_across_chunks() {
+        let events = [
+            json!({"type": "response.output_text.delta", "delta": "你好，"}),
+            json!({"type": "response.output_text.delta", "delta": "世界"}),
+            json!({"type": "response.completed", "response": {}}),
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| format!("data: {event}\n\n"))
+            .collect();
+        // 逐字节切分，每个中文字符都跨越多个字节块
+        let chunks: Vec<std::result::Result<Vec<u8>, anyhow::Error>> =
+            body.bytes().map(|byte| Ok(vec![byte])).collect();
+
+        let decisions: Vec<Decision> =
+            parse_event_stream(futures::stream::iter(chunks), Arc::new(NoRedaction))
+                .map(|decision| decision.unwrap())
+                .collect()
+                .await;
+        assert_eq!(decisions.len(), 2);
+        assert!(matches!(&decisions[0], Decision::Respond(s) if s == "你好，"));
+        assert!(matches!(&decisions[1], Decision::Respond(s) if s == "世界"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_collects_function_calls_until_completed() {
+        let events = [
+            json!({"type": "response.created", "response": {}}),
+            json!({"type": "response.output_text.delta", "delta": "Checking"}),
+            json!({"type": "response.function_call_arguments.delta", "item_id": "fc_1", "delta": "{\"text\""}),
+            json!({"type": "response.output_item.done", "item": {
+                "type": "function_call", "id": "fc_1", "call_id": "call_1",
+                "name": "echo", "arguments": "{\"text\":\"a\"}"
+            }}),
+            json!({"type": "response.output_item.done", "item": {
+                "type": "function_call", "id": "fc_2", "call_id": "call_2",
+                "name": "echo", "arguments": "{\"text\":\"b\"}"
+            }}),
+            json!({"type": "response.completed", "response": {}}),
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {event}\n\n",
+                    event["type"].as_str().unwrap()
+                )
+            })
+            .collect();
+        let (url, request) = serve_once(200, &body).await;
+        let client = OpenaiResponsesLlmClient::new("key", "gpt-4o", url);
+
+        let decisions: Vec<Decision> = client
+            .stream_complete(&[], vec![], None, &RequestOptions::default())
+            .await
+            .unwrap()
+            .map(|decision| decision.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(decisions.len(), 2);
+        assert!(matches!(&decisions[0], Decision::Respond(s) if s == "Checking"));
+        let Decision::ExecuteTool(_, tool_calls) = &decisions[1] else {
+            panic!("expected tool calls, got {:?}", decisions[1]);
+        };
+        let mut ids: Vec<_> = tool_calls.keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, ["call_1", "call_2"]);
+        assert_eq!(tool_calls["call_2"].args, json!({"text": "b"}));
+        assert!(request.await.unwrap().contains("\"stream\":true"));
+    }
+
+    #[test]
+    fn test_tool_round_is_converted_to_input_items() {
+        let mut tool_calls = ToolCalls::new();
+        tool_calls.insert(
+            "call_1".into(),
+            ToolCallArgs {
+                tool_type: "function".into(),
+                tool_name: "echo".into(),
+                args: json!({"text": "hi"}),
+            },
+        );
+        let messages = [
+            Message::User {
+                content: "Echo hi".into(),
+            },
+            Message::Assistant {
+                content: String::new(),
+                tool_calls: Some(tool_calls),
+            },
+            Message::Tool {
+                content: "hi".into(),
+                tool_call_id: "call_1".into(),
+            },
+        ];
+
+        assert_eq!(
+            convert_messages_to_input(&messages),
+            vec![
+                json!({"role": "user", "content": "Echo hi"}),
+                json!({
+                    "type": "function_call",
+                    "call_id": "call_1",
+                    "name": "echo",
+                    "arguments": "{\"text\":\"hi\"}",
+                }),
+                json!({"type": "function_call_output", "call_id": "call_1", "output": "hi"}),
+            ]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use futures::Stream;
+use tokio::time::Instant;
+
+use super::{DecisionAccumulator, LLMClient, RequestOptions, Usage};
+use crate::tools::Tool;
+use crate::types::{Decision, Message};
+
+/// 以固定间隔放行请求的限速器：每秒最多 `requests_per_second` 个请求，不允许突发
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    /// 预约下一个可用时刻并等待到该时刻
+    async fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_slot.map_or(now, |next| next.max(now));
+            *next_slot = Some(slot + self.interval);
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// 对所有请求进行全局限速的 `LLMClient` 包装
+///
+/// 克隆得到的实例共享同一个内部客户端与限速器，因此可以分发给多个 Agent，
+/// 使它们（如共用一个 API key 时）的请求总速率不超过上限。
+/// 限速只作用于请求的发起，流式响应的读取不受影响。
+pub struct RateLimitedLlmClient<L> {
+    inner: Arc<L>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<L> Clone for RateLimitedLlmClient<L> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<L: LLMClient> RateLimitedLlmClient<L> {
+    pub fn new(inner: L, requests_per_second: f64) -> Result<Self> {
+        if !(requests_per_second.is_finite() && requests_per_second > 0.0) {
+            bail!("requests_per_second must be positive, got {requests_per_second}");
+        }
+        Ok(Self {
+            inner: Arc::new(inner),
+            limiter: Arc::new(RateLimiter {
+                interval: Duration::from_secs_f64(1.0 / requests_per_second),
+                next_slot: Mutex::new(None),
+            }),
+        })
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<L: LLMClient> LLMClient for RateLimitedLlmClient<L> {
+    fn model_name(&self) -> Option<&str> {
+        self.inner.model_name()
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Decision> {
+        self.limiter.acquire().await;
+        self.inner
+            .complete(messages, tools, max_tokens, options)
+            .await
+    }
+
+    async fn complete_with_usage(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<(Decision, Option<Usage>)> {
+        self.limiter.acquire().await;
+        self.inner
+            .complete_with_usage(messages, tools, max_tokens, options)
+            .await
+    }
+
+    async fn stream_complete(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Decision>> + Send>>> {
+        self.limiter.acquire().await;
+        self.inner
+            .stream_complete(messages, tools, max_tokens, options)
+            .await
+    }
+
+    async fn stream_complete_into(
+        &self,
+        messages: &[Message],
+        tools: Vec<&dyn Tool>,
+        max_tokens: Option<usize>,
+        options: &RequestOptions,
+        accumulator: &mut dyn DecisionAccumulator,
+    ) -> Result<Decision> {
+        self.limiter.acquire().await;
+        self.inner
+            .stream_complete_into(messages, tools, max_tokens, options, accumulator)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::MockLLMClient;
+
+    #[test]
+    fn test_rejects_non_positive_rate() {
+        assert!(RateLimitedLlmClient::new(MockLLMClient::new(), 0.0).is_err());
+        assert!(RateLimitedLlmClient::new(MockLLMClient::new(), f64::NAN).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_are_spaced_by_interval() {
+        let client = RateLimitedLlmClient::new(MockLLMClient::new(), 4.0).unwrap();
+        let start = Instant::now();
+        let messages = [Message::User {
+            content: "hi".to_string(),
+        }];
+        let options = RequestOptions::default();
+        for expected_ms in [0, 250, 500] {
+            client
+                .complete(&messages, vec![], None, &options)
+                .await
+                .unwrap();
+            assert_eq!(start.elapsed(), Duration::from_millis(expected_ms));
+        }
+    }
+}
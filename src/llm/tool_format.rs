@@ -0,0 +1,154 @@
+use serde_json::{json, Value};
+
+use crate::types::ToolCallArgs;
+
+/// 工具调用参数在请求中的编码方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolArgsEncoding {
+    /// 序列化为 JSON 字符串，如 OpenAI 的 `arguments`
+    JsonString,
+    /// 原样作为 JSON 对象，如 Anthropic 的 `input`、Ollama 的 `arguments`
+    JsonObject,
+}
+
+impl ToolArgsEncoding {
+    pub fn encode(self, args: &Value) -> Value {
+        match self {
+            ToolArgsEncoding::JsonString => Value::String(args.to_string()),
+            ToolArgsEncoding::JsonObject => args.clone(),
+        }
+    }
+}
+
+/// 将共享的 `ToolCallArgs` 编码为某个服务商 assistant 消息中的一条工具调用
+///
+/// 各服务商的消息转换都通过对应的实现编码历史中的工具调用，保证同一份调用在不同服务商下格式正确。
+pub trait ToolCallFormatter: Send + Sync {
+    /// 参数的编码方式
+    fn args_encoding(&self) -> ToolArgsEncoding;
+
+    fn format_tool_call(&self, tool_call_id: &str, call: &ToolCallArgs) -> Value;
+}
+
+/// OpenAI Chat Completions：`tool_calls[].function.arguments` 为 JSON 字符串
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenaiToolCallFormatter;
+
+impl ToolCallFormatter for OpenaiToolCallFormatter {
+    fn args_encoding(&self) -> ToolArgsEncoding {
+        ToolArgsEncoding::JsonString
+    }
+
+    fn format_tool_call(&self, tool_call_id: &str, call: &ToolCallArgs) -> Value {
+        json!({
+            "id": tool_call_id,
+            "type": call.tool_type,
+            "function": {
+                "arguments": self.args_encoding().encode(&call.args),
+                "name": call.tool_name,
+            },
+        })
+    }
+}
+
+/// OpenAI Responses API：工具调用是独立的 `function_call` item，`arguments` 为 JSON 字符串
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenaiResponsesToolCallFormatter;
+
+impl ToolCallFormatter for OpenaiResponsesToolCallFormatter {
+    fn args_encoding(&self) -> ToolArgsEncoding {
+        ToolArgsEncoding::JsonString
+    }
+
+    fn format_tool_call(&self, tool_call_id: &str, call: &ToolCallArgs) -> Value {
+        json!({
+            "type": "function_call",
+            "call_id": tool_call_id,
+            "name": call.tool_name,
+            "arguments": self.args_encoding().encode(&call.args),
+        })
+    }
+}
+
+/// Anthropic Messages：`tool_use` 内容块，`input` 为 JSON 对象
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClaudeToolCallFormatter;
+
+impl ToolCallFormatter for ClaudeToolCallFormatter {
+    fn args_encoding(&self) -> ToolArgsEncoding {
+        ToolArgsEncoding::JsonObject
+    }
+
+    fn format_tool_call(&self, tool_call_id: &str, call: &ToolCallArgs) -> Value {
+        json!({
+            "type": "tool_use",
+            "id": tool_call_id,
+            "name": call.tool_name,
+            "input": self.args_encoding().encode(&call.args),
+        })
+    }
+}
+
+/// Ollama `/api/chat`：`arguments` 为 JSON 对象，调用没有 id
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OllamaToolCallFormatter;
+
+impl ToolCallFormatter for OllamaToolCallFormatter {
+    fn args_encoding(&self) -> ToolArgsEncoding {
+        ToolArgsEncoding::JsonObject
+    }
+
+    fn format_tool_call(&self, _tool_call_id: &str, call: &ToolCallArgs) -> Value {
+        json!({
+            "function": {
+                "name": call.tool_name,
+                "arguments": self.args_encoding().encode(&call.args),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn echo_call() -> ToolCallArgs {
+        ToolCallArgs {
+            tool_type: "function".to_string(),
+            tool_name: "echo".to_string(),
+            args: json!({"text": "hi", "times": 2}),
+        }
+    }
+
+    #[test]
+    fn test_same_tool_call_is_encoded_per_provider() {
+        let call = echo_call();
+
+        let openai = OpenaiToolCallFormatter.format_tool_call("call_1", &call);
+        let arguments = openai["function"]["arguments"].as_str().unwrap();
+        assert_eq!(serde_json::from_str::<Value>(arguments).unwrap(), call.args);
+        assert_eq!(openai["id"], "call_1");
+
+        let responses = OpenaiResponsesToolCallFormatter.format_tool_call("call_1", &call);
+        assert_eq!(responses["arguments"], openai["function"]["arguments"]);
+
+        let claude = ClaudeToolCallFormatter.format_tool_call("call_1", &call);
+        assert!(claude["input"].is_object());
+        assert_eq!(claude["input"], call.args);
+        assert_eq!(claude["id"], "call_1");
+
+        let ollama = OllamaToolCallFormatter.format_tool_call("call_1", &call);
+        assert_eq!(ollama["function"]["arguments"], call.args);
+    }
+
+    #[test]
+    fn test_args_encoding() {
+        let args = json!({"text": "hi"});
+        assert_eq!(
+            ToolArgsEncoding::JsonString.encode(&args),
+            Value::String(r#"{"text":"hi"}"#.to_string())
+        );
+        assert_eq!(ToolArgsEncoding::JsonObject.encode(&args), args);
+    }
+}
@@ -1,8 +1,14 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::ops::Range;
 
-use crate::types::Message;
+use crate::types::{Message, Visibility};
+
+pub mod tee;
+
+pub use tee::TeeShortTermMemory;
 
 // 记忆查询
 #[derive(Debug)]
@@ -11,6 +17,8 @@ pub enum MemoryQuery {
     Semantic {
         description: String,
         limit: usize,
+        // 最低相似度，低于该值的记忆不返回
+        min_similarity: f32,
     },
     // 按时间范围查询
     TimeRange {
@@ -24,7 +32,8 @@ pub enum MemoryQuery {
 // 记忆条目
 #[derive(Debug, Clone)]
 pub struct MemoryEntry {
-    pub result: String,
+    /// 记忆内容，可以是纯文本（`Value::String`）或结构化的数据（如对话摘要）
+    pub content: serde_json::Value,
     pub metadata: MemoryMetadata,
 }
 
@@ -55,11 +64,331 @@ pub trait ShortTermMemory: Send + Sync {
     /// 获取当前的对话上下文，根据 token 限制进行裁剪
     /// 如果 max_tokens 为 None，则返回所有消息
     fn get_context_messages(&self, max_tokens: Option<usize>) -> Vec<Message>;
+
+    /// 添加一条带可见性标记的消息
+    ///
+    /// 默认实现不保存标记：对模型隐藏的消息会被丢弃，其余消息按普通消息添加。
+    fn add_message_with_visibility(&mut self, message: Message, visibility: Visibility) {
+        if visibility.is_visible_to_model() {
+            self.add_message(message);
+        }
+    }
+
+    /// 面向用户展示或导出的完整历史，不裁剪，且不包含对用户隐藏的消息
+    fn user_history(&self) -> Vec<Message> {
+        self.get_context_messages(None)
+    }
+}
+
+/// 消息存储后端（内存、文件、redis 等），只负责原始的存取，不负责裁剪
+pub trait MessageStore: Send + Sync {
+    /// 追加一条消息
+    fn push(&mut self, message: Message);
+
+    /// 按时间顺序返回全部消息
+    fn messages(&self) -> Vec<Message>;
+
+    /// 清空全部消息
+    fn clear(&mut self);
+
+    /// 追加一条带可见性标记的消息，默认实现忽略标记
+    fn push_with_visibility(&mut self, message: Message, visibility: Visibility) {
+        let _ = visibility;
+        self.push(message);
+    }
+
+    /// 与 `messages` 一一对应的可见性标记，默认全部可见
+    fn visibilities(&self) -> Vec<Visibility> {
+        vec![Visibility::All; self.messages().len()]
+    }
+}
+
+/// 最简单的内存消息存储
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryStore {
+    messages: Vec<Message>,
+    visibilities: Vec<Visibility>,
+}
+
+impl MessageStore for InMemoryStore {
+    fn push(&mut self, message: Message) {
+        self.push_with_visibility(message, Visibility::All);
+    }
+
+    fn messages(&self) -> Vec<Message> {
+        self.messages.clone()
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.visibilities.clear();
+    }
+
+    fn push_with_visibility(&mut self, message: Message, visibility: Visibility) {
+        self.messages.push(message);
+        self.visibilities.push(visibility);
+    }
+
+    fn visibilities(&self) -> Vec<Visibility> {
+        self.visibilities.clone()
+    }
+}
+
+/// 裁剪策略：控制各角色的消息如何计入 token 预算
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrimPolicy {
+    /// 角色名（见 `Message::role`）=> 计入预算时的 token 权重，未列出的角色为 1.0；
+    /// 权重小于 1 的角色更容易被保留，为 0 时不占预算
+    pub role_weights: HashMap<&'static str, f32>,
+    /// 无论预算如何，始终保留最近的若干条工具结果，其余消息在剩余预算内从新到旧保留；
+    /// 工具结果总是与发起调用的 assistant 消息及同一轮的其他结果一起保留（见 `trim_with_policy`）
+    pub keep_last_tool_results: usize,
+}
+
+impl TrimPolicy {
+    fn weighted_tokens(&self, message: &Message) -> usize {
+        let weight = self
+            .role_weights
+            .get(message.role())
+            .copied()
+            .unwrap_or(1.0);
+        (estimate_tokens(message.content()) as f32 * weight).ceil() as usize
+    }
+}
+
+/// 默认的裁剪层：可与任意 `MessageStore` 组合成 `ShortTermMemory`，
+/// 按 token 预算从最新的消息开始保留
+#[derive(Debug, Clone, Default)]
+pub struct TrimmingMemory<S: MessageStore> {
+    store: S,
+    policy: TrimPolicy,
+    max_tool_results: Option<usize>,
+    assistant_truncation: Option<AssistantTruncation>,
+}
+
+/// 截断较早的冗长 assistant 回复，避免每次请求都重复发送
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssistantTruncation {
+    /// 超过该字符数的回复被截断到该长度
+    pub max_chars: usize,
+    /// 最近的若干条 assistant 回复始终保持完整
+    pub keep_recent: usize,
+}
+
+impl<S: MessageStore> TrimmingMemory<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            policy: TrimPolicy::default(),
+            max_tool_results: None,
+            assistant_truncation: None,
+        }
+    }
+
+    /// 设置裁剪策略
+    pub fn with_policy(mut self, policy: TrimPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// 只保留最近 `max` 条工具结果的内容，更早的工具结果替换为 `OMITTED_TOOL_RESULT`
+    ///
+    /// 存储中的原始消息不受影响；被替换的消息保留 tool_call_id，以维持与工具调用的对应关系。
+    pub fn with_max_tool_results(mut self, max: usize) -> Self {
+        self.max_tool_results = Some(max);
+        self
+    }
+
+    /// 构建上下文时截断较早的冗长 assistant 回复，存储中的原始消息不受影响
+    pub fn with_assistant_truncation(mut self, truncation: AssistantTruncation) -> Self {
+        self.assistant_truncation = Some(truncation);
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    fn visible_messages(&self, visible: fn(Visibility) -> bool) -> Vec<Message> {
+        self.store
+            .messages()
+            .into_iter()
+            .zip(self.store.visibilities())
+            .filter(|(_, visibility)| visible(*visibility))
+            .map(|(message, _)| message)
+            .collect()
+    }
+}
+
+impl<S: MessageStore> ShortTermMemory for TrimmingMemory<S> {
+    fn add_message(&mut self, message: Message) {
+        self.store.push(message);
+    }
+
+    fn get_context_messages(&self, max_tokens: Option<usize>) -> Vec<Message> {
+        let mut messages = self.visible_messages(Visibility::is_visible_to_model);
+        if let Some(max) = self.max_tool_results {
+            omit_older_tool_results(&mut messages, max);
+        }
+        if let Some(truncation) = self.assistant_truncation {
+            truncate_older_assistant_messages(&mut messages, truncation);
+        }
+        match max_tokens {
+            Some(max_tokens) => trim_with_policy(messages, max_tokens, &self.policy),
+            None => messages,
+        }
+    }
+
+    fn add_message_with_visibility(&mut self, message: Message, visibility: Visibility) {
+        self.store.push_with_visibility(message, visibility);
+    }
+
+    fn user_history(&self) -> Vec<Message> {
+        self.visible_messages(Visibility::is_visible_to_user)
+    }
+}
+
+/// 替换过旧工具结果内容的占位文本
+pub const OMITTED_TOOL_RESULT: &str = "[older tool results omitted]";
+
+/// 将最近 `keep` 条之前的工具结果内容替换为占位文本
+fn omit_older_tool_results(messages: &mut [Message], keep: usize) {
+    for message in messages
+        .iter_mut()
+        .rev()
+        .filter(|message| matches!(message, Message::Tool { .. }))
+        .skip(keep)
+    {
+        if let Message::Tool { content, .. } = message {
+            *content = OMITTED_TOOL_RESULT.to_string();
+        }
+    }
+}
+
+/// 截断后附加在 assistant 回复末尾的说明
+pub const TRUNCATED_ASSISTANT_SUFFIX: &str = "… [earlier answer truncated]";
+
+/// 截断最近 `keep_recent` 条之前、超过 `max_chars` 的 assistant 回复
+fn truncate_older_assistant_messages(messages: &mut [Message], truncation: AssistantTruncation) {
+    for message in messages
+        .iter_mut()
+        .rev()
+        .filter(|message| matches!(message, Message::Assistant { .. }))
+        .skip(truncation.keep_recent)
+    {
+        if let Message::Assistant { content, .. } = message {
+            if let Some((cut, _)) = content.char_indices().nth(truncation.max_chars) {
+                content.truncate(cut);
+                content.push_str(TRUNCATED_ASSISTANT_SUFFIX);
+            }
+        }
+    }
+}
+
+/// 简单估算文本的 token 数: 每个单词约等于1.3个token
+pub fn estimate_tokens(text: &str) -> usize {
+    (text.split_whitespace().count() as f32 * 1.3) as usize
+}
+
+/// 从最新的消息开始保留，直到超出 token 预算为止
+pub fn trim_to_token_budget(messages: Vec<Message>, max_tokens: usize) -> Vec<Message> {
+    trim_with_policy(messages, max_tokens, &TrimPolicy::default())
+}
+
+/// 按裁剪策略保留消息：先保留策略要求固定保留的工具结果，再在剩余预算内从新到旧保留其余消息
+///
+/// 带工具调用的 assistant 消息与紧随其后的工具结果作为一个整体保留或丢弃（整体的 token 数计入预算），
+/// 避免裁剪后出现缺少调用方的工具结果或缺少结果的工具调用，服务商会拒绝这样的请求。
+pub fn trim_with_policy(
+    messages: Vec<Message>,
+    max_tokens: usize,
+    policy: &TrimPolicy,
+) -> Vec<Message> {
+    let units = trim_units(&messages);
+    let unit_tokens = |unit: &Range<usize>| -> usize {
+        messages[unit.clone()]
+            .iter()
+            .map(|message| policy.weighted_tokens(message))
+            .sum()
+    };
+
+    // 最近的若干条工具结果所在的整组消息固定保留
+    let mut pinned = vec![false; units.len()];
+    let mut total_tokens = 0;
+    let mut to_pin = policy.keep_last_tool_results;
+    for (i, unit) in units.iter().enumerate().rev() {
+        if to_pin == 0 {
+            break;
+        }
+        let results = messages[unit.clone()]
+            .iter()
+            .filter(|message| matches!(message, Message::Tool { .. }))
+            .count();
+        if results > 0 {
+            pinned[i] = true;
+            total_tokens += unit_tokens(unit);
+            to_pin = to_pin.saturating_sub(results);
+        }
+    }
+
+    // 从最新的消息开始添加
+    let mut keep = pinned.clone();
+    for (i, unit) in units.iter().enumerate().rev() {
+        if pinned[i] {
+            continue;
+        }
+        let tokens = unit_tokens(unit);
+        if total_tokens + tokens > max_tokens {
+            break;
+        }
+        total_tokens += tokens;
+        keep[i] = true;
+    }
+
+    let mut kept = vec![false; messages.len()];
+    for (unit, keep) in units.into_iter().zip(keep) {
+        if keep {
+            kept[unit].fill(true);
+        }
+    }
+    messages
+        .into_iter()
+        .zip(kept)
+        .filter_map(|(message, keep)| keep.then_some(message))
+        .collect()
+}
+
+/// 将消息划分为裁剪时不可拆分的连续区间：带工具调用的 assistant 消息与紧随其后的工具结果为一组，
+/// 其余消息各自一组
+fn trim_units(messages: &[Message]) -> Vec<Range<usize>> {
+    let mut units = Vec::new();
+    let mut i = 0;
+    while i < messages.len() {
+        let start = i;
+        i += 1;
+        if matches!(&messages[start], Message::Assistant { tool_calls: Some(calls), .. } if !calls.is_empty())
+        {
+            while i < messages.len() && matches!(messages[i], Message::Tool { .. }) {
+                i += 1;
+            }
+        }
+        units.push(start..i);
+    }
+    units
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crate::types::{ToolCallArgs, ToolCalls};
     use pretty_assertions::assert_eq;
 
     // 模拟的长期记忆实现
@@ -75,9 +404,12 @@ pub(crate) mod tests {
         }
 
         // 简单的相似度计算(模拟)
-        fn calculate_similarity(query: &str, content: &str) -> f32 {
+        fn calculate_similarity(query: &str, content: &serde_json::Value) -> f32 {
             // 将内容转换为字符串并序列化为小写
-            let content_str = content.to_lowercase();
+            let content_str = match content {
+                serde_json::Value::String(text) => text.to_lowercase(),
+                other => other.to_string().to_lowercase(),
+            };
             let query = query.to_lowercase();
 
             // 检查内容中是否包含查询词的任何部分
@@ -99,20 +431,23 @@ pub(crate) mod tests {
 
         async fn recall(&self, query: &MemoryQuery) -> Result<Vec<MemoryEntry>> {
             match query {
-                MemoryQuery::Semantic { description, limit } => {
+                MemoryQuery::Semantic {
+                    description,
+                    limit,
+                    min_similarity,
+                } => {
                     // 模拟语义搜索
                     let mut results: Vec<(f32, &MemoryEntry)> = self
                         .memories
                         .iter()
                         .map(|entry| {
-                            let similarity = 0.1;
-                            // let similarity = Self::calculate_similarity(
-                            //     description,
-                            //     entry.content.to_string().as_str(),
-                            // );
+                            let similarity =
+                                Self::calculate_similarity(description, &entry.content);
                             (similarity, entry)
                         })
-                        .filter(|(similarity, _)| *similarity > 0.0)
+                        .filter(|(similarity, _)| {
+                            *similarity > 0.0 && *similarity >= *min_similarity
+                        })
                         .collect();
 
                     // 按相似度排序
@@ -162,132 +497,116 @@ pub(crate) mod tests {
         }
     }
 
-    // #[tokio::test]
-    // async fn test_mock_long_term_memory() {
-    //     let mut memory = MockLongTermMemory::new();
-
-    //     // 1. 存储测试数据
-    //     let entry1 = MemoryEntry {
-    //         content: serde_json::json!({"message": "Hello world"}),
-    //         metadata: MemoryMetadata {
-    //             timestamp: Utc::now(),
-    //             tags: vec!["greeting".to_string()],
-    //             source: "test".to_string(),
-    //         },
-    //     };
-
-    //     let entry2 = MemoryEntry {
-    //         content: serde_json::json!({"message": "Testing memory system"}),
-    //         metadata: MemoryMetadata {
-    //             timestamp: Utc::now(),
-    //             tags: vec!["test".to_string()],
-    //             source: "test".to_string(),
-    //         },
-    //     };
-
-    //     memory.store(entry1).await.unwrap();
-    //     memory.store(entry2).await.unwrap();
-
-    //     // 2. 测试语义查询
-    //     let results = memory
-    //         .recall(&MemoryQuery::Semantic {
-    //             description: "hello".to_string(),
-    //             limit: 1,
-    //         })
-    //         .await
-    //         .unwrap();
-
-    //     assert_eq!(results.len(), 1);
-    //     assert!(results[0].content["message"]
-    //         .as_str()
-    //         .unwrap()
-    //         .contains("Hello"));
-
-    //     // 3. 测试标签查询
-    //     let results = memory
-    //         .recall(&MemoryQuery::ByTags(vec!["test".to_string()]))
-    //         .await
-    //         .unwrap();
-
-    //     assert_eq!(results.len(), 1);
-    //     assert!(results[0].content["message"]
-    //         .as_str()
-    //         .unwrap()
-    //         .contains("Testing"));
-
-    //     // 4. 测试遗忘功能
-    //     memory
-    //         .forget(&MemoryQuery::ByTags(vec!["greeting".to_string()]))
-    //         .await
-    //         .unwrap();
-
-    //     let results = memory
-    //         .recall(&MemoryQuery::Semantic {
-    //             description: "hello".to_string(),
-    //             limit: 1,
-    //         })
-    //         .await
-    //         .unwrap();
-
-    //     assert_eq!(results.len(), 0);
-    // }
-
-    pub(crate) struct BasicShortTermMemory {
-        messages: Vec<Message>,
-    }
-
-    impl BasicShortTermMemory {
-        pub(crate) fn new() -> Self {
-            Self {
-                messages: Vec::new(),
-            }
-        }
-
-        fn estimate_tokens(text: &str) -> usize {
-            // 简单估算: 每个单词约等于1.3个token
-            (text.split_whitespace().count() as f32 * 1.3) as usize
-        }
+    #[tokio::test]
+    async fn test_mock_long_term_memory() {
+        let mut memory = MockLongTermMemory::new();
+
+        // 1. 存储测试数据
+        let entry1 = MemoryEntry {
+            content: serde_json::json!({"message": "Hello world"}),
+            metadata: MemoryMetadata {
+                timestamp: Utc::now(),
+                tags: vec!["greeting".to_string()],
+                source: "test".to_string(),
+            },
+        };
+
+        let entry2 = MemoryEntry {
+            content: serde_json::json!({"message": "Testing memory system"}),
+            metadata: MemoryMetadata {
+                timestamp: Utc::now(),
+                tags: vec!["test".to_string()],
+                source: "test".to_string(),
+            },
+        };
+
+        memory.store(entry1).await.unwrap();
+        memory.store(entry2).await.unwrap();
+
+        // 2. 测试语义查询
+        let results = memory
+            .recall(&MemoryQuery::Semantic {
+                description: "hello".to_string(),
+                limit: 1,
+                min_similarity: 0.0,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert!(results[0].content["message"]
+            .as_str()
+            .unwrap()
+            .contains("Hello"));
+
+        // 3. 测试标签查询
+        let results = memory
+            .recall(&MemoryQuery::ByTags(vec!["test".to_string()]))
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert!(results[0].content["message"]
+            .as_str()
+            .unwrap()
+            .contains("Testing"));
+
+        // 4. 测试遗忘功能
+        memory
+            .forget(&MemoryQuery::ByTags(vec!["greeting".to_string()]))
+            .await
+            .unwrap();
+
+        let results = memory
+            .recall(&MemoryQuery::Semantic {
+                description: "hello".to_string(),
+                limit: 1,
+                min_similarity: 0.0,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 0);
     }
 
-    impl ShortTermMemory for BasicShortTermMemory {
-        fn add_message(&mut self, message: Message) {
-            self.messages.push(message);
-        }
-
-        fn get_context_messages(&self, max_tokens: Option<usize>) -> Vec<Message> {
-            if let Some(max_tokens) = max_tokens {
-                let mut total_tokens = 0;
-                let mut result = Vec::new();
-
-                // 从最新的消息开始添加
-                for message in self.messages.iter().rev() {
-                    let content = match message {
-                        Message::Developer { content }
-                        | Message::System { content }
-                        | Message::User { content }
-                        | Message::Assistant { content, .. }
-                        | Message::Tool { content, .. } => content.as_str(),
-                    };
-                    let tokens = Self::estimate_tokens(content);
-                    if total_tokens + tokens > max_tokens {
-                        break;
-                    }
-                    total_tokens += tokens;
-                    result.push(message.clone());
-                }
-
-                // 反转回正常顺序
-                result.reverse();
-                result
-            } else {
-                self.messages.clone()
-            }
-        }
+    #[tokio::test]
+    async fn test_structured_summary_round_trips() {
+        let mut memory = MockLongTermMemory::new();
+        let summary = serde_json::json!({
+            "topic": "trip planning",
+            "decisions": ["fly to Lisbon", "stay 5 nights"],
+            "open_questions": [],
+        });
+        memory
+            .store(MemoryEntry {
+                content: summary.clone(),
+                metadata: MemoryMetadata {
+                    timestamp: Utc::now(),
+                    tags: vec!["summary".to_string()],
+                    source: "conversation".to_string(),
+                },
+            })
+            .await
+            .unwrap();
+
+        let results = memory
+            .recall(&MemoryQuery::Semantic {
+                description: "Lisbon".to_string(),
+                limit: 5,
+                min_similarity: 0.0,
+            })
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].content, summary);
+        assert_eq!(results[0].content["decisions"][1], "stay 5 nights");
     }
 
+    pub(crate) type BasicShortTermMemory = TrimmingMemory<InMemoryStore>;
+
     #[test]
     fn test_basic_short_term_memory() {
-        let mut memory = BasicShortTermMemory::new();
+        let mut memory = BasicShortTermMemory::default();
 
         // Test adding and retrieving messages
         memory.add_message(Message::User {
@@ -301,4 +620,231 @@ pub(crate) mod tests {
         let context = memory.get_context_messages(Some(5)); // Only allow ~5 tokens
         assert_eq!(context.len(), 2); // Both messages should fit as they're very short
     }
+
+    // 一个最简单的自定义存储，用于验证裁剪层可以与任意存储组合
+    #[derive(Default)]
+    struct VecStore(Vec<Message>);
+
+    impl MessageStore for VecStore {
+        fn push(&mut self, message: Message) {
+            self.0.push(message);
+        }
+
+        fn messages(&self) -> Vec<Message> {
+            self.0.clone()
+        }
+
+        fn clear(&mut self) {
+            self.0.clear();
+        }
+    }
+
+    #[test]
+    fn test_trimming_memory_over_custom_store() {
+        let mut memory = TrimmingMemory::new(VecStore::default());
+        memory.add_message(Message::User {
+            content: "one two three four five six seven eight".to_string(),
+        });
+        memory.add_message(Message::Assistant {
+            content: "nine ten".to_string(),
+            tool_calls: None,
+        });
+        memory.add_message(Message::User {
+            content: "eleven".to_string(),
+        });
+
+        // 不限制时返回全部消息
+        assert_eq!(memory.get_context_messages(None).len(), 3);
+
+        // 预算不足时丢弃最早的长消息，保留最新的消息
+        let context = memory.get_context_messages(Some(5));
+        assert_eq!(
+            context,
+            vec![
+                Message::Assistant {
+                    content: "nine ten".to_string(),
+                    tool_calls: None,
+                },
+                Message::User {
+                    content: "eleven".to_string(),
+                },
+            ]
+        );
+
+        memory.store_mut().clear();
+        assert!(memory.get_context_messages(None).is_empty());
+    }
+
+    #[test]
+    fn test_max_tool_results_omits_older_tool_results() {
+        let mut memory = TrimmingMemory::new(InMemoryStore::default()).with_max_tool_results(2);
+        memory.add_message(Message::User {
+            content: "look things up".to_string(),
+        });
+        for i in 0..5 {
+            memory.add_message(Message::Tool {
+                content: format!("result {i}"),
+                tool_call_id: format!("call_{i}"),
+            });
+        }
+
+        let context = memory.get_context_messages(None);
+        assert_eq!(context.len(), 6);
+        let tool_results: Vec<(&str, &str)> = context
+            .iter()
+            .filter_map(|message| match message {
+                Message::Tool {
+                    content,
+                    tool_call_id,
+                } => Some((tool_call_id.as_str(), content.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            tool_results,
+            [
+                ("call_0", OMITTED_TOOL_RESULT),
+                ("call_1", OMITTED_TOOL_RESULT),
+                ("call_2", OMITTED_TOOL_RESULT),
+                ("call_3", "result 3"),
+                ("call_4", "result 4"),
+            ]
+        );
+        // 存储中仍保留原始内容
+        assert_eq!(
+            memory.store().messages()[1],
+            Message::Tool {
+                content: "result 0".to_string(),
+                tool_call_id: "call_0".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_older_long_assistant_messages_are_truncated() {
+        let long_answer = "word ".repeat(100);
+        let mut memory = TrimmingMemory::new(InMemoryStore::default()).with_assistant_truncation(
+            AssistantTruncation {
+                max_chars: 20,
+                keep_recent: 1,
+            },
+        );
+        for question in ["first", "second"] {
+            memory.add_message(Message::User {
+                content: question.to_string(),
+            });
+            memory.add_message(Message::Assistant {
+                content: long_answer.clone(),
+                tool_calls: None,
+            });
+        }
+
+        let context = memory.get_context_messages(None);
+        assert_eq!(
+            context[1],
+            Message::Assistant {
+                content: format!("{}{TRUNCATED_ASSISTANT_SUFFIX}", "word ".repeat(4)),
+                tool_calls: None,
+            }
+        );
+        // 最新的回复保持完整
+        assert_eq!(
+            context[3],
+            Message::Assistant {
+                content: long_answer.clone(),
+                tool_calls: None,
+            }
+        );
+        // 截断后的回复占用更少的预算，因此更早的消息也能保留
+        assert_eq!(memory.get_context_messages(Some(145)).len(), 4);
+        assert_eq!(memory.store().messages()[1].content(), long_answer);
+    }
+
+    #[test]
+    fn test_trim_policy_prefers_tool_results_over_old_user_messages() {
+        let tool_call = Message::Assistant {
+            content: "let me check the weather".to_string(),
+            tool_calls: Some(ToolCalls::from([(
+                "call_1".to_string(),
+                ToolCallArgs {
+                    tool_type: "function".to_string(),
+                    tool_name: "weather".to_string(),
+                    args: serde_json::json!({}),
+                },
+            )])),
+        };
+        let tool_result = Message::Tool {
+            content: "temperature 21 degrees humidity 40 percent".to_string(),
+            tool_call_id: "call_1".to_string(),
+        };
+        let messages = vec![
+            Message::User {
+                content: "hi there how are you".to_string(),
+            },
+            tool_call.clone(),
+            tool_result.clone(),
+            Message::User {
+                content: "so what do you think about all of that".to_string(),
+            },
+            Message::User {
+                content: "and then".to_string(),
+            },
+        ];
+
+        // 默认策略下，较新的用户闲聊占满预算，工具调用与结果一起被丢弃
+        let trimmed = trim_to_token_budget(messages.clone(), 20);
+        assert_eq!(trimmed, messages[3..].to_vec());
+
+        // 固定保留的工具结果连同发起调用的 assistant 消息一起保留，裁剪结果不会以工具结果开头
+        let policy = TrimPolicy {
+            keep_last_tool_results: 1,
+            ..Default::default()
+        };
+        let trimmed = trim_with_policy(messages.clone(), 20, &policy);
+        assert_eq!(
+            trimmed,
+            vec![tool_call.clone(), tool_result.clone(), messages[4].clone()]
+        );
+
+        // 降低工具结果的权重同样可以让它留在预算内，assistant 消息照常计入预算，且保持原有顺序
+        let policy = TrimPolicy {
+            role_weights: HashMap::from([("tool", 0.0)]),
+            ..Default::default()
+        };
+        let mut memory = BasicShortTermMemory::default().with_policy(policy);
+        for message in messages.clone() {
+            memory.add_message(message);
+        }
+        assert_eq!(
+            memory.get_context_messages(Some(20)),
+            messages[1..].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_visibility_filters_model_and_user_views() {
+        let mut memory = BasicShortTermMemory::default();
+        memory.add_message(Message::User {
+            content: "Hello".to_string(),
+        });
+        let debug_note = Message::System {
+            content: "debug: cache miss".to_string(),
+        };
+        let scaffold = Message::System {
+            content: "Answer briefly.".to_string(),
+        };
+        memory.add_message_with_visibility(debug_note.clone(), Visibility::HiddenFromModel);
+        memory.add_message_with_visibility(scaffold.clone(), Visibility::HiddenFromUser);
+
+        let context = memory.get_context_messages(None);
+        assert!(!context.contains(&debug_note));
+        assert!(context.contains(&scaffold));
+
+        let history = memory.user_history();
+        assert!(history.contains(&debug_note));
+        assert!(!history.contains(&scaffold));
+
+        // 完整历史中两条消息都保留
+        assert_eq!(memory.store().messages().len(), 3);
+    }
 }
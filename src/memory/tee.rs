@@ -0,0 +1,140 @@
+use crate::types::{Message, Visibility};
+
+use super::ShortTermMemory;
+
+/// 将消息同时写入多个短期记忆，读取时只使用主记忆
+///
+/// 例如主记忆保存裁剪后的工作集，副记忆保存完整日志：发送给模型的上下文来自主记忆，
+/// 全部消息仍会被副记忆持久化。
+pub struct TeeShortTermMemory<P: ShortTermMemory> {
+    primary: P,
+    secondaries: Vec<Box<dyn ShortTermMemory>>,
+}
+
+impl<P: ShortTermMemory> TeeShortTermMemory<P> {
+    pub fn new(primary: P) -> Self {
+        Self {
+            primary,
+            secondaries: Vec::new(),
+        }
+    }
+
+    /// 追加一个只写入、不参与读取的副记忆
+    pub fn with_secondary(mut self, memory: impl ShortTermMemory + 'static) -> Self {
+        self.secondaries.push(Box::new(memory));
+        self
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn primary_mut(&mut self) -> &mut P {
+        &mut self.primary
+    }
+
+    /// 按添加顺序返回副记忆
+    pub fn secondaries(&self) -> &[Box<dyn ShortTermMemory>] {
+        &self.secondaries
+    }
+}
+
+impl<P: ShortTermMemory> ShortTermMemory for TeeShortTermMemory<P> {
+    fn add_message(&mut self, message: Message) {
+        for memory in &mut self.secondaries {
+            memory.add_message(message.clone());
+        }
+        self.primary.add_message(message);
+    }
+
+    fn get_context_messages(&self, max_tokens: Option<usize>) -> Vec<Message> {
+        self.primary.get_context_messages(max_tokens)
+    }
+
+    fn add_message_with_visibility(&mut self, message: Message, visibility: Visibility) {
+        for memory in &mut self.secondaries {
+            memory.add_message_with_visibility(message.clone(), visibility);
+        }
+        self.primary
+            .add_message_with_visibility(message, visibility);
+    }
+
+    fn user_history(&self) -> Vec<Message> {
+        self.primary.user_history()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{InMemoryStore, MessageStore, TrimmingMemory, OMITTED_TOOL_RESULT};
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    /// 将每条消息以 JSON 行追加到文件的记忆，读取时解析整个文件
+    struct JsonlFileMemory {
+        path: PathBuf,
+    }
+
+    impl ShortTermMemory for JsonlFileMemory {
+        fn add_message(&mut self, message: Message) {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .unwrap();
+            writeln!(file, "{}", serde_json::to_string(&message).unwrap()).unwrap();
+        }
+
+        fn get_context_messages(&self, _max_tokens: Option<usize>) -> Vec<Message> {
+            std::fs::read_to_string(&self.path)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_tee_writes_everywhere_and_reads_from_primary() {
+        let path =
+            std::env::temp_dir().join(format!("chimerai-tee-{}.jsonl", uuid::Uuid::new_v4()));
+        let working_set = TrimmingMemory::new(InMemoryStore::default()).with_max_tool_results(1);
+        let mut memory = TeeShortTermMemory::new(working_set)
+            .with_secondary(JsonlFileMemory { path: path.clone() });
+
+        let messages = vec![
+            Message::User {
+                content: "look it up".to_string(),
+            },
+            Message::Tool {
+                content: "first result".to_string(),
+                tool_call_id: "call_1".to_string(),
+            },
+            Message::Tool {
+                content: "second result".to_string(),
+                tool_call_id: "call_2".to_string(),
+            },
+        ];
+        for message in messages.clone() {
+            memory.add_message(message);
+        }
+
+        // 文件中保存了完整的消息
+        assert_eq!(memory.secondaries()[0].get_context_messages(None), messages);
+        assert_eq!(memory.primary().store().messages(), messages);
+        // 上下文来自主记忆，较早的工具结果被省略
+        let context = memory.get_context_messages(None);
+        assert_eq!(
+            context[1],
+            Message::Tool {
+                content: OMITTED_TOOL_RESULT.to_string(),
+                tool_call_id: "call_1".to_string(),
+            }
+        );
+        assert_eq!(context[2], messages[2]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+
+/// 将文本流逐个转发到有界 channel，每次发送都会等待 channel 有空位，
+/// 因此消费者较慢时生产者随之减速，缓冲的片段不会超过 channel 的容量
+///
+/// 接收端被丢弃后停止转发并丢弃剩余的流。
+pub async fn forward_to_channel<S>(stream: S, tx: mpsc::Sender<Result<String>>)
+where
+    S: Stream<Item = Result<String>>,
+{
+    futures::pin_mut!(stream);
+    while let Some(chunk) = stream.next().await {
+        if tx.send(chunk).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_consumer_throttles_producer() {
+        let produced = AtomicUsize::new(0);
+        let stream = futures::stream::iter(0..100).map(|i| {
+            produced.fetch_add(1, Ordering::SeqCst);
+            Ok(i.to_string())
+        });
+        let (tx, mut rx) = mpsc::channel::<Result<String>>(4);
+
+        let consumer = async {
+            let mut received = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                received.push(chunk.unwrap());
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                // 已生成的片段 = 已消费 + channel 中缓冲 + 至多一个等待发送的片段
+                assert!(produced.load(Ordering::SeqCst) <= received.len() + 4 + 1);
+            }
+            received
+        };
+        let ((), received) = tokio::join!(forward_to_channel(stream, tx), consumer);
+
+        assert_eq!(received.len(), 100);
+        assert_eq!(received[99], "99");
+    }
+
+    #[tokio::test]
+    async fn test_forwarding_stops_when_receiver_is_dropped() {
+        let produced = AtomicUsize::new(0);
+        let stream = futures::stream::iter(0..100).map(|i| {
+            produced.fetch_add(1, Ordering::SeqCst);
+            Ok(i.to_string())
+        });
+        let (tx, mut rx) = mpsc::channel(1);
+
+        let consumer = async move {
+            rx.recv().await;
+        };
+        tokio::join!(forward_to_channel(stream, tx), consumer);
+
+        assert!(produced.load(Ordering::SeqCst) < 100);
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::types::ToolCalls;
+
+/// Agent 事件流中的单个事件
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// Assistant 回复的增量文本
+    Text(String),
+    /// 模型计划执行的工具调用，需由调用方批准后才会执行（见 `AgentConfig::require_tool_approval`）
+    AwaitingToolApproval(ToolApprovalRequest),
+    /// 模型发起的工具调用，在审批与执行之前产出
+    ToolCall {
+        call_id: String,
+        name: String,
+        args: Value,
+    },
+    /// 单个工具调用完成后的结果，即写入记忆的工具消息内容（失败时为错误说明）
+    ToolResult {
+        call_id: String,
+        name: String,
+        output: String,
+    },
+}
+
+/// 流式输出中途出错时产出的错误，携带本轮出错前已生成的 assistant 文本
+///
+/// 显示内容与原始错误相同，原始错误可通过 `source()` 或 anyhow 的错误链取得。
+#[derive(Debug, thiserror::Error)]
+#[error("{source}")]
+pub struct StreamInterrupted {
+    /// 出错前已输出的文本
+    pub partial: String,
+    #[source]
+    pub source: anyhow::Error,
+}
+
+impl StreamInterrupted {
+    pub(crate) fn wrap(source: anyhow::Error, partial: &str) -> anyhow::Error {
+        Self {
+            partial: partial.to_string(),
+            source,
+        }
+        .into()
+    }
+
+    /// 若错误由流式输出中断产生，返回已生成的部分文本
+    pub fn partial(err: &anyhow::Error) -> Option<&str> {
+        err.downcast_ref::<StreamInterrupted>()
+            .map(|interrupted| interrupted.partial.as_str())
+    }
+}
+
+/// 等待调用方批准的一组工具调用
+///
+/// 流会暂停直到调用方通过 `approve`/`approve_all`/`deny_all` 作出答复；
+/// 若请求在答复前被丢弃，则视为全部拒绝。被拒绝的调用不会执行，而是以失败结果告知模型。
+#[derive(Debug)]
+pub struct ToolApprovalRequest {
+    calls: ToolCalls,
+    responder: oneshot::Sender<HashSet<String>>,
+}
+
+impl ToolApprovalRequest {
+    pub(crate) fn new(calls: ToolCalls) -> (Self, oneshot::Receiver<HashSet<String>>) {
+        let (responder, receiver) = oneshot::channel();
+        (Self { calls, responder }, receiver)
+    }
+
+    /// 计划执行的工具调用（tool_call_id => 调用参数）
+    pub fn calls(&self) -> &ToolCalls {
+        &self.calls
+    }
+
+    /// 只批准指定 tool_call_id 的调用，其余全部拒绝
+    pub fn approve<I, S>(self, tool_call_ids: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let approved = tool_call_ids.into_iter().map(Into::into).collect();
+        let _ = self.responder.send(approved);
+    }
+
+    /// 批准全部调用
+    pub fn approve_all(self) {
+        let approved = self.calls.keys().cloned().collect();
+        let _ = self.responder.send(approved);
+    }
+
+    /// 拒绝全部调用
+    pub fn deny_all(self) {
+        let _ = self.responder.send(HashSet::new());
+    }
+}
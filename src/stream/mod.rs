@@ -0,0 +1,12 @@
+//! Agent 流式输出的适配器
+
+pub mod channel;
+pub mod event;
+pub mod sentence;
+pub mod sse;
+pub mod tee;
+
+pub use channel::forward_to_channel;
+pub use event::{StreamEvent, StreamInterrupted, ToolApprovalRequest};
+pub use sentence::StreamMode;
+pub use tee::tee;
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+
+/// 流式输出的分块方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamMode {
+    /// 原样输出模型返回的每个片段
+    #[default]
+    Token,
+    /// 缓冲片段，只在句子边界（`.`、`!`、`?`、换行、`。`、`！`、`？`）处输出
+    Sentence,
+}
+
+impl StreamMode {
+    /// 按当前模式包装一个文本流
+    pub fn apply<'a, S>(self, stream: S) -> Pin<Box<dyn Stream<Item = Result<String>> + 'a>>
+    where
+        S: Stream<Item = Result<String>> + 'a,
+    {
+        match self {
+            StreamMode::Token => Box::pin(stream),
+            StreamMode::Sentence => Box::pin(buffer_sentences(stream)),
+        }
+    }
+}
+
+fn is_sentence_boundary(c: char) -> bool {
+    matches!(c, '.' | '!' | '?' | '\n' | '。' | '！' | '？')
+}
+
+/// 从缓冲区头部取出所有完整的句子，连续的边界字符（如 `...`、`?!`）归入同一句
+fn drain_sentences(buffer: &mut String) -> Vec<String> {
+    let mut sentences = Vec::new();
+    loop {
+        let mut chars = buffer.char_indices().peekable();
+        let mut end = None;
+        while let Some((i, c)) = chars.next() {
+            if is_sentence_boundary(c) {
+                let mut last = i + c.len_utf8();
+                while let Some(&(j, next)) = chars.peek() {
+                    if !is_sentence_boundary(next) {
+                        break;
+                    }
+                    last = j + next.len_utf8();
+                    chars.next();
+                }
+                end = Some(last);
+                break;
+            }
+        }
+        match end {
+            Some(end) => sentences.push(buffer.drain(..end).collect()),
+            None => return sentences,
+        }
+    }
+}
+
+/// 将逐 token 的文本流转换为逐句输出的文本流
+///
+/// 流结束时输出剩余的不完整句子；遇到错误时先输出已缓冲的内容再传递错误。
+pub fn buffer_sentences<'a, S>(stream: S) -> impl Stream<Item = Result<String>> + 'a
+where
+    S: Stream<Item = Result<String>> + 'a,
+{
+    async_stream::stream! {
+        futures::pin_mut!(stream);
+        let mut buffer = String::new();
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(text) => {
+                    buffer.push_str(&text);
+                    for sentence in drain_sentences(&mut buffer) {
+                        yield Ok(sentence);
+                    }
+                }
+                Err(e) => {
+                    if !buffer.is_empty() {
+                        yield Ok(std::mem::take(&mut buffer));
+                    }
+                    yield Err(e);
+                }
+            }
+        }
+        if !buffer.is_empty() {
+            yield Ok(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn fragments(parts: &[&str]) -> impl Stream<Item = Result<String>> {
+        futures::stream::iter(parts.iter().map(|p| Ok(p.to_string())).collect::<Vec<_>>())
+    }
+
+    #[tokio::test]
+    async fn test_sentence_mode_yields_whole_sentences() {
+        let stream = fragments(&["Hel", "lo wor", "ld. How", " are", " you?"]);
+        let chunks: Vec<String> = StreamMode::Sentence
+            .apply(stream)
+            .map(|c| c.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec!["Hello world.", " How are you?"]);
+    }
+
+    #[tokio::test]
+    async fn test_sentence_mode_handles_cjk_and_trailing_text() {
+        let stream = fragments(&["你好", "。今天", "天气不错！！还有", "没说完"]);
+        let chunks: Vec<String> = buffer_sentences(stream).map(|c| c.unwrap()).collect().await;
+        assert_eq!(chunks, vec!["你好。", "今天天气不错！！", "还有没说完"]);
+    }
+
+    #[tokio::test]
+    async fn test_token_mode_passes_through() {
+        let stream = fragments(&["a", "b."]);
+        let chunks: Vec<String> = StreamMode::Token
+            .apply(stream)
+            .map(|c| c.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec!["a", "b."]);
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+
+/// 一个 Server-Sent Events 事件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SseEvent {
+    /// 一段文本输出，以默认的 `message` 事件发送
+    Data(String),
+    /// 流式输出出错，发送 `error` 事件后结束
+    Error(String),
+    /// 输出结束，发送 `done` 事件
+    Done,
+}
+
+impl SseEvent {
+    /// 按 SSE 协议格式化为一帧文本，多行内容会拆分为多个 `data:` 行
+    pub fn to_frame(&self) -> String {
+        let (event, data) = match self {
+            SseEvent::Data(data) => (None, data.as_str()),
+            SseEvent::Error(error) => (Some("error"), error.as_str()),
+            SseEvent::Done => (Some("done"), "[DONE]"),
+        };
+        let mut frame = String::new();
+        if let Some(event) = event {
+            frame.push_str("event: ");
+            frame.push_str(event);
+            frame.push('\n');
+        }
+        // split('\n') 保证空内容与末尾换行也会产生对应的 data 行
+        for line in data.split('\n') {
+            frame.push_str("data: ");
+            frame.push_str(line.strip_suffix('\r').unwrap_or(line));
+            frame.push('\n');
+        }
+        frame.push('\n');
+        frame
+    }
+}
+
+/// 将 `Agent::handle_message_stream` 的输出转换为 SSE 事件流
+///
+/// 每个文本块对应一个 `Data` 事件；遇到错误时发送 `Error` 事件并结束；
+/// 正常结束时追加一个 `Done` 事件。
+pub fn to_sse_events<'a, S>(stream: S) -> impl Stream<Item = SseEvent> + 'a
+where
+    S: Stream<Item = Result<String>> + 'a,
+{
+    async_stream::stream! {
+        futures::pin_mut!(stream);
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(text) => yield SseEvent::Data(text),
+                Err(e) => {
+                    yield SseEvent::Error(e.to_string());
+                    return;
+                }
+            }
+        }
+        yield SseEvent::Done;
+    }
+}
+
+/// 将 Agent 的输出转换为 SSE 字节流，可直接作为 HTTP 响应体，
+/// 例如 axum 的 `Body::from_stream` 或 hyper 的 `StreamBody`
+/// （需要同时设置 `Content-Type: text/event-stream` 响应头）
+#[cfg(feature = "sse-server")]
+pub fn to_sse_body<'a, S>(
+    stream: S,
+) -> impl Stream<Item = Result<bytes::Bytes, std::convert::Infallible>> + 'a
+where
+    S: Stream<Item = Result<String>> + 'a,
+{
+    to_sse_events(stream).map(|event| Ok(bytes::Bytes::from(event.to_frame())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_sse_frames_for_scripted_response() {
+        let chunks = futures::stream::iter(vec![
+            Ok("Hello".to_string()),
+            Ok(", world\nsecond line".to_string()),
+        ]);
+        let frames: Vec<String> = to_sse_events(chunks)
+            .map(|event| event.to_frame())
+            .collect()
+            .await;
+
+        assert_eq!(
+            frames,
+            vec![
+                "data: Hello\n\n",
+                "data: , world\ndata: second line\n\n",
+                "event: done\ndata: [DONE]\n\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sse_error_ends_stream() {
+        let chunks = futures::stream::iter(vec![
+            Ok("partial".to_string()),
+            Err(anyhow!("LLM request timed out")),
+            Ok("never sent".to_string()),
+        ]);
+        let events: Vec<SseEvent> = to_sse_events(chunks).collect().await;
+
+        assert_eq!(
+            events,
+            vec![
+                SseEvent::Data("partial".into()),
+                SseEvent::Error("LLM request timed out".into()),
+            ]
+        );
+        assert_eq!(
+            events[1].to_frame(),
+            "event: error\ndata: LLM request timed out\n\n"
+        );
+    }
+
+    #[cfg(feature = "sse-server")]
+    #[tokio::test]
+    async fn test_sse_body_bytes() {
+        let chunks = futures::stream::iter(vec![Ok("hi".to_string())]);
+        let body: Vec<bytes::Bytes> = to_sse_body(chunks)
+            .map(|frame| frame.unwrap())
+            .collect()
+            .await;
+        assert_eq!(body.concat(), b"data: hi\n\nevent: done\ndata: [DONE]\n\n");
+    }
+}
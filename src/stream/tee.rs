@@ -0,0 +1,68 @@
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+
+/// 将文本流原样转发给消费者，同时把每个文本片段交给 `sink`（如写日志、写数据库或发送到 channel）
+///
+/// 片段按到达顺序、在转发前交给 `sink`，错误不会交给 `sink`。只有被消费的片段才会到达 `sink`，
+/// 流被提前丢弃时 `sink` 也只收到已消费的部分。
+pub fn tee<'a, S, F>(stream: S, mut sink: F) -> impl Stream<Item = Result<String>> + 'a
+where
+    S: Stream<Item = Result<String>> + 'a,
+    F: FnMut(&str) + 'a,
+{
+    stream.map(move |chunk| {
+        if let Ok(text) = &chunk {
+            sink(text);
+        }
+        chunk
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_tee_feeds_sink_with_consumed_content() {
+        let stream = futures::stream::iter(vec![
+            Ok("Hello".to_string()),
+            Ok(", world".to_string()),
+            Err(anyhow!("connection reset")),
+            Ok("!".to_string()),
+        ]);
+        let mut logged = String::new();
+        let consumed: Vec<_> = tee(stream, |text| logged.push_str(text)).collect().await;
+
+        let text: String = consumed
+            .iter()
+            .filter_map(|c| c.as_ref().ok())
+            .cloned()
+            .collect();
+        assert_eq!(text, "Hello, world!");
+        assert_eq!(logged, text);
+        assert_eq!(
+            consumed[2].as_ref().unwrap_err().to_string(),
+            "connection reset"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tee_into_channel() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let stream = futures::stream::iter(vec![Ok("a".to_string()), Ok("b".to_string())]);
+        let consumed: Vec<String> = tee(stream, move |text| {
+            let _ = tx.send(text.to_string());
+        })
+        .map(|c| c.unwrap())
+        .collect()
+        .await;
+
+        let mut received = Vec::new();
+        while let Some(text) = rx.recv().await {
+            received.push(text);
+        }
+        assert_eq!(received, consumed);
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Result};
+use serde_json::{Number, Value};
+
+/// 工具参数的类型化访问器，取值失败时返回指明工具与字段名的错误
+///
+/// ```ignore
+/// let args = ToolArgs::new(self.name(), args);
+/// let op = args.require_str("op")?;
+/// let precision = args.opt_f64("precision")?.unwrap_or(2.0);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolArgs {
+    tool: String,
+    value: Value,
+}
+
+impl ToolArgs {
+    pub fn new(tool: impl Into<String>, value: Value) -> Self {
+        Self {
+            tool: tool.into(),
+            value,
+        }
+    }
+
+    /// 原始参数
+    pub fn as_value(&self) -> &Value {
+        &self.value
+    }
+
+    pub fn into_inner(self) -> Value {
+        self.value
+    }
+
+    pub fn require_str(&self, field: &str) -> Result<&str> {
+        self.require(field, "a string", Value::as_str)
+    }
+
+    pub fn require_f64(&self, field: &str) -> Result<f64> {
+        self.require(field, "a number", Value::as_f64)
+    }
+
+    pub fn require_i64(&self, field: &str) -> Result<i64> {
+        self.require(field, "an integer", Value::as_i64)
+    }
+
+    /// 保留整数与浮点数区别的数字，配合 `apply_arithmetic` 使用
+    pub fn require_number(&self, field: &str) -> Result<&Number> {
+        self.require(field, "a number", Value::as_number)
+    }
+
+    pub fn require_bool(&self, field: &str) -> Result<bool> {
+        self.require(field, "a boolean", Value::as_bool)
+    }
+
+    /// 字段缺失或为 null 时返回 `None`，类型不符时仍然报错
+    pub fn opt_str(&self, field: &str) -> Result<Option<&str>> {
+        self.optional(field, "a string", Value::as_str)
+    }
+
+    pub fn opt_f64(&self, field: &str) -> Result<Option<f64>> {
+        self.optional(field, "a number", Value::as_f64)
+    }
+
+    pub fn opt_i64(&self, field: &str) -> Result<Option<i64>> {
+        self.optional(field, "an integer", Value::as_i64)
+    }
+
+    pub fn opt_number(&self, field: &str) -> Result<Option<&Number>> {
+        self.optional(field, "a number", Value::as_number)
+    }
+
+    pub fn opt_bool(&self, field: &str) -> Result<Option<bool>> {
+        self.optional(field, "a boolean", Value::as_bool)
+    }
+
+    fn require<'a, T>(
+        &'a self,
+        field: &str,
+        expected: &str,
+        convert: impl Fn(&'a Value) -> Option<T>,
+    ) -> Result<T> {
+        self.optional(field, expected, convert)?
+            .ok_or_else(|| anyhow!("tool `{}`: missing required argument `{field}`", self.tool))
+    }
+
+    fn optional<'a, T>(
+        &'a self,
+        field: &str,
+        expected: &str,
+        convert: impl Fn(&'a Value) -> Option<T>,
+    ) -> Result<Option<T>> {
+        match self.value.get(field) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => convert(value).map(Some).ok_or_else(|| {
+                anyhow!(
+                    "tool `{}`: argument `{field}` must be {expected}, got {}",
+                    self.tool,
+                    type_name(value)
+                )
+            }),
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_tool_args_errors_name_tool_and_field() {
+        let args = ToolArgs::new(
+            "calculator",
+            json!({"op": "add", "num1": "three", "num2": 4, "note": null}),
+        );
+
+        assert_eq!(args.require_str("op").unwrap(), "add");
+        assert_eq!(args.require_f64("num2").unwrap(), 4.0);
+        assert_eq!(args.require_number("num2").unwrap().to_string(), "4");
+        assert_eq!(args.opt_str("note").unwrap(), None);
+        assert_eq!(args.opt_f64("missing").unwrap(), None);
+
+        assert_eq!(
+            args.require_f64("num1").unwrap_err().to_string(),
+            "tool `calculator`: argument `num1` must be a number, got a string"
+        );
+        assert_eq!(
+            args.require_str("precision").unwrap_err().to_string(),
+            "tool `calculator`: missing required argument `precision`"
+        );
+        assert_eq!(
+            args.opt_str("num2").unwrap_err().to_string(),
+            "tool `calculator`: argument `num2` must be a string, got a number"
+        );
+    }
+}
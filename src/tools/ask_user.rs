@@ -0,0 +1,82 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::Value;
+
+use super::{Tool, ToolArgs};
+
+/// `AskUserTool` 的工具名称
+pub const ASK_USER_TOOL: &str = "ask_user";
+
+/// 请求用户澄清的工具
+///
+/// 注册后，`Agent::handle_message` 遇到对它的调用时不会执行，而是进入
+/// `AgentState::WaitingForUserInput` 并把问题作为本次回复返回；用户的下一条消息
+/// 会作为该调用的结果交给模型。在不支持暂停的场景（如流式处理）下按普通工具执行，
+/// 提示模型自行做出合理假设。
+#[derive(Debug, Clone, Default)]
+pub struct AskUserTool;
+
+impl AskUserTool {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// 从调用参数中取出问题
+    pub fn question(args: &Value) -> Result<String> {
+        let args = ToolArgs::new(ASK_USER_TOOL, args.clone());
+        Ok(args.require_str("question")?.to_string())
+    }
+}
+
+#[async_trait]
+impl Tool for AskUserTool {
+    fn name(&self) -> String {
+        ASK_USER_TOOL.to_string()
+    }
+
+    fn description(&self) -> Option<String> {
+        Some(
+            "Ask the user a clarifying question when the request is ambiguous, instead of guessing"
+                .to_string(),
+        )
+    }
+
+    fn args_schema(&self) -> Option<Value> {
+        Some(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "question": {
+                    "type": "string",
+                    "description": "The question to ask the user"
+                }
+            },
+            "required": ["question"]
+        }))
+    }
+
+    async fn execute(&self, args: Value) -> Result<String> {
+        Self::question(&args)?;
+        Ok("The user is not available to answer right now. \
+            Proceed with the most reasonable assumption and state it in your reply."
+            .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_execute_without_agent_asks_model_to_assume() {
+        let tool = AskUserTool::new();
+        let output = tool
+            .execute(json!({"question": "Which city?"}))
+            .await
+            .unwrap();
+        assert!(output.contains("assumption"));
+
+        let err = tool.execute(json!({})).await.unwrap_err();
+        assert!(err.to_string().contains("question"));
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{FixedOffset, Utc};
+use serde_json::Value;
+
+use super::{Tool, ToolArgs};
+
+/// 返回当前时间的工具
+///
+/// 默认返回 RFC 3339 格式的 UTC 时间，可选参数：
+/// - `utc_offset`: 时区偏移，例如 `+08:00`、`-05:00`
+/// - `format`: strftime 风格的格式字符串，例如 `%Y-%m-%d %H:%M:%S`
+#[derive(Debug, Clone, Default)]
+pub struct ClockTool;
+
+impl ClockTool {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+/// 解析 `+08:00` / `-0530` / `Z` 形式的时区偏移
+fn parse_utc_offset(offset: &str) -> Result<FixedOffset> {
+    if offset.eq_ignore_ascii_case("z") || offset.eq_ignore_ascii_case("utc") {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+    let invalid = || anyhow!("Invalid 'utc_offset' argument: {offset}");
+    let (sign, rest) = match offset.as_bytes().first() {
+        Some(b'+') => (1, &offset[1..]),
+        Some(b'-') => (-1, &offset[1..]),
+        _ => return Err(invalid()),
+    };
+    let digits = rest.replace(':', "");
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let hours: i32 = digits[..2].parse()?;
+    let minutes: i32 = digits[2..].parse()?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+#[async_trait]
+impl Tool for ClockTool {
+    fn name(&self) -> String {
+        "clock".to_string()
+    }
+
+    fn description(&self) -> Option<String> {
+        Some("Returns the current date and time (UTC by default)".to_string())
+    }
+
+    fn args_schema(&self) -> Option<Value> {
+        Some(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "utc_offset": {
+                    "type": "string",
+                    "description": "Timezone offset from UTC, e.g. \"+08:00\""
+                },
+                "format": {
+                    "type": "string",
+                    "description": "strftime-style format string, e.g. \"%Y-%m-%d %H:%M:%S\". Defaults to RFC 3339"
+                }
+            }
+        }))
+    }
+
+    async fn execute(&self, args: Value) -> Result<String> {
+        let args = ToolArgs::new(self.name(), args);
+        let offset = match args.opt_str("utc_offset")? {
+            Some(offset) => parse_utc_offset(offset)?,
+            None => FixedOffset::east_opt(0).unwrap(),
+        };
+        let now = Utc::now().with_timezone(&offset);
+
+        match args.opt_str("format")? {
+            Some(format) => {
+                use std::fmt::Write;
+                let mut output = String::new();
+                write!(output, "{}", now.format(format))
+                    .map_err(|_| anyhow!("Invalid 'format' argument: {format}"))?;
+                Ok(output)
+            }
+            None => Ok(now.to_rfc3339()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, NaiveDate};
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_clock_tool_returns_parseable_timestamp() {
+        let tool = ClockTool::new();
+        let output = tool.execute(serde_json::json!({})).await.unwrap();
+        let parsed = DateTime::parse_from_rfc3339(&output).unwrap();
+        assert_eq!(parsed.offset().local_minus_utc(), 0);
+        assert!(
+            (Utc::now() - parsed.with_timezone(&Utc))
+                .num_seconds()
+                .abs()
+                < 5
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clock_tool_honors_format_and_offset() {
+        let tool = ClockTool::new();
+        let output = tool
+            .execute(serde_json::json!({"format": "%Y-%m-%d"}))
+            .await
+            .unwrap();
+        assert!(NaiveDate::parse_from_str(&output, "%Y-%m-%d").is_ok());
+
+        let output = tool
+            .execute(serde_json::json!({"utc_offset": "+08:00"}))
+            .await
+            .unwrap();
+        let parsed = DateTime::parse_from_rfc3339(&output).unwrap();
+        assert_eq!(parsed.offset().local_minus_utc(), 8 * 3600);
+
+        let result = tool
+            .execute(serde_json::json!({"utc_offset": "eight"}))
+            .await;
+        assert!(result.is_err());
+        let result = tool.execute(serde_json::json!({"format": "%Q"})).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,742 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use super::{abs_number, compare_numbers, serialize_json_output, JsonOutputLimits, Tool, ToolArgs};
+
+/// 用 jq 风格的过滤器提取或变换 JSON 数据的工具
+///
+/// 支持 jq 的一个常用子集：
+/// - 路径：`.`、`.foo`、`."key with space"`、`.[0]`、`.[-1]`、`.[1:3]`、`.[]`
+/// - 管道 `|`、括号、收集为数组 `[ ... ]`
+/// - 比较 `==`、`!=`、`<`、`<=`、`>`、`>=`，以及字符串、数字、`true`/`false`/`null` 字面量
+/// - 函数 `select(f)`、`map(f)`、`length`、`keys`
+///
+/// 每个结果序列化为一行 JSON，多个结果以换行分隔；序列化受 `JsonOutputLimits` 限制。
+#[derive(Debug, Clone, Default)]
+pub struct JsonTool {
+    limits: JsonOutputLimits,
+}
+
+impl JsonTool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 设置每个结果的序列化限制
+    pub fn with_output_limits(mut self, limits: JsonOutputLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+#[async_trait]
+impl Tool for JsonTool {
+    fn name(&self) -> String {
+        "json".to_string()
+    }
+
+    fn description(&self) -> Option<String> {
+        Some(
+            "Extracts or transforms data from a JSON document with a jq-style filter, \
+             e.g. `.items[] | select(.price > 10) | .name`"
+                .to_string(),
+        )
+    }
+
+    fn args_schema(&self) -> Option<Value> {
+        Some(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "input": {
+                    "description": "The JSON document, either as a JSON value or as a string containing JSON"
+                },
+                "filter": {
+                    "type": "string",
+                    "description": "jq-style filter. Supports paths (.a.b, .[0], .[1:3], .[]), |, [ ], comparisons, select(), map(), length and keys. Defaults to ."
+                }
+            },
+            "required": ["input"]
+        }))
+    }
+
+    async fn execute(&self, args: Value) -> Result<String> {
+        let args = ToolArgs::new(self.name(), args);
+        let filter = args.opt_str("filter")?.unwrap_or(".");
+        let input = match args.as_value().get("input") {
+            None | Some(Value::Null) => {
+                bail!("tool `{}`: missing required argument `input`", self.name())
+            }
+            // 模型常把 JSON 作为字符串传入
+            Some(Value::String(text)) => {
+                serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.clone()))
+            }
+            Some(value) => value.clone(),
+        };
+        let results = apply_filter(filter, &input)?;
+        Ok(results
+            .iter()
+            .map(|value| serialize_json_output(value, &self.limits))
+            .collect::<Result<Vec<_>>>()?
+            .join("\n"))
+    }
+}
+
+/// 对 `input` 执行 jq 风格的过滤器，返回全部结果
+pub fn apply_filter(filter: &str, input: &Value) -> Result<Vec<Value>> {
+    let tokens = tokenize(filter).map_err(|e| anyhow!("invalid filter `{filter}`: {e}"))?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    let ast = parser
+        .parse()
+        .map_err(|e| anyhow!("invalid filter `{filter}`: {e}"))?;
+    eval(&ast, input).map_err(|e| anyhow!("filter `{filter}` failed: {e}"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Dot,
+    Field(String),
+    Ident(String),
+    Str(String),
+    Num(f64),
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Pipe,
+    Colon,
+    Cmp(CmpOp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn tokenize(filter: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = filter.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '.' if next.is_some_and(is_ident_start) => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                tokens.push(Token::Field(chars[start..i].iter().collect()));
+            }
+            '.' if next == Some('"') => {
+                let (text, end) = read_string(&chars, i + 1)?;
+                tokens.push(Token::Field(text));
+                i = end;
+            }
+            '.' => {
+                tokens.push(Token::Dot);
+                i += 1;
+            }
+            '"' => {
+                let (text, end) = read_string(&chars, i)?;
+                tokens.push(Token::Str(text));
+                i = end;
+            }
+            '[' | ']' | '(' | ')' | '|' | ':' => {
+                tokens.push(match c {
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '|' => Token::Pipe,
+                    _ => Token::Colon,
+                });
+                i += 1;
+            }
+            '=' | '!' | '<' | '>' => {
+                let (op, len) = match (c, next) {
+                    ('=', Some('=')) => (CmpOp::Eq, 2),
+                    ('!', Some('=')) => (CmpOp::Ne, 2),
+                    ('<', Some('=')) => (CmpOp::Le, 2),
+                    ('>', Some('=')) => (CmpOp::Ge, 2),
+                    ('<', _) => (CmpOp::Lt, 1),
+                    ('>', _) => (CmpOp::Gt, 1),
+                    _ => bail!("unexpected `{c}` at position {i}"),
+                };
+                tokens.push(Token::Cmp(op));
+                i += len;
+            }
+            c if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let number = text
+                    .parse()
+                    .map_err(|_| anyhow!("invalid number `{text}`"))?;
+                tokens.push(Token::Num(number));
+            }
+            c if is_ident_start(c) => {
+                let start = i;
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            _ => bail!("unexpected `{c}` at position {i}"),
+        }
+    }
+    Ok(tokens)
+}
+
+/// 读取从 `start`（指向开头的引号）开始的字符串字面量，返回内容与结束后的位置
+fn read_string(chars: &[char], start: usize) -> Result<(String, usize)> {
+    let mut i = start + 1;
+    let mut escaped = false;
+    while i < chars.len() {
+        match chars[i] {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => {
+                let literal: String = chars[start..=i].iter().collect();
+                let text = serde_json::from_str(&literal)
+                    .map_err(|_| anyhow!("invalid string literal {literal}"))?;
+                return Ok((text, i + 1));
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    bail!("unterminated string")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Identity,
+    Field(String),
+    Index(i64),
+    Slice(Option<i64>, Option<i64>),
+    Iterate,
+    Literal(Value),
+    /// 依次执行的各段；连续的管道展开为一层，避免长管道链形成很深的语法树
+    Pipe(Vec<Filter>),
+    Collect(Box<Filter>),
+    Compare(Box<Filter>, CmpOp, Box<Filter>),
+    Select(Box<Filter>),
+    Length,
+    Keys,
+}
+
+impl Filter {
+    fn pipe(self, next: Filter) -> Filter {
+        match self {
+            Filter::Identity => next,
+            Filter::Pipe(mut stages) => {
+                stages.push(next);
+                Filter::Pipe(stages)
+            }
+            first => Filter::Pipe(vec![first, next]),
+        }
+    }
+}
+
+/// 括号、`[ ... ]`、`select`/`map` 允许嵌套的最大层数
+///
+/// 解析与求值都按嵌套层数递归，模型给出的过滤器嵌套过深时返回错误，而不是耗尽栈空间。
+const MAX_FILTER_DEPTH: usize = 64;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// 当前所在的嵌套层数
+    depth: usize,
+}
+
+impl Parser {
+    fn parse(&mut self) -> Result<Filter> {
+        let filter = self.parse_pipe()?;
+        match self.peek() {
+            None => Ok(filter),
+            Some(token) => bail!("unexpected {token:?}"),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => bail!("expected {expected:?}, found {token:?}"),
+            None => bail!("expected {expected:?}, found end of filter"),
+        }
+    }
+
+    /// 解析括号等嵌套结构内部的过滤器，超过 `MAX_FILTER_DEPTH` 层时报错
+    fn parse_nested(&mut self) -> Result<Filter> {
+        if self.depth >= MAX_FILTER_DEPTH {
+            bail!("filter is nested more than {MAX_FILTER_DEPTH} levels deep");
+        }
+        self.depth += 1;
+        let filter = self.parse_pipe();
+        self.depth -= 1;
+        filter
+    }
+
+    fn parse_pipe(&mut self) -> Result<Filter> {
+        let mut filter = self.parse_comparison()?;
+        while self.peek() == Some(&Token::Pipe) {
+            self.pos += 1;
+            filter = filter.pipe(self.parse_comparison()?);
+        }
+        Ok(filter)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Filter> {
+        let left = self.parse_postfix()?;
+        if let Some(Token::Cmp(op)) = self.peek().cloned() {
+            self.pos += 1;
+            let right = self.parse_postfix()?;
+            return Ok(Filter::Compare(Box::new(left), op, Box::new(right)));
+        }
+        Ok(left)
+    }
+
+    fn parse_postfix(&mut self) -> Result<Filter> {
+        let mut filter = self.parse_primary()?;
+        loop {
+            match self.peek() {
+                Some(Token::Field(name)) => {
+                    filter = filter.pipe(Filter::Field(name.clone()));
+                    self.pos += 1;
+                }
+                Some(Token::LBracket) => {
+                    self.pos += 1;
+                    filter = filter.pipe(self.parse_brackets()?);
+                }
+                _ => return Ok(filter),
+            }
+        }
+    }
+
+    /// 解析 `[` 之后的下标、切片或 `[]`
+    fn parse_brackets(&mut self) -> Result<Filter> {
+        let filter = match self.next() {
+            Some(Token::RBracket) => return Ok(Filter::Iterate),
+            Some(Token::Str(key)) => Filter::Field(key),
+            Some(Token::Colon) => Filter::Slice(None, Some(self.parse_integer()?)),
+            Some(Token::Num(n)) => {
+                let start = as_integer(n)?;
+                if self.peek() == Some(&Token::Colon) {
+                    self.pos += 1;
+                    let end = match self.peek() {
+                        Some(Token::RBracket) => None,
+                        _ => Some(self.parse_integer()?),
+                    };
+                    Filter::Slice(Some(start), end)
+                } else {
+                    Filter::Index(start)
+                }
+            }
+            Some(token) => bail!("unexpected {token:?} in brackets"),
+            None => bail!("unterminated brackets"),
+        };
+        self.expect(Token::RBracket)?;
+        Ok(filter)
+    }
+
+    fn parse_integer(&mut self) -> Result<i64> {
+        match self.next() {
+            Some(Token::Num(n)) => as_integer(n),
+            Some(token) => bail!("expected an integer, found {token:?}"),
+            None => bail!("expected an integer, found end of filter"),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Filter> {
+        match self.next() {
+            Some(Token::Dot) => Ok(Filter::Identity),
+            Some(Token::Field(name)) => Ok(Filter::Field(name)),
+            Some(Token::Str(text)) => Ok(Filter::Literal(Value::String(text))),
+            Some(Token::Num(n)) => Ok(Filter::Literal(serde_json::json!(n))),
+            Some(Token::LParen) => {
+                let filter = self.parse_nested()?;
+                self.expect(Token::RParen)?;
+                Ok(filter)
+            }
+            Some(Token::LBracket) => {
+                if self.peek() == Some(&Token::RBracket) {
+                    self.pos += 1;
+                    return Ok(Filter::Literal(Value::Array(Vec::new())));
+                }
+                let filter = self.parse_nested()?;
+                self.expect(Token::RBracket)?;
+                Ok(Filter::Collect(Box::new(filter)))
+            }
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Filter::Literal(Value::Bool(true))),
+                "false" => Ok(Filter::Literal(Value::Bool(false))),
+                "null" => Ok(Filter::Literal(Value::Null)),
+                "length" => Ok(Filter::Length),
+                "keys" => Ok(Filter::Keys),
+                "select" | "map" => {
+                    self.expect(Token::LParen)?;
+                    let inner = self.parse_nested()?;
+                    self.expect(Token::RParen)?;
+                    Ok(if name == "select" {
+                        Filter::Select(Box::new(inner))
+                    } else {
+                        Filter::Collect(Box::new(Filter::Iterate.pipe(inner)))
+                    })
+                }
+                _ => bail!("unknown function `{name}`"),
+            },
+            Some(token) => bail!("unexpected {token:?}"),
+            None => bail!("unexpected end of filter"),
+        }
+    }
+}
+
+fn as_integer(n: f64) -> Result<i64> {
+    if n.fract() != 0.0 {
+        bail!("expected an integer, found {n}");
+    }
+    Ok(n as i64)
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// 将可能为负的下标换算为数组中的位置
+fn resolve_index(index: i64, len: usize) -> i64 {
+    if index < 0 {
+        len as i64 + index
+    } else {
+        index
+    }
+}
+
+fn eval(filter: &Filter, input: &Value) -> Result<Vec<Value>> {
+    Ok(match filter {
+        Filter::Identity => vec![input.clone()],
+        Filter::Field(name) => match input {
+            Value::Object(map) => vec![map.get(name).cloned().unwrap_or(Value::Null)],
+            Value::Null => vec![Value::Null],
+            other => bail!("cannot index {} with \"{name}\"", type_name(other)),
+        },
+        Filter::Index(index) => match input {
+            Value::Array(items) => {
+                let index = resolve_index(*index, items.len());
+                let item = usize::try_from(index).ok().and_then(|i| items.get(i));
+                vec![item.cloned().unwrap_or(Value::Null)]
+            }
+            Value::Null => vec![Value::Null],
+            other => bail!("cannot index {} with a number", type_name(other)),
+        },
+        Filter::Slice(start, end) => match input {
+            Value::Array(items) => {
+                let len = items.len() as i64;
+                let clamp = |i: i64| resolve_index(i, items.len()).clamp(0, len) as usize;
+                let start = start.map_or(0, clamp);
+                let end = end.map_or(items.len(), clamp).max(start);
+                vec![Value::Array(items[start..end].to_vec())]
+            }
+            Value::Null => vec![Value::Null],
+            other => bail!("cannot slice {}", type_name(other)),
+        },
+        Filter::Iterate => match input {
+            Value::Array(items) => items.clone(),
+            Value::Object(map) => map.values().cloned().collect(),
+            other => bail!("cannot iterate over {}", type_name(other)),
+        },
+        Filter::Literal(value) => vec![value.clone()],
+        Filter::Pipe(stages) => {
+            let mut results = vec![input.clone()];
+            for stage in stages {
+                let mut next = Vec::new();
+                for value in &results {
+                    next.extend(eval(stage, value)?);
+                }
+                results = next;
+            }
+            results
+        }
+        Filter::Collect(inner) => vec![Value::Array(eval(inner, input)?)],
+        Filter::Compare(left, op, right) => {
+            let mut results = Vec::new();
+            for l in eval(left, input)? {
+                for r in eval(right, input)? {
+                    results.push(Value::Bool(compare(&l, *op, &r)?));
+                }
+            }
+            results
+        }
+        Filter::Select(condition) => {
+            let keep = eval(condition, input)?
+                .iter()
+                .any(|v| !matches!(v, Value::Null | Value::Bool(false)));
+            if keep {
+                vec![input.clone()]
+            } else {
+                Vec::new()
+            }
+        }
+        Filter::Length => vec![match input {
+            Value::Null => serde_json::json!(0),
+            Value::Bool(_) => bail!("boolean has no length"),
+            Value::Number(n) => Value::Number(abs_number(n)),
+            Value::String(text) => serde_json::json!(text.chars().count()),
+            Value::Array(items) => serde_json::json!(items.len()),
+            Value::Object(map) => serde_json::json!(map.len()),
+        }],
+        Filter::Keys => vec![match input {
+            Value::Object(map) => {
+                let mut keys: Vec<_> = map.keys().cloned().map(Value::String).collect();
+                keys.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+                Value::Array(keys)
+            }
+            Value::Array(items) => (0..items.len()).map(|i| serde_json::json!(i)).collect(),
+            other => bail!("{} has no keys", type_name(other)),
+        }],
+    })
+}
+
+fn compare(left: &Value, op: CmpOp, right: &Value) -> Result<bool> {
+    let ordering = match (left, right) {
+        (Value::Number(l), Value::Number(r)) => compare_numbers(l, r),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        _ => None,
+    };
+    Ok(match (op, ordering) {
+        (CmpOp::Eq, Some(ordering)) => ordering == Ordering::Equal,
+        (CmpOp::Ne, Some(ordering)) => ordering != Ordering::Equal,
+        (CmpOp::Eq, None) => left == right,
+        (CmpOp::Ne, None) => left != right,
+        (_, None) => bail!(
+            "cannot compare {} with {}",
+            type_name(left),
+            type_name(right)
+        ),
+        (CmpOp::Lt, Some(ordering)) => ordering == Ordering::Less,
+        (CmpOp::Le, Some(ordering)) => ordering != Ordering::Greater,
+        (CmpOp::Gt, Some(ordering)) => ordering == Ordering::Greater,
+        (CmpOp::Ge, Some(ordering)) => ordering != Ordering::Less,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn catalog() -> Value {
+        json!({
+            "store": "corner shop",
+            "items": [
+                {"name": "apple", "price": 3, "tags": ["fruit"]},
+                {"name": "cheese", "price": 12, "tags": ["dairy"]},
+                {"name": "wine", "price": 25, "tags": []}
+            ]
+        })
+    }
+
+    #[test]
+    fn test_json_filter_extracts_paths() {
+        let input = catalog();
+        assert_eq!(apply_filter(".", &input).unwrap(), vec![input.clone()]);
+        assert_eq!(
+            apply_filter(".store", &input).unwrap(),
+            vec![json!("corner shop")]
+        );
+        assert_eq!(
+            apply_filter(".items[0].name", &input).unwrap(),
+            vec![json!("apple")]
+        );
+        assert_eq!(
+            apply_filter(".items[-1].price", &input).unwrap(),
+            vec![json!(25)]
+        );
+        assert_eq!(
+            apply_filter(".items[1:][].name", &input).unwrap(),
+            vec![json!("cheese"), json!("wine")]
+        );
+        assert_eq!(
+            apply_filter(".[\"store\"]", &input).unwrap(),
+            vec![json!("corner shop")]
+        );
+        assert_eq!(
+            apply_filter("[.items[].name] | length", &input).unwrap(),
+            vec![json!(3)]
+        );
+        assert_eq!(
+            apply_filter(".missing.deeper", &input).unwrap(),
+            vec![Value::Null]
+        );
+        assert_eq!(
+            apply_filter("keys", &input).unwrap(),
+            vec![json!(["items", "store"])]
+        );
+    }
+
+    #[test]
+    fn test_json_filter_keeps_integers_exact() {
+        let input =
+            json!({"delta": -3, "ids": [9_007_199_254_740_993_i64, 9_007_199_254_740_992_i64]});
+        // 数字的 length 是其绝对值，整数不会变成浮点数
+        assert_eq!(
+            apply_filter(".delta | length", &input).unwrap(),
+            vec![json!(3)]
+        );
+        // 大整数之间精确比较，不经过 f64
+        assert_eq!(
+            apply_filter(".ids[0] > .ids[1]", &input).unwrap(),
+            vec![json!(true)]
+        );
+    }
+
+    #[test]
+    fn test_json_filter_selects_and_maps() {
+        let input = catalog();
+        assert_eq!(
+            apply_filter(".items[] | select(.price > 10) | .name", &input).unwrap(),
+            vec![json!("cheese"), json!("wine")]
+        );
+        assert_eq!(
+            apply_filter(".items | map(select(.name == \"apple\") | .price)", &input).unwrap(),
+            vec![json!([3])]
+        );
+        assert_eq!(
+            apply_filter(".items | map(.tags | length)", &input).unwrap(),
+            vec![json!([1, 1, 0])]
+        );
+    }
+
+    #[test]
+    fn test_json_filter_reports_invalid_paths() {
+        let input = catalog();
+        let err = apply_filter(".items[", &input).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid filter `.items[`: unterminated brackets"
+        );
+        let err = apply_filter(".store[0]", &input).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "filter `.store[0]` failed: cannot index string with a number"
+        );
+        assert!(apply_filter("frobnicate", &input).is_err());
+    }
+
+    #[test]
+    fn test_json_filter_rejects_deep_nesting() {
+        let input = catalog();
+        // 嵌套过深时返回错误，而不是递归耗尽栈空间
+        for (open, close) in [("(", ")"), ("[", "]"), ("map(", ")")] {
+            let filter = format!("{}.{}", open.repeat(50_000), close.repeat(50_000));
+            let err = apply_filter(&filter, &input).unwrap_err();
+            assert!(err
+                .to_string()
+                .ends_with("filter is nested more than 64 levels deep"));
+        }
+        let filter = format!("{}.store{}", "(".repeat(64), ")".repeat(64));
+        assert_eq!(
+            apply_filter(&filter, &input).unwrap(),
+            vec![json!("corner shop")]
+        );
+
+        // 很长的管道链不会形成深层的语法树
+        let filter = vec![".items"; 50_000].join(" | ");
+        assert!(apply_filter(&filter, &input).is_err());
+        let filter = format!(".items{}", " | .".repeat(50_000));
+        assert_eq!(apply_filter(&filter, &input).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_json_tool_accepts_json_strings() {
+        let tool = JsonTool::new();
+        let output = tool
+            .execute(json!({
+                "input": catalog().to_string(),
+                "filter": ".items[] | select(.tags | length == 0) | .name"
+            }))
+            .await
+            .unwrap();
+        assert_eq!(output, "\"wine\"");
+
+        let output = tool
+            .execute(json!({"input": {"a": [1, 2]}, "filter": ".a[]"}))
+            .await
+            .unwrap();
+        assert_eq!(output, "1\n2");
+
+        let err = tool.execute(json!({"filter": "."})).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tool `json`: missing required argument `input`"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_tool_guards_large_output() {
+        let tool = JsonTool::new().with_output_limits(JsonOutputLimits {
+            max_depth: 2,
+            max_bytes: 16,
+        });
+        let err = tool
+            .execute(json!({"input": {"a": {"b": {"c": 1}}}}))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("nested deeper than 2 levels"));
+
+        let output = tool
+            .execute(json!({"input": {"a": {"b": {"c": 1}}}, "filter": ".a"}))
+            .await
+            .unwrap();
+        assert_eq!(output, "{\"b\":{\"c\":1}}");
+
+        let output = tool
+            .execute(json!({"input": [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]}))
+            .await
+            .unwrap();
+        assert!(output.starts_with("[1,2,3,4,5,6,7,8"));
+        assert!(output.ends_with("[truncated: JSON output exceeds 16 bytes]"));
+    }
+}
@@ -1,7 +1,49 @@
+pub mod args;
+pub mod ask_user;
+pub mod clock;
+pub mod json;
+pub mod number;
+pub mod output;
+pub mod schema;
+pub mod scratchpad;
+
+pub use args::ToolArgs;
+pub use ask_user::{AskUserTool, ASK_USER_TOOL};
+pub use clock::ClockTool;
+pub use json::JsonTool;
+pub use number::{abs_number, apply_arithmetic, compare_numbers, Arithmetic};
+pub use output::{serialize_json_output, JsonOutputLimits};
+pub use schema::validate_schema;
+pub use scratchpad::ScratchpadTool;
+
 use anyhow::Result;
 use async_trait::async_trait;
+use futures::Stream;
 use serde_json::Value;
 use std::fmt::Debug;
+use std::pin::Pin;
+
+/// 工具调用的相对开销（费用与耗时），写入工具清单供模型在多个可用工具间取舍
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum ToolCost {
+    Cheap,
+    #[default]
+    Moderate,
+    Expensive,
+}
+
+impl ToolCost {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ToolCost::Cheap => "cheap",
+            ToolCost::Moderate => "moderate",
+            ToolCost::Expensive => "expensive",
+        }
+    }
+}
+
+/// `Tool::execute_stream` 产出的输出片段流
+pub type ToolOutputStream<'a> = Pin<Box<dyn Stream<Item = Result<String>> + Send + 'a>>;
 
 #[async_trait]
 pub trait Tool: Send + Sync + Debug {
@@ -16,6 +58,91 @@ pub trait Tool: Send + Sync + Debug {
 
     /// 执行工具
     async fn execute(&self, args: Value) -> Result<String>;
+
+    /// 流式执行，输出逐段产出，拼接后即为完整输出
+    ///
+    /// 开启 `AgentConfig::partial_tool_output_after` 时 Agent 通过该方法执行工具，
+    /// 到时仍未结束的工具以已产出的部分作为结果。默认实现将 `execute` 的结果作为唯一的一段。
+    fn execute_stream(&self, args: Value) -> ToolOutputStream<'_> {
+        Box::pin(futures::stream::once(self.execute(args)))
+    }
+
+    /// 调用开销的提示，开启 `AgentConfig::include_tool_manifest` 时写入工具清单
+    fn cost_hint(&self) -> ToolCost {
+        ToolCost::default()
+    }
+
+    /// 工具执行是否会长时间占用 CPU（解析、加密、图像处理等）
+    ///
+    /// 为 true 时，Agent 通过 `tokio::task::spawn_blocking` 在阻塞线程池中执行该工具，
+    /// 运行时的工作线程（包括单线程运行时）以及同一轮中并行执行的其他工具都不会因此停滞。
+    /// 轮次被取消或超时后 Agent 不再等待其结果，但已经开始的执行会在阻塞线程上继续到结束。
+    fn is_blocking(&self) -> bool {
+        false
+    }
+
+    /// 异步初始化（建立连接、认证等），由 Agent 在处理第一条消息前调用一次；
+    /// 失败时工具保持未初始化，下次处理消息时重试
+    async fn init(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// 以新的名称与描述包装已有工具，参数 schema 与执行逻辑仍由原工具提供
+#[derive(Debug, Clone)]
+pub struct RenamedTool<T> {
+    name: String,
+    description: String,
+    inner: T,
+}
+
+impl<T: Tool> RenamedTool<T> {
+    pub fn new(name: impl Into<String>, description: impl Into<String>, inner: T) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            inner,
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<T: Tool> Tool for RenamedTool<T> {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn description(&self) -> Option<String> {
+        Some(self.description.clone())
+    }
+
+    fn args_schema(&self) -> Option<Value> {
+        self.inner.args_schema()
+    }
+
+    async fn execute(&self, args: Value) -> Result<String> {
+        self.inner.execute(args).await
+    }
+
+    fn execute_stream(&self, args: Value) -> ToolOutputStream<'_> {
+        self.inner.execute_stream(args)
+    }
+
+    fn cost_hint(&self) -> ToolCost {
+        self.inner.cost_hint()
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.inner.is_blocking()
+    }
+
+    async fn init(&mut self) -> Result<()> {
+        self.inner.init().await
+    }
 }
 
 #[cfg(test)]
@@ -72,7 +199,7 @@ pub(crate) mod tests {
 
         // Test tool metadata
         assert_eq!(tool.name(), "echo");
-        assert!(!tool.description().is_none());
+        assert!(tool.description().is_some());
 
         // Test successful execution
         let args = serde_json::json!({"text": "Hello, World!"});
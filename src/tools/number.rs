@@ -0,0 +1,140 @@
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Number;
+
+/// 计算类工具支持的四则运算
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arithmetic {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// 对两个 JSON 数字做四则运算，保留整数与浮点数的区别
+///
+/// 两个操作数都是整数时按 `i64` 精确计算，结果仍是整数（如 `2 + 2` 得到 `4` 而不是 `4.0`），
+/// 溢出或除不尽时才改用浮点数；任一操作数为浮点数时按 `f64` 计算，不做舍入。
+pub fn apply_arithmetic(op: Arithmetic, left: &Number, right: &Number) -> Result<Number> {
+    if let (Some(l), Some(r)) = (left.as_i64(), right.as_i64()) {
+        let exact = match op {
+            Arithmetic::Add => l.checked_add(r),
+            Arithmetic::Sub => l.checked_sub(r),
+            Arithmetic::Mul => l.checked_mul(r),
+            Arithmetic::Div if r == 0 => bail!("division by zero"),
+            Arithmetic::Div => (l.checked_rem(r) == Some(0))
+                .then(|| l.checked_div(r))
+                .flatten(),
+        };
+        if let Some(result) = exact {
+            return Ok(result.into());
+        }
+    }
+    let (l, r) = (to_f64(left)?, to_f64(right)?);
+    let result = match op {
+        Arithmetic::Add => l + r,
+        Arithmetic::Sub => l - r,
+        Arithmetic::Mul => l * r,
+        Arithmetic::Div if r == 0.0 => bail!("division by zero"),
+        Arithmetic::Div => l / r,
+    };
+    Number::from_f64(result).ok_or_else(|| anyhow!("result is not a finite number"))
+}
+
+/// 绝对值，整数保持为整数
+pub fn abs_number(number: &Number) -> Number {
+    match number.as_i64().and_then(i64::checked_abs) {
+        Some(abs) => abs.into(),
+        None if number.is_u64() => number.clone(),
+        None => Number::from_f64(number.as_f64().unwrap_or_default().abs())
+            .unwrap_or_else(|| number.clone()),
+    }
+}
+
+/// 比较两个数字，整数之间精确比较，不经过 `f64`（大整数转换为 `f64` 会丢失精度）
+pub fn compare_numbers(left: &Number, right: &Number) -> Option<Ordering> {
+    match (left.as_i64(), right.as_i64(), left.as_u64(), right.as_u64()) {
+        (Some(l), Some(r), _, _) => Some(l.cmp(&r)),
+        (_, _, Some(l), Some(r)) => Some(l.cmp(&r)),
+        _ => left.as_f64()?.partial_cmp(&right.as_f64()?),
+    }
+}
+
+fn to_f64(number: &Number) -> Result<f64> {
+    number
+        .as_f64()
+        .ok_or_else(|| anyhow!("{number} cannot be represented as a float"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn number(value: serde_json::Value) -> Number {
+        match value {
+            serde_json::Value::Number(number) => number,
+            other => panic!("not a number: {other}"),
+        }
+    }
+
+    fn calc(op: Arithmetic, left: serde_json::Value, right: serde_json::Value) -> String {
+        apply_arithmetic(op, &number(left), &number(right))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_integer_operations_return_integers() {
+        assert_eq!(calc(Arithmetic::Add, json!(2), json!(2)), "4");
+        assert_eq!(calc(Arithmetic::Sub, json!(2), json!(5)), "-3");
+        assert_eq!(calc(Arithmetic::Mul, json!(6), json!(7)), "42");
+        assert_eq!(calc(Arithmetic::Div, json!(8), json!(2)), "4");
+        // 超出 f64 精确表示范围的整数不丢失精度
+        assert_eq!(
+            calc(Arithmetic::Add, json!(9_007_199_254_740_993_i64), json!(2)),
+            "9007199254740995"
+        );
+        // 除不尽或溢出时改用浮点数
+        assert_eq!(calc(Arithmetic::Div, json!(7), json!(2)), "3.5");
+        assert_eq!(
+            calc(Arithmetic::Mul, json!(i64::MAX), json!(2)),
+            "1.8446744073709552e19"
+        );
+    }
+
+    #[test]
+    fn test_float_operations_keep_precision() {
+        assert_eq!(
+            calc(Arithmetic::Add, json!(0.1), json!(0.2)),
+            "0.30000000000000004"
+        );
+        assert_eq!(calc(Arithmetic::Mul, json!(1.5), json!(2)), "3.0");
+        assert_eq!(
+            calc(Arithmetic::Div, json!(1), json!(3.0)),
+            "0.3333333333333333"
+        );
+
+        let err = apply_arithmetic(Arithmetic::Div, &number(json!(1)), &number(json!(0)));
+        assert_eq!(err.unwrap_err().to_string(), "division by zero");
+    }
+
+    #[test]
+    fn test_abs_and_compare() {
+        assert_eq!(abs_number(&number(json!(-3))).to_string(), "3");
+        assert_eq!(abs_number(&number(json!(-2.5))).to_string(), "2.5");
+        assert_eq!(
+            compare_numbers(
+                &number(json!(9_007_199_254_740_993_i64)),
+                &number(json!(9_007_199_254_740_992_i64))
+            ),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            compare_numbers(&number(json!(1)), &number(json!(1.5))),
+            Some(Ordering::Less)
+        );
+    }
+}
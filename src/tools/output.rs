@@ -0,0 +1,150 @@
+use std::io::{self, Write};
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// 工具输出中 JSON 值的序列化限制
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsonOutputLimits {
+    /// 允许的最大嵌套深度，超出时返回错误而不是继续序列化
+    pub max_depth: usize,
+    /// 序列化结果的最大字节数，超出部分被截断
+    pub max_bytes: usize,
+}
+
+impl Default for JsonOutputLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// 按限制将 JSON 值序列化为工具输出
+///
+/// 先以非递归方式检查嵌套深度，超出时直接报错，避免递归序列化耗尽栈空间；
+/// 序列化时一旦超过 `max_bytes` 立即停止，结果截断并附加说明，不会先生成完整的巨大字符串。
+pub fn serialize_json_output(value: &Value, limits: &JsonOutputLimits) -> Result<String> {
+    let depth = json_depth(value, limits.max_depth);
+    if depth > limits.max_depth {
+        bail!(
+            "JSON output is nested deeper than {} levels; return a flatter structure",
+            limits.max_depth
+        );
+    }
+
+    let mut writer = LimitedWriter {
+        buffer: Vec::new(),
+        limit: limits.max_bytes,
+    };
+    let truncated = serde_json::to_writer(&mut writer, value).is_err();
+    let mut output = String::from_utf8_lossy(&writer.buffer).into_owned();
+    if truncated {
+        // 截断处可能落在多字节字符中间，from_utf8_lossy 会将其替换为 U+FFFD
+        output.truncate(output.trim_end_matches('\u{FFFD}').len());
+        output.push_str(&format!(
+            "... [truncated: JSON output exceeds {} bytes]",
+            limits.max_bytes
+        ));
+    }
+    Ok(output)
+}
+
+/// 计算嵌套深度（标量为 0），超过 `limit` 后不再继续遍历
+fn json_depth(value: &Value, limit: usize) -> usize {
+    let mut max_depth = 0;
+    let mut stack = vec![(value, 0)];
+    while let Some((value, depth)) = stack.pop() {
+        let children: Box<dyn Iterator<Item = &Value>> = match value {
+            Value::Array(items) => Box::new(items.iter()),
+            Value::Object(map) => Box::new(map.values()),
+            _ => continue,
+        };
+        let depth = depth + 1;
+        max_depth = max_depth.max(depth);
+        if max_depth > limit {
+            break;
+        }
+        stack.extend(children.map(|child| (child, depth)));
+    }
+    max_depth
+}
+
+/// 写入超过上限时返回错误，使序列化提前结束
+struct LimitedWriter {
+    buffer: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = self.limit - self.buffer.len();
+        if buf.len() > remaining {
+            self.buffer.extend_from_slice(&buf[..remaining]);
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "output limit reached",
+            ));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_small_values_are_serialized_unchanged() {
+        let value = json!({"a": [1, 2, {"b": "c"}]});
+        let output = serialize_json_output(&value, &JsonOutputLimits::default()).unwrap();
+        assert_eq!(output, value.to_string());
+    }
+
+    #[test]
+    fn test_deeply_nested_value_is_rejected() {
+        let mut value = json!("leaf");
+        for _ in 0..1000 {
+            value = json!([value]);
+        }
+        let err = serialize_json_output(&value, &JsonOutputLimits::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "JSON output is nested deeper than 64 levels; return a flatter structure"
+        );
+
+        // 恰好处于上限时仍然允许
+        let mut value = json!(1);
+        for _ in 0..3 {
+            value = json!({"k": value});
+        }
+        let limits = JsonOutputLimits {
+            max_depth: 3,
+            ..Default::default()
+        };
+        assert!(serialize_json_output(&value, &limits).is_ok());
+    }
+
+    #[test]
+    fn test_huge_value_is_truncated() {
+        let value = Value::Array(vec![json!("数据"); 10_000]);
+        let limits = JsonOutputLimits {
+            max_bytes: 101,
+            ..Default::default()
+        };
+        let output = serialize_json_output(&value, &limits).unwrap();
+        let (kept, note) = output.split_once("...").unwrap();
+        assert!(kept.len() <= 101);
+        assert!(kept.starts_with("[\"数据\",\"数据\""));
+        assert!(!kept.contains('\u{FFFD}'));
+        assert_eq!(note, " [truncated: JSON output exceeds 101 bytes]");
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+/// JSON Schema 中合法的基本类型
+const SCHEMA_TYPES: &[&str] = &[
+    "string", "number", "integer", "boolean", "object", "array", "null",
+];
+
+/// 检查工具的参数 schema 是否为结构合法的 JSON Schema
+///
+/// 只检查函数定义中常用的关键字（`type`、`properties`、`required`、`items`、`enum`、
+/// `anyOf`/`oneOf`/`allOf`、`additionalProperties`、`description`），未知关键字原样放过。
+/// 顶层必须是对象类型的 schema，这是各服务商对函数参数的共同要求。
+/// 出错时返回以 JSON Pointer 指明位置的错误，如 `/properties/count/type: unknown type "int"`。
+pub fn validate_schema(schema: &Value) -> Result<()> {
+    let Some(root) = schema.as_object() else {
+        bail!("/: schema must be an object");
+    };
+    if let Some(ty) = root.get("type") {
+        if ty != "object" {
+            bail!("/type: the top-level schema must have type \"object\", got {ty}");
+        }
+    }
+    check_schema(root, "")
+}
+
+fn check_schema(schema: &Map<String, Value>, path: &str) -> Result<()> {
+    if let Some(ty) = schema.get("type") {
+        let types = match ty {
+            Value::String(_) => std::slice::from_ref(ty),
+            Value::Array(types) if !types.is_empty() => types.as_slice(),
+            _ => bail!("{path}/type: must be a type name or a non-empty array of type names"),
+        };
+        for ty in types {
+            match ty.as_str() {
+                Some(name) if SCHEMA_TYPES.contains(&name) => {}
+                _ => bail!("{path}/type: unknown type {ty}"),
+            }
+        }
+    }
+    if let Some(description) = schema.get("description") {
+        if !description.is_string() {
+            bail!("{path}/description: must be a string");
+        }
+    }
+
+    let properties = match schema.get("properties") {
+        None => None,
+        Some(Value::Object(properties)) => {
+            for (name, property) in properties {
+                check_subschema(property, &format!("{path}/properties/{name}"))?;
+            }
+            Some(properties)
+        }
+        Some(_) => bail!("{path}/properties: must be an object"),
+    };
+    if let Some(required) = schema.get("required") {
+        let Some(required) = required.as_array() else {
+            bail!("{path}/required: must be an array of property names");
+        };
+        for name in required {
+            let Some(name) = name.as_str() else {
+                bail!("{path}/required: must be an array of property names");
+            };
+            if properties.is_some_and(|properties| !properties.contains_key(name)) {
+                bail!("{path}/required: \"{name}\" is not defined in properties");
+            }
+        }
+    }
+
+    match schema.get("items") {
+        None => {}
+        Some(Value::Array(items)) => {
+            for (index, item) in items.iter().enumerate() {
+                check_subschema(item, &format!("{path}/items/{index}"))?;
+            }
+        }
+        Some(items) => check_subschema(items, &format!("{path}/items"))?,
+    }
+    if let Some(additional) = schema.get("additionalProperties") {
+        check_subschema(additional, &format!("{path}/additionalProperties"))?;
+    }
+    for keyword in ["anyOf", "oneOf", "allOf"] {
+        let Some(schemas) = schema.get(keyword) else {
+            continue;
+        };
+        match schemas.as_array() {
+            Some(schemas) if !schemas.is_empty() => {
+                for (index, subschema) in schemas.iter().enumerate() {
+                    check_subschema(subschema, &format!("{path}/{keyword}/{index}"))?;
+                }
+            }
+            _ => bail!("{path}/{keyword}: must be a non-empty array of schemas"),
+        }
+    }
+    if let Some(values) = schema.get("enum") {
+        if values.as_array().is_none_or(Vec::is_empty) {
+            bail!("{path}/enum: must be a non-empty array");
+        }
+    }
+    Ok(())
+}
+
+fn check_subschema(schema: &Value, path: &str) -> Result<()> {
+    match schema {
+        Value::Object(schema) => check_schema(schema, path),
+        // `true`/`false` 是合法的 schema，分别表示接受与拒绝任意值
+        Value::Bool(_) => Ok(()),
+        _ => bail!("{path}: schema must be an object or a boolean"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn error(schema: Value) -> String {
+        validate_schema(&schema).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_valid_schemas_pass() {
+        validate_schema(&json!({})).unwrap();
+        validate_schema(&json!({
+            "type": "object",
+            "description": "calculator input",
+            "properties": {
+                "op": {"type": "string", "enum": ["add", "sub"]},
+                "values": {"type": "array", "items": {"type": ["number", "null"]}},
+                "options": {
+                    "type": "object",
+                    "additionalProperties": {"anyOf": [{"type": "string"}, true]}
+                }
+            },
+            "required": ["op", "values"],
+            "additionalProperties": false
+        }))
+        .unwrap();
+
+        // 内置工具的 schema 都应通过检查
+        let builtin: [Box<dyn crate::tools::Tool>; 4] = [
+            Box::new(crate::tools::JsonTool::new()),
+            Box::new(crate::tools::ClockTool::new()),
+            Box::new(crate::tools::ScratchpadTool::new()),
+            Box::new(crate::tools::AskUserTool::new()),
+        ];
+        for tool in builtin {
+            if let Some(schema) = tool.args_schema() {
+                validate_schema(&schema).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_schemas_report_the_location() {
+        assert_eq!(error(json!("object")), "/: schema must be an object");
+        assert_eq!(
+            error(json!({"type": "array"})),
+            "/type: the top-level schema must have type \"object\", got \"array\""
+        );
+        assert_eq!(
+            error(json!({"properties": {"count": {"type": "int"}}})),
+            "/properties/count/type: unknown type \"int\""
+        );
+        assert_eq!(
+            error(json!({"properties": {"a": {"type": "string"}}, "required": ["b"]})),
+            "/required: \"b\" is not defined in properties"
+        );
+        assert_eq!(
+            error(json!({"properties": {"list": {"items": "string"}}})),
+            "/properties/list/items: schema must be an object or a boolean"
+        );
+        assert_eq!(
+            error(json!({"properties": {"mode": {"enum": []}}})),
+            "/properties/mode/enum: must be a non-empty array"
+        );
+        assert_eq!(
+            error(json!({"properties": []})),
+            "/properties: must be an object"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::{Tool, ToolArgs};
+
+/// 供模型保存中间结果的草稿本工具，内容在多轮对话间保留且不进入提示词
+///
+/// 支持的操作（`op` 参数）：
+/// - `write`: 将 `value` 写入 `key`，覆盖旧值
+/// - `read`: 读取 `key` 的值
+/// - `list`: 列出已有的全部 key
+/// - `delete`: 删除 `key`
+///
+/// 克隆的工具共享同一份内容，调用方可以保留一份克隆来查看模型写入的数据。
+#[derive(Debug, Clone, Default)]
+pub struct ScratchpadTool {
+    entries: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl ScratchpadTool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 当前全部内容的快照
+    pub fn entries(&self) -> HashMap<String, String> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Tool for ScratchpadTool {
+    fn name(&self) -> String {
+        "scratchpad".to_string()
+    }
+
+    fn description(&self) -> Option<String> {
+        Some(
+            "A persistent key-value scratchpad for intermediate results. \
+             Use op=write with key and value to save, op=read with key to load, \
+             op=list to see saved keys, op=delete with key to remove"
+                .to_string(),
+        )
+    }
+
+    fn args_schema(&self) -> Option<Value> {
+        Some(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "op": {
+                    "type": "string",
+                    "enum": ["write", "read", "list", "delete"]
+                },
+                "key": {
+                    "type": "string",
+                    "description": "Required for write, read and delete"
+                },
+                "value": {
+                    "type": "string",
+                    "description": "The value to save, required for write"
+                }
+            },
+            "required": ["op"]
+        }))
+    }
+
+    async fn execute(&self, args: Value) -> Result<String> {
+        let args = ToolArgs::new(self.name(), args);
+        let op = args.require_str("op")?;
+        let mut entries = self.entries.lock().unwrap();
+        match op {
+            "write" => {
+                let key = args.require_str("key")?;
+                // 非字符串的值按 JSON 保存
+                let value = match args.as_value().get("value") {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(Value::Null) | None => {
+                        bail!("tool `scratchpad`: missing required argument `value`")
+                    }
+                    Some(value) => value.to_string(),
+                };
+                entries.insert(key.to_string(), value);
+                Ok(format!("saved `{key}`"))
+            }
+            "read" => {
+                let key = args.require_str("key")?;
+                match entries.get(key) {
+                    Some(value) => Ok(value.clone()),
+                    None => bail!("no scratchpad entry named `{key}`"),
+                }
+            }
+            "list" => {
+                let mut keys: Vec<_> = entries.keys().cloned().collect();
+                keys.sort();
+                Ok(serde_json::to_string(&keys)?)
+            }
+            "delete" => {
+                let key = args.require_str("key")?;
+                match entries.remove(key) {
+                    Some(_) => Ok(format!("deleted `{key}`")),
+                    None => bail!("no scratchpad entry named `{key}`"),
+                }
+            }
+            other => bail!("unknown scratchpad op `{other}`, expected write, read, list or delete"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_scratchpad_write_then_read() {
+        let tool = ScratchpadTool::new();
+        let handle = tool.clone();
+
+        let output = tool
+            .execute(json!({"op": "write", "key": "subtotal", "value": "42.50"}))
+            .await
+            .unwrap();
+        assert_eq!(output, "saved `subtotal`");
+        tool.execute(json!({"op": "write", "key": "items", "value": [1, 2]}))
+            .await
+            .unwrap();
+
+        let value = tool
+            .execute(json!({"op": "read", "key": "subtotal"}))
+            .await
+            .unwrap();
+        assert_eq!(value, "42.50");
+        let keys = tool.execute(json!({"op": "list"})).await.unwrap();
+        assert_eq!(keys, r#"["items","subtotal"]"#);
+        assert_eq!(handle.entries()["items"], "[1,2]");
+
+        let err = tool
+            .execute(json!({"op": "read", "key": "total"}))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no scratchpad entry named `total`");
+    }
+}
@@ -0,0 +1,74 @@
+//! 工具调用 id 生成器
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// 为需要自行合成的工具调用生成唯一的 tool_call_id
+///
+/// 内部为单调递增的计数器，经种子混合后格式化为 `call_<16 位十六进制>`。同一生成器产出的 id
+/// 互不重复；`new` 使用随机种子，`seeded` 使用固定种子，相同种子的生成器产出相同的 id 序列，便于测试。
+/// 克隆的生成器共享同一个计数器。
+#[derive(Debug, Clone)]
+pub struct ToolCallIdGenerator {
+    seed: u64,
+    counter: Arc<AtomicU64>,
+}
+
+impl ToolCallIdGenerator {
+    pub fn new() -> Self {
+        Self::seeded(uuid::Uuid::new_v4().as_u64_pair().0)
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seed,
+            counter: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn next_id(&self) -> String {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        format!("call_{:016x}", mix(self.seed.wrapping_add(n)))
+    }
+}
+
+impl Default for ToolCallIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// splitmix64 的混合步骤，是 u64 上的双射，因此不同的计数值不会得到相同的 id
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_tool_call_ids_are_unique_and_reproducible_when_seeded() {
+        let generator = ToolCallIdGenerator::new();
+        let ids: HashSet<_> = (0..1000).map(|_| generator.next_id()).collect();
+        assert_eq!(ids.len(), 1000);
+        assert!(ids.iter().all(|id| id.starts_with("call_")));
+
+        let first = ToolCallIdGenerator::seeded(42);
+        let second = ToolCallIdGenerator::seeded(42);
+        let a: Vec<_> = (0..5).map(|_| first.next_id()).collect();
+        let b: Vec<_> = (0..5).map(|_| second.next_id()).collect();
+        assert_eq!(a, b);
+
+        // 克隆共享计数器，不会重复产出已用过的 id
+        let clone = first.clone();
+        assert!(!a.contains(&clone.next_id()));
+    }
+}
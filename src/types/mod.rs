@@ -1,8 +1,17 @@
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
 use std::time::Duration;
 
-pub type ToolCalls = HashMap<String, ToolCallArgs>;
+pub mod call_id;
+
+pub use call_id::ToolCallIdGenerator;
+
+/// tool_call_id => 调用参数，按模型发出调用的顺序排列
+pub type ToolCalls = IndexMap<String, ToolCallArgs>;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Message {
@@ -25,6 +34,52 @@ pub enum Message {
     },
 }
 
+impl Message {
+    /// 消息的角色名，与 OpenAI 的 role 字段一致
+    pub fn role(&self) -> &'static str {
+        match self {
+            Message::Developer { .. } => "developer",
+            Message::System { .. } => "system",
+            Message::User { .. } => "user",
+            Message::Assistant { .. } => "assistant",
+            Message::Tool { .. } => "tool",
+        }
+    }
+
+    /// 消息的文本内容
+    pub fn content(&self) -> &str {
+        match self {
+            Message::Developer { content }
+            | Message::System { content }
+            | Message::User { content }
+            | Message::Assistant { content, .. }
+            | Message::Tool { content, .. } => content,
+        }
+    }
+}
+
+/// 存储消息的可见性：决定消息是否发送给模型、是否出现在面向用户的历史中
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Visibility {
+    /// 模型与用户均可见
+    #[default]
+    All,
+    /// 仅保存，不发送给模型（如调试备注）
+    HiddenFromModel,
+    /// 发送给模型，但不展示给用户（如提示脚手架）
+    HiddenFromUser,
+}
+
+impl Visibility {
+    pub fn is_visible_to_model(self) -> bool {
+        self != Visibility::HiddenFromModel
+    }
+
+    pub fn is_visible_to_user(self) -> bool {
+        self != Visibility::HiddenFromUser
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ToolCallArgs {
     pub tool_type: String,
@@ -49,24 +104,283 @@ pub struct ToolExecutionResult {
     pub failure_result: HashMap<String, String>,
 }
 
+/// 一次 `Agent::handle_message` 中按顺序发生的决策与工具执行结果，用于调试 Agent 的推理过程
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TurnTrace {
+    pub steps: Vec<TraceStep>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TraceStep {
+    /// 模型返回的决策
+    Decision(Decision),
+    /// 一轮工具调用的执行结果
+    ToolResults(ToolExecutionResult),
+}
+
+impl TurnTrace {
+    /// 按顺序返回全部决策
+    pub fn decisions(&self) -> impl Iterator<Item = &Decision> {
+        self.steps.iter().filter_map(|step| match step {
+            TraceStep::Decision(decision) => Some(decision),
+            TraceStep::ToolResults(_) => None,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AgentConfig {
     pub system_prompt: String,
+    /// 分层的系统指令（如基础人设、任务说明、安全规则），按优先级从高到低排列，
+    /// 每次请求时作为独立的 system 消息依次放在上下文最前面；不写入短期记忆，因此不会被裁剪，
+    /// 其占用的 token 会从裁剪预算中预先扣除
+    pub system_layers: Vec<String>,
+    /// 一次处理中最多执行的工具调用轮数，模型在此之后仍要求调用工具时中止并返回错误
     pub max_turns: usize,
+    /// 既是每次请求的输出上限，也是短期记忆裁剪上下文的 token 预算；`None` 表示不限制
+    /// （裁剪时退回到模型的默认预算）。`Some(0)` 会被服务商拒绝且会裁掉全部上下文，
+    /// 因此 `validate` 将其视为无效配置
     pub max_tokens: Option<usize>,
+    /// 一轮中的多个工具调用是否同时执行，对 `Agent::handle_message` 与流式处理同样生效。
+    /// 同时执行时 `ToolFailureMode::FailFast` 不再跳过失败之后的调用，但仍会丢弃整轮的成功结果
     pub enable_parallel: bool,
     pub retry_config: RetryConfig,
     pub temperature: f32,
+    /// 每次重试时温度的变化量（可为负），用于让重试得到不同的输出；结果限制在 [0, 2] 内
+    pub temperature_step: f32,
     pub timeout: Duration,
+    /// 一次完整处理（所有 LLM 请求与工具执行）的总时限，超时后中止并恢复为 Ready；
+    /// `timeout` 只限制单次 LLM 请求。流式处理从创建流时开始计时，超时后流以错误结束
+    pub turn_timeout: Option<Duration>,
+    /// 一次多轮处理（一条消息引发的全部 LLM 请求与工具轮次）的总时限，从第一次 LLM 请求前开始计时；
+    /// 超过后无论处于哪一步都立即中止并恢复为 Ready，下一条消息重新计时。
+    /// 对 `Agent::handle_message` 与流式处理同样生效
+    pub conversation_timeout: Option<Duration>,
+    /// 工具执行超过该时长时不再等待，以其已通过 `Tool::execute_stream` 产出的部分输出
+    /// 作为结果发起后续请求；为 `None` 时等待工具执行完成
+    pub partial_tool_output_after: Option<Duration>,
+    /// 工具调用的置信度阈值：调用参数中的数值字段 `confidence` 低于该值时不执行，
+    /// 而是让模型先向用户确认；参数中没有该字段的调用不受影响
+    pub tool_confidence_threshold: Option<f64>,
+    /// 同时包含文本与工具调用的决策，其 assistant 文本与工具结果在历史中的先后顺序
+    pub tool_message_order: ToolMessageOrder,
+    /// Agent 的最大嵌套深度：工具内部再调用 Agent 时，每深入一层加 1，超过时子 Agent 直接报错
+    pub max_depth: usize,
+    /// 预测输出的参考内容，设置后随每次请求发送（见 `RequestOptions::prediction`）
+    pub prediction: Option<String>,
+    /// 是否在发送的系统提示后附加自动生成的工具清单（名称、描述与参数 schema），
+    /// 清单在每次请求时根据当前注册的工具生成
+    pub include_tool_manifest: bool,
+    /// 原样合并到请求体中的额外参数（如 `top_p` 或服务商特有的字段），与内置字段冲突时以此处为准
+    pub extra_params: serde_json::Map<String, serde_json::Value>,
+    /// 核采样概率阈值，取值范围 [0, 1]
+    pub top_p: Option<f32>,
+    /// 频率惩罚，取值范围 [-2, 2]
+    pub frequency_penalty: Option<f32>,
+    /// 存在惩罚，取值范围 [-2, 2]
+    pub presence_penalty: Option<f32>,
+    /// token id（字符串形式）=> 偏置值，取值范围 [-100, 100]；负值抑制、正值鼓励该 token 出现
+    pub logit_bias: Option<HashMap<String, f32>>,
+    /// 流式回复中途因可重试的错误（如连接断开）中断时，是否重新发起请求续写：
+    /// 已收到的部分回复作为末尾的 assistant 消息（prefill）发送，新的输出接在其后，
+    /// 需要服务端支持从末尾的 assistant 消息续写。只适用于不含工具调用的回复，
+    /// 与首个片段之前的重试共用 `RetryConfig::max_retries` 次数
+    pub resume_interrupted_streams: bool,
+    /// 流式输出时单轮回复累积的最大字节数，超过后流以错误结束；默认为 None，不限制
+    pub max_stream_response_bytes: Option<usize>,
+    /// `Agent::handle_message_channel` 使用的 channel 容量，消费者落后时最多缓冲这么多个片段
+    pub stream_channel_capacity: usize,
+    /// 事件流在执行工具前是否先产出 `StreamEvent::AwaitingToolApproval` 并等待调用方批准
+    pub require_tool_approval: bool,
+    /// 最终回复的校验器，校验失败时会附上错误信息让模型重新回答
+    pub response_validator: Option<ResponseValidator>,
+    /// 校验失败后最多重新询问的次数，用尽后返回校验错误
+    pub max_validation_retries: usize,
+    /// `handle_message` 的最终回复以文本形式提到调用某个已注册工具（如 `calculator(2+2)`）时，
+    /// 要求模型改用结构化的工具调用重新回答；与校验共用 `max_validation_retries` 次数，用尽后原样返回
+    pub reprompt_text_tool_calls: bool,
+    /// `handle_message` 的最终回复没有用到本轮最近一次工具输出中的任何内容时，提醒模型使用工具结果并重新询问一次
+    pub reprompt_ignored_tool_results: bool,
+    /// 工具输出的估算 token 数超过该值时，先由 LLM 概括到该预算之内再写入上下文
+    pub summarize_tool_output_over: Option<usize>,
+    /// 连续多少轮完全相同的工具调用视为死循环：达到该轮数时要求模型停止调用工具，
+    /// 仍然重复则返回错误；默认为 None，不检测
+    pub tool_loop_window: Option<usize>,
+    /// 本次对话使用的系统提示词版本，写入每条审计记录，便于离线对比不同版本的效果
+    pub prompt_version: Option<String>,
+    /// 一轮中有工具调用失败时的处理方式
+    pub tool_failure_mode: ToolFailureMode,
+    /// `Agent::build_memory_context` 最多注入的记忆条数
+    pub memory_recall_limit: usize,
+    /// `Agent::build_memory_context` 召回记忆的最低相似度
+    pub memory_min_similarity: f32,
+    /// 每次记录 assistant 消息后以完整历史调用，返回 true 时立即结束本次处理并返回该条回复
+    pub stop_condition: Option<StopCondition>,
+    /// 处于 `AgentState::Error` 时，下一条消息是否自动恢复为 `Ready`（记录此前的错误）；
+    /// 关闭时需要显式调用 `Agent::reset`
+    pub auto_recover_from_error: bool,
+    /// 服务商返回“不支持工具调用”时，是否改为不带工具重新请求：
+    /// 工具清单以文本形式附加到系统提示中，由模型以纯文本作答
+    pub fallback_without_tools: bool,
+    /// 模型的最终回复为空（没有内容也没有工具调用）时的处理方式，对 `Agent::handle_message` 与流式处理同样生效
+    pub on_empty_response: EmptyResponsePolicy,
+    /// 是否要求每一步只能是“恰好一个工具调用”或“最终回复”之一；
+    /// 流式处理时已经输出的文本无法撤回，只是不写入记忆
+    pub strict_step_mode: StrictStepMode,
+}
+
+/// 校验 assistant 最终回复的函数（如 JSON schema 校验），返回的错误会反馈给模型
+#[derive(Clone)]
+pub struct ResponseValidator(Arc<ValidatorFn>);
+
+type ValidatorFn = dyn Fn(&str) -> anyhow::Result<()> + Send + Sync;
+
+impl ResponseValidator {
+    pub fn new<F>(validator: F) -> Self
+    where
+        F: Fn(&str) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        Self(Arc::new(validator))
+    }
+
+    pub fn validate(&self, response: &str) -> anyhow::Result<()> {
+        (self.0)(response)
+    }
+}
+
+impl fmt::Debug for ResponseValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseValidator")
+    }
+}
+
+/// 对话级的停止条件，如回复中出现约定的结束语或调用过某个工具
+#[derive(Clone)]
+pub struct StopCondition(Arc<StopFn>);
+
+type StopFn = dyn Fn(&[Message]) -> bool + Send + Sync;
+
+impl StopCondition {
+    pub fn new<F>(condition: F) -> Self
+    where
+        F: Fn(&[Message]) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(condition))
+    }
+
+    pub fn should_stop(&self, messages: &[Message]) -> bool {
+        (self.0)(messages)
+    }
+}
+
+impl fmt::Debug for StopCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StopCondition")
+    }
+}
+
+/// 每次 `handle_message` 结束后产生的审计记录
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditRecord {
+    pub conversation_id: String,
+    pub prompt_version: Option<String>,
+    /// 本次对话中的第几轮用户消息，从 1 开始
+    pub turn: usize,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// 处理失败时的错误信息
+    pub error: Option<String>,
+    /// 调用方通过 `RequestContext` 传入的请求 id
+    pub request_id: Option<String>,
+    /// 调用方通过 `RequestContext` 传入的标签
+    pub tags: BTreeMap<String, String>,
+}
+
+/// 单次 `handle_message` 调用的元数据，会写入 tracing span 与审计记录，用于跨服务关联日志
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RequestContext {
+    pub request_id: Option<String>,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl RequestContext {
+    pub fn new(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: Some(request_id.into()),
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// 添加一个标签
+    pub fn with_tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// assistant 文本与工具结果消息的排列策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToolMessageOrder {
+    /// 先记录 assistant 文本（连同工具调用），再记录工具结果
+    #[default]
+    AssistantThenTools,
+    /// 先记录工具调用与工具结果，再将 assistant 文本作为单独的消息记录
+    ToolsThenAssistant,
+}
+
+/// 一轮工具调用中部分调用失败时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToolFailureMode {
+    /// 其余调用照常执行，成功的结果正常交给模型
+    #[default]
+    Continue,
+    /// 全有或全无：任一调用失败后不再执行剩余调用，已成功的结果也被丢弃，整轮按失败告知模型
+    FailFast,
+}
+
+/// 模型返回空的最终回复时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyResponsePolicy {
+    /// 照常记录并返回空字符串
+    #[default]
+    ReturnEmpty,
+    /// 不记录空回复，以相同的上下文重新请求，最多 `RetryConfig::max_retries` 次，用尽后返回错误
+    Retry,
+    /// 返回错误
+    Error,
+}
+
+/// 严格步骤模式：模型的一次决策同时包含文本与工具调用，或包含多个工具调用时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StrictStepMode {
+    /// 不限制，文本与工具调用照常一起处理
+    #[default]
+    Off,
+    /// 只保留工具调用：丢弃同时给出的文本，多个调用时只执行模型发出的第一个
+    KeepToolCall,
+    /// 不记录该决策，附上被拒绝的回复要求模型重新回答，最多 `AgentConfig::max_validation_retries` 次，
+    /// 用尽后按 `KeepToolCall` 处理
+    Reask,
 }
 
 #[derive(Debug, Clone)]
 pub struct RetryConfig {
+    /// 单轮中向 LLM 发出请求的最大次数（含首次请求），同步与流式处理使用同一上限
     pub max_retries: usize,
+    /// 每次重试前等待的时长
     pub retry_delay: Duration,
+    /// LLM 请求失败或超时后是否重试；为 false 时直接返回错误。
+    /// `LlmError` 描述的确定性错误（认证失败、拒绝等）始终不重试
     pub should_retry_on_error: bool,
 }
 
+impl RetryConfig {
+    /// 已经重试 `retries` 次后是否还允许再发出一次请求
+    pub fn allows_retry(&self, retries: usize) -> bool {
+        retries + 1 < self.max_retries
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum AgentState {
     Ready,
@@ -80,6 +394,7 @@ impl Default for AgentConfig {
     fn default() -> Self {
         Self {
             system_prompt: "You are a helpful AI assistant.".to_string(),
+            system_layers: Vec::new(),
             max_turns: 10,
             max_tokens: Some(2048),
             enable_parallel: false,
@@ -89,8 +404,71 @@ impl Default for AgentConfig {
                 should_retry_on_error: true,
             },
             temperature: 0.7,
+            temperature_step: 0.0,
             timeout: Duration::from_secs(30),
+            turn_timeout: None,
+            conversation_timeout: None,
+            partial_tool_output_after: None,
+            tool_confidence_threshold: None,
+            tool_message_order: ToolMessageOrder::default(),
+            max_depth: 5,
+            prediction: None,
+            include_tool_manifest: false,
+            extra_params: serde_json::Map::new(),
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            logit_bias: None,
+            resume_interrupted_streams: false,
+            max_stream_response_bytes: None,
+            stream_channel_capacity: 32,
+            require_tool_approval: false,
+            response_validator: None,
+            max_validation_retries: 2,
+            reprompt_text_tool_calls: false,
+            reprompt_ignored_tool_results: false,
+            summarize_tool_output_over: None,
+            tool_loop_window: None,
+            prompt_version: None,
+            tool_failure_mode: ToolFailureMode::default(),
+            memory_recall_limit: 3,
+            memory_min_similarity: 0.5,
+            stop_condition: None,
+            auto_recover_from_error: false,
+            fallback_without_tools: false,
+            on_empty_response: EmptyResponsePolicy::default(),
+            strict_step_mode: StrictStepMode::default(),
+        }
+    }
+}
+
+impl AgentConfig {
+    /// 校验配置中各参数是否在允许的范围内
+    pub fn validate(&self) -> anyhow::Result<()> {
+        fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> anyhow::Result<()> {
+            match value {
+                Some(v) if !(min..=max).contains(&v) => {
+                    anyhow::bail!("{name} must be within [{min}, {max}], got {v}")
+                }
+                _ => Ok(()),
+            }
+        }
+        if self.max_tokens == Some(0) {
+            anyhow::bail!("max_tokens must be greater than 0; use None for no limit");
+        }
+        check_range("temperature", Some(self.temperature), 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        for (token, bias) in self.logit_bias.iter().flatten() {
+            check_range(&format!("logit_bias[{token}]"), Some(*bias), -100.0, 100.0)?;
         }
+        Ok(())
+    }
+
+    /// 第 `retries` 次重试时使用的温度
+    pub fn temperature_for_retry(&self, retries: usize) -> f32 {
+        (self.temperature + self.temperature_step * retries as f32).clamp(0.0, 2.0)
     }
 }
 
@@ -110,4 +488,94 @@ mod tests {
 
         assert_eq!(message, deserialized);
     }
+
+    #[test]
+    fn test_config_validation_rejects_out_of_range_sampling_params() {
+        assert!(AgentConfig::default().validate().is_ok());
+
+        let config = AgentConfig {
+            top_p: Some(1.0),
+            frequency_penalty: Some(-2.0),
+            presence_penalty: Some(2.0),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        for config in [
+            AgentConfig {
+                top_p: Some(1.5),
+                ..Default::default()
+            },
+            AgentConfig {
+                frequency_penalty: Some(2.5),
+                ..Default::default()
+            },
+            AgentConfig {
+                presence_penalty: Some(-3.0),
+                ..Default::default()
+            },
+            AgentConfig {
+                top_p: Some(f32::NAN),
+                ..Default::default()
+            },
+        ] {
+            assert!(config.validate().is_err());
+        }
+
+        let err = AgentConfig {
+            presence_penalty: Some(-3.0),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "presence_penalty must be within [-2, 2], got -3"
+        );
+    }
+
+    #[test]
+    fn test_config_validation_rejects_zero_max_tokens() {
+        let err = AgentConfig {
+            max_tokens: Some(0),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "max_tokens must be greater than 0; use None for no limit"
+        );
+
+        for max_tokens in [None, Some(1)] {
+            let config = AgentConfig {
+                max_tokens,
+                ..Default::default()
+            };
+            assert!(config.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_config_validation_rejects_out_of_range_logit_bias() {
+        let config = AgentConfig {
+            logit_bias: Some(HashMap::from([
+                ("50256".to_string(), -100.0),
+                ("1234".to_string(), 100.0),
+            ])),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let err = AgentConfig {
+            logit_bias: Some(HashMap::from([("50256".to_string(), -150.0)])),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "logit_bias[50256] must be within [-100, 100], got -150"
+        );
+    }
 }